@@ -0,0 +1,24 @@
+function search() {
+    const input = document.getElementById('searchInput').value;
+    if (!input.trim()) return;
+
+    const currentUrl = new URL(window.location.href);
+    currentUrl.searchParams.set("q", input);
+    window.location.href = currentUrl.toString();
+}
+
+document.querySelector("#searchButton").addEventListener("click", search);
+
+document.querySelector("#searchInput").addEventListener("keyup", event => {
+    if (event.key !== "Enter") return;
+    document.querySelector("#searchButton").click();
+    event.preventDefault();
+});
+
+// Hide thumbnails that fail to load rather than showing a broken image. Images
+// that already failed before this script ran are caught by the second check.
+document.querySelectorAll(".thumbnail").forEach(image => {
+    const hide = () => image.remove();
+    image.addEventListener("error", hide);
+    if (image.complete && image.naturalWidth === 0) hide();
+});
@@ -1,4 +1,9 @@
+use anyhow::Context;
 use serde::Deserialize;
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+};
 
 #[derive(Clone, Debug, Deserialize)]
 pub struct Config {
@@ -10,16 +15,195 @@ pub struct Config {
 /// Server settings
 #[derive(Clone, Debug, Deserialize)]
 pub struct ServerConfig {
+    /// IP address to listen on, e.g. "127.0.0.1" for local connections only.
+    pub bind_address: String,
+    /// Port to listen on. The `PORT` environment variable takes precedence.
+    pub port: u16,
     pub name: String,
+    /// Description shown on the `/about` page. May contain HTML.
+    pub about: String,
     pub results_per_query: usize,
+    /// Largest number of results API clients may request with `n`. Larger requests are rejected
+    /// with 400.
+    pub max_results_per_query: usize,
+    /// Allow users to disable safe search per request with `?safe=0`.
+    pub allow_disabling_safe_search: bool,
+    /// Number of threads used to generate result snippets per search. 1 disables parallelism.
+    pub snippet_threads: usize,
+    /// Allow crawling domains on demand with `POST /api/crawl`.
+    pub enable_crawl_api: bool,
+    /// Pages to show on the home page when there is no query.
+    pub empty_query_behavior: EmptyQueryBehavior,
+    /// Maximum number of highlighted body fragments per snippet, joined with "…".
+    pub snippet_fragments: usize,
+    /// Maximum length of each snippet fragment. Longer fragments are cut at a word boundary.
+    pub snippet_max_chars: usize,
+    /// Where a result's snippet is taken from, in order of preference. The first source matching
+    /// the query is used.
+    pub snippet_preference: Vec<SnippetSource>,
+    /// Skip snippets without words or identical to the title, falling back to the next source
+    /// in `snippet_preference`, or else to the start of the description or body.
+    pub collapse_redundant_snippets: bool,
+    /// Maximum number of related searches suggested below the results. 0 disables them.
+    pub related_searches: usize,
+    /// Maximum number of domains with the most matches shown next to the results as `site:`
+    /// filters. 0 disables them.
+    pub domain_facets: usize,
+    /// Number of the user's recent searches kept in a cookie and shown on the home page. 0
+    /// disables them.
+    pub recent_searches: usize,
+    /// Query parameters (e.g. "utm_*" for a prefix) removed from result URLs before they are shown.
+    /// Stored URLs are unchanged.
+    pub hidden_url_params: Vec<String>,
+    /// Units for page and domain sizes on the stats page.
+    pub size_format: SizeFormat,
+    /// Served as our own `/robots.txt`.
+    pub robots_txt: String,
+    /// Requests with larger bodies are rejected with 413.
+    pub max_request_body_bytes: usize,
+    /// Requests taking longer than this are aborted with 408.
+    pub request_timeout_ms: u64,
+    /// `Content-Security-Policy` header of HTML pages. Empty disables it.
+    pub content_security_policy: String,
+    /// Route result links through `/click` to record which results are clicked for each query.
+    pub click_tracking: bool,
+    /// Serve the stored text of indexed pages at `/cached?url=...`, linked from each result.
+    /// Needs `store_body`.
+    pub cached_pages: bool,
+    /// Show only the highest-ranked result among results with the same title, e.g. mirrors.
+    pub dedupe_by_title: bool,
+    /// Show the `og:image` of pages that have one next to their result.
+    pub thumbnails: bool,
+    /// Results from these domains, or their subdomains, get a "Trusted" badge.
+    pub trusted_domains: Vec<String>,
+    /// Serve the results of a query as an Atom feed at `/feed?q=...`.
+    pub feeds: bool,
+    /// How long clients may cache feeds, in seconds.
+    pub feed_ttl_secs: u64,
+    /// Redirect `/lucky?q=...` to the top result of the query.
+    pub lucky: bool,
+    /// Allow grouping results by top-level domain with `?group=tld`.
+    pub tld_grouping: bool,
+    /// Show the number of results and how long the search took above the results.
+    pub show_result_count: bool,
+    /// Show how long each phase of a search took (parsing, collection and snippets) on the web
+    /// page and in API responses, for tuning.
+    pub search_timings: bool,
+    /// Include the query terms each result matched as `matched_terms` in API responses, e.g. for
+    /// highlighting on the client.
+    pub matched_terms: bool,
+    /// Allow `?mode=title`, which matches queries against page titles only. Faster and more
+    /// precise for navigational queries like the name of a page.
+    pub title_search: bool,
+    /// Serve the terms of indexed fields with their document frequencies at `/api/vocabulary`.
+    pub vocabulary_export: bool,
+    /// The lowest `min_freq` for `/api/vocabulary`, and its default.
+    pub vocabulary_min_freq: u64,
+    /// Directory of templates that replace the default ones of the same name, e.g. a themed
+    /// `index.html`. Templates it doesn't have are taken from `templates/`.
+    pub theme_dir: Option<String>,
+    /// API keys with their daily request quotas. If any are set, `/api/*` requests need one of
+    /// them in an `X-API-Key` or `Authorization: Bearer` header. Empty leaves the API open.
+    pub api_keys: HashMap<String, u64>,
+}
+
+/// What to show when the query is empty.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum EmptyQueryBehavior {
+    /// Just the search box.
+    None,
+    /// The most recently crawled pages.
+    Recent,
+    /// Random pages.
+    Random,
+}
+
+/// How sizes are displayed.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SizeFormat {
+    /// Base 1000, e.g. "1.50 kB".
+    Decimal,
+    /// Base 1024, e.g. "1.46 KiB".
+    Binary,
+}
+
+impl SizeFormat {
+    pub fn format_size(self, size: u64) -> String {
+        match self {
+            SizeFormat::Decimal => humansize::format_size(size, humansize::DECIMAL),
+            SizeFormat::Binary => humansize::format_size(size, humansize::BINARY),
+        }
+    }
 }
 
 /// Crawler settings
-#[derive(Clone, Debug, Deserialize)]
+#[derive(Clone, Debug, Deserialize, PartialEq)]
 pub struct CrawlerConfig {
     pub domains_file: String,
     pub log_interval_per_domain: u32,
     pub max_pages_per_domain: u32,
+    /// Maximum number of pages per crawl across all domains. Once reached, the remaining domains
+    /// are not crawled. `None` means no limit.
+    pub max_total_pages: Option<u64>,
+    /// Maximum duration of a full crawl in seconds. Once exceeded, running domain crawls are
+    /// stopped and the server starts with what was indexed. `None` means no limit.
+    pub max_crawl_duration_secs: Option<u64>,
+    /// Log a warning when the queue of crawled pages waiting for the indexer stays nearly full
+    /// for this many seconds, i.e. the indexer can't keep up. 0 disables the warning.
+    pub backlog_warning_secs: u64,
+    /// Start fewer domain crawls at a time while the indexer can't keep up, and more again once
+    /// it has caught up. Uses the same threshold as `backlog_warning_secs`, so that must not be 0.
+    pub throttle_on_backlog: bool,
+    /// Append the URL, domain and HTML of each crawled page to this file as JSON lines, keeping
+    /// the raw crawl for external tools. The search engine itself never reads the file.
+    pub save_crawl_to: Option<String>,
+    /// Store pages of `www.example.com` under the domain `example.com`, so both hosts share
+    /// stats.
+    pub strip_www: bool,
+    /// Drop crawled pages whose host isn't the domain being crawled before indexing them, in
+    /// case the crawler follows links to other hosts. `www.` is ignored when comparing hosts.
+    pub strict_domain: bool,
+    /// How pages of a domain are fetched. Both orders visit pages level by level from the root,
+    /// so shallow pages are preferred under `max_pages_per_domain`.
+    pub crawl_order: CrawlOrder,
+    /// Crawl the domains of `domains_file` in random order, so an interrupted crawl covers a
+    /// representative sample rather than the first domains of the file.
+    pub shuffle_domains: bool,
+    /// Seed for `shuffle_domains`, for a reproducible order. `None` picks a new order each crawl.
+    pub shuffle_seed: Option<u64>,
+    /// Skip the domains of `domains_file` that are already in the stats DB, and crawl into the
+    /// existing index even without `new_index`, so new domains can be added cheaply.
+    pub skip_indexed_domains: bool,
+    /// Domains with fewer indexed pages than this are crawled again with `skip_indexed_domains`.
+    pub skip_indexed_domains_min_pages: u64,
+    /// How many past crawls are kept in the stats DB, shown on `/crawls`. Older crawls are
+    /// dropped. 0 keeps no history.
+    pub crawl_history_size: usize,
+}
+
+/// The order in which spider fetches the pages of a domain.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum CrawlOrder {
+    /// Each level of links is fetched concurrently. Fastest, but when the page limit is reached
+    /// mid-level, which pages of that level made it in depends on response times.
+    BreadthFirst,
+    /// Pages are fetched one at a time in the order they were discovered. Slower, but the pages
+    /// kept under the page limit are deterministic.
+    Sequential,
+}
+
+/// When searches see newly committed pages.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ReaderReload {
+    /// Readers reload shortly after every commit. Freshest, but each reload re-warms caches.
+    OnCommit,
+    /// Readers reload at most once every `reload_delay_ms`, and when the index is flushed (e.g.
+    /// after the crawl or a reindex).
+    Manual,
 }
 
 /// Indexer settings
@@ -29,7 +213,211 @@ pub struct IndexerConfig {
     pub index_dir: String,
     pub db_dir: String,
     pub commit_interval_ms: u64,
+    /// When searches see committed pages. See [`ReaderReload`].
+    pub reload_policy: ReaderReload,
+    /// With `reload_policy: manual`, the minimum time between reloads. Checked on each commit
+    /// interval. 0 only reloads when the index is flushed.
+    pub reload_delay_ms: u64,
+    /// Commit once the initial crawl is done and its pages are indexed, so they are all searchable
+    /// when the server starts instead of after the next periodic commit.
+    pub commit_after_crawl: bool,
     pub tech_term_boost: f32,
+    /// File with one `term: alt1, alt2` synonym entry per line, used to expand queries.
+    pub synonyms_file: Option<String>,
+    /// Boost for synonyms of query terms, usually below 1 so exact terms rank higher.
+    pub synonym_boost: f32,
+    /// Also expand equivalent tech terms (e.g. `golang` and `go`) like synonyms.
+    pub expand_tech_terms: bool,
+    /// Fraction of the score removed per URL path segment. 0 disables the penalty.
+    pub depth_penalty: f32,
+    /// Age in seconds at which a result's score is halved in news mode (`?mode=news`), so fresh
+    /// pages rank above stale ones of similar relevance. 0 disables news mode.
+    pub news_half_life_secs: u64,
+    /// Slightly boost HTTPS pages, so they rank above identical HTTP pages.
+    pub prefer_https: bool,
+    /// Re-scores results with an expression over the query score (`bm25`) and fast fields, e.g.
+    /// `bm25 / (1 + depth) + recency`. Checked at startup. Empty keeps the query score. See
+    /// `ScoreExpression` for the syntax.
+    pub score_expression: String,
+    pub safe_search: SafeSearchConfig,
+    /// Remove the space before standalone punctuation in extracted text ("word ." -> "word.").
+    pub trim_space_before_punctuation: bool,
+    /// Keep the boundaries between blocks like paragraphs and list items in the extracted body,
+    /// so that snippets don't run from one into the next. Doesn't change how pages are ranked.
+    pub preserve_paragraphs: bool,
+    /// Decode entities left in extracted text, e.g. double-escaped ones like "&amp;amp;", to the
+    /// characters they stand for. Non-breaking spaces are always folded into regular spaces.
+    pub decode_entities: bool,
+    /// Decode crawled pages using the encoding declared in their `Content-Type` header or
+    /// `<meta>` charset instead of always assuming UTF-8.
+    pub detect_encoding: bool,
+    /// Extract and index the text of PDF documents. Requires the `pdf` feature. When disabled,
+    /// PDFs are skipped.
+    pub index_pdfs: bool,
+    /// Boost for documents containing the whole query as an exact phrase. 0 disables it.
+    pub phrase_boost: f32,
+    /// How much the top results are boosted for query terms close together in the body: up to
+    /// this fraction of their score for adjacent terms, less the further apart they are. Needs
+    /// body positions. 0 disables the re-ranking.
+    pub proximity_boost: f32,
+    /// Number of top results re-ranked by `proximity_boost`. Results outside of them keep their
+    /// rank, however close their terms are.
+    pub proximity_candidates: usize,
+    /// Score added to titles containing all of the query's terms, and proportionally less for
+    /// titles with some of them. 0 disables it.
+    pub title_coverage_boost: f32,
+    /// Score added for each query term found in more than one of the title, description and
+    /// body, split between the terms. 0 disables it.
+    pub multi_field_boost: f32,
+    /// Docstore compression: "lz4", "zstd", "zstd(compression_level=N)" or "none". Only applied
+    /// when a new index is created, so changing it requires a reindex.
+    pub docstore_compression: String,
+    /// Split the index by domain into this many indexes (shards) in subdirectories of
+    /// `index_dir`, which are written and searched in parallel. Each shard has its own writer
+    /// with 50 MB of memory. Changing it requires a new index.
+    pub num_shards: usize,
+    /// Leave junk tokens like hashes and base64 fragments out of the index (and queries), so they
+    /// don't each become a term. Requires a reindex to apply to existing pages.
+    pub filter_junk_tokens: bool,
+    /// With `filter_junk_tokens`, tokens with more characters than this are left out.
+    pub max_token_length: usize,
+    /// Elements whose text is left out of the indexed body, e.g. "script".
+    pub ignored_elements: Vec<String>,
+    /// CSS selectors by domain for the main content of its pages, e.g. "#content". Matching
+    /// elements are indexed as the body instead of the whole `<body>`, which is still used for
+    /// pages without a match.
+    pub content_selectors: HashMap<String, String>,
+    /// Where the description of a page is taken from, in order of precedence. The first source
+    /// with a non-empty description is used.
+    pub description_sources: Vec<DescriptionSource>,
+    /// Only index pages of these domains and their subdomains. Other crawled pages are still
+    /// visited for links. Empty indexes everything.
+    pub index_allowlist: Vec<String>,
+    /// Remove `^` boosts from user queries, so that only our own boosts apply.
+    pub strip_query_boosts: bool,
+    /// What to do with queries without searchable terms, e.g. only punctuation.
+    pub no_terms_behavior: NoTermsBehavior,
+    /// Replace `*` wildcards in user queries with spaces. Trailing `*` prefix terms like `prog*`
+    /// are handled separately and still work.
+    pub strip_query_wildcards: bool,
+    /// Match URLs pasted into queries against page URLs, and emails as phrases, instead of
+    /// splitting them into words.
+    pub exact_url_queries: bool,
+    /// Whether body positions are indexed. Positions are needed for phrase queries but take a lot
+    /// of space. Only applied when a new index is created.
+    pub body_index_option: BodyIndexOption,
+    /// Whether the body text is stored. It's needed for body snippets and for reindexing pages
+    /// without raw HTML, but roughly doubles the size of the index. Only applied when a new index
+    /// is created.
+    pub store_body: bool,
+    /// Separators like "|" after which a title suffix matching the site's domain or name is
+    /// removed, e.g. "Article Title | Example" -> "Article Title". Empty disables trimming.
+    pub title_suffix_separators: Vec<String>,
+    /// Run a search and load fast fields before serving, so the first request isn't slow.
+    pub warmup: bool,
+    /// Index the words of URL paths (split on `/`, `-` and `_`), so pages can be found by slugs
+    /// like `/how-to-configure-nginx`. Slug matches count less than body matches.
+    pub index_url_slug: bool,
+    /// Index the comma-separated `<meta name="keywords">` of pages, with a low weight since
+    /// they're invisible to readers. Requires a reindex to apply to existing pages.
+    pub index_keywords: bool,
+    /// With `index_keywords`, only the first this many distinct keywords of a page are indexed.
+    pub max_keywords: usize,
+    /// Store each page's breadcrumb (from a breadcrumb `nav` or JSON-LD) to show with its
+    /// results. Requires a reindex to apply to existing pages.
+    pub extract_breadcrumbs: bool,
+    /// Collect the text of links to each page and index it with the page, as others describe it.
+    /// Only links seen before the page is indexed count, so a `reindex` picks up the rest.
+    pub index_anchor_text: bool,
+    /// Store each page's raw HTML so the index can be rebuilt with `reindex` without a crawl.
+    /// Increases the size of the index considerably.
+    pub store_raw_html: bool,
+    /// Accumulate domain stats in memory and write them to the stats DB on each commit, instead
+    /// of once per page.
+    pub batch_domain_stats: bool,
+    /// If the stats DB can't be opened, e.g. after an unclean shutdown, move it aside and start a
+    /// new one instead of failing.
+    pub recover_stats_db: bool,
+    /// Skip re-indexing pages whose `ETag` (or without one, `Last-Modified`) header is unchanged
+    /// since they were last indexed. Pages are still downloaded, spider sends no conditional
+    /// requests.
+    pub skip_unchanged_pages: bool,
+    /// Skip HTML pages whose quality score (0 to 1, see `quality_score`) is below this, e.g. link
+    /// lists with little prose. Around 0.3 drops link farms. 0 indexes everything.
+    pub min_quality: f32,
+}
+
+/// How queries without searchable terms are handled.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum NoTermsBehavior {
+    /// Show an error explaining that the query has no searchable terms.
+    Error,
+    /// Match all pages.
+    MatchAll,
+}
+
+/// What is recorded for each term of the body.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum BodyIndexOption {
+    /// Term frequencies only. Phrase queries on the body are not supported.
+    Freqs,
+    /// Term frequencies and positions.
+    FreqsAndPositions,
+}
+
+/// A place in a page's HTML that may contain its description.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum DescriptionSource {
+    /// `<meta name="description">`.
+    Meta,
+    /// `<meta property="og:description">`.
+    OpenGraph,
+    /// `<meta name="twitter:description">`.
+    Twitter,
+    /// The `description` of the first JSON-LD object (`<script type="application/ld+json">`)
+    /// that has one.
+    JsonLd,
+}
+
+/// A part of a page that a result snippet may be taken from.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SnippetSource {
+    /// The highlighted body fragments that match the query.
+    BodyMatch,
+    /// The highlighted description, if it matches the query.
+    Description,
+    /// The title, highlighted where it matches the query. Never empty, so any later source is
+    /// unused.
+    Title,
+}
+
+/// Search-time blocklists. Matching pages are hidden from results but remain in the index.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct SafeSearchConfig {
+    pub blocked_domains: Vec<String>,
+    /// Pages containing any of these terms are hidden. Multi-word entries require all words.
+    pub blocked_terms: Vec<String>,
+}
+
+impl ServerConfig {
+    /// The address to listen on. `port_override` is the `PORT` environment variable, if set.
+    pub fn socket_addr(&self, port_override: Option<&str>) -> anyhow::Result<SocketAddr> {
+        let ip: IpAddr = self
+            .bind_address
+            .parse()
+            .with_context(|| format!("invalid bind_address '{}'", self.bind_address))?;
+        let port = match port_override {
+            Some(port) => port
+                .parse()
+                .with_context(|| format!("invalid PORT '{port}'"))?,
+            None => self.port,
+        };
+        Ok(SocketAddr::new(ip, port))
+    }
 }
 
 impl Config {
@@ -44,27 +432,260 @@ impl Config {
         unimplemented!("load() should not be called in tests, see load_test()");
     }
 
+    /// Returns the `reloaded` config, but with the settings that only apply at startup kept as in
+    /// `self`. Each of them that changed is reported, since it needs a restart.
+    pub fn reloaded(&self, mut reloaded: Config) -> Config {
+        macro_rules! keep {
+            ($($section:ident.$field:ident),* $(,)?) => {$(
+                if reloaded.$section.$field != self.$section.$field {
+                    eprintln!(
+                        "WARNING: Changing {}.{} requires a restart, ignoring the new value",
+                        stringify!($section),
+                        stringify!($field),
+                    );
+                    reloaded.$section.$field = self.$section.$field.clone();
+                }
+            )*};
+        }
+
+        // Applied to the listener or the router when they're created.
+        keep!(
+            server.bind_address,
+            server.port,
+            server.max_request_body_bytes,
+            server.request_timeout_ms,
+            server.content_security_policy,
+            server.theme_dir,
+        );
+        // Used when the indexer is created, or tied to the index on disk.
+        keep!(
+            indexer.new_index,
+            indexer.index_dir,
+            indexer.db_dir,
+            indexer.commit_interval_ms,
+            indexer.reload_policy,
+            indexer.reload_delay_ms,
+            indexer.synonyms_file,
+            indexer.docstore_compression,
+            indexer.num_shards,
+            indexer.body_index_option,
+            indexer.store_body,
+            indexer.content_selectors,
+            indexer.score_expression,
+            indexer.filter_junk_tokens,
+            indexer.max_token_length,
+            indexer.warmup,
+            indexer.batch_domain_stats,
+            indexer.recover_stats_db,
+        );
+        if reloaded.crawler != self.crawler {
+            eprintln!("WARNING: Changing crawler settings requires a restart, ignoring them");
+            reloaded.crawler = self.crawler.clone();
+        }
+
+        reloaded
+    }
+
     #[cfg(test)]
     pub fn load_test(test_name: &str) -> Self {
         use crate::test_utils::TEST_DIR;
 
         Config {
             server: ServerConfig {
+                bind_address: "127.0.0.1".to_string(),
+                port: 3000,
                 name: "test_server".to_string(),
+                about: String::new(),
                 results_per_query: 10,
+                max_results_per_query: 20,
+                allow_disabling_safe_search: false,
+                snippet_threads: 1,
+                enable_crawl_api: false,
+                empty_query_behavior: EmptyQueryBehavior::None,
+                snippet_fragments: 1,
+                snippet_max_chars: 150,
+                snippet_preference: vec![SnippetSource::Description, SnippetSource::BodyMatch],
+                collapse_redundant_snippets: false,
+                related_searches: 3,
+                domain_facets: 0,
+                recent_searches: 0,
+                hidden_url_params: Vec::new(),
+                size_format: SizeFormat::Decimal,
+                robots_txt: "User-agent: *\nAllow: /assets/\nDisallow: /\n".to_string(),
+                max_request_body_bytes: 1024,
+                request_timeout_ms: 10_000,
+                content_security_policy: "script-src 'self'; object-src 'none'; base-uri 'self'"
+                    .to_string(),
+                click_tracking: false,
+                cached_pages: false,
+                dedupe_by_title: false,
+                thumbnails: false,
+                trusted_domains: Vec::new(),
+                feeds: false,
+                feed_ttl_secs: 3600,
+                lucky: false,
+                tld_grouping: false,
+                show_result_count: true,
+                search_timings: false,
+                matched_terms: false,
+                title_search: false,
+                vocabulary_export: false,
+                vocabulary_min_freq: 1,
+                theme_dir: None,
+                api_keys: HashMap::new(),
             },
             crawler: CrawlerConfig {
                 domains_file: format!("{TEST_DIR}/test_domains"),
                 log_interval_per_domain: 1,
                 max_pages_per_domain: 1,
+                max_total_pages: None,
+                max_crawl_duration_secs: None,
+                backlog_warning_secs: 10,
+                throttle_on_backlog: false,
+                save_crawl_to: None,
+                strip_www: false,
+                strict_domain: false,
+                crawl_order: CrawlOrder::BreadthFirst,
+                shuffle_domains: false,
+                shuffle_seed: None,
+                skip_indexed_domains: false,
+                skip_indexed_domains_min_pages: 1,
+                crawl_history_size: 2,
             },
             indexer: IndexerConfig {
                 new_index: true,
                 index_dir: format!("{TEST_DIR}/index_{test_name}"),
                 db_dir: format!("{TEST_DIR}/db_{test_name}.db"),
                 commit_interval_ms: 1000,
+                reload_policy: ReaderReload::OnCommit,
+                reload_delay_ms: 0,
+                commit_after_crawl: true,
                 tech_term_boost: 1.0,
+                synonyms_file: None,
+                synonym_boost: 0.5,
+                expand_tech_terms: false,
+                depth_penalty: 0.0,
+                news_half_life_secs: 86_400,
+                prefer_https: false,
+                score_expression: String::new(),
+                safe_search: SafeSearchConfig::default(),
+                trim_space_before_punctuation: false,
+                preserve_paragraphs: false,
+                decode_entities: false,
+                detect_encoding: true,
+                index_pdfs: false,
+                phrase_boost: 0.0,
+                proximity_boost: 0.0,
+                proximity_candidates: 50,
+                title_coverage_boost: 0.0,
+                multi_field_boost: 0.0,
+                docstore_compression: "lz4".to_string(),
+                num_shards: 1,
+                filter_junk_tokens: false,
+                max_token_length: 30,
+                ignored_elements: vec!["script".to_string()],
+                content_selectors: HashMap::new(),
+                description_sources: vec![
+                    DescriptionSource::Meta,
+                    DescriptionSource::OpenGraph,
+                    DescriptionSource::Twitter,
+                    DescriptionSource::JsonLd,
+                ],
+                index_allowlist: Vec::new(),
+                body_index_option: BodyIndexOption::FreqsAndPositions,
+                store_body: true,
+                title_suffix_separators: Vec::new(),
+                warmup: false,
+                strip_query_boosts: false,
+                strip_query_wildcards: false,
+                exact_url_queries: false,
+                no_terms_behavior: NoTermsBehavior::Error,
+                store_raw_html: false,
+                index_url_slug: false,
+                index_keywords: false,
+                max_keywords: 10,
+                extract_breadcrumbs: false,
+                index_anchor_text: false,
+                batch_domain_stats: false,
+                recover_stats_db: false,
+                skip_unchanged_pages: false,
+                min_quality: 0.0,
             },
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_default_config_parses() {
+        let config: Config = serde_yaml::from_str(include_str!("../config.yaml")).unwrap();
+        assert_eq!(config.crawler.crawl_order, CrawlOrder::BreadthFirst);
+    }
+
+    #[test]
+    fn test_reloaded() {
+        let config = Config::load_test("test_reloaded");
+        let mut new_config = Config::load_test("test_reloaded_new");
+        new_config.server.results_per_query = 3;
+        new_config.server.request_timeout_ms = 1;
+        new_config.server.theme_dir = Some("themes/new".to_string());
+        new_config.indexer.tech_term_boost = 2.0;
+        new_config.crawler.max_pages_per_domain = 100;
+
+        let reloaded = config.reloaded(new_config);
+        assert_eq!(reloaded.server.results_per_query, 3);
+        assert_eq!(reloaded.indexer.tech_term_boost, 2.0);
+        // Startup settings are kept.
+        assert_eq!(reloaded.server.request_timeout_ms, 10_000);
+        assert_eq!(reloaded.server.theme_dir, None);
+        assert_eq!(reloaded.indexer.index_dir, config.indexer.index_dir);
+        assert_eq!(reloaded.crawler, config.crawler);
+    }
+
+    #[tokio::test]
+    async fn test_socket_addr() {
+        let mut config = Config::load_test("test_socket_addr").server;
+        config.bind_address = "127.0.0.1".to_string();
+        config.port = 3001;
+        assert_eq!(
+            config.socket_addr(None).unwrap(),
+            "127.0.0.1:3001".parse().unwrap()
+        );
+        // The `PORT` environment variable overrides the configured port.
+        assert_eq!(
+            config.socket_addr(Some("8080")).unwrap(),
+            "127.0.0.1:8080".parse().unwrap()
+        );
+        assert!(config.socket_addr(Some("http")).is_err());
+
+        config.bind_address = "::1".to_string();
+        assert_eq!(
+            config.socket_addr(None).unwrap(),
+            "[::1]:3001".parse().unwrap()
+        );
+        config.bind_address = "localhost".to_string();
+        assert!(config.socket_addr(None).is_err());
+
+        // A listener bound to the address only accepts connections on that interface.
+        config.bind_address = "127.0.0.1".to_string();
+        let listener = tokio::net::TcpListener::bind(config.socket_addr(Some("0")).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(
+            listener.local_addr().unwrap().ip(),
+            IpAddr::from([127, 0, 0, 1])
+        );
+    }
+
+    #[test]
+    fn test_crawl_order() {
+        assert_eq!(
+            serde_yaml::from_str::<CrawlOrder>("sequential").unwrap(),
+            CrawlOrder::Sequential
+        );
+        assert!(serde_yaml::from_str::<CrawlOrder>("depth_first").is_err());
+    }
+}
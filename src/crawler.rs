@@ -1,41 +1,400 @@
 use anyhow::{self, Context};
+use serde::{Deserialize, Serialize};
 use spider::{page::Page, tokio, website::Website};
 use std::{
+    collections::{BTreeMap, HashSet},
+    fs::{File, OpenOptions},
+    hash::{BuildHasher, RandomState},
+    io::Write,
     sync::{
-        atomic::{AtomicU32, Ordering},
-        Arc,
+        atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex, RwLock,
     },
-    time::Instant,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 use tokio::{
-    sync::{broadcast, mpsc},
+    sync::{broadcast, mpsc, Notify},
     task::{JoinHandle, JoinSet},
 };
 
-use crate::{config::CrawlerConfig, indexer::SearchPage};
+use crate::{
+    config::{CrawlOrder, CrawlerConfig},
+    indexer::{is_pdf, Indexer, SearchPage},
+};
+
+pub type SharedCrawlStatus = Arc<RwLock<CrawlStatus>>;
+
+/// Live progress of the current crawl, or a summary of the last one when idle.
+///
+/// `state` refers to full crawls of the domains file. Domains crawled on demand show up in
+/// `domains_in_progress`, but are counted separately from the crawl of the domains file.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct CrawlStatus {
+    pub state: CrawlState,
+    /// Unix timestamp (seconds) of when the current crawl started.
+    pub started_at: Option<u64>,
+    pub pages_crawled: u64,
+    /// Pages crawled so far for each domain that is still being crawled.
+    pub domains_in_progress: BTreeMap<String, u64>,
+    pub domains_finished: usize,
+    /// Domains whose crawl ended with an error. These count as finished too.
+    pub domains_failed: usize,
+    /// Crawled pages waiting for the indexer, as of the last check.
+    pub indexer_backlog: usize,
+    pub last_crawl: Option<CrawlSummary>,
+    /// Pages crawled on demand (`/api/crawl`) since the server started.
+    pub on_demand_pages_crawled: u64,
+    /// Domains crawled on demand since the server started.
+    pub on_demand_domains_finished: usize,
+    /// The domains in progress that are crawled on demand.
+    #[serde(skip)]
+    on_demand_domains: HashSet<String>,
+    #[serde(skip)]
+    next_job_id: u64,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CrawlState {
+    #[default]
+    Idle,
+    Crawling,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct CrawlSummary {
+    /// Unix timestamp (seconds) of when the crawl started.
+    pub started_at: u64,
+    pub duration_secs: f64,
+    pub pages_crawled: u64,
+    pub domains_crawled: usize,
+    pub domains_failed: usize,
+    /// Whether the crawl was stopped early by `max_crawl_duration_secs`.
+    pub time_limited: bool,
+}
+
+impl CrawlStatus {
+    pub fn start_crawl(&mut self) {
+        *self = CrawlStatus {
+            state: CrawlState::Crawling,
+            started_at: Some(unix_timestamp()),
+            last_crawl: self.last_crawl.take(),
+            domains_in_progress: std::mem::take(&mut self.domains_in_progress),
+            on_demand_pages_crawled: self.on_demand_pages_crawled,
+            on_demand_domains_finished: self.on_demand_domains_finished,
+            on_demand_domains: std::mem::take(&mut self.on_demand_domains),
+            next_job_id: self.next_job_id,
+            ..Default::default()
+        };
+    }
+
+    /// Marks the domain as being crawled. Returns `false` if it is already in progress.
+    pub fn start_domain(&mut self, domain: &str) -> bool {
+        if self.domains_in_progress.contains_key(domain) {
+            return false;
+        }
+        self.domains_in_progress.insert(domain.to_string(), 0);
+        true
+    }
+
+    /// Like [`CrawlStatus::start_domain`], for a domain crawled on demand.
+    fn start_on_demand_domain(&mut self, domain: &str) -> bool {
+        if !self.start_domain(domain) {
+            return false;
+        }
+        self.on_demand_domains.insert(domain.to_string());
+        true
+    }
+
+    fn page_crawled(&mut self, domain: &str) {
+        if self.on_demand_domains.contains(domain) {
+            self.on_demand_pages_crawled += 1;
+        } else {
+            self.pages_crawled += 1;
+        }
+        if let Some(pages) = self.domains_in_progress.get_mut(domain) {
+            *pages += 1;
+        }
+    }
+
+    fn finish_domain(&mut self, domain: &str) {
+        self.domains_in_progress.remove(domain);
+        if self.on_demand_domains.remove(domain) {
+            self.on_demand_domains_finished += 1;
+        } else {
+            self.domains_finished += 1;
+        }
+    }
+
+    fn finish_crawl(&mut self, duration: Duration, time_limited: bool) -> CrawlSummary {
+        let summary = CrawlSummary {
+            started_at: self.started_at.unwrap_or_default(),
+            duration_secs: duration.as_secs_f64(),
+            pages_crawled: self.pages_crawled,
+            domains_crawled: self.domains_finished,
+            domains_failed: self.domains_failed,
+            time_limited,
+        };
+        self.last_crawl = Some(summary.clone());
+        self.state = CrawlState::Idle;
+        self.started_at = None;
+        summary
+    }
+}
+
+/// Counts pages across all domains of a crawl, to enforce `max_total_pages`. The budget can also
+/// be used up early with [`PageBudget::stop`], e.g. for `max_crawl_duration_secs`.
+#[derive(Debug, Default)]
+struct PageBudget {
+    limit: Option<u64>,
+    pages: AtomicU64,
+    stopped: AtomicBool,
+    exhausted: Notify,
+}
+
+impl PageBudget {
+    fn new(limit: Option<u64>) -> Self {
+        Self {
+            limit,
+            ..Default::default()
+        }
+    }
+
+    /// Takes a page from the budget. Returns `false` if the budget is exhausted.
+    fn take_page(&self) -> bool {
+        if self.is_stopped() {
+            return false;
+        }
+        let Some(limit) = self.limit else {
+            return true;
+        };
+
+        match self
+            .pages
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |pages| {
+                (pages < limit).then_some(pages + 1)
+            }) {
+            Ok(pages) => {
+                if pages + 1 == limit {
+                    println!("Reached the limit of {limit} pages for this crawl, stopping...");
+                    self.exhausted.notify_waiters();
+                }
+                true
+            }
+            Err(_) => false,
+        }
+    }
+
+    fn is_exhausted(&self) -> bool {
+        self.is_stopped()
+            || self
+                .limit
+                .is_some_and(|limit| self.pages.load(Ordering::SeqCst) >= limit)
+    }
+
+    /// Uses up the budget regardless of the limit, stopping the crawl.
+    fn stop(&self) {
+        self.stopped.store(true, Ordering::SeqCst);
+        self.exhausted.notify_waiters();
+    }
+
+    fn is_stopped(&self) -> bool {
+        self.stopped.load(Ordering::SeqCst)
+    }
+
+    /// Stops the crawl once `duration` has passed, unless the returned task is aborted first.
+    fn stop_after(self: &Arc<Self>, duration: Duration) -> JoinHandle<()> {
+        let page_budget = self.clone();
+        tokio::task::spawn(async move {
+            tokio::time::sleep(duration).await;
+            println!("Reached the time limit of {duration:?} for this crawl, stopping...");
+            page_budget.stop();
+        })
+    }
+
+    /// Resolves once the budget is exhausted. Never resolves without a limit.
+    async fn wait_until_exhausted(&self) {
+        loop {
+            // Register before checking, so a notification in between isn't missed.
+            let notified = self.exhausted.notified();
+            if self.is_exhausted() {
+                return;
+            }
+            notified.await;
+        }
+    }
+}
+
+/// A crawled page as saved to `save_crawl_to`, one JSON object per line.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct SavedPage {
+    pub url: String,
+    pub domain: String,
+    pub html: String,
+}
+
+/// Appends the pages of a crawl to `save_crawl_to`, so they can be reindexed without crawling
+/// again.
+#[derive(Debug)]
+struct CrawlArchive {
+    file: Mutex<File>,
+}
+
+impl CrawlArchive {
+    /// Opens the configured file for appending, or returns `None` if pages aren't saved.
+    fn open(config: &CrawlerConfig) -> anyhow::Result<Option<Arc<Self>>> {
+        let Some(path) = &config.save_crawl_to else {
+            return Ok(None);
+        };
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("Failed to open crawl archive '{path}'"))?;
+
+        Ok(Some(Arc::new(Self {
+            file: Mutex::new(file),
+        })))
+    }
+
+    fn save(&self, page: &SavedPage) -> anyhow::Result<()> {
+        let mut line = serde_json::to_vec(page)?;
+        line.push(b'\n');
+        // Write whole lines under the lock, so pages of concurrent domains don't interleave.
+        self.file.lock().unwrap().write_all(&line)?;
+        Ok(())
+    }
+}
+
+pub fn unix_timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// What to crawl for a line of the domains file: a host, optionally followed by a path prefix that
+/// restricts the crawl to a subtree, e.g. `example.com/docs/`.
+#[derive(Clone, Debug, PartialEq)]
+struct CrawlScope {
+    /// The bare host, stored as the domain of indexed pages. Without a leading `www.` with
+    /// `strip_www`.
+    domain: String,
+    path_prefix: Option<String>,
+}
+
+impl CrawlScope {
+    fn parse(entry: &str, strip_www: bool) -> Self {
+        let entry = entry.trim();
+        let entry = entry
+            .split_once("://")
+            .map_or(entry, |(_scheme, rest)| rest);
+        let entry = if strip_www {
+            entry.strip_prefix("www.").unwrap_or(entry)
+        } else {
+            entry
+        };
+
+        match entry.split_once('/') {
+            Some((domain, path)) if !path.is_empty() => Self {
+                domain: domain.to_string(),
+                path_prefix: Some(format!("/{path}")),
+            },
+            Some((domain, _empty_path)) => Self {
+                domain: domain.to_string(),
+                path_prefix: None,
+            },
+            None => Self {
+                domain: entry.to_string(),
+                path_prefix: None,
+            },
+        }
+    }
+
+    /// The pattern for spider's whitelist that keeps the crawl within the path prefix, if any.
+    /// Links outside of it aren't fetched at all.
+    fn whitelist_pattern(&self) -> Option<String> {
+        self.path_prefix
+            .as_ref()
+            .map(|path_prefix| format!("^https?://[^/]+{}", regex::escape(path_prefix)))
+    }
+
+    /// Whether `url` is within the scope. Only the path is checked, spider keeps to the host.
+    fn contains(&self, url: &str) -> bool {
+        let Some(path_prefix) = &self.path_prefix else {
+            return true;
+        };
+
+        let without_scheme = url.split_once("://").map_or(url, |(_scheme, rest)| rest);
+        let path = without_scheme
+            .find('/')
+            .map_or("/", |start| &without_scheme[start..]);
+        path.starts_with(path_prefix.as_str())
+    }
+
+    /// Whether `url` is on the host of the scope, ignoring case, a leading `www.` and any user
+    /// info.
+    fn is_on_host(&self, url: &str) -> bool {
+        let without_scheme = url.split_once("://").map_or(url, |(_scheme, rest)| rest);
+        let authority = without_scheme
+            .split(['/', '?', '#'])
+            .next()
+            .unwrap_or_default();
+        let host = authority
+            .rsplit_once('@')
+            .map_or(authority, |(_user_info, host)| host);
+        let normalize = |host: &str| {
+            let host = host.to_lowercase();
+            host.strip_prefix("www.").unwrap_or(&host).to_string()
+        };
+        normalize(host) == normalize(&self.domain)
+    }
+}
 
 struct DomainCrawler {
     website: Website,
+    /// The line of the domains file, used as the crawl root and to report progress.
     domain: String,
+    scope: CrawlScope,
     config: Arc<CrawlerConfig>,
+    crawl_status: SharedCrawlStatus,
+    page_budget: Arc<PageBudget>,
+    archive: Option<Arc<CrawlArchive>>,
 }
 
 impl DomainCrawler {
-    fn new(domain: &str, config: Arc<CrawlerConfig>) -> anyhow::Result<Self> {
+    fn new(
+        domain: &str,
+        config: Arc<CrawlerConfig>,
+        crawl_status: SharedCrawlStatus,
+        page_budget: Arc<PageBudget>,
+        archive: Option<Arc<CrawlArchive>>,
+        weight: u32,
+    ) -> anyhow::Result<Self> {
+        let scope = CrawlScope::parse(domain, config.strip_www);
         let website = Website::new(domain)
-            .with_limit(config.max_pages_per_domain)
+            .with_limit(config.max_pages_per_domain.saturating_mul(weight))
             .with_depth(0) // No max crawl depth. Use page limit only.
             // NOTE: Accept invalid certs as we prioritize relevance over security.
             .with_danger_accept_invalid_certs(true)
             .with_block_assets(true)
             .with_respect_robots_txt(true)
             .with_normalize(true)
+            .with_whitelist_url(
+                scope
+                    .whitelist_pattern()
+                    .map(|pattern| vec![pattern.into()]),
+            )
             .build()?;
 
         Ok(Self {
             website,
             domain: domain.to_string(),
+            scope,
             config,
+            crawl_status,
+            page_budget,
+            archive,
         })
     }
 
@@ -49,7 +408,18 @@ impl DomainCrawler {
         // Spawn task that receives pages from the crawler.
         let recv_handle = self.spawn_page_handler(crawl_rx, indexer_tx).await;
 
-        self.website.crawl().await;
+        // Stop crawling early (by dropping the crawl) if the crawl-wide page limit is reached.
+        let page_budget = self.page_budget.clone();
+        let crawl = async {
+            match self.config.crawl_order {
+                CrawlOrder::BreadthFirst => self.website.crawl().await,
+                CrawlOrder::Sequential => self.website.crawl_sequential().await,
+            }
+        };
+        tokio::select! {
+            () = crawl => {}
+            () = page_budget.wait_until_exhausted() => {}
+        }
         self.website.unsubscribe();
 
         recv_handle.await?
@@ -63,7 +433,11 @@ impl DomainCrawler {
         indexer_tx: mpsc::Sender<SearchPage>,
     ) -> JoinHandle<anyhow::Result<()>> {
         let domain = Arc::new(self.domain.to_owned()); // Create owned value for the async task.
+        let scope = Arc::new(self.scope.clone());
         let config = Arc::new(self.config.clone());
+        let crawl_status = self.crawl_status.clone();
+        let page_budget = self.page_budget.clone();
+        let archive = self.archive.clone();
 
         tokio::task::spawn(async move {
             let page_count = Arc::new(AtomicU32::new(0));
@@ -71,19 +445,61 @@ impl DomainCrawler {
             let mut crawl_page_tasks: JoinSet<anyhow::Result<()>> = JoinSet::new();
 
             while let Ok(page) = crawl_rx.recv().await {
+                // spider's whitelist keeps to the path prefix, but drop anything outside of it
+                // that gets through anyway, e.g. after a redirect.
+                if !scope.contains(page.get_url()) {
+                    continue;
+                }
+                if config.strict_domain && !scope.is_on_host(page.get_url()) {
+                    eprintln!(
+                        "WARNING: {domain}: Dropping page from another host: {}",
+                        page.get_url()
+                    );
+                    continue;
+                }
+                // Pages still in flight when the crawl-wide limit is reached are dropped.
+                if !page_budget.take_page() {
+                    continue;
+                }
+
                 let page_count = page_count.clone();
                 let indexer_tx = indexer_tx.clone();
                 let domain = domain.clone();
+                let scope = scope.clone();
                 let config = config.clone();
+                let crawl_status = crawl_status.clone();
+                let archive = archive.clone();
 
                 // We use async and potentially-blocking methods, so spawn a task to avoid
                 // losing messages. See [`spider::website::Website::subscribe`].
                 crawl_page_tasks.spawn(async move {
                     let url = page.get_url().to_string();
 
-                    Self::handle_page(page, indexer_tx, page_count, domain.as_ref(), &config)
-                        .await
-                        .with_context(|| format!("Failed to handle crawled page: {url}"))
+                    // PDFs have no HTML to save. The page is still indexed if saving fails.
+                    if let Some(archive) =
+                        archive.filter(|_| !page.get_bytes().is_some_and(|bytes| is_pdf(bytes)))
+                    {
+                        let saved_page = SavedPage {
+                            url: url.clone(),
+                            domain: scope.domain.clone(),
+                            html: page.get_html(),
+                        };
+                        if let Err(e) = archive.save(&saved_page) {
+                            eprintln!("ERROR: Failed to save crawled page '{url}': {e}");
+                        }
+                    }
+
+                    Self::handle_page(
+                        page,
+                        indexer_tx,
+                        page_count,
+                        domain.as_ref(),
+                        &scope,
+                        &config,
+                        &crawl_status,
+                    )
+                    .await
+                    .with_context(|| format!("Failed to handle crawled page: {url}"))
                 });
 
                 // Limit the number of tasks per domain.
@@ -107,8 +523,12 @@ impl DomainCrawler {
         indexer_tx: mpsc::Sender<SearchPage>,
         page_count: Arc<AtomicU32>,
         domain: &str,
+        scope: &CrawlScope,
         config: &CrawlerConfig,
+        crawl_status: &RwLock<CrawlStatus>,
     ) -> anyhow::Result<()> {
+        crawl_status.write().unwrap().page_crawled(domain);
+
         // Provide some visual indication of crawl progress.
         let cur_count = page_count
             .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |x| Some(x + 1))
@@ -122,7 +542,7 @@ impl DomainCrawler {
         indexer_tx
             .send(SearchPage {
                 page,
-                domain: domain.to_string(),
+                domain: scope.domain.clone(),
             })
             .await
             .context("index receiver dropped")?;
@@ -131,55 +551,194 @@ impl DomainCrawler {
     }
 }
 
+/// Crawls the domains of the domains file. With `skip_indexed_domains`, domains in
+/// `indexed_domains` are skipped. The crawl is added to the crawl history of `indexer` once done,
+/// even if it failed.
 pub async fn initial_crawl(
     indexer_tx: mpsc::Sender<SearchPage>,
     config: &CrawlerConfig,
+    crawl_status: SharedCrawlStatus,
+    indexed_domains: &HashSet<String>,
+    indexer: &Indexer,
 ) -> anyhow::Result<()> {
-    let domains = get_domains_to_crawl(config).await?;
+    let domains = get_domains_to_crawl(config, indexed_domains).await?;
 
+    crawl_status.write().unwrap().start_crawl();
     let start = Instant::now();
-    crawl_domains(domains, indexer_tx, config).await?;
+    let result = crawl_domains(domains, indexer_tx, config, crawl_status.clone()).await;
     let duration = start.elapsed();
+    let time_limited = result.as_ref().is_ok_and(|time_limited| *time_limited);
+    let summary = crawl_status
+        .write()
+        .unwrap()
+        .finish_crawl(duration, time_limited);
+    if let Err(e) = indexer.record_crawl(&summary, config.crawl_history_size) {
+        eprintln!("ERROR: Failed to record crawl history: {e}");
+    }
+    result?;
 
     println!();
-    println!("Finished crawling in {:?}", duration);
+    if time_limited {
+        println!("Stopped crawling after {duration:?}, serving the pages indexed so far");
+    } else {
+        println!("Finished crawling in {:?}", duration);
+    }
 
     Ok(())
 }
 
+/// The maximum number of domains crawled at the same time.
+const MAX_CONCURRENT_DOMAINS: usize = 16;
+
+/// Watches the queue of crawled pages waiting for the indexer. When it stays nearly full, the
+/// indexer can't keep up and crawlers are blocked on it.
+struct BacklogMonitor {
+    /// How long the queue has to be nearly full to count as saturated.
+    warning_after: Duration,
+    /// Since when the queue has been nearly full, if it is.
+    full_since: Option<Instant>,
+    /// When a warning was last logged, so that it's repeated at most every `warning_after`.
+    last_warning: Option<Instant>,
+    warnings_logged: u64,
+}
+
+impl BacklogMonitor {
+    /// The fraction of the queue capacity from which the queue counts as nearly full.
+    const NEARLY_FULL: f64 = 0.9;
+
+    fn new(warning_after: Duration) -> Self {
+        Self {
+            warning_after,
+            full_since: None,
+            last_warning: None,
+            warnings_logged: 0,
+        }
+    }
+
+    /// Records the number of queued pages, and returns whether the queue has been nearly full for
+    /// `warning_after`, logging a warning if so.
+    fn sample(&mut self, backlog: usize, capacity: usize, now: Instant) -> bool {
+        if (backlog as f64) < capacity as f64 * Self::NEARLY_FULL {
+            self.full_since = None;
+            return false;
+        }
+        let full_since = *self.full_since.get_or_insert(now);
+        if now - full_since < self.warning_after {
+            return false;
+        }
+
+        if self
+            .last_warning
+            .is_none_or(|last_warning| now - last_warning >= self.warning_after)
+        {
+            eprintln!(
+                "WARNING: {backlog} of {capacity} crawled pages have been waiting for the indexer \
+                 for {:?}, indexing can't keep up with the crawl",
+                now - full_since
+            );
+            self.last_warning = Some(now);
+            self.warnings_logged += 1;
+        }
+        true
+    }
+
+    /// Checks the indexer queue every second, recording its length in `crawl_status`. With
+    /// `throttle_on_backlog`, `domain_limit` is halved while the queue is saturated and raised
+    /// again by one once it's less than half full. Runs until the returned task is aborted.
+    fn spawn(
+        indexer_tx: mpsc::Sender<SearchPage>,
+        config: &CrawlerConfig,
+        crawl_status: SharedCrawlStatus,
+        domain_limit: Arc<AtomicUsize>,
+    ) -> JoinHandle<()> {
+        let warning_after = Duration::from_secs(config.backlog_warning_secs);
+        let throttle = config.throttle_on_backlog;
+        tokio::task::spawn(async move {
+            let mut monitor = Self::new(warning_after);
+            let mut interval = tokio::time::interval(Duration::from_secs(1));
+            loop {
+                interval.tick().await;
+                let capacity = indexer_tx.max_capacity();
+                let backlog = capacity - indexer_tx.capacity();
+                crawl_status.write().unwrap().indexer_backlog = backlog;
+
+                let saturated =
+                    !warning_after.is_zero() && monitor.sample(backlog, capacity, Instant::now());
+                if !throttle {
+                    continue;
+                }
+                let limit = domain_limit.load(Ordering::SeqCst);
+                let new_limit = if saturated {
+                    (limit / 2).max(1)
+                } else if backlog < capacity / 2 {
+                    (limit + 1).min(MAX_CONCURRENT_DOMAINS)
+                } else {
+                    limit
+                };
+                if new_limit != limit {
+                    println!("Crawling up to {new_limit} domains at a time");
+                    domain_limit.store(new_limit, Ordering::SeqCst);
+                }
+            }
+        })
+    }
+}
+
+/// Crawls all domains, returning whether the crawl was stopped by `max_crawl_duration_secs`.
 async fn crawl_domains(
-    domains: Vec<String>,
+    domains: Vec<DomainEntry>,
     indexer_tx: mpsc::Sender<SearchPage>,
     config: &CrawlerConfig,
-) -> anyhow::Result<()> {
+    crawl_status: SharedCrawlStatus,
+) -> anyhow::Result<bool> {
     // Have separate tasks for each domain. We'll process multiple domains in parallel, and
     // hopefully not get blocked or rate-limited from any target domain. This also follows the
     // `spider` examples (except they didn't use a `JoinSet`).
     let mut crawl_domain_tasks: JoinSet<anyhow::Result<String>> = JoinSet::new();
+    let page_budget = Arc::new(PageBudget::new(config.max_total_pages));
+    let archive = CrawlArchive::open(config)?;
+    // Running domain crawlers stop as if the page limit was reached.
+    let deadline = config
+        .max_crawl_duration_secs
+        .map(|secs| page_budget.stop_after(Duration::from_secs(secs)));
+    let domain_limit = Arc::new(AtomicUsize::new(MAX_CONCURRENT_DOMAINS));
+    let backlog_monitor = BacklogMonitor::spawn(
+        indexer_tx.clone(),
+        config,
+        crawl_status.clone(),
+        domain_limit.clone(),
+    );
 
-    for domain in domains {
+    for DomainEntry { domain, weight } in domains {
+        if page_budget.is_exhausted() {
+            println!("Crawl limit reached, not crawling the remaining domains");
+            break;
+        }
+        if !crawl_status.write().unwrap().start_domain(&domain) {
+            println!("{domain}: already being crawled, skipping");
+            continue;
+        }
         println!("Crawling domain: {}", domain);
 
-        let indexer_tx = indexer_tx.clone();
-        let config = Arc::new(config.clone());
-
-        crawl_domain_tasks.spawn(async move {
-            let mut crawler = DomainCrawler::new(&domain, config)
-                .with_context(|| format!("{domain}: Failed to create crawler"))?;
-            crawler
-                .crawl_domain(indexer_tx)
-                .await
-                .with_context(|| format!("{domain}: Failed to crawl domain"))?;
-
-            Ok(domain)
-        });
+        crawl_domain_tasks.spawn(crawl_single_domain(
+            domain,
+            indexer_tx.clone(),
+            Arc::new(config.clone()),
+            crawl_status.clone(),
+            page_budget.clone(),
+            archive.clone(),
+            weight,
+        ));
 
         // Limit the number of domains we crawl concurrently.
-        while crawl_domain_tasks.len() > 16 {
+        while crawl_domain_tasks.len() >= domain_limit.load(Ordering::SeqCst) {
             // We just checked the length, unwrap.
             match crawl_domain_tasks.join_next().await.unwrap()? {
                 Ok(domain) => println!("{domain}: finished crawling!"),
-                Err(e) => eprintln!("ERROR: {e}"),
+                Err(e) => {
+                    eprintln!("ERROR: {e}");
+                    crawl_status.write().unwrap().domains_failed += 1;
+                }
             }
         }
     }
@@ -188,16 +747,774 @@ async fn crawl_domains(
     while let Some(result) = crawl_domain_tasks.join_next().await {
         match result? {
             Ok(domain) => println!("{domain}: finished crawling!"),
-            Err(e) => eprintln!("ERROR: {e}"),
+            Err(e) => {
+                eprintln!("ERROR: {e}");
+                crawl_status.write().unwrap().domains_failed += 1;
+            }
         }
     }
 
-    Ok(())
+    if let Some(deadline) = deadline {
+        deadline.abort();
+    }
+    backlog_monitor.abort();
+    crawl_status.write().unwrap().indexer_backlog = 0;
+    Ok(page_budget.is_stopped())
 }
 
-async fn get_domains_to_crawl(config: &CrawlerConfig) -> anyhow::Result<Vec<String>> {
-    // We assume one valid domain per line.
+/// Crawls a domain that was already marked as started in `crawl_status`, marking it as finished
+/// when done. The domain's page limit is scaled by its `weight`.
+async fn crawl_single_domain(
+    domain: String,
+    indexer_tx: mpsc::Sender<SearchPage>,
+    config: Arc<CrawlerConfig>,
+    crawl_status: SharedCrawlStatus,
+    page_budget: Arc<PageBudget>,
+    archive: Option<Arc<CrawlArchive>>,
+    weight: u32,
+) -> anyhow::Result<String> {
+    let result: anyhow::Result<()> = async {
+        let mut crawler = DomainCrawler::new(
+            &domain,
+            config,
+            crawl_status.clone(),
+            page_budget,
+            archive,
+            weight,
+        )
+        .with_context(|| format!("{domain}: Failed to create crawler"))?;
+        crawler
+            .crawl_domain(indexer_tx)
+            .await
+            .with_context(|| format!("{domain}: Failed to crawl domain"))
+    }
+    .await;
+    crawl_status.write().unwrap().finish_domain(&domain);
+
+    result.map(|()| domain)
+}
+
+/// Starts crawling a single domain in the background, feeding pages to the indexer, where they
+/// replace the pages already indexed. Returns a job ID, or `None` if the domain is already being
+/// crawled.
+pub fn spawn_domain_crawl(
+    domain: String,
+    indexer_tx: mpsc::Sender<SearchPage>,
+    config: &CrawlerConfig,
+    crawl_status: SharedCrawlStatus,
+) -> Option<u64> {
+    let job_id = {
+        let mut status = crawl_status.write().unwrap();
+        if !status.start_on_demand_domain(&domain) {
+            return None;
+        }
+        status.next_job_id += 1;
+        status.next_job_id
+    };
+    println!("Crawling domain on demand (job {job_id}): {domain}");
+
+    // An on-demand crawl is a crawl of its own, with its own page limit.
+    let page_budget = Arc::new(PageBudget::new(config.max_total_pages));
+    // Not being able to save the pages shouldn't keep them from being indexed.
+    let archive = CrawlArchive::open(config).unwrap_or_else(|e| {
+        eprintln!("ERROR: {e:#}");
+        None
+    });
+    let config = Arc::new(config.clone());
+    tokio::task::spawn(async move {
+        match crawl_single_domain(
+            domain,
+            indexer_tx,
+            config,
+            crawl_status,
+            page_budget,
+            archive,
+            1,
+        )
+        .await
+        {
+            Ok(domain) => println!("{domain}: finished crawling!"),
+            Err(e) => eprintln!("ERROR: {e}"),
+        }
+    });
+
+    Some(job_id)
+}
+
+/// A line of the domains file.
+#[derive(Debug, PartialEq)]
+struct DomainEntry {
+    domain: String,
+    /// Domains with a higher weight are crawled first, and their page limit is multiplied by it.
+    weight: u32,
+}
+
+impl DomainEntry {
+    /// Parses a line like `example.com` or `example.com 5`. Blank lines give `None`. A missing
+    /// or invalid weight is 1.
+    fn parse(line: &str) -> Option<Self> {
+        let mut parts = line.split_whitespace();
+        let domain = parts.next()?.to_string();
+        let weight = match parts.next() {
+            None => 1,
+            Some(weight) => match weight.parse() {
+                Ok(weight) if weight > 0 => weight,
+                _ => {
+                    eprintln!("WARNING: {domain}: invalid weight '{weight}', using 1");
+                    1
+                }
+            },
+        };
+        Some(Self { domain, weight })
+    }
+}
+
+async fn get_domains_to_crawl(
+    config: &CrawlerConfig,
+    indexed_domains: &HashSet<String>,
+) -> anyhow::Result<Vec<DomainEntry>> {
+    // We assume one valid domain per line, optionally followed by a weight.
     let domains = tokio::fs::read_to_string(&config.domains_file).await?;
     // let domains = domains.lines().take(20);
-    Ok(domains.lines().map(|s| s.to_string()).collect())
+    let mut domains: Vec<DomainEntry> = domains.lines().filter_map(DomainEntry::parse).collect();
+    if config.skip_indexed_domains {
+        let num_domains = domains.len();
+        // Compare the domains as they are stored, e.g. without `www.` with `strip_www`.
+        domains.retain(|entry| {
+            !indexed_domains.contains(&CrawlScope::parse(&entry.domain, config.strip_www).domain)
+        });
+        println!(
+            "Skipping {} already indexed domains",
+            num_domains - domains.len()
+        );
+    }
+    if config.shuffle_domains {
+        let seed = config
+            .shuffle_seed
+            .unwrap_or_else(|| RandomState::new().hash_one(SystemTime::now()));
+        shuffle(&mut domains, seed);
+    }
+    // Stable, so domains of the same weight keep their (possibly shuffled) order.
+    domains.sort_by_key(|entry| std::cmp::Reverse(entry.weight));
+    Ok(domains)
+}
+
+/// Shuffles `items` in place (Fisher-Yates), with the same order for the same seed. Uses
+/// splitmix64, which is plenty for picking a crawl order.
+fn shuffle<T>(items: &mut [T], seed: u64) {
+    let mut state = seed;
+    let mut next = || {
+        state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    };
+    for i in (1..items.len()).rev() {
+        let j = (next() % (i as u64 + 1)) as usize;
+        items.swap(i, j);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::Config, test_utils::TEST_DIR};
+
+    #[tokio::test]
+    async fn test_get_domains_to_crawl() {
+        let mut config = Config::load_test("test_get_domains_to_crawl").crawler;
+        config.domains_file = format!("{TEST_DIR}/domains_test_get_domains_to_crawl");
+        std::fs::create_dir_all(TEST_DIR).unwrap();
+        std::fs::write(
+            &config.domains_file,
+            "a.com\nb.com 5\n\nc.com 2\nd.com\ne.com 5\nf.com zero\n",
+        )
+        .unwrap();
+
+        let domains = get_domains_to_crawl(&config, &HashSet::new())
+            .await
+            .unwrap();
+        let order: Vec<_> = domains
+            .iter()
+            .map(|entry| (entry.domain.as_str(), entry.weight))
+            .collect();
+        // Higher weights first, in file order otherwise.
+        assert_eq!(
+            order,
+            [
+                ("b.com", 5),
+                ("e.com", 5),
+                ("c.com", 2),
+                ("a.com", 1),
+                ("d.com", 1),
+                ("f.com", 1),
+            ]
+        );
+
+        // Shuffling only reorders domains of the same weight.
+        config.shuffle_domains = true;
+        config.shuffle_seed = Some(7);
+        let domains = get_domains_to_crawl(&config, &HashSet::new())
+            .await
+            .unwrap();
+        let weights: Vec<_> = domains.iter().map(|entry| entry.weight).collect();
+        assert_eq!(weights, [5, 5, 2, 1, 1, 1]);
+
+        std::fs::remove_file(&config.domains_file).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_skip_indexed_domains() {
+        let config = Config::load_test("test_skip_indexed_domains");
+        let indexer = crate::indexer::Indexer::new(&config.indexer).await.unwrap();
+        for (domain, pages) in [("a.com", 2), ("b.com", 1)] {
+            for page in 0..pages {
+                indexer
+                    .add_html(
+                        &format!("https://{domain}/{page}"),
+                        "<title>Page</title><body>Text</body>",
+                        domain,
+                    )
+                    .unwrap();
+            }
+        }
+        indexer.commit_and_reload().unwrap();
+        let indexed_domains = indexer.indexed_domains(2).unwrap();
+        assert_eq!(indexed_domains, HashSet::from(["a.com".to_string()]));
+
+        let mut config = config.crawler;
+        config.domains_file = format!("{TEST_DIR}/domains_test_skip_indexed_domains");
+        config.strip_www = true;
+        std::fs::create_dir_all(TEST_DIR).unwrap();
+        std::fs::write(&config.domains_file, "www.a.com\nb.com\nc.com\n").unwrap();
+        let names = |domains: Vec<DomainEntry>| {
+            domains
+                .into_iter()
+                .map(|entry| entry.domain)
+                .collect::<Vec<_>>()
+        };
+
+        let domains = get_domains_to_crawl(&config, &indexed_domains).await;
+        assert_eq!(names(domains.unwrap()), ["www.a.com", "b.com", "c.com"]);
+        config.skip_indexed_domains = true;
+        let domains = get_domains_to_crawl(&config, &indexed_domains).await;
+        assert_eq!(names(domains.unwrap()), ["b.com", "c.com"]);
+
+        // Clean up after test.
+        std::fs::remove_file(&config.domains_file).unwrap();
+        indexer.delete().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_recrawl_partly_indexed_domain() {
+        /// Crawls `domain` with up to `max_pages` pages and indexes them.
+        async fn crawl(indexer: &Indexer, domain: &str, mut config: CrawlerConfig, max_pages: u32) {
+            config.max_pages_per_domain = max_pages;
+            let (indexer_tx, mut indexer_rx) = mpsc::channel(16);
+            let mut crawler = DomainCrawler::new(
+                domain,
+                Arc::new(config),
+                SharedCrawlStatus::default(),
+                Arc::new(PageBudget::new(None)),
+                None,
+                1,
+            )
+            .unwrap();
+            crawler.crawl_domain(indexer_tx).await.unwrap();
+            while let Ok(page) = indexer_rx.try_recv() {
+                indexer.add_page(&page).unwrap();
+            }
+            indexer.commit_and_reload().unwrap();
+        }
+
+        let config = Config::load_test("test_recrawl_partly_indexed_domain");
+        let indexer = Indexer::new(&config.indexer).await.unwrap();
+        let (addr, _requested) = serve_site(&[
+            (
+                "/",
+                r#"<title>Home</title><a href="/a">A</a> <a href="/b">B</a>"#,
+            ),
+            ("/a", "<title>A</title>"),
+            ("/b", "<title>B</title>"),
+        ])
+        .await;
+        let domain = format!("http://{addr}/");
+
+        // Too few pages to be skipped, so the domain is crawled again as a whole.
+        crawl(&indexer, &domain, config.crawler.clone(), 1).await;
+        assert!(indexer.indexed_domains(2).unwrap().is_empty());
+        crawl(&indexer, &domain, config.crawler.clone(), 10).await;
+
+        // The page indexed by the first crawl is replaced, not indexed twice.
+        assert_eq!(indexer.summary().unwrap().num_docs, 3);
+        let stats = indexer
+            .get_domain_stats(crate::config::SizeFormat::Decimal)
+            .unwrap();
+        assert_eq!(stats.len(), 1);
+        assert_eq!(stats[0].page_count, 3);
+
+        // Clean up after test.
+        indexer.delete().await.unwrap();
+    }
+
+    #[test]
+    fn test_shuffle() {
+        let domains: Vec<u32> = (0..20).collect();
+
+        let mut first = domains.clone();
+        shuffle(&mut first, 42);
+        let mut second = domains.clone();
+        shuffle(&mut second, 42);
+        assert_eq!(first, second);
+        assert_ne!(first, domains);
+
+        // A permutation of the same domains.
+        first.sort();
+        assert_eq!(first, domains);
+
+        let mut other_seed = domains.clone();
+        shuffle(&mut other_seed, 43);
+        assert_ne!(other_seed, second);
+    }
+
+    #[test]
+    fn test_crawl_scope() {
+        let scope = CrawlScope::parse("www.php.net/manual/en/", false);
+        assert_eq!(scope.domain, "www.php.net");
+        assert!(scope.contains("https://www.php.net/manual/en/"));
+        assert!(scope.contains("https://www.php.net/manual/en/function.strlen.php"));
+        assert!(!scope.contains("https://www.php.net/manual/de/"));
+        assert!(!scope.contains("https://www.php.net/downloads"));
+        assert!(!scope.contains("https://www.php.net"));
+        let whitelist = regex::Regex::new(&scope.whitelist_pattern().unwrap()).unwrap();
+        assert!(whitelist.is_match("https://www.php.net/manual/en/function.strlen.php"));
+        assert!(!whitelist.is_match("https://www.php.net/manual/de/"));
+        assert!(!whitelist.is_match("https://www.php.net/?next=/manual/en/"));
+
+        for entry in ["docs.rs", "docs.rs/", "https://docs.rs"] {
+            let scope = CrawlScope::parse(entry, false);
+            assert_eq!(
+                scope,
+                CrawlScope {
+                    domain: "docs.rs".to_string(),
+                    path_prefix: None,
+                }
+            );
+            assert!(scope.contains("https://docs.rs/tokio"));
+            assert_eq!(scope.whitelist_pattern(), None);
+        }
+    }
+
+    /// Serves `pages` (path and HTML) on a local port. Returns the address and the paths of the
+    /// pages requested so far.
+    async fn serve_site(pages: &[(&str, &str)]) -> (std::net::SocketAddr, Arc<Mutex<Vec<String>>>) {
+        let requested = Arc::new(Mutex::new(Vec::new()));
+        let mut router = axum::Router::new();
+        for (path, html) in pages {
+            let (path, html) = (path.to_string(), html.to_string());
+            let requested = requested.clone();
+            router = router.route(
+                &path.clone(),
+                axum::routing::get(|| async move {
+                    requested.lock().unwrap().push(path);
+                    axum::response::Html(html)
+                }),
+            );
+        }
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, router).await });
+        (addr, requested)
+    }
+
+    #[tokio::test]
+    async fn test_crawl_path_prefix() {
+        let mut config = Config::load_test("test_crawl_path_prefix").crawler;
+        config.max_pages_per_domain = 10;
+        let (addr, requested) = serve_site(&[
+            ("/", r#"<a href="/docs/">Docs</a>"#),
+            (
+                "/docs/",
+                r#"<a href="/docs/a">A</a> <a href="/blog/b">B</a>"#,
+            ),
+            ("/docs/a", r#"<a href="/">Home</a>"#),
+            ("/blog/b", "Blog"),
+        ])
+        .await;
+        let (indexer_tx, mut indexer_rx) = mpsc::channel(16);
+
+        let mut crawler = DomainCrawler::new(
+            &format!("http://{addr}/docs/"),
+            Arc::new(config),
+            SharedCrawlStatus::default(),
+            Arc::new(PageBudget::new(None)),
+            None,
+            1,
+        )
+        .unwrap();
+        crawler.crawl_domain(indexer_tx).await.unwrap();
+
+        let mut paths = Vec::new();
+        while let Ok(SearchPage { page, .. }) = indexer_rx.try_recv() {
+            paths.push(page.get_url().replace(&format!("http://{addr}"), ""));
+        }
+        paths.sort();
+        assert_eq!(paths, ["/docs/", "/docs/a"]);
+        // Pages outside of the prefix aren't even fetched.
+        assert!(!requested.lock().unwrap().contains(&"/blog/b".to_string()));
+    }
+
+    #[test]
+    fn test_on_demand_crawl_status() {
+        let mut status = CrawlStatus::default();
+        status.start_crawl();
+        assert!(status.start_domain("a.com"));
+        assert!(status.start_on_demand_domain("b.com"));
+        assert!(!status.start_on_demand_domain("a.com"));
+        status.page_crawled("a.com");
+        status.page_crawled("b.com");
+        status.page_crawled("b.com");
+        assert_eq!(status.domains_in_progress["b.com"], 2);
+        status.finish_domain("a.com");
+        status.finish_domain("b.com");
+
+        // On-demand crawls don't count towards the crawl of the domains file.
+        let summary = status.finish_crawl(Duration::from_secs(1), false);
+        assert_eq!((summary.pages_crawled, summary.domains_crawled), (1, 1));
+        assert_eq!(status.on_demand_pages_crawled, 2);
+        assert_eq!(status.on_demand_domains_finished, 1);
+
+        // A domain of the domains file can be crawled on demand later.
+        assert!(status.start_on_demand_domain("a.com"));
+        status.page_crawled("a.com");
+        status.finish_domain("a.com");
+        assert_eq!((status.pages_crawled, status.domains_finished), (1, 1));
+        assert_eq!(status.on_demand_domains_finished, 2);
+    }
+
+    #[tokio::test]
+    async fn test_on_demand_recrawl() {
+        let mut config = Config::load_test("test_on_demand_recrawl");
+        config.crawler.max_pages_per_domain = 10;
+        let indexer = Indexer::new(&config.indexer).await.unwrap();
+        let (addr, _requested) = serve_site(&[
+            ("/", r#"<title>Home</title><a href="/a">A</a>"#),
+            ("/a", "<title>A</title>"),
+        ])
+        .await;
+        let crawl_status = SharedCrawlStatus::default();
+        let (indexer_tx, mut indexer_rx) = mpsc::channel(16);
+
+        // The same domain crawled on demand twice.
+        for finished in 1..=2 {
+            spawn_domain_crawl(
+                format!("http://{addr}/"),
+                indexer_tx.clone(),
+                &config.crawler,
+                crawl_status.clone(),
+            )
+            .unwrap();
+            while crawl_status.read().unwrap().on_demand_domains_finished < finished {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+            while let Ok(page) = indexer_rx.try_recv() {
+                indexer.add_page(&page).unwrap();
+            }
+            indexer.commit_and_reload().unwrap();
+        }
+
+        // Its pages are indexed once.
+        assert_eq!(indexer.summary().unwrap().num_docs, 2);
+        let stats = indexer
+            .get_domain_stats(crate::config::SizeFormat::Decimal)
+            .unwrap();
+        assert_eq!(stats[0].page_count, 2);
+
+        // Clean up after test.
+        indexer.delete().await.unwrap();
+    }
+
+    #[test]
+    fn test_crawl_scope_is_on_host() {
+        let scope = CrawlScope::parse("www.php.net/manual/en/", true);
+        assert!(scope.is_on_host("https://php.net/manual/en/"));
+        assert!(scope.is_on_host("https://www.php.net/downloads"));
+        assert!(scope.is_on_host("http://WWW.PHP.NET"));
+        assert!(scope.is_on_host("https://user@www.php.net?q=1"));
+        // Off-domain pages are dropped with `strict_domain`, including lookalike hosts.
+        assert!(!scope.is_on_host("https://example.com/manual/en/"));
+        assert!(!scope.is_on_host("https://wiki.php.net/"));
+        assert!(!scope.is_on_host("https://php.net.example.com/"));
+        assert!(!scope.is_on_host("https://example.com/?url=https://php.net"));
+
+        let scope = CrawlScope::parse("localhost:3000", false);
+        assert!(scope.is_on_host("http://localhost:3000/search"));
+        assert!(!scope.is_on_host("http://localhost:8080/search"));
+    }
+
+    #[tokio::test]
+    async fn test_strip_www() {
+        let scope = CrawlScope::parse("https://www.php.net/manual/", true);
+        assert_eq!(scope.domain, "php.net");
+        assert!(scope.contains("https://www.php.net/manual/en/"));
+        assert_eq!(
+            CrawlScope::parse("www.php.net", false).domain,
+            "www.php.net"
+        );
+
+        // Pages of both hosts are counted under the same domain.
+        let config = crate::config::Config::load_test("test_strip_www").indexer;
+        let indexer = crate::indexer::Indexer::new(&config).await.unwrap();
+        for (entry, url) in [
+            ("www.example.com", "https://www.example.com/a"),
+            ("example.com", "https://example.com/b"),
+        ] {
+            let scope = CrawlScope::parse(entry, true);
+            indexer
+                .add_html(url, "<title>Page</title><body>Text</body>", &scope.domain)
+                .unwrap();
+        }
+        let stats = indexer
+            .get_domain_stats(crate::config::SizeFormat::Decimal)
+            .unwrap();
+        assert_eq!(stats.len(), 1);
+        assert_eq!(stats[0].domain, "example.com");
+        assert_eq!(stats[0].page_count, 2);
+
+        // Clean up after test.
+        indexer.delete().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_initial_crawl_history() {
+        let config = Config::load_test("test_initial_crawl_history");
+        let indexer = crate::indexer::Indexer::new(&config.indexer).await.unwrap();
+        let mut config = config.crawler;
+        config.domains_file = format!("{TEST_DIR}/domains_test_initial_crawl_history");
+        std::fs::create_dir_all(TEST_DIR).unwrap();
+        std::fs::write(&config.domains_file, "").unwrap();
+        let (indexer_tx, _indexer_rx) = mpsc::channel(1);
+        let crawl_status = SharedCrawlStatus::default();
+        assert!(indexer.crawl_history().unwrap().is_empty());
+
+        initial_crawl(
+            indexer_tx,
+            &config,
+            crawl_status.clone(),
+            &HashSet::new(),
+            &indexer,
+        )
+        .await
+        .unwrap();
+        let last_crawl = crawl_status.read().unwrap().last_crawl.clone().unwrap();
+        assert_eq!(indexer.crawl_history().unwrap(), [last_crawl]);
+
+        // Clean up after test.
+        std::fs::remove_file(&config.domains_file).unwrap();
+        indexer.delete().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_page_budget() {
+        let page_budget = Arc::new(PageBudget::new(Some(3)));
+        let waiter = tokio::spawn({
+            let page_budget = page_budget.clone();
+            async move { page_budget.wait_until_exhausted().await }
+        });
+
+        // Pages from several domains share the budget.
+        let taken = ["a.com", "b.com", "a.com", "b.com", "c.com"]
+            .iter()
+            .filter(|_domain| page_budget.take_page())
+            .count();
+        assert_eq!(taken, 3);
+        assert!(page_budget.is_exhausted());
+
+        // Domain crawlers waiting on the budget are told to stop.
+        tokio::time::timeout(Duration::from_secs(1), waiter)
+            .await
+            .unwrap()
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_max_total_pages() {
+        let mut config = Config::load_test("test_max_total_pages").crawler;
+        config.max_pages_per_domain = 10;
+        config.max_total_pages = Some(3);
+        let pages = [
+            (
+                "/",
+                r#"<a href="/1">1</a> <a href="/2">2</a> <a href="/3">3</a>"#,
+            ),
+            ("/1", "1"),
+            ("/2", "2"),
+            ("/3", "3"),
+        ];
+        let mut domains = Vec::new();
+        for _site in 0..2 {
+            let (addr, _requested) = serve_site(&pages).await;
+            domains.push(DomainEntry {
+                domain: format!("http://{addr}/"),
+                weight: 1,
+            });
+        }
+        let (indexer_tx, mut indexer_rx) = mpsc::channel(16);
+        let crawl_status = SharedCrawlStatus::default();
+
+        crawl_domains(domains, indexer_tx, &config, crawl_status.clone())
+            .await
+            .unwrap();
+
+        // Both domains together send no more than the crawl-wide limit to the indexer.
+        let mut sent = 0;
+        while indexer_rx.try_recv().is_ok() {
+            sent += 1;
+        }
+        assert_eq!(sent, 3);
+        assert_eq!(crawl_status.read().unwrap().pages_crawled, 3);
+    }
+
+    #[tokio::test]
+    async fn test_page_budget_stop_after() {
+        let page_budget = Arc::new(PageBudget::new(None));
+        let deadline = page_budget.stop_after(Duration::from_millis(10));
+        assert!(page_budget.take_page());
+
+        // Domain crawlers are told to stop once the time is up, well before they would finish.
+        tokio::time::timeout(Duration::from_secs(1), page_budget.wait_until_exhausted())
+            .await
+            .unwrap();
+        deadline.await.unwrap();
+        assert!(page_budget.is_stopped());
+        assert!(!page_budget.take_page());
+    }
+
+    #[tokio::test]
+    async fn test_backlog_monitor() {
+        // Nobody receives, as when the indexer is stuck.
+        let (tx, _rx) = mpsc::channel(10);
+        for _ in 0..10 {
+            tx.try_send(()).unwrap();
+        }
+        assert!(tx.try_send(()).is_err());
+        let backlog = tx.max_capacity() - tx.capacity();
+        assert_eq!(backlog, 10);
+
+        let mut monitor = BacklogMonitor::new(Duration::from_secs(5));
+        let start = Instant::now();
+        assert!(!monitor.sample(backlog, 10, start));
+        assert!(!monitor.sample(backlog, 10, start + Duration::from_secs(4)));
+        assert_eq!(monitor.warnings_logged, 0);
+        assert!(monitor.sample(backlog, 10, start + Duration::from_secs(5)));
+        assert_eq!(monitor.warnings_logged, 1);
+        // Still saturated, but the warning isn't repeated right away.
+        assert!(monitor.sample(backlog, 10, start + Duration::from_secs(6)));
+        assert_eq!(monitor.warnings_logged, 1);
+        assert!(monitor.sample(backlog, 10, start + Duration::from_secs(10)));
+        assert_eq!(monitor.warnings_logged, 2);
+
+        // Once the indexer catches up, the queue has to stay full for a while again.
+        assert!(!monitor.sample(3, 10, start + Duration::from_secs(11)));
+        assert!(!monitor.sample(9, 10, start + Duration::from_secs(12)));
+        assert!(!monitor.sample(9, 10, start + Duration::from_secs(16)));
+        assert!(monitor.sample(9, 10, start + Duration::from_secs(17)));
+        assert_eq!(monitor.warnings_logged, 3);
+    }
+
+    #[test]
+    fn test_crawl_archive() {
+        let mut config = crate::config::Config::load_test("test_crawl_archive").crawler;
+        let path = format!(
+            "{}/crawl_test_crawl_archive.jsonl",
+            crate::test_utils::TEST_DIR
+        );
+        let _ = std::fs::remove_file(&path);
+        assert!(CrawlArchive::open(&config).unwrap().is_none());
+
+        config.save_crawl_to = Some(path.clone());
+        let archive = CrawlArchive::open(&config).unwrap().unwrap();
+        for (url, html) in [
+            ("https://example.com/", "<title>Home</title>"),
+            ("https://example.com/a", "<body>Line\nbreak</body>"),
+        ] {
+            archive
+                .save(&SavedPage {
+                    url: url.to_string(),
+                    domain: "example.com".to_string(),
+                    html: html.to_string(),
+                })
+                .unwrap();
+        }
+
+        let saved: Vec<SavedPage> = std::fs::read_to_string(&path)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(saved.len(), 2);
+        assert_eq!(saved[1].url, "https://example.com/a");
+        assert_eq!(saved[1].domain, "example.com");
+        assert_eq!(saved[1].html, "<body>Line\nbreak</body>");
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_crawl_saves_pages() {
+        let mut config = Config::load_test("test_crawl_saves_pages").crawler;
+        config.max_pages_per_domain = 10;
+        let path = format!("{TEST_DIR}/crawl_test_crawl_saves_pages.jsonl");
+        std::fs::create_dir_all(TEST_DIR).unwrap();
+        let _ = std::fs::remove_file(&path);
+        config.save_crawl_to = Some(path.clone());
+        let (addr, _requested) = serve_site(&[
+            ("/", r#"<title>Home</title><a href="/a">A</a>"#),
+            ("/a", "<title>A</title>"),
+        ])
+        .await;
+        let (indexer_tx, mut indexer_rx) = mpsc::channel(16);
+
+        let archive = CrawlArchive::open(&config).unwrap();
+        let mut crawler = DomainCrawler::new(
+            &format!("http://{addr}/"),
+            Arc::new(config),
+            SharedCrawlStatus::default(),
+            Arc::new(PageBudget::new(None)),
+            archive,
+            1,
+        )
+        .unwrap();
+        crawler.crawl_domain(indexer_tx).await.unwrap();
+
+        // Every page sent to the indexer is saved, with the stored domain.
+        let mut indexed = Vec::new();
+        while let Ok(SearchPage { page, .. }) = indexer_rx.try_recv() {
+            indexed.push(page.get_url().to_string());
+        }
+        let mut saved: Vec<SavedPage> = std::fs::read_to_string(&path)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        saved.sort_by(|a, b| a.url.cmp(&b.url));
+        indexed.sort();
+        assert_eq!(indexed.len(), 2);
+        assert_eq!(
+            saved.iter().map(|page| &page.url).collect::<Vec<_>>(),
+            indexed.iter().collect::<Vec<_>>()
+        );
+        assert!(saved.iter().all(|page| page.domain == addr.to_string()));
+        assert_eq!(saved[1].html, "<title>A</title>");
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_page_budget_unlimited() {
+        let page_budget = PageBudget::new(None);
+        assert!((0..1000).all(|_| page_budget.take_page()));
+        assert!(!page_budget.is_exhausted());
+    }
 }
@@ -0,0 +1,125 @@
+//! A token filter that drops junk tokens, like hashes and base64 fragments, which would otherwise
+//! each become a term that only ever appears once.
+
+use tantivy::tokenizer::{
+    Language, LowerCaser, RemoveLongFilter, SimpleTokenizer, Stemmer, TextAnalyzer, Token,
+    TokenFilter, TokenStream, Tokenizer,
+};
+
+/// Like tantivy's `en_stem` tokenizer, but without junk tokens.
+pub fn en_stem_without_junk(max_token_length: usize) -> TextAnalyzer {
+    TextAnalyzer::builder(SimpleTokenizer::default())
+        .filter(RemoveLongFilter::limit(40))
+        .filter(LowerCaser)
+        .filter(JunkTokenFilter { max_token_length })
+        .filter(Stemmer::new(Language::English))
+        .build()
+}
+
+/// Whether a lowercased token looks like junk rather than a word:
+///
+/// - It's longer than `max_token_length` characters.
+/// - It's long and mixes letters and digits, like hashes ("3f2a9c8e7d1b").
+/// - It's long and has no vowels, like base64 fragments ("xkcdqzrtpl").
+fn is_junk(token: &str, max_token_length: usize) -> bool {
+    const MIN_RANDOM_LENGTH: usize = 12;
+    const MIN_VOWELLESS_LENGTH: usize = 8;
+
+    let length = token.chars().count();
+    if length > max_token_length {
+        return true;
+    }
+
+    let has_digits = token.chars().any(|c| c.is_ascii_digit());
+    let has_letters = token.chars().any(|c| c.is_alphabetic());
+    if length >= MIN_RANDOM_LENGTH && has_digits && has_letters {
+        return true;
+    }
+
+    length >= MIN_VOWELLESS_LENGTH
+        && token.chars().all(|c| c.is_ascii_alphabetic())
+        && !token.chars().any(|c| "aeiouy".contains(c))
+}
+
+#[derive(Clone)]
+struct JunkTokenFilter {
+    max_token_length: usize,
+}
+
+impl TokenFilter for JunkTokenFilter {
+    type Tokenizer<T: Tokenizer> = JunkTokenFilterWrapper<T>;
+
+    fn transform<T: Tokenizer>(self, tokenizer: T) -> JunkTokenFilterWrapper<T> {
+        JunkTokenFilterWrapper {
+            max_token_length: self.max_token_length,
+            inner: tokenizer,
+        }
+    }
+}
+
+#[derive(Clone)]
+struct JunkTokenFilterWrapper<T: Tokenizer> {
+    max_token_length: usize,
+    inner: T,
+}
+
+impl<T: Tokenizer> Tokenizer for JunkTokenFilterWrapper<T> {
+    type TokenStream<'a> = JunkTokenFilterStream<T::TokenStream<'a>>;
+
+    fn token_stream<'a>(&'a mut self, text: &'a str) -> Self::TokenStream<'a> {
+        JunkTokenFilterStream {
+            max_token_length: self.max_token_length,
+            tail: self.inner.token_stream(text),
+        }
+    }
+}
+
+struct JunkTokenFilterStream<T> {
+    max_token_length: usize,
+    tail: T,
+}
+
+impl<T: TokenStream> TokenStream for JunkTokenFilterStream<T> {
+    fn advance(&mut self) -> bool {
+        while self.tail.advance() {
+            if !is_junk(&self.tail.token().text, self.max_token_length) {
+                return true;
+            }
+        }
+        false
+    }
+
+    fn token(&self) -> &Token {
+        self.tail.token()
+    }
+
+    fn token_mut(&mut self) -> &mut Token {
+        self.tail.token_mut()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_junk() {
+        assert!(!is_junk("ownership", 20));
+        assert!(!is_junk("utf8", 20));
+        assert!(!is_junk("windows10", 20));
+        assert!(!is_junk("rhythm", 20));
+        assert!(is_junk("internationalizations", 20));
+        assert!(is_junk("3f2a9c8e7d1b", 20));
+        assert!(is_junk("xkcdqzrtpl", 20));
+    }
+
+    #[test]
+    fn test_en_stem_without_junk() {
+        let mut tokenizer = en_stem_without_junk(20);
+        let mut tokens = Vec::new();
+        tokenizer
+            .token_stream("Parsing 3f2a9c8e7d1b0a4f xkcdqzrtplmnbv files")
+            .process(&mut |token| tokens.push(token.text.clone()));
+        assert_eq!(tokens, ["pars", "file"]);
+    }
+}
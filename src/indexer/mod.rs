@@ -1,64 +1,167 @@
+mod junk_tokens;
+mod score_expression;
+mod shards;
 mod tech_terms;
 
 use anyhow::Context;
+use arc_swap::ArcSwap;
+use encoding_rs::Encoding;
 use scraper::{ElementRef, Html, Node, Selector};
 use serde::{Deserialize, Serialize};
 use spider::page::Page;
 use std::{
+    borrow::Cow,
+    cmp::Reverse,
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    hash::{BuildHasher, RandomState},
+    ops::Range,
+    path::Path,
     sync::{
-        atomic::{AtomicBool, Ordering},
-        Arc, RwLock,
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex, RwLock,
     },
-    time::Duration,
+    time::{Duration, Instant},
 };
 use tantivy::{
+    aggregation::{agg_req::Aggregations, AggregationCollector},
     collector::TopDocs,
     doc,
-    query::{Query, QueryParser},
-    schema::{IndexRecordOption, Schema, TextFieldIndexing, TextOptions, Value, FAST, STORED},
-    snippet::SnippetGenerator,
-    Index, IndexReader, IndexWriter, ReloadPolicy, TantivyDocument,
+    postings::Postings,
+    query::{
+        AllQuery, BooleanQuery, BoostQuery, ConstScoreQuery, EmptyQuery, Occur, PhraseQuery, Query,
+        QueryParser, QueryParserError, RegexQuery, TermQuery,
+    },
+    schema::{
+        Field, FieldEntry, IndexRecordOption, Schema, TextFieldIndexing, TextOptions, Value, FAST,
+        STORED, STRING,
+    },
+    snippet::{Snippet, SnippetGenerator},
+    store::Compressor,
+    DocAddress, DocId, DocSet, Index, IndexReader, IndexSettings, IndexWriter, Order, ReloadPolicy,
+    Score, SegmentReader, TantivyDocument, Term,
 };
-use tokio::sync::mpsc;
+use tokio::sync::{mpsc, Notify};
 
-use crate::config::IndexerConfig;
+use crate::{
+    config::{
+        BodyIndexOption, DescriptionSource, IndexerConfig, NoTermsBehavior, ReaderReload,
+        SafeSearchConfig, SizeFormat, SnippetSource,
+    },
+    crawler::CrawlSummary,
+};
+use score_expression::ScoreExpression;
+use shards::{has_shard_layout, shard_dir, shard_of, ShardSearcher};
 use tech_terms::*;
 
+lazy_static::lazy_static! {
+    // Parsed once rather than for every indexed page.
+    static ref TITLE_SELECTOR: Selector = Selector::parse("title").unwrap();
+    static ref H1_SELECTOR: Selector = Selector::parse("h1").unwrap();
+    static ref LINK_SELECTOR: Selector = Selector::parse("a[href]").unwrap();
+    static ref DESCRIPTION_SELECTOR: Selector =
+        Selector::parse(r#"meta[name="description"]"#).unwrap();
+    static ref KEYWORDS_SELECTOR: Selector = Selector::parse(r#"meta[name="keywords" i]"#).unwrap();
+    static ref OG_DESCRIPTION_SELECTOR: Selector =
+        Selector::parse(r#"meta[property="og:description"]"#).unwrap();
+    static ref TWITTER_DESCRIPTION_SELECTOR: Selector =
+        Selector::parse(r#"meta[name="twitter:description"]"#).unwrap();
+    static ref JSON_LD_SELECTOR: Selector =
+        Selector::parse(r#"script[type="application/ld+json"]"#).unwrap();
+    static ref BODY_SELECTOR: Selector = Selector::parse("body").unwrap();
+    static ref SITE_NAME_SELECTOR: Selector =
+        Selector::parse(r#"meta[property="og:site_name"]"#).unwrap();
+    static ref IMAGE_SELECTOR: Selector = Selector::parse(r#"meta[property="og:image"]"#).unwrap();
+    static ref BREADCRUMB_SELECTOR: Selector = Selector::parse(
+        r#"nav[aria-label="breadcrumb" i], nav[aria-label="breadcrumbs" i], [itemtype$="schema.org/BreadcrumbList"]"#
+    )
+    .unwrap();
+    static ref LIST_ITEM_SELECTOR: Selector = Selector::parse("li").unwrap();
+}
+
 pub struct Indexer {
-    #[allow(dead_code)]
-    index: Index,
-    index_writer: Arc<RwLock<IndexWriter>>,
+    /// The index, split by domain into `num_shards` shards.
+    shards: Vec<Shard>,
     schema: Schema,
-    reader: Arc<RwLock<IndexReader>>,
     query_parser: Arc<RwLock<QueryParser>>,
+    /// Parses queries against the title alone, for [`SearchOptions::title_only`].
+    title_query_parser: QueryParser,
     stats_db: sled::Db,
+    synonyms: Synonyms,
+    /// `synonyms` plus [`TECH_TERM_EQUIVALENTS`], used with `expand_tech_terms`.
+    synonyms_with_tech_terms: Synonyms,
+    /// Parsed `content_selectors` by domain.
+    content_selectors: HashMap<String, Selector>,
+    /// Parsed `score_expression`, if any.
+    score_expression: Option<Arc<ScoreExpression>>,
     is_dirty: AtomicBool,
-    config: IndexerConfig,
+    /// Number of pages from the crawler that went through [`Indexer::add_page`], successfully or
+    /// not. Waiters on `page_processed` are notified for each one.
+    pages_processed: AtomicU64,
+    page_processed: Notify,
+    /// Number of pages not indexed again because they were unchanged, see
+    /// [`Indexer::is_unchanged`].
+    pages_unchanged: AtomicU64,
+    /// Number of pages not indexed because of `min_quality`, see [`quality_score`].
+    pages_low_quality: AtomicU64,
+    /// Domain stats not yet written to `stats_db`, with `batch_domain_stats`.
+    pending_domain_stats: Mutex<HashMap<String, RawDomainStats>>,
+    /// Replaced when the config is reloaded, see [`Indexer::set_config`].
+    config: ArcSwap<IndexerConfig>,
+    #[cfg(test)]
+    fail_stats_writes: AtomicBool,
+    #[cfg(test)]
+    fail_reindex: AtomicBool,
+}
+
+/// One of the indexes the pages are split into by domain, see [`IndexerConfig::num_shards`].
+struct Shard {
+    index: Index,
+    index_writer: Arc<RwLock<IndexWriter>>,
+    reader: IndexReader,
 }
 
 impl Indexer {
     pub async fn new(config: &IndexerConfig) -> anyhow::Result<Self> {
-        let schema = Self::create_schema();
-        let index = Self::create_index(&schema, &config.index_dir, config.new_index).await?;
-        let reader = Self::create_reader(&index)?;
-        let index_writer: Arc<RwLock<IndexWriter>> =
-            Arc::new(RwLock::new(index.writer(50_000_000)?));
-        let query_parser = Self::create_query_parser(&index, &schema)?;
-        let stats_db = Self::create_stats_db(config.new_index, &config.db_dir).await?;
+        anyhow::ensure!(
+            cfg!(feature = "pdf") || !config.index_pdfs,
+            "index_pdfs requires building with the `pdf` feature (cargo build --features pdf)"
+        );
+        let content_selectors = Self::parse_content_selectors(config)?;
+        let schema = Self::create_schema(config);
+        let shards = Self::create_shards(&schema, config).await?;
+        let query_parser = Self::create_query_parser(&shards[0].index, &schema)?;
+        let title_query_parser =
+            QueryParser::for_index(&shards[0].index, vec![schema.get_field("title").unwrap()]);
+        let score_expression = Self::parse_score_expression(config, &schema)?;
+        let stats_db = Self::create_stats_db(config).await?;
+        let synonyms = Self::load_synonyms(config.synonyms_file.as_deref()).await?;
+        let synonyms_with_tech_terms = with_tech_term_synonyms(&synonyms);
 
         Ok(Indexer {
-            index,
-            index_writer,
+            shards,
             schema,
-            reader,
             query_parser,
+            title_query_parser,
             stats_db,
+            synonyms,
+            synonyms_with_tech_terms,
+            content_selectors,
+            score_expression,
             is_dirty: AtomicBool::new(false),
-            config: config.clone(),
+            pages_processed: AtomicU64::new(0),
+            page_processed: Notify::new(),
+            pages_unchanged: AtomicU64::new(0),
+            pages_low_quality: AtomicU64::new(0),
+            pending_domain_stats: Default::default(),
+            config: ArcSwap::from_pointee(config.clone()),
+            #[cfg(test)]
+            fail_stats_writes: AtomicBool::new(false),
+            #[cfg(test)]
+            fail_reindex: AtomicBool::new(false),
         })
     }
 
-    fn create_schema() -> Schema {
+    fn create_schema(config: &IndexerConfig) -> Schema {
         let mut schema_builder = Schema::builder();
 
         let text_options_fast = TextOptions::default()
@@ -69,53 +172,162 @@ impl Indexer {
             )
             .set_stored()
             .set_fast(Some("en_stem"));
-        let text_options_body = TextOptions::default()
-            .set_indexing_options(
-                TextFieldIndexing::default()
-                    .set_tokenizer("en_stem")
-                    .set_index_option(IndexRecordOption::WithFreqsAndPositions),
-            )
-            .set_stored();
+        let text_options_body = TextOptions::default().set_indexing_options(
+            TextFieldIndexing::default()
+                .set_tokenizer("en_stem")
+                .set_index_option(match config.body_index_option {
+                    BodyIndexOption::Freqs => IndexRecordOption::WithFreqs,
+                    BodyIndexOption::FreqsAndPositions => IndexRecordOption::WithFreqsAndPositions,
+                }),
+        );
+        let text_options_body = if config.store_body {
+            text_options_body.set_stored()
+        } else {
+            text_options_body
+        };
 
-        schema_builder.add_text_field("title", text_options_fast.clone());
+        // The title and URL are also kept as raw strings in fast fields, so results without
+        // snippets don't have to load the stored document.
+        schema_builder.add_text_field("title", text_options_fast.clone().set_fast(None));
         schema_builder.add_text_field("description", text_options_fast);
         schema_builder.add_text_field("body", text_options_body);
-        schema_builder.add_text_field("url", STORED);
-        schema_builder.add_text_field("domain", STORED | FAST);
+        // Words of the URL path, only filled in with `index_url_slug`.
+        schema_builder.add_text_field(
+            "url_slug",
+            TextOptions::default().set_indexing_options(
+                TextFieldIndexing::default()
+                    .set_tokenizer("en_stem")
+                    .set_index_option(IndexRecordOption::WithFreqs),
+            ),
+        );
+        // `<meta name="keywords">`, only filled in with `index_keywords`. Stored, so a reindex
+        // without raw HTML keeps them.
+        schema_builder.add_text_field(
+            "keywords",
+            TextOptions::default()
+                .set_indexing_options(
+                    TextFieldIndexing::default()
+                        .set_tokenizer("en_stem")
+                        .set_index_option(IndexRecordOption::WithFreqs),
+                )
+                .set_stored(),
+        );
+        // Text of links to the page, only filled in with `index_anchor_text`.
+        schema_builder.add_text_field(
+            "anchor_text",
+            TextOptions::default().set_indexing_options(
+                TextFieldIndexing::default()
+                    .set_tokenizer("en_stem")
+                    .set_index_option(IndexRecordOption::WithFreqs),
+            ),
+        );
+        // Indexed as a raw string so clicked URLs can be checked against the index.
+        schema_builder.add_text_field("url", STRING | STORED | FAST);
+        // Indexed as a raw string so results can be filtered by domain.
+        schema_builder.add_text_field("domain", STRING | STORED | FAST);
         schema_builder.add_u64_field("size", STORED | FAST);
+        schema_builder.add_u64_field("depth", FAST);
+        schema_builder.add_bool_field("https", FAST);
+        // Indexed as a raw string so results can be filtered with `type:`.
+        schema_builder.add_text_field("doc_type", STRING | STORED | FAST);
+        // Absolute URL of the page's `og:image`, shown as a thumbnail.
+        schema_builder.add_text_field("image_url", STRING | STORED | FAST);
+        // Only filled in with `extract_breadcrumbs`. Fast, for results without a snippet.
+        schema_builder.add_text_field("breadcrumb", STORED | FAST);
+        // Unix timestamp of when the page was first indexed.
+        schema_builder.add_u64_field("crawled_at", STORED | FAST);
+        // Only filled in with `store_raw_html`, so pages can be reindexed without a crawl.
+        schema_builder.add_text_field("raw_html", STORED);
 
         schema_builder.build()
     }
 
-    async fn create_index(
-        schema: &Schema,
-        index_path: &str,
-        new_index: bool,
-    ) -> anyhow::Result<Index> {
-        if new_index {
+    /// Opens the shards of the index, or creates them with `new_index`. See
+    /// [`shards::shard_dir`] for where they are stored.
+    async fn create_shards(schema: &Schema, config: &IndexerConfig) -> anyhow::Result<Vec<Shard>> {
+        /// Memory of each shard's writer, split among its indexing threads.
+        const WRITER_MEMORY_BYTES: usize = 50_000_000;
+
+        let index_dir = &config.index_dir;
+        anyhow::ensure!(config.num_shards > 0, "num_shards must be at least 1");
+        if config.new_index {
             // Delete any existing index.
-            let _ = tokio::fs::remove_dir_all(index_path).await;
+            let _ = tokio::fs::remove_dir_all(index_dir).await;
+        } else if !has_shard_layout(index_dir, config.num_shards) {
+            anyhow::bail!(
+                "The index in '{index_dir}' doesn't exist or doesn't have num_shards = {} shards. \
+                 Set new_index: true to create it again with this number of shards.",
+                config.num_shards
+            );
+        }
+
+        let mut shards = Vec::with_capacity(config.num_shards);
+        for shard in 0..config.num_shards {
+            let index_path = shard_dir(index_dir, shard, config.num_shards);
+            let index = Self::create_index(schema, config, &index_path).await?;
+            shards.push(Shard {
+                index_writer: Arc::new(RwLock::new(index.writer(WRITER_MEMORY_BYTES)?)),
+                reader: Self::create_reader(&index, config.reload_policy)?,
+                index,
+            });
         }
+        Ok(shards)
+    }
 
-        // Create index directory if it doesn't exist
-        tokio::fs::create_dir_all(index_path).await?;
+    async fn create_index(
+        schema: &Schema,
+        config: &IndexerConfig,
+        index_path: &Path,
+    ) -> anyhow::Result<Index> {
+        // Validate even when opening an existing index, so a typo doesn't go unnoticed until the
+        // next reindex.
+        let docstore_compression = parse_docstore_compression(&config.docstore_compression)?;
 
-        let index = if new_index {
-            Index::create_in_dir(index_path, schema.clone())?
+        let index = if config.new_index {
+            tokio::fs::create_dir_all(index_path).await?;
+            // Index settings are persisted in the index meta, so they only apply to new indexes.
+            let settings = IndexSettings {
+                docstore_compression,
+                ..Default::default()
+            };
+            Index::builder()
+                .schema(schema.clone())
+                .settings(settings)
+                .create_in_dir(index_path)?
         } else {
-            Index::open_in_dir(index_path)?
+            let index = Index::open_in_dir(index_path)?;
+            let changed_fields = changed_fields(&index.schema(), schema);
+            if !changed_fields.is_empty() {
+                anyhow::bail!(
+                    "The index in '{}' was created with a different schema (changed fields: {}), \
+                     e.g. by an older version or with other field options. Set new_index: true \
+                     to create it again.",
+                    index_path.display(),
+                    changed_fields.join(", ")
+                );
+            }
+            index
         };
+        // Replaces the built-in tokenizer, so the schema stays the same either way.
+        if config.filter_junk_tokens {
+            index.tokenizers().register(
+                "en_stem",
+                junk_tokens::en_stem_without_junk(config.max_token_length),
+            );
+        }
 
         Ok(index)
     }
 
-    fn create_reader(index: &Index) -> anyhow::Result<Arc<RwLock<IndexReader>>> {
-        Ok(Arc::new(RwLock::new(
-            index
-                .reader_builder()
-                .reload_policy(ReloadPolicy::OnCommitWithDelay)
-                .try_into()?,
-        )))
+    fn create_reader(index: &Index, reload: ReaderReload) -> anyhow::Result<IndexReader> {
+        let reload_policy = match reload {
+            ReaderReload::OnCommit => ReloadPolicy::OnCommitWithDelay,
+            ReaderReload::Manual => ReloadPolicy::Manual,
+        };
+        Ok(index
+            .reader_builder()
+            .reload_policy(reload_policy)
+            .try_into()?)
     }
 
     fn create_query_parser(
@@ -125,14 +337,31 @@ impl Indexer {
         let title_field = schema.get_field("title").unwrap();
         let description_field = schema.get_field("description").unwrap();
         let body_field = schema.get_field("body").unwrap();
+        let url_slug_field = schema.get_field("url_slug").unwrap();
+        let anchor_text_field = schema.get_field("anchor_text").unwrap();
+        let keywords_field = schema.get_field("keywords").unwrap();
 
-        let mut query_parser =
-            QueryParser::for_index(index, vec![title_field, body_field, description_field]);
+        let mut query_parser = QueryParser::for_index(
+            index,
+            vec![
+                title_field,
+                body_field,
+                description_field,
+                url_slug_field,
+                anchor_text_field,
+                keywords_field,
+            ],
+        );
 
-        // Boost title and description fields for more relevant searches.
+        // Boost title and description fields for more relevant searches. URL slugs are often
+        // generic words like "index", so they count less than the body. Meta keywords are
+        // invisible to readers and easily stuffed, so they count least.
         query_parser.set_field_boost(title_field, 2.0);
         query_parser.set_field_boost(body_field, 1.0);
         query_parser.set_field_boost(description_field, 1.5);
+        query_parser.set_field_boost(url_slug_field, 0.5);
+        query_parser.set_field_boost(anchor_text_field, 1.5);
+        query_parser.set_field_boost(keywords_field, 0.3);
 
         // Enable fuzzy search for more error tolerance for the user.
         // REMOVED: breaks snippet generation.
@@ -143,8 +372,10 @@ impl Indexer {
         Ok(Arc::new(RwLock::new(query_parser)))
     }
 
-    async fn create_stats_db(new_index: bool, db_dir: &str) -> anyhow::Result<sled::Db> {
-        if new_index {
+    async fn create_stats_db(config: &IndexerConfig) -> anyhow::Result<sled::Db> {
+        let db_dir = &config.db_dir;
+
+        if config.new_index {
             let _ = tokio::fs::remove_dir_all(db_dir).await;
         }
 
@@ -153,13 +384,165 @@ impl Indexer {
             tokio::fs::create_dir_all(dir).await?;
         }
 
-        Ok(sled::open(db_dir)?)
+        match sled::open(db_dir) {
+            Ok(stats_db) => Ok(stats_db),
+            // sled only reports this as a generic I/O error. The DB is in use, so leave it alone.
+            Err(sled::Error::Io(e)) if e.to_string().contains("could not acquire lock") => Err(e)
+                .with_context(|| {
+                    format!("Stats DB '{db_dir}' is locked, is another instance running?")
+                }),
+            Err(e) if config.recover_stats_db => {
+                let broken_dir = format!("{db_dir}.broken-{}", crate::crawler::unix_timestamp());
+                eprintln!(
+                    "ERROR: Could not open stats DB '{db_dir}': {e}. Moving it to '{broken_dir}' \
+                     and starting a new one. Domain stats can be rebuilt with `reindex`."
+                );
+                tokio::fs::rename(db_dir, &broken_dir)
+                    .await
+                    .with_context(|| format!("Failed to move broken stats DB '{db_dir}'"))?;
+                Ok(sled::open(db_dir)?)
+            }
+            Err(e) => Err(e).with_context(|| {
+                format!(
+                    "Could not open stats DB '{db_dir}', it may have been corrupted by an unclean \
+                     shutdown. Move it away and rebuild domain stats with `reindex`, or enable \
+                     `recover_stats_db` to do this automatically"
+                )
+            }),
+        }
+    }
+
+    async fn load_synonyms(synonyms_file: Option<&str>) -> anyhow::Result<Synonyms> {
+        let Some(synonyms_file) = synonyms_file else {
+            return Ok(Synonyms::new());
+        };
+
+        let contents = tokio::fs::read_to_string(synonyms_file)
+            .await
+            .with_context(|| format!("Failed to read synonyms file '{synonyms_file}'"))?;
+        Ok(parse_synonyms(&contents))
+    }
+
+    /// Parses the selectors up front, so a typo fails at startup rather than on every page.
+    fn parse_content_selectors(
+        config: &IndexerConfig,
+    ) -> anyhow::Result<HashMap<String, Selector>> {
+        config
+            .content_selectors
+            .iter()
+            .map(|(domain, selector)| {
+                let parsed = Selector::parse(selector).map_err(|e| {
+                    anyhow::anyhow!("Invalid content selector '{selector}' for '{domain}': {e}")
+                })?;
+                Ok((domain.clone(), parsed))
+            })
+            .collect()
+    }
+
+    fn parse_score_expression(
+        config: &IndexerConfig,
+        schema: &Schema,
+    ) -> anyhow::Result<Option<Arc<ScoreExpression>>> {
+        if config.score_expression.trim().is_empty() {
+            return Ok(None);
+        }
+        let score_expression = ScoreExpression::parse(&config.score_expression, schema)
+            .with_context(|| format!("Invalid score_expression '{}'", config.score_expression))?;
+        Ok(Some(Arc::new(score_expression)))
+    }
+
+    pub fn schema(&self) -> &Schema {
+        &self.schema
+    }
+
+    /// The current config. Settings only used at startup, like `index_dir`, never change.
+    pub fn config(&self) -> Arc<IndexerConfig> {
+        self.config.load_full()
+    }
+
+    /// Applies a reloaded config to subsequent searches and indexed pages. Settings only used at
+    /// startup should be kept as they were, see [`crate::config::Config::reloaded`].
+    pub fn set_config(&self, config: IndexerConfig) {
+        self.config.store(Arc::new(config));
+    }
+
+    /// The index of the first shard. All shards have the same schema and tokenizers.
+    fn index(&self) -> &Index {
+        &self.shards[0].index
+    }
+
+    /// The shard storing the pages of `domain`.
+    fn shard(&self, domain: &str) -> &Shard {
+        &self.shards[shard_of(domain, self.shards.len())]
+    }
+
+    /// A snapshot of all shards to search.
+    fn searcher(&self) -> ShardSearcher {
+        ShardSearcher::new(
+            self.shards
+                .iter()
+                .map(|shard| shard.reader.searcher())
+                .collect(),
+        )
+    }
+
+    /// Commits pending documents of all shards, returning the first error.
+    fn commit(&self) -> anyhow::Result<()> {
+        let mut result = Ok(());
+        for shard in &self.shards {
+            if let Err(e) = shard.index_writer.write().unwrap().commit() {
+                result = result.and(Err(e));
+            }
+        }
+        Ok(result?)
+    }
+
+    /// Makes committed documents of all shards visible to searches.
+    fn reload(&self) -> anyhow::Result<()> {
+        for shard in &self.shards {
+            shard.reader.reload()?;
+        }
+        Ok(())
+    }
+
+    /// Commits pending documents and makes them visible to the reader immediately.
+    pub fn commit_and_reload(&self) -> anyhow::Result<()> {
+        self.commit_and_flush()?;
+        self.reload()
+    }
+
+    /// Commits pending documents, then writes their batched domain stats. Stats are only written
+    /// after a successful commit, so they never count pages that aren't in the index.
+    pub fn commit_and_flush(&self) -> anyhow::Result<()> {
+        self.commit()?;
+        self.is_dirty.store(false, Ordering::Relaxed);
+        self.flush_domain_stats()
+    }
+
+    /// Counts a page from the crawler as processed, see [`Indexer::commit_crawl`].
+    fn finish_page(&self) {
+        self.pages_processed.fetch_add(1, Ordering::SeqCst);
+        self.page_processed.notify_waiters();
+    }
+
+    /// Waits until `pages_crawled` pages from the crawler have been processed, then commits and
+    /// reloads, so the whole crawl is searchable without waiting for the next periodic commit.
+    pub async fn commit_crawl(&self, pages_crawled: u64) -> anyhow::Result<()> {
+        loop {
+            // Register before checking, so a notification in between isn't missed.
+            let notified = self.page_processed.notified();
+            if self.pages_processed.load(Ordering::SeqCst) >= pages_crawled {
+                break;
+            }
+            notified.await;
+        }
+        self.commit_and_reload()
     }
 
     #[allow(dead_code)]
     pub async fn delete(&self) -> anyhow::Result<()> {
-        let index_path = &self.config.index_dir;
-        let db_dir = &self.config.db_dir;
+        let index_path = &self.config().index_dir;
+        let db_dir = &self.config().db_dir;
 
         // Delete the index directory and stats database.
         let _ = tokio::fs::remove_dir_all(index_path).await;
@@ -169,31 +552,442 @@ impl Indexer {
     }
 
     pub fn add_page(&self, SearchPage { page, domain }: &SearchPage) -> anyhow::Result<()> {
-        let html = page.get_html();
         let url = page.get_url();
 
-        let document = Html::parse_document(&html);
+        // PDFs would be garbage if parsed as HTML, so they are either extracted or skipped.
+        if let Some(bytes) = page.get_bytes().filter(|bytes| is_pdf(bytes)) {
+            return if self.config().index_pdfs {
+                self.add_pdf(url, bytes, domain)
+            } else {
+                Ok(())
+            };
+        }
+
+        let html = match page.get_bytes() {
+            Some(bytes) if self.config().detect_encoding => {
+                decode_html(bytes, page_header(page, "content-type").as_deref())
+            }
+            _ => page.get_html(),
+        };
+        let validators = PageValidators {
+            etag: page_header(page, "etag"),
+            last_modified: page_header(page, "last-modified"),
+        };
+        self.add_html_if_changed(url, &html, domain, &validators)?;
+        Ok(())
+    }
+
+    /// Indexes the HTML of a page unless its validators show it's unchanged since it was last
+    /// indexed, with `skip_unchanged_pages` (see [`Indexer::is_unchanged`]). The page has been
+    /// downloaded either way, only re-indexing is skipped. Returns whether it was indexed.
+    fn add_html_if_changed(
+        &self,
+        url: &str,
+        html: &str,
+        domain: &str,
+        validators: &PageValidators,
+    ) -> anyhow::Result<bool> {
+        if !self.config().skip_unchanged_pages {
+            self.add_html(url, html, domain)?;
+            return Ok(true);
+        }
+        if self.is_unchanged(url, validators)? {
+            self.pages_unchanged.fetch_add(1, Ordering::Relaxed);
+            return Ok(false);
+        }
+
+        self.add_html(url, html, domain)?;
+        // Validators are only needed to skip pages, so failing to store them isn't an error.
+        if let Err(e) = self.store_validators(url, validators) {
+            eprintln!("ERROR: could not store validators of page '{url}': {e}");
+        }
+        Ok(true)
+    }
+
+    /// Whether the page at `url` was indexed before with the same `ETag`, or without an `ETag`,
+    /// the same `Last-Modified`. As in HTTP, the `ETag` takes precedence, since a page can change
+    /// more than once within the second resolution of `Last-Modified`.
+    fn is_unchanged(&self, url: &str, validators: &PageValidators) -> anyhow::Result<bool> {
+        let tree = self.stats_db.open_tree("validators")?;
+        let Some(value) = tree.get(url)? else {
+            return Ok(false);
+        };
+        let stored: PageValidators = bincode::deserialize(&value)?;
+        Ok(match (&validators.etag, &validators.last_modified) {
+            (Some(etag), _) => stored.etag.as_ref() == Some(etag),
+            (None, Some(last_modified)) => stored.last_modified.as_ref() == Some(last_modified),
+            (None, None) => false,
+        })
+    }
+
+    fn store_validators(&self, url: &str, validators: &PageValidators) -> anyhow::Result<()> {
+        let tree = self.stats_db.open_tree("validators")?;
+        if validators.etag.is_none() && validators.last_modified.is_none() {
+            tree.remove(url)?;
+        } else {
+            tree.insert(url, bincode::serialize(validators)?)?;
+        }
+        Ok(())
+    }
+
+    /// Parses and indexes the HTML of a single page.
+    pub fn add_html(&self, url: &str, html: &str, domain: &str) -> anyhow::Result<()> {
+        let config = self.config();
+        let is_allowlisted = self.is_allowlisted(&config, domain);
+        if !is_allowlisted && !config.index_anchor_text {
+            return Ok(());
+        }
+
+        // Parsed once for the anchor text, the content and the quality score.
+        let document = Html::parse_document(html);
+        if config.index_anchor_text {
+            // Links are recorded even for pages that aren't indexed, since they may point at
+            // pages that are. Anchor text only improves ranking, so failing to store it isn't an
+            // error.
+            if let Err(e) = self.store_anchor_texts(&config, url, &document) {
+                eprintln!("ERROR: could not store anchor text of page '{url}': {e}");
+            }
+        }
+        if !is_allowlisted {
+            return Ok(());
+        }
+        let content = self.parse_html(&config, url, &document, domain);
+        let min_quality = config.min_quality;
+        if min_quality > 0.0 && quality_score(&document, &content) < min_quality {
+            self.pages_low_quality.fetch_add(1, Ordering::Relaxed);
+            return Ok(());
+        }
+        self.add_document(url, domain, content, Some(html))
+    }
+
+    /// Appends the text of each link in the page to the anchor text of its target in `stats_db`.
+    /// Each target keeps every distinct text once, up to a total length.
+    fn store_anchor_texts(
+        &self,
+        config: &IndexerConfig,
+        url: &str,
+        document: &Html,
+    ) -> anyhow::Result<()> {
+        /// Enough for dozens of distinct link texts, without letting popular pages grow unbounded.
+        const MAX_ANCHOR_TEXT_LEN: usize = 2000;
+
+        let tree = self.stats_db.open_tree("anchor_text")?;
+        for (target, texts) in anchor_texts(document, url) {
+            let texts: Vec<_> = texts
+                .iter()
+                .map(|text| self.clean_text(config, text))
+                .filter(|text| !text.is_empty())
+                .collect();
+            if texts.is_empty() {
+                continue;
+            }
+            tree.fetch_and_update(&target, |current| {
+                let mut anchor_text =
+                    String::from_utf8_lossy(current.unwrap_or_default()).into_owned();
+                for text in &texts {
+                    if anchor_text.lines().any(|line| line == text)
+                        || anchor_text.len() + text.len() >= MAX_ANCHOR_TEXT_LEN
+                    {
+                        continue;
+                    }
+                    if !anchor_text.is_empty() {
+                        anchor_text.push('\n');
+                    }
+                    anchor_text.push_str(text);
+                }
+                Some(anchor_text.into_bytes())
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Returns the text of the links to `url` seen so far, one distinct text per line.
+    pub fn anchor_text(&self, url: &str) -> anyhow::Result<String> {
+        let tree = self.stats_db.open_tree("anchor_text")?;
+        Ok(tree
+            .get(url)?
+            .map(|text| String::from_utf8_lossy(&text).into_owned())
+            .unwrap_or_default())
+    }
+
+    /// Whether pages of the domain may be indexed under `index_allowlist`. Subdomains of listed
+    /// domains are allowed too.
+    fn is_allowlisted(&self, config: &IndexerConfig, domain: &str) -> bool {
+        let allowlist = &config.index_allowlist;
+        allowlist.is_empty()
+            || allowlist.iter().any(|allowed| {
+                domain.eq_ignore_ascii_case(allowed)
+                    || domain
+                        .to_ascii_lowercase()
+                        .ends_with(&format!(".{}", allowed.to_ascii_lowercase()))
+            })
+    }
 
-        let title_selector = Selector::parse("title").unwrap();
-        let description_selector = Selector::parse(r#"meta[name="description"]"#).unwrap();
-        let body_selector = Selector::parse("body").unwrap();
+    fn parse_html(
+        &self,
+        config: &IndexerConfig,
+        url: &str,
+        document: &Html,
+        domain: &str,
+    ) -> PageContent {
+        // Titles shorter than this (e.g. "Home") are too generic, so the <h1> is used instead.
+        const MIN_TITLE_LENGTH: usize = 5;
 
-        let title = document
-            .select(&title_selector)
+        let mut title = document
+            .select(&TITLE_SELECTOR)
             .next()
-            .map(|el| el.inner_html())
+            .map(|el| self.clean_text(config, &el.text().collect::<String>()))
+            .unwrap_or_default();
+        if title.trim().chars().count() < MIN_TITLE_LENGTH {
+            let h1 = document
+                .select(&H1_SELECTOR)
+                .next()
+                .map(|el| {
+                    extract_text(
+                        el,
+                        &config.ignored_elements,
+                        config.trim_space_before_punctuation,
+                        false,
+                    )
+                })
+                .unwrap_or_default();
+            if !h1.is_empty() {
+                title = h1;
+            }
+        }
+        if !config.title_suffix_separators.is_empty() {
+            let site_name = document
+                .select(&SITE_NAME_SELECTOR)
+                .next()
+                .and_then(|el| el.value().attr("content"));
+            title = trim_title_suffix(&title, &config.title_suffix_separators, domain, site_name)
+                .to_string();
+        }
+
+        let description = config
+            .description_sources
+            .iter()
+            .map(|source| self.clean_text(config, &description(document, *source)))
+            .find(|description| !description.is_empty())
             .unwrap_or_default();
-        let description = document
-            .select(&description_selector)
+        let extract = |element| {
+            self.clean_text(
+                config,
+                &extract_text(
+                    element,
+                    &config.ignored_elements,
+                    config.trim_space_before_punctuation,
+                    config.preserve_paragraphs,
+                ),
+            )
+        };
+        // The domain's content selector, if any, replaces the body. Pages without a match fall
+        // back to the whole body.
+        let content = self
+            .content_selectors
+            .get(domain)
+            .map(|selector| {
+                document
+                    .select(selector)
+                    .map(extract)
+                    .filter(|text| !text.is_empty())
+                    .collect::<Vec<_>>()
+                    .join(" ")
+            })
+            .filter(|content| !content.is_empty());
+        let body = match content {
+            Some(content) => content,
+            None => document
+                .select(&BODY_SELECTOR)
+                .next()
+                .map(extract)
+                .unwrap_or_default(),
+        };
+
+        let image_url = document
+            .select(&IMAGE_SELECTOR)
             .next()
-            .map(|el| el.value().attr("content").unwrap_or_default())
+            .and_then(|el| el.value().attr("content"))
+            .and_then(|image_url| resolve_url(url, image_url))
             .unwrap_or_default();
-        let body = if let Some(body) = document.select(&body_selector).next() {
-            extract_text(body)
+
+        let keywords = if config.index_keywords {
+            keywords(document, config.max_keywords)
+                .iter()
+                .map(|keyword| self.clean_text(config, keyword))
+                .collect::<Vec<_>>()
+                .join(", ")
+        } else {
+            String::new()
+        };
+
+        let breadcrumb = if config.extract_breadcrumbs {
+            breadcrumb(document)
+                .iter()
+                .map(|item| self.clean_text(config, item))
+                // Skip separators like "/" that some sites put in their own items.
+                .filter(|item| item.chars().any(char::is_alphanumeric))
+                .collect::<Vec<_>>()
+                .join(BREADCRUMB_SEPARATOR)
         } else {
             String::new()
         };
+
+        PageContent {
+            title,
+            description,
+            body,
+            doc_type: DOC_TYPE_HTML.to_string(),
+            image_url,
+            breadcrumb,
+            keywords,
+        }
+    }
+
+    /// Decodes entities left in extracted text if configured, and normalizes its whitespace,
+    /// including non-breaking spaces.
+    fn clean_text(&self, config: &IndexerConfig, text: &str) -> String {
+        let text = if config.decode_entities {
+            decode_entities(text)
+        } else {
+            Cow::Borrowed(text)
+        };
+        normalize_whitespace(&text, config.trim_space_before_punctuation)
+    }
+
+    /// Extracts and indexes the text of a PDF document, using its metadata title if present.
+    /// Password-protected and image-only PDFs are skipped.
+    #[cfg(feature = "pdf")]
+    fn add_pdf(&self, url: &str, bytes: &[u8], domain: &str) -> anyhow::Result<()> {
+        let config = self.config();
+        if !self.is_allowlisted(&config, domain) {
+            return Ok(());
+        }
+        let pdf = lopdf::Document::load_mem(bytes).context("Failed to parse PDF")?;
+
+        // pdf-extract panics on some malformed PDFs, which would stop the indexer task for good.
+        let extracted = std::panic::catch_unwind(|| pdf_extract::extract_text_from_mem(bytes))
+            .map_err(|_| anyhow::anyhow!("Failed to extract PDF text: malformed PDF"))?;
+        let body = match extracted {
+            Ok(body) => body,
+            Err(_) if pdf.is_encrypted() => {
+                println!("{domain}: skipping password-protected PDF: {url}");
+                return Ok(());
+            }
+            Err(e) => return Err(e).context("Failed to extract PDF text"),
+        };
+        let body = normalize_whitespace(&body, config.trim_space_before_punctuation);
+        if body.is_empty() {
+            println!("{domain}: skipping PDF without text: {url}");
+            return Ok(());
+        }
+
+        self.add_document(
+            url,
+            domain,
+            PageContent {
+                title: pdf_title(&pdf).unwrap_or_default(),
+                description: String::new(),
+                body,
+                doc_type: DOC_TYPE_PDF.to_string(),
+                image_url: String::new(),
+                breadcrumb: String::new(),
+                keywords: String::new(),
+            },
+            None,
+        )
+    }
+
+    /// Without the `pdf` feature, `index_pdfs` can only be enabled by a config reload, which
+    /// leaves PDFs skipped.
+    #[cfg(not(feature = "pdf"))]
+    fn add_pdf(&self, _url: &str, _bytes: &[u8], _domain: &str) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    fn add_document(
+        &self,
+        url: &str,
+        domain: &str,
+        content: PageContent,
+        raw_html: Option<&str>,
+    ) -> anyhow::Result<()> {
+        let index_writer_wlock = self.shard(domain).index_writer.write().unwrap();
+        self.remove_indexed_page(&index_writer_wlock, url, domain)?;
+        let size = self.write_document(
+            &index_writer_wlock,
+            url,
+            domain,
+            content,
+            raw_html,
+            crate::crawler::unix_timestamp(),
+        )?;
+
+        // The document is indexed at this point. Stats are nice to have, so a failure to update
+        // them shouldn't fail indexing.
+        if let Err(e) = self.update_domain_stats(domain, url, size) {
+            eprintln!("ERROR: could not update domain stats for page '{url}': {e}");
+        }
+
+        Ok(())
+    }
+
+    /// Deletes the indexed version of the page at `url`, if any, so that a page crawled again
+    /// replaces it instead of being indexed twice. Its size is taken out of the domain stats.
+    /// Pages indexed since the last commit aren't visible yet, so their document is deleted but
+    /// their stats are left as they are.
+    fn remove_indexed_page(
+        &self,
+        index_writer: &IndexWriter,
+        url: &str,
+        domain: &str,
+    ) -> anyhow::Result<()> {
+        let url_field = self.schema.get_field("url").unwrap();
+        let size_field = self.schema.get_field("size").unwrap();
+
+        let searcher = self.searcher();
+        let previous_size = match self.find_url(&searcher, url, &[])? {
+            Some(doc_address) => {
+                let document: TantivyDocument = searcher.doc(doc_address)?;
+                document
+                    .get_first(size_field)
+                    .and_then(|value| value.as_u64())
+            }
+            None => None,
+        };
+        index_writer.delete_term(Term::from_field_text(url_field, url));
+
+        if let Some(size) = previous_size {
+            // Like in `add_document`, stats are nice to have, so a failure isn't an error.
+            if let Err(e) = self.remove_from_domain_stats(domain, size) {
+                eprintln!("ERROR: could not update domain stats for page '{url}': {e}");
+            }
+        }
+        Ok(())
+    }
+
+    /// Adds a document for the page to `index_writer`. Returns the size of the page for the
+    /// domain stats, which are left to the caller.
+    fn write_document(
+        &self,
+        index_writer: &IndexWriter,
+        url: &str,
+        domain: &str,
+        PageContent {
+            title,
+            description,
+            body,
+            doc_type,
+            image_url,
+            breadcrumb,
+            keywords,
+        }: PageContent,
+        raw_html: Option<&str>,
+        crawled_at: u64,
+    ) -> anyhow::Result<u64> {
+        let config = self.config();
         let size = u64::try_from(body.len())?;
+        let depth = url_depth(url);
 
         let title_field = self.schema.get_field("title").unwrap();
         let description_field = self.schema.get_field("description").unwrap();
@@ -201,341 +995,5988 @@ impl Indexer {
         let url_field = self.schema.get_field("url").unwrap();
         let domain_field = self.schema.get_field("domain").unwrap();
         let size_field = self.schema.get_field("size").unwrap();
+        let depth_field = self.schema.get_field("depth").unwrap();
+        let https_field = self.schema.get_field("https").unwrap();
+        let doc_type_field = self.schema.get_field("doc_type").unwrap();
+        let crawled_at_field = self.schema.get_field("crawled_at").unwrap();
+        let raw_html_field = self.schema.get_field("raw_html").unwrap();
+        let url_slug_field = self.schema.get_field("url_slug").unwrap();
+        let image_url_field = self.schema.get_field("image_url").unwrap();
+        let breadcrumb_field = self.schema.get_field("breadcrumb").unwrap();
+        let keywords_field = self.schema.get_field("keywords").unwrap();
+        let anchor_text_field = self.schema.get_field("anchor_text").unwrap();
 
-        let index_writer_wlock = self.index_writer.write().unwrap();
-        index_writer_wlock.add_document(doc!(
+        let mut document = doc!(
             title_field => title,
             description_field => description,
             body_field => body,
             url_field => url,
-            domain_field => domain.clone(),
+            domain_field => domain,
             size_field => size,
-        ))?;
+            depth_field => depth,
+            https_field => url.starts_with("https://"),
+            doc_type_field => doc_type,
+            crawled_at_field => crawled_at,
+        );
+        if let Some(raw_html) = raw_html.filter(|_| config.store_raw_html) {
+            document.add_text(raw_html_field, raw_html);
+        }
+        if !image_url.is_empty() {
+            document.add_text(image_url_field, image_url);
+        }
+        if !breadcrumb.is_empty() {
+            document.add_text(breadcrumb_field, breadcrumb);
+        }
+        if !keywords.is_empty() {
+            document.add_text(keywords_field, keywords);
+        }
+        if config.index_url_slug {
+            document.add_text(url_slug_field, url_slug(url));
+        }
+        if config.index_anchor_text {
+            match self.anchor_text(url) {
+                Ok(anchor_text) if !anchor_text.is_empty() => {
+                    document.add_text(anchor_text_field, anchor_text);
+                }
+                Ok(_) => {}
+                Err(e) => eprintln!("ERROR: could not read anchor text of page '{url}': {e}"),
+            }
+        }
+        index_writer.add_document(document)?;
 
         self.is_dirty.store(true, Ordering::Relaxed);
 
-        self.update_domain_stats(domain, url, size)?;
+        Ok(size)
+    }
 
-        Ok(())
+    pub fn search(
+        &self,
+        query_str: &str,
+        num_docs: usize,
+        options: &SearchOptions,
+    ) -> anyhow::Result<Vec<SearchResult>> {
+        self.search_timed(query_str, num_docs, options)
+            .map(|(results, _timings)| results)
     }
 
-    pub fn search(&self, query_str: &str, num_docs: usize) -> anyhow::Result<Vec<SearchResult>> {
+    /// Like [`Indexer::search`], but also returns how long each phase of the search took.
+    pub fn search_timed(
+        &self,
+        query_str: &str,
+        num_docs: usize,
+        options: &SearchOptions,
+    ) -> anyhow::Result<(Vec<SearchResult>, SearchTimings)> {
         const MAX_QUERY_LENGTH: usize = 256;
+        /// Just enough to break ties between otherwise identical pages.
+        const HTTPS_BOOST: f32 = 1.05;
+        /// How many results are collected per requested result when deduplicating by title.
+        const DEDUPE_OVERFETCH: usize = 3;
 
         if query_str.len() > MAX_QUERY_LENGTH {
-            return Err(anyhow::anyhow!(
-                "Query too long - maximum length is {} characters",
-                MAX_QUERY_LENGTH
-            ));
+            return Err(InvalidQuery(format!(
+                "Query too long - maximum length is {MAX_QUERY_LENGTH} characters"
+            ))
+            .into());
         }
 
-        let reader = self.reader.read().unwrap();
-        let searcher = reader.searcher();
+        let start = Instant::now();
+        let config = self.config();
+        let searcher = self.searcher();
 
         let schema = &self.schema;
         let body_field = schema.get_field("body").unwrap();
 
-        let query = self.construct_query(query_str)?;
-
-        // Collect top results.
-        let top_docs = searcher
-            .search(&query, &TopDocs::with_limit(num_docs))
-            .context("Could not execute search")?;
+        let query = self.construct_query(&config, query_str, options)?;
+        let parsed = Instant::now();
 
-        // Display results.
-        //
-        // NOTE: We parallelize this because snippet generation can be expensive. We use threads
-        // instead of tasks because of a strange compiler error. (Snippet generation is blocking,
-        // anyway.)
-        let mut threads = vec![];
-        for (_score, doc_address) in top_docs {
-            let (snippet_generator, retrieved_doc) = {
-                let retrieved_doc: TantivyDocument = searcher.doc(doc_address).unwrap();
-
-                // Create a SnippetGenerator
-                let snippet_generator =
-                    SnippetGenerator::create(&searcher, &*query, body_field).unwrap();
-
-                (snippet_generator, retrieved_doc)
+        // When deduplicating, collect extra results so the page can be filled back up.
+        let rerank_limit = if options.dedupe_by_title {
+            num_docs * DEDUPE_OVERFETCH
+        } else {
+            num_docs
+        };
+        // Proximity re-ranking may move results up from below the limit.
+        let proximity_terms =
+            if config.proximity_boost > 0.0 && !options.title_only && self.body_has_positions() {
+                self.query_body_terms(query_str)?
+            } else {
+                Vec::new()
             };
+        let limit = if proximity_terms.len() >= 2 {
+            rerank_limit.max(config.proximity_candidates)
+        } else {
+            rerank_limit
+        };
 
-            let title_field = schema.get_field("title").unwrap();
-            let url_field = schema.get_field("url").unwrap();
+        // Collect top results, re-scoring them with the score expression, penalizing deep pages,
+        // preferring HTTPS and decaying old pages in news mode if configured.
+        let prefer_https = config.prefer_https;
+        let news_half_life_secs = if options.news_mode {
+            config.news_half_life_secs
+        } else {
+            0
+        };
+        let score_expression = self.score_expression.clone();
+        let top_docs = if config.depth_penalty > 0.0
+            || prefer_https
+            || news_half_life_secs > 0
+            || score_expression.is_some()
+        {
+            let depth_penalty = config.depth_penalty.clamp(0.0, 1.0);
+            let now = crate::crawler::unix_timestamp();
+            let collector =
+                TopDocs::with_limit(limit).tweak_score(move |segment_reader: &SegmentReader| {
+                    let score_expression = score_expression.as_ref().map(|score_expression| {
+                        score_expression.segment_scorer(segment_reader, now)
+                    });
+                    let fast_fields = segment_reader.fast_fields();
+                    let depth_reader = fast_fields.u64("depth").unwrap().first_or_default_col(0);
+                    let https_reader = fast_fields
+                        .bool("https")
+                        .unwrap()
+                        .first_or_default_col(false);
+                    let crawled_at_reader = fast_fields
+                        .u64("crawled_at")
+                        .unwrap()
+                        .first_or_default_col(0);
 
-            threads.push(std::thread::spawn(move || {
-                let title = retrieved_doc
-                    .get_first(title_field)
-                    .unwrap()
-                    .as_str()
-                    .unwrap()
-                    .to_string();
-                let url = retrieved_doc
+                    move |doc: DocId, score: Score| {
+                        let score = match &score_expression {
+                            Some(score_expression) => score_expression(doc, score),
+                            None => score,
+                        };
+                        let depth = depth_reader.get_val(doc);
+                        let mut score = score * (1.0 - depth_penalty).powi(depth as i32);
+                        if news_half_life_secs > 0 {
+                            let age = now.saturating_sub(crawled_at_reader.get_val(doc));
+                            score *= 0.5f32.powf(age as f32 / news_half_life_secs as f32);
+                        }
+                        if prefer_https && https_reader.get_val(doc) {
+                            score * HTTPS_BOOST
+                        } else {
+                            score
+                        }
+                    }
+                });
+            searcher.search(&query, &collector)
+        } else {
+            searcher.search(&query, &TopDocs::with_limit(limit))
+        }
+        .context("Could not execute search")?;
+        let top_docs = if proximity_terms.len() >= 2 {
+            let mut top_docs =
+                self.rerank_by_proximity(&config, &searcher, top_docs, &proximity_terms)?;
+            top_docs.truncate(rerank_limit);
+            top_docs
+        } else {
+            top_docs
+        };
+        let top_docs = if options.dedupe_by_title {
+            dedupe_by_title(&searcher, top_docs, num_docs)?
+        } else {
+            top_docs
+        };
+        let matched_terms = if options.matched_terms {
+            Some(self.matched_terms(&searcher, &*query, &top_docs)?)
+        } else {
+            None
+        };
+        let collected = Instant::now();
+        let timings = |end: Instant| SearchTimings {
+            parse: parsed - start,
+            collect: collected - parsed,
+            snippets: end - collected,
+            total: start.elapsed(),
+        };
+
+        if !options.include_snippet {
+            let mut results = top_docs
+                .into_iter()
+                .map(|(_score, doc_address)| fast_search_result(&searcher, doc_address, options))
+                .collect::<anyhow::Result<Vec<_>>>()?;
+            add_matched_terms(&mut results, matched_terms);
+            return Ok((results, timings(Instant::now())));
+        }
+
+        let title_field = schema.get_field("title").unwrap();
+        let url_field = schema.get_field("url").unwrap();
+
+        let retrieved_docs = top_docs
+            .into_iter()
+            .map(|(_score, doc_address)| searcher.doc(doc_address))
+            .collect::<Result<Vec<_>, _>>()
+            .context("Could not retrieve documents")?;
+        let description_field = schema.get_field("description").unwrap();
+        let image_url_field = schema.get_field("image_url").unwrap();
+        let breadcrumb_field = schema.get_field("breadcrumb").unwrap();
+        let crawled_at_field = schema.get_field("crawled_at").unwrap();
+        let domain_field = schema.get_field("domain").unwrap();
+        // Body snippets are generated from the stored body, so without it only the description
+        // is highlighted.
+        let body_snippet_generator = if searcher.schema().get_field_entry(body_field).is_stored() {
+            let mut body_snippet_generator = searcher
+                .snippet_generator(&*query, body_field)
+                .context("Could not create snippet generator")?;
+            body_snippet_generator.set_max_num_chars(options.snippet_max_chars);
+            Some(body_snippet_generator)
+        } else {
+            None
+        };
+        let mut description_snippet_generator = searcher
+            .snippet_generator(&*query, description_field)
+            .context("Could not create description snippet generator")?;
+        description_snippet_generator.set_max_num_chars(options.snippet_max_chars);
+        let title_snippet_generator = if options.snippet_preference.contains(&SnippetSource::Title)
+        {
+            Some(
+                searcher
+                    .snippet_generator(&*query, title_field)
+                    .context("Could not create title snippet generator")?,
+            )
+        } else {
+            None
+        };
+
+        let to_search_result = |retrieved_doc: &TantivyDocument| {
+            let title = retrieved_doc
+                .get_first(title_field)
+                .unwrap()
+                .as_str()
+                .unwrap()
+                .to_string();
+            let url = display_url(
+                retrieved_doc
                     .get_first(url_field)
                     .unwrap()
                     .as_str()
-                    .unwrap()
-                    .to_string();
+                    .unwrap(),
+                &options.hidden_url_params,
+            );
 
-                let snippet = snippet_generator.snippet_from_doc(&retrieved_doc);
-                let snippet = snippet.to_html();
+            let description_snippet = snippet_html(
+                &description_snippet_generator.snippet_from_doc(retrieved_doc),
+                options.snippet_max_chars,
+            );
+            // Snippets are empty unless they match the query, so the first non-empty one wins.
+            let is_shown = |source: &SnippetSource, snippet: &str| {
+                !snippet.is_empty()
+                    && (!options.collapse_redundant_snippets
+                        || *source == SnippetSource::Title
+                        || !is_redundant_snippet(snippet, &title))
+            };
+            let snippet = options
+                .snippet_preference
+                .iter()
+                .map(|source| {
+                    let snippet = match source {
+                        SnippetSource::BodyMatch => match &body_snippet_generator {
+                            Some(body_snippet_generator) if options.snippet_fragments > 1 => {
+                                let body = retrieved_doc
+                                    .get_first(body_field)
+                                    .and_then(|value| value.as_str())
+                                    .unwrap_or_default();
+                                multi_fragment_snippet(
+                                    body_snippet_generator,
+                                    body,
+                                    options.snippet_fragments,
+                                    options.snippet_max_chars,
+                                )
+                            }
+                            Some(body_snippet_generator) => snippet_html(
+                                &body_snippet_generator.snippet_from_doc(retrieved_doc),
+                                options.snippet_max_chars,
+                            ),
+                            None => String::new(),
+                        },
+                        SnippetSource::Description => description_snippet.clone(),
+                        SnippetSource::Title => {
+                            match title_snippet_generator
+                                .as_ref()
+                                .map(|generator| generator.snippet(&title))
+                            {
+                                Some(title_snippet) if !title_snippet.is_empty() => {
+                                    title_snippet.to_html()
+                                }
+                                _ => escape_snippet_text(&title),
+                            }
+                        }
+                    };
+                    (source, snippet)
+                })
+                .find(|(source, snippet)| is_shown(source, snippet))
+                .map(|(_source, snippet)| snippet);
+            // Rather than nothing, show the start of the description or body, unhighlighted.
+            let snippet = match snippet {
+                Some(snippet) => snippet,
+                None if options.collapse_redundant_snippets => [description_field, body_field]
+                    .into_iter()
+                    .filter_map(|field| retrieved_doc.get_first(field)?.as_str())
+                    .map(|text| plain_snippet(text, options.snippet_max_chars))
+                    .find(|snippet| is_shown(&SnippetSource::Description, snippet))
+                    .unwrap_or_default(),
+                None => String::new(),
+            };
 
-                SearchResult {
-                    title,
-                    url,
-                    snippet,
-                }
-            }));
-        }
+            let image_url = retrieved_doc
+                .get_first(image_url_field)
+                .and_then(|value| value.as_str())
+                .unwrap_or_default()
+                .to_string();
+            let breadcrumb = retrieved_doc
+                .get_first(breadcrumb_field)
+                .and_then(|value| value.as_str())
+                .unwrap_or_default()
+                .to_string();
+            let crawled_at = retrieved_doc
+                .get_first(crawled_at_field)
+                .and_then(|value| value.as_u64())
+                .unwrap_or_default();
+            let domain = retrieved_doc
+                .get_first(domain_field)
+                .and_then(|value| value.as_str())
+                .unwrap_or_default();
 
-        // TODO: add some timeout in case of a stalled thread.
-        let results = threads
-            .into_iter()
-            .map(|handle| handle.join().unwrap())
-            .collect();
+            SearchResult {
+                title,
+                url,
+                snippet,
+                description_snippet,
+                image_url,
+                breadcrumb,
+                crawled_at,
+                tld: tld(domain),
+                is_trusted: is_trusted_domain(domain, &options.trusted_domains),
+                matched_terms: None,
+            }
+        };
+
+        // Display results.
+        //
+        // NOTE: Snippet generation can be expensive, so we split the results into chunks and
+        // generate their snippets on a fixed number of threads. With a single thread or a single
+        // result, we don't spawn any threads at all.
+        let num_threads = options
+            .snippet_threads
+            .clamp(1, retrieved_docs.len().max(1));
+        let mut results: Vec<_> = if num_threads == 1 {
+            retrieved_docs.iter().map(to_search_result).collect()
+        } else {
+            let chunk_size = retrieved_docs.len().div_ceil(num_threads);
+            std::thread::scope(|scope| {
+                let handles = retrieved_docs
+                    .chunks(chunk_size)
+                    .map(|chunk| {
+                        scope.spawn(|| chunk.iter().map(to_search_result).collect::<Vec<_>>())
+                    })
+                    .collect::<Vec<_>>();
+
+                handles
+                    .into_iter()
+                    .flat_map(|handle| handle.join().unwrap())
+                    .collect()
+            })
+        };
+        add_matched_terms(&mut results, matched_terms);
 
-        Ok(results)
+        Ok((results, timings(Instant::now())))
     }
 
     /// Constructs a [`Query`] from the user input. We add a boost to certain tech terms to provide
     /// more relevant results.
-    fn construct_query(&self, query_str: &str) -> anyhow::Result<Box<dyn Query>> {
+    fn construct_query(
+        &self,
+        config: &IndexerConfig,
+        query_str: &str,
+        options: &SearchOptions,
+    ) -> anyhow::Result<Box<dyn Query>> {
         let query_parser = self.query_parser.read().unwrap();
 
-        // For better performance, remove semicolons from the query before passing it to tantivy.
-        let query_str = query_str.replace(";", " ");
-
-        let boosted_query = boost_tech_terms(&query_str, self.config.tech_term_boost);
+        // Pasted URLs and emails would be split into fragments by the query parser, so they're
+        // taken out before anything else and matched as a whole. Both are matched outside the
+        // title, so they're left to the title in title-only mode.
+        let (query_str, urls, emails) = if config.exact_url_queries && !options.title_only {
+            extract_urls_and_emails(query_str)
+        } else {
+            (query_str.to_string(), Vec::new(), Vec::new())
+        };
 
-        // Parse the user query on a best-effort basis, ignoring any errors.
-        let (query, _ignored_errors) = query_parser.parse_query_lenient(&boosted_query);
+        // Prefix terms are taken out first, so they aren't affected by wildcard stripping or our
+        // boosts.
+        let (query_str, prefixes) = extract_prefix_terms(&query_str);
+        let query_str = sanitize_query(
+            &query_str,
+            config.strip_query_boosts,
+            config.strip_query_wildcards,
+        );
+        let (query_str, doc_types) = extract_filters(&query_str, "type");
+        let (query_str, sites) = extract_filters(&query_str, "site");
 
-        Ok(query)
-    }
+        let boosted_query = boost_tech_terms(&query_str, config.tech_term_boost);
+        let synonyms = if config.expand_tech_terms {
+            &self.synonyms_with_tech_terms
+        } else {
+            &self.synonyms
+        };
+        let mut boosted_query = expand_synonyms(&boosted_query, synonyms, config.synonym_boost);
+        // Added after the boosts, so the words of an email aren't boosted as tech terms.
+        for email in &emails {
+            boosted_query.push(' ');
+            boosted_query.push_str(&self.email_query(email));
+        }
 
-    fn update_domain_stats(&self, domain: &str, url: &str, size: u64) -> anyhow::Result<()> {
-        let stats_key = format!("domain:{domain}");
-        let current_stats = self.stats_db.get(&stats_key)?.unwrap_or_default();
-        let mut stats: RawDomainStats =
-            bincode::deserialize(&current_stats).unwrap_or_else(|_| Default::default());
+        // Parse the user query on a best-effort basis, ignoring most errors. Silently dropping a
+        // phrase would be confusing, though.
+        let (query, errors) = if options.title_only {
+            self.title_query_parser.parse_query_lenient(&boosted_query)
+        } else {
+            query_parser.parse_query_lenient(&boosted_query)
+        };
+        if errors.iter().any(|e| {
+            matches!(e, QueryParserError::FieldDoesNotHavePositionsIndexed(field) if field == "body")
+        }) {
+            return Err(InvalidQuery(
+                "Phrase queries are not supported by this search engine".to_string(),
+            )
+            .into());
+        }
+
+        // Depending on the parser, queries of only punctuation match nothing or everything, neither
+        // of which is what the user wanted. A `*` is left to the parser.
+        let has_terms = !prefixes.is_empty()
+            || !urls.is_empty()
+            || !emails.is_empty()
+            || !doc_types.is_empty()
+            || !sites.is_empty()
+            || query_str.contains('*')
+            || self.has_searchable_terms(&query_str)?;
+        let query = if has_terms {
+            query
+        } else {
+            match config.no_terms_behavior {
+                NoTermsBehavior::Error => {
+                    return Err(InvalidQuery(
+                        "The query has no searchable terms, try some words instead".to_string(),
+                    )
+                    .into())
+                }
+                NoTermsBehavior::MatchAll => Box::new(AllQuery),
+            }
+        };
 
-        stats.page_count += 1;
-        stats.total_size += size;
+        let query = self.add_prefix_queries(query, &prefixes, options.title_only)?;
+        let query = self.add_url_queries(query, &urls);
+        let query = self.add_title_coverage_boost(config, query, &query_str)?;
+        // Both reward matches in the body.
+        let query = if options.title_only {
+            query
+        } else {
+            let query = self.add_phrase_boost(config, query, &query_str)?;
+            self.add_multi_field_boost(config, query, &query_str)?
+        };
+        let match_all = query_str.trim().is_empty()
+            && prefixes.is_empty()
+            && urls.is_empty()
+            && emails.is_empty();
+        let query = self.apply_filters(query, match_all, "doc_type", &doc_types);
+        let query = self.apply_filters(query, match_all, "domain", &sites);
 
-        // Update min size and URL
-        if size < stats.min_size {
-            stats.min_size = size;
-            stats.min_url = url.to_string();
+        if options.safe_search {
+            Ok(self.apply_safe_search(config, query, &query_parser))
+        } else {
+            Ok(query)
         }
-        // Update max size and URL
-        if size > stats.max_size {
-            stats.max_size = size;
-            stats.max_url = url.to_string();
+    }
+
+    /// Restricts results to documents with one of `values` in `field_name`, for `type:` and
+    /// `site:` filters. A query with only filters (`match_all`) lists all matching documents.
+    fn apply_filters(
+        &self,
+        query: Box<dyn Query>,
+        match_all: bool,
+        field_name: &str,
+        values: &[String],
+    ) -> Box<dyn Query> {
+        if values.is_empty() {
+            return query;
         }
-        self.stats_db
-            .insert(stats_key, bincode::serialize(&stats)?)?;
 
-        Ok(())
+        let field = self.schema.get_field(field_name).unwrap();
+        let filter_query = BooleanQuery::new(
+            values
+                .iter()
+                .map(|value| {
+                    let term = Term::from_field_text(field, value);
+                    let query: Box<dyn Query> =
+                        Box::new(TermQuery::new(term, IndexRecordOption::Basic));
+                    (Occur::Should, query)
+                })
+                .collect(),
+        );
+        let query = if match_all { Box::new(AllQuery) } else { query };
+
+        Box::new(BooleanQuery::new(vec![
+            (Occur::Must, query),
+            (Occur::Must, Box::new(filter_query)),
+        ]))
     }
 
-    pub fn get_domain_stats(&self) -> anyhow::Result<Vec<DomainStats>> {
-        let mut stats = Vec::new();
+    /// Whether the query text contains anything that is indexed as a term, unlike punctuation.
+    fn has_searchable_terms(&self, query_str: &str) -> anyhow::Result<bool> {
+        let body_field = self.schema.get_field("body").unwrap();
+        let mut tokenizer = self.index().tokenizer_for_field(body_field)?;
+        let has_terms = tokenizer.token_stream(query_str).advance();
+        Ok(has_terms)
+    }
 
-        for item in self.stats_db.scan_prefix("domain:") {
-            let (key, value) = item?;
-            let domain = String::from_utf8(key.as_ref()[7..].to_vec())?;
-            let raw_stats: RawDomainStats = bincode::deserialize(&value)?;
+    /// Adds a query for each prefix from [`extract_prefix_terms`], matching any indexed term that
+    /// starts with it. Since indexed terms are stemmed, the stem of the prefix is tried as well, so
+    /// "programming*" still matches "program". Prefix matches have a constant score per field.
+    /// With `title_only`, only the title is matched.
+    fn add_prefix_queries(
+        &self,
+        query: Box<dyn Query>,
+        prefixes: &[String],
+        title_only: bool,
+    ) -> anyhow::Result<Box<dyn Query>> {
+        if prefixes.is_empty() {
+            return Ok(query);
+        }
 
-            stats.push(DomainStats {
-                domain,
-                page_count: raw_stats.page_count,
-                total_size: humansize::format_size(raw_stats.total_size, humansize::DECIMAL),
-                min_page_size: humansize::format_size(raw_stats.min_size, humansize::DECIMAL),
-                max_page_size: humansize::format_size(raw_stats.max_size, humansize::DECIMAL),
-                min_page_url: raw_stats.min_url,
-                max_page_url: raw_stats.max_url,
+        // Same boosts as the query parser.
+        let fields: &[(&str, Score)] = if title_only {
+            &[("title", 2.0)]
+        } else {
+            &[
+                ("title", 2.0),
+                ("description", 1.5),
+                ("body", 1.0),
+                ("url_slug", 0.5),
+                ("anchor_text", 1.5),
+                ("keywords", 0.3),
+            ]
+        };
+        let body_field = self.schema.get_field("body").unwrap();
+        let mut tokenizer = self.index().tokenizer_for_field(body_field)?;
+
+        // Pages may match either the query or a prefix, but never an excluded word.
+        let (query, exclusions) = split_exclusions(query);
+        let mut alternatives = vec![(Occur::Should, query)];
+        for prefix in prefixes {
+            let mut patterns = BTreeSet::from([prefix.clone()]);
+            tokenizer.token_stream(prefix).process(&mut |token| {
+                patterns.insert(token.text.clone());
             });
+
+            for pattern in patterns {
+                for &(field_name, boost) in fields {
+                    let field = self.schema.get_field(field_name).unwrap();
+                    // Prefixes are alphanumeric, so they need no escaping.
+                    let regex_query = RegexQuery::from_pattern(&format!("{pattern}.*"), field)
+                        .context("Could not create prefix query")?;
+                    alternatives.push((
+                        Occur::Should,
+                        Box::new(BoostQuery::new(Box::new(regex_query), boost)),
+                    ));
+                }
+            }
         }
 
-        stats.sort_by(|a, b| a.domain.cmp(&b.domain));
-        Ok(stats)
+        let mut clauses: Vec<(Occur, Box<dyn Query>)> =
+            vec![(Occur::Must, Box::new(BooleanQuery::new(alternatives)))];
+        clauses.extend(exclusions.into_iter().map(|query| (Occur::MustNot, query)));
+        Ok(Box::new(BooleanQuery::new(clauses)))
     }
-}
 
-fn extract_text(element: ElementRef) -> String {
-    const IGNORED_ELEMENTS: &[&str] = &["script"];
+    /// Adds a query for each URL from [`extract_urls_and_emails`], matching the page with that URL
+    /// (see [`url_variants`]). A match scores [`URL_MATCH_SCORE`], so it ranks above pages that
+    /// only match the rest of the query.
+    fn add_url_queries(&self, query: Box<dyn Query>, urls: &[String]) -> Box<dyn Query> {
+        if urls.is_empty() {
+            return query;
+        }
 
-    let mut text = String::new();
+        let url_field = self.schema.get_field("url").unwrap();
+        let mut clauses = vec![(Occur::Should, query)];
+        for url in urls {
+            let url_query = BooleanQuery::new(
+                url_variants(url)
+                    .iter()
+                    .map(|variant| -> (Occur, Box<dyn Query>) {
+                        let term = Term::from_field_text(url_field, variant);
+                        (
+                            Occur::Should,
+                            Box::new(TermQuery::new(term, IndexRecordOption::Basic)),
+                        )
+                    })
+                    .collect(),
+            );
+            clauses.push((
+                Occur::Should,
+                Box::new(ConstScoreQuery::new(Box::new(url_query), URL_MATCH_SCORE)),
+            ));
+        }
 
-    for child in element.children() {
-        match child.value() {
-            // If the child is an element, check if it's a <script>
-            Node::Element(e) => {
-                if !IGNORED_ELEMENTS.contains(&e.name()) {
-                    if let Some(el_ref) = ElementRef::wrap(child) {
-                        text.push_str(&extract_text(el_ref));
+        Box::new(BooleanQuery::new(clauses))
+    }
+
+    /// The query syntax for an email from [`extract_urls_and_emails`]: its words as a phrase, or
+    /// as plain words if the body has no positions for phrase queries.
+    fn email_query(&self, email: &str) -> String {
+        let words = email
+            .split(|c: char| !c.is_alphanumeric())
+            .filter(|word| !word.is_empty())
+            .collect::<Vec<_>>()
+            .join(" ");
+        if self.body_has_positions() {
+            format!("\"{words}\"")
+        } else {
+            words
+        }
+    }
+
+    /// Rewards titles by the fraction of the query's distinct terms they contain, so a title with
+    /// all of them ranks above one with a single (maybe rare) term. Each title term adds a constant
+    /// score, unlike BM25 where one rare term can outweigh the rest.
+    fn add_title_coverage_boost(
+        &self,
+        config: &IndexerConfig,
+        query: Box<dyn Query>,
+        query_str: &str,
+    ) -> anyhow::Result<Box<dyn Query>> {
+        if config.title_coverage_boost <= 0.0 {
+            return Ok(query);
+        }
+
+        let title_field = self.schema.get_field("title").unwrap();
+        let mut tokenizer = self.index().tokenizer_for_field(title_field)?;
+
+        let mut terms = Vec::new();
+        tokenizer
+            .token_stream(&positive_query_text(query_str).replace('"', " "))
+            .process(&mut |token| terms.push(Term::from_field_text(title_field, &token.text)));
+        terms.sort();
+        terms.dedup();
+
+        // Coverage means nothing for a single term.
+        if terms.len() < 2 {
+            return Ok(query);
+        }
+
+        // Covering every term adds the whole boost. Only pages matching the query are boosted.
+        let term_boost = config.title_coverage_boost / terms.len() as f32;
+        let mut clauses = vec![(Occur::Must, query)];
+        for term in terms {
+            let term_query = TermQuery::new(term, IndexRecordOption::Basic);
+            clauses.push((
+                Occur::Should,
+                Box::new(ConstScoreQuery::new(Box::new(term_query), term_boost)),
+            ));
+        }
+
+        Ok(Box::new(BooleanQuery::new(clauses)))
+    }
+
+    /// Rewards documents for each query term found in more than one of the title, description and
+    /// body, which static field boosts alone don't capture. Every pair of fields containing a term
+    /// adds a fixed share of the boost, so term frequencies don't matter.
+    fn add_multi_field_boost(
+        &self,
+        config: &IndexerConfig,
+        query: Box<dyn Query>,
+        query_str: &str,
+    ) -> anyhow::Result<Box<dyn Query>> {
+        if config.multi_field_boost <= 0.0 {
+            return Ok(query);
+        }
+
+        let fields =
+            ["title", "description", "body"].map(|name| self.schema.get_field(name).unwrap());
+        // All three fields use the same tokenizer.
+        let mut tokenizer = self.index().tokenizer_for_field(fields[2])?;
+
+        let mut words = Vec::new();
+        tokenizer
+            .token_stream(&positive_query_text(query_str).replace('"', " "))
+            .process(&mut |token| words.push(token.text.clone()));
+        words.sort();
+        words.dedup();
+        if words.is_empty() {
+            return Ok(query);
+        }
+
+        // Only pages matching the query are boosted.
+        let pair_boost = config.multi_field_boost / words.len() as f32;
+        let mut clauses = vec![(Occur::Must, query)];
+        for word in &words {
+            for (i, &first) in fields.iter().enumerate() {
+                for &second in &fields[i + 1..] {
+                    let both_fields = BooleanQuery::intersection(
+                        [first, second]
+                            .map(|field| -> Box<dyn Query> {
+                                Box::new(TermQuery::new(
+                                    Term::from_field_text(field, word),
+                                    IndexRecordOption::Basic,
+                                ))
+                            })
+                            .into(),
+                    );
+                    clauses.push((
+                        Occur::Should,
+                        Box::new(ConstScoreQuery::new(Box::new(both_fields), pair_boost)),
+                    ));
+                }
+            }
+        }
+
+        Ok(Box::new(BooleanQuery::new(clauses)))
+    }
+
+    /// Whether body positions are indexed. Checks the schema of the index itself, which may
+    /// predate a config change.
+    fn body_has_positions(&self) -> bool {
+        let body_field = self.schema.get_field("body").unwrap();
+        self.index()
+            .schema()
+            .get_field_entry(body_field)
+            .field_type()
+            .get_index_record_option()
+            .is_some_and(|option| option.has_positions())
+    }
+
+    /// Rewards documents whose body contains the whole query as an exact phrase, without
+    /// requiring the user to quote it.
+    fn add_phrase_boost(
+        &self,
+        config: &IndexerConfig,
+        query: Box<dyn Query>,
+        query_str: &str,
+    ) -> anyhow::Result<Box<dyn Query>> {
+        let body_field = self.schema.get_field("body").unwrap();
+
+        if config.phrase_boost <= 0.0 || !self.body_has_positions() {
+            return Ok(query);
+        }
+
+        let mut tokenizer = self.index().tokenizer_for_field(body_field)?;
+
+        let mut terms = Vec::new();
+        tokenizer
+            .token_stream(&positive_query_text(query_str).replace('"', " "))
+            .process(&mut |token| terms.push(Term::from_field_text(body_field, &token.text)));
+
+        // A phrase needs at least two terms.
+        if terms.len() < 2 {
+            return Ok(query);
+        }
+
+        let phrase_query = BoostQuery::new(Box::new(PhraseQuery::new(terms)), config.phrase_boost);
+
+        // The phrase only adds to the score of pages matching the query.
+        Ok(Box::new(BooleanQuery::new(vec![
+            (Occur::Must, query),
+            (Occur::Should, Box::new(phrase_query)),
+        ])))
+    }
+
+    /// Returns the distinct body terms of the words the user typed, without filters, prefix terms
+    /// or query syntax.
+    fn query_body_terms(&self, query_str: &str) -> anyhow::Result<Vec<Term>> {
+        let (query_str, _prefixes) = extract_prefix_terms(query_str);
+        let query_str = sanitize_query(&query_str, true, true);
+        let (query_str, _doc_types) = extract_filters(&query_str, "type");
+        let (query_str, _sites) = extract_filters(&query_str, "site");
+
+        let body_field = self.schema.get_field("body").unwrap();
+        let mut tokenizer = self.index().tokenizer_for_field(body_field)?;
+        let mut terms = Vec::new();
+        tokenizer
+            .token_stream(&query_str.replace('"', " "))
+            .process(&mut |token| {
+                let term = Term::from_field_text(body_field, &token.text);
+                if !terms.contains(&term) {
+                    terms.push(term);
+                }
+            });
+        Ok(terms)
+    }
+
+    /// Returns the terms of `query` that occur in each of `top_docs`, in query order and by their
+    /// indexed text, so a term matching several fields is listed once. Filters such as `site:` are
+    /// not included. Only the first [`MAX_MATCHED_TERMS`] distinct terms are checked, since each
+    /// costs a postings lookup per result.
+    fn matched_terms(
+        &self,
+        searcher: &ShardSearcher,
+        query: &dyn Query,
+        top_docs: &[(Score, DocAddress)],
+    ) -> anyhow::Result<Vec<Vec<String>>> {
+        let text_fields: Vec<Field> = [
+            "title",
+            "body",
+            "description",
+            "url_slug",
+            "anchor_text",
+            "keywords",
+        ]
+        .into_iter()
+        .map(|name| self.schema.get_field(name).unwrap())
+        .collect();
+        let mut terms: Vec<Term> = Vec::new();
+        query.query_terms(&mut |term, _need_positions| {
+            if terms.len() < MAX_MATCHED_TERMS
+                && text_fields.contains(&term.field())
+                && !terms.contains(term)
+            {
+                terms.push(term.clone());
+            }
+        });
+
+        top_docs
+            .iter()
+            .map(|(_score, doc_address)| {
+                let segment_reader = searcher.segment_reader(doc_address.segment_ord);
+                let mut matched: Vec<String> = Vec::new();
+                for term in &terms {
+                    let value = term.value();
+                    let Some(text) = value.as_str() else {
+                        continue;
+                    };
+                    if matched.iter().any(|matched| matched == text) {
+                        continue;
+                    }
+                    let inverted_index = segment_reader.inverted_index(term.field())?;
+                    if let Some(mut postings) =
+                        inverted_index.read_postings(term, IndexRecordOption::Basic)?
+                    {
+                        if postings.seek(doc_address.doc_id) == doc_address.doc_id {
+                            matched.push(text.to_string());
+                        }
+                    }
+                }
+                Ok(matched)
+            })
+            .collect()
+    }
+
+    /// Boosts documents by how close together all of the `terms` appear in their body, and sorts
+    /// them by their new scores. The boost is `proximity_boost` for adjacent terms, and shrinks
+    /// as the smallest span of the body containing all terms grows. Documents without all terms
+    /// keep their score.
+    fn rerank_by_proximity(
+        &self,
+        config: &IndexerConfig,
+        searcher: &ShardSearcher,
+        top_docs: Vec<(Score, DocAddress)>,
+        terms: &[Term],
+    ) -> anyhow::Result<Vec<(Score, DocAddress)>> {
+        let body_field = self.schema.get_field("body").unwrap();
+        let proximity_boost = config.proximity_boost;
+
+        let mut reranked = Vec::with_capacity(top_docs.len());
+        for (score, doc_address) in top_docs {
+            let inverted_index = searcher
+                .segment_reader(doc_address.segment_ord)
+                .inverted_index(body_field)?;
+            let mut term_positions = Vec::with_capacity(terms.len());
+            for term in terms {
+                let mut positions = Vec::new();
+                if let Some(mut postings) =
+                    inverted_index.read_postings(term, IndexRecordOption::WithFreqsAndPositions)?
+                {
+                    if postings.seek(doc_address.doc_id) == doc_address.doc_id {
+                        postings.positions(&mut positions);
                     }
                 }
+                term_positions.push(positions);
             }
-            // If the child is a text node, append its content
-            Node::Text(t) => {
-                let t = t.trim();
-                if !t.is_empty() {
-                    text.push_str(t);
-                    text.push(' '); // Add a space between text nodes
+
+            let score = match min_span(&term_positions) {
+                Some(span) => {
+                    // The smallest possible span has one position per term.
+                    let closeness = (terms.len() - 1) as f32 / (span - 1).max(1) as f32;
+                    score * (1.0 + proximity_boost * closeness.min(1.0))
+                }
+                None => score,
+            };
+            reranked.push((score, doc_address));
+        }
+
+        reranked.sort_by(|(a, _), (b, _)| b.total_cmp(a));
+        Ok(reranked)
+    }
+
+    /// Runs a trivial search and reads every fast field once, so that the first real search doesn't
+    /// pay for loading term dictionaries and columns. Returns how long it took.
+    pub fn warmup(&self) -> anyhow::Result<Duration> {
+        let start = std::time::Instant::now();
+
+        self.search("the", 1, &SearchOptions::default())
+            .context("Warm-up search failed")?;
+
+        let searcher = self.searcher();
+        for segment_reader in searcher.segment_readers() {
+            if segment_reader.max_doc() == 0 {
+                continue;
+            }
+            let fast_fields = segment_reader.fast_fields();
+            for field_name in ["size", "depth", "crawled_at"] {
+                let column = fast_fields.u64(field_name)?;
+                let _ = column.first(0);
+            }
+            for field_name in ["title", "url", "domain"] {
+                if let Some(column) = fast_fields.str(field_name)? {
+                    let _ = column.term_ords(0).next();
                 }
             }
-            _ => {}
         }
+
+        Ok(start.elapsed())
     }
 
-    text
-}
+    /// Calls `f` with each indexed term of `field_name` in order, with its document frequency
+    /// across all shards, for terms in at least `min_freq` documents. The term dictionaries of the
+    /// segments are merged as they're read, so the vocabulary is never held in memory. Terms of
+    /// text fields are stemmed, like the query terms they're matched against.
+    pub fn vocabulary(
+        &self,
+        field_name: &str,
+        min_freq: u64,
+        mut f: impl FnMut(&str, u64) -> anyhow::Result<()>,
+    ) -> anyhow::Result<()> {
+        let field = self
+            .schema
+            .get_field(field_name)
+            .ok()
+            .filter(|&field| self.schema.get_field_entry(field).is_indexed())
+            .ok_or_else(|| InvalidQuery(format!("'{field_name}' is not an indexed field")))?;
 
-/// Splits a query string into terms, preserving quoted phrases
-fn split_query_terms(query_str: &str) -> Vec<String> {
-    let mut terms = Vec::new();
-    let mut current_term = String::new();
-    let mut in_quotes = false;
+        let searcher = self.searcher();
+        let inverted_indexes = searcher
+            .segment_readers()
+            .iter()
+            .map(|segment_reader| segment_reader.inverted_index(field))
+            .collect::<tantivy::Result<Vec<_>>>()?;
+        let mut streams = Vec::with_capacity(inverted_indexes.len());
+        for inverted_index in &inverted_indexes {
+            let mut stream = inverted_index.terms().stream()?;
+            if stream.advance() {
+                streams.push(stream);
+            }
+        }
 
-    for c in query_str.chars() {
-        if c == '"' {
-            in_quotes = !in_quotes;
+        // Each step takes the smallest term of all segments and advances the segments that have it.
+        while let Some(term) = streams.iter().map(|stream| stream.key()).min() {
+            let term = term.to_vec();
+            let mut doc_freq = 0;
+            streams.retain_mut(|stream| {
+                if stream.key() != term {
+                    return true;
+                }
+                doc_freq += u64::from(stream.value().doc_freq);
+                stream.advance()
+            });
+            if doc_freq >= min_freq {
+                if let Ok(term) = std::str::from_utf8(&term) {
+                    f(term, doc_freq)?;
+                }
+            }
         }
 
-        match c {
-            ' ' if !in_quotes => {
-                // Space outside quotes - add current term if non-empty
-                if !current_term.is_empty() {
-                    terms.push(current_term.clone());
-                    current_term.clear();
+        Ok(())
+    }
+
+    /// Counts the documents matching a query per domain, for the `max_domains` domains with the
+    /// most matches. Uses a terms aggregation over the `domain` fast field, so all matches are
+    /// counted, not just the returned results.
+    pub fn domain_counts(
+        &self,
+        query_str: &str,
+        max_domains: usize,
+        options: &SearchOptions,
+    ) -> anyhow::Result<Vec<DomainCount>> {
+        if max_domains == 0 {
+            return Ok(Vec::new());
+        }
+
+        let searcher = self.searcher();
+        let query = self.construct_query(&self.config(), query_str, options)?;
+        let aggregations: Aggregations = serde_json::from_value(serde_json::json!({
+            "domains": { "terms": { "field": "domain", "size": max_domains } }
+        }))?;
+        let collector = AggregationCollector::from_aggs(aggregations, Default::default());
+        let results = searcher
+            .search(&query, &collector)
+            .context("Could not count domains")?;
+
+        // The buckets are read from their JSON form, which is stable across tantivy versions.
+        let results = serde_json::to_value(results)?;
+        let mut counts: Vec<DomainCount> = results["domains"]["buckets"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|bucket| {
+                Some(DomainCount {
+                    domain: bucket["key"].as_str()?.to_string(),
+                    count: bucket["doc_count"].as_u64()?,
+                })
+            })
+            .collect();
+        counts.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.domain.cmp(&b.domain)));
+        Ok(counts)
+    }
+
+    /// Suggests refinements of a query by appending words that are common in its top results but
+    /// rare in the index as a whole (tf-idf style). To bound the cost, only the title, description
+    /// and start of the body of a few top results are looked at.
+    pub fn related_searches(
+        &self,
+        query_str: &str,
+        max_suggestions: usize,
+        options: &SearchOptions,
+    ) -> anyhow::Result<Vec<String>> {
+        const MAX_DOCS: usize = 10;
+        const MAX_BODY_BYTES: usize = 2000;
+        const MIN_WORD_LENGTH: usize = 3;
+
+        if max_suggestions == 0 {
+            return Ok(Vec::new());
+        }
+
+        let searcher = self.searcher();
+        let query = self.construct_query(&self.config(), query_str, options)?;
+        let top_docs = searcher.search(&query, &TopDocs::with_limit(MAX_DOCS))?;
+        if top_docs.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let title_field = self.schema.get_field("title").unwrap();
+        let description_field = self.schema.get_field("description").unwrap();
+        let body_field = self.schema.get_field("body").unwrap();
+
+        // Words are compared by their stem, like in the index.
+        let mut tokenizer = self.index().tokenizer_for_field(body_field)?;
+        let mut stem = |text: &str| {
+            let mut stems = Vec::new();
+            tokenizer
+                .token_stream(text)
+                .process(&mut |token| stems.push(token.text.clone()));
+            stems
+        };
+        let query_stems: HashSet<String> = stem(query_str).into_iter().collect();
+
+        // The number of top results containing each stem, and how it was first written.
+        let mut result_counts: HashMap<String, (usize, String)> = HashMap::new();
+        for (_score, doc_address) in &top_docs {
+            let document: TantivyDocument = searcher.doc(*doc_address)?;
+            let get_text = |field| {
+                document
+                    .get_first(field)
+                    .and_then(|value| value.as_str())
+                    .unwrap_or_default()
+            };
+            let body = get_text(body_field);
+            let mut body_end = body.len().min(MAX_BODY_BYTES);
+            while !body.is_char_boundary(body_end) {
+                body_end -= 1;
+            }
+            let body = &body[..body_end];
+            let text = [get_text(title_field), get_text(description_field), body].join(" ");
+
+            let mut seen = HashSet::new();
+            for word in text.split(|c: char| !c.is_alphanumeric()) {
+                if word.chars().count() < MIN_WORD_LENGTH || word.chars().all(char::is_numeric) {
+                    continue;
+                }
+                let Some(word_stem) = stem(word).pop() else {
+                    continue;
+                };
+                if query_stems.contains(&word_stem) || !seen.insert(word_stem.clone()) {
+                    continue;
                 }
+                result_counts
+                    .entry(word_stem)
+                    .or_insert_with(|| (0, word.to_lowercase()))
+                    .0 += 1;
+            }
+        }
+
+        // Words of a single result don't say much about the query, unless there is only one.
+        let min_result_count = top_docs.len().min(2);
+        let num_docs = searcher.num_docs() as f32;
+        let mut candidates = Vec::new();
+        for (word_stem, (result_count, word)) in result_counts {
+            if result_count < min_result_count {
+                continue;
+            }
+            let doc_freq = searcher.doc_freq(&Term::from_field_text(body_field, &word_stem))?;
+            // Words in most pages, like "the", don't refine anything.
+            if doc_freq as f32 > num_docs / 2.0 {
+                continue;
+            }
+            let idf = (num_docs / (doc_freq + 1) as f32).ln_1p();
+            candidates.push((result_count as f32 * idf, word));
+        }
+        candidates.sort_by(|(score_a, word_a), (score_b, word_b)| {
+            score_b.total_cmp(score_a).then_with(|| word_a.cmp(word_b))
+        });
+
+        let query_str = query_str.trim();
+        Ok(candidates
+            .into_iter()
+            .take(max_suggestions)
+            .map(|(_score, word)| format!("{query_str} {word}"))
+            .collect())
+    }
+
+    /// Lists pages without a query, e.g. to browse from an empty search. Results have no
+    /// snippet.
+    pub fn browse(
+        &self,
+        order: BrowseOrder,
+        num_docs: usize,
+        options: &SearchOptions,
+    ) -> anyhow::Result<Vec<SearchResult>> {
+        let searcher = self.searcher();
+
+        let query: Box<dyn Query> = Box::new(AllQuery);
+        let query = if options.safe_search {
+            self.apply_safe_search(&self.config(), query, &self.query_parser.read().unwrap())
+        } else {
+            query
+        };
+
+        let doc_addresses: Vec<DocAddress> = match order {
+            BrowseOrder::Recent => searcher
+                .search(
+                    &query,
+                    &TopDocs::with_limit(num_docs)
+                        .order_by_fast_field::<u64>("crawled_at", Order::Desc),
+                )?
+                .into_iter()
+                .map(|(_crawled_at, doc_address)| doc_address)
+                .collect(),
+            BrowseOrder::Random => {
+                // Rank by a hash with a fresh random key, for a different order every time.
+                let random_state = RandomState::new();
+                let collector = TopDocs::with_limit(num_docs).custom_score(
+                    move |segment_reader: &SegmentReader| {
+                        let random_state = random_state.clone();
+                        let segment_id = segment_reader.segment_id();
+                        move |doc: DocId| random_state.hash_one((segment_id, doc))
+                    },
+                );
+                searcher
+                    .search(&query, &collector)?
+                    .into_iter()
+                    .map(|(_rank, doc_address)| doc_address)
+                    .collect()
+            }
+        };
+
+        let title_field = self.schema.get_field("title").unwrap();
+        let url_field = self.schema.get_field("url").unwrap();
+        let image_url_field = self.schema.get_field("image_url").unwrap();
+        let breadcrumb_field = self.schema.get_field("breadcrumb").unwrap();
+        let crawled_at_field = self.schema.get_field("crawled_at").unwrap();
+        let domain_field = self.schema.get_field("domain").unwrap();
+
+        doc_addresses
+            .into_iter()
+            .map(|doc_address| {
+                let doc = searcher.doc(doc_address)?;
+                let get_text = |field| {
+                    doc.get_first(field)
+                        .and_then(|value| value.as_str())
+                        .unwrap_or_default()
+                        .to_string()
+                };
+
+                Ok(SearchResult {
+                    title: get_text(title_field),
+                    url: display_url(&get_text(url_field), &options.hidden_url_params),
+                    snippet: String::new(),
+                    description_snippet: String::new(),
+                    image_url: get_text(image_url_field),
+                    breadcrumb: get_text(breadcrumb_field),
+                    crawled_at: doc
+                        .get_first(crawled_at_field)
+                        .and_then(|value| value.as_u64())
+                        .unwrap_or_default(),
+                    tld: tld(&get_text(domain_field)),
+                    is_trusted: is_trusted_domain(
+                        &get_text(domain_field),
+                        &options.trusted_domains,
+                    ),
+                    matched_terms: None,
+                })
+            })
+            .collect()
+    }
+
+    /// Excludes results from blocked domains or containing blocked terms. This only filters search
+    /// results; blocked pages remain in the index.
+    fn apply_safe_search(
+        &self,
+        config: &IndexerConfig,
+        query: Box<dyn Query>,
+        query_parser: &QueryParser,
+    ) -> Box<dyn Query> {
+        let SafeSearchConfig {
+            blocked_domains,
+            blocked_terms,
+        } = &config.safe_search;
+
+        if blocked_domains.is_empty() && blocked_terms.is_empty() {
+            return query;
+        }
+
+        let domain_field = self.schema.get_field("domain").unwrap();
+
+        let mut clauses = vec![(Occur::Must, query)];
+        for domain in blocked_domains {
+            let term = Term::from_field_text(domain_field, domain);
+            clauses.push((
+                Occur::MustNot,
+                Box::new(TermQuery::new(term, IndexRecordOption::Basic)),
+            ));
+        }
+        for blocked_term in blocked_terms {
+            // Require every word of a multi-word entry to be present.
+            let blocked_query = blocked_term
+                .split_whitespace()
+                .map(|word| format!("+{word}"))
+                .collect::<Vec<_>>()
+                .join(" ");
+            let (blocked_query, _ignored_errors) = query_parser.parse_query_lenient(&blocked_query);
+            clauses.push((Occur::MustNot, blocked_query));
+        }
+
+        Box::new(BooleanQuery::new(clauses))
+    }
+
+    /// Re-extracts every page that has stored raw HTML and rewrites the whole index, e.g. after
+    /// changing `ignored_elements`. Other pages, like PDFs, are kept as they are. Domain stats are
+    /// rebuilt along the way, and only replace the old ones once the new index is committed.
+    /// Returns the number of re-extracted pages.
+    ///
+    /// Without `store_body`, pages without raw HTML would lose their body, so the index is left
+    /// untouched and an error returned if there are any.
+    pub fn reindex(&self) -> anyhow::Result<u64> {
+        let title_field = self.schema.get_field("title").unwrap();
+        let description_field = self.schema.get_field("description").unwrap();
+        let body_field = self.schema.get_field("body").unwrap();
+        let url_field = self.schema.get_field("url").unwrap();
+        let domain_field = self.schema.get_field("domain").unwrap();
+        let crawled_at_field = self.schema.get_field("crawled_at").unwrap();
+        let raw_html_field = self.schema.get_field("raw_html").unwrap();
+        let doc_type_field = self.schema.get_field("doc_type").unwrap();
+        let image_url_field = self.schema.get_field("image_url").unwrap();
+        let breadcrumb_field = self.schema.get_field("breadcrumb").unwrap();
+        let keywords_field = self.schema.get_field("keywords").unwrap();
+
+        let get_text = |document: &TantivyDocument, field| {
+            document
+                .get_first(field)
+                .and_then(|value| value.as_str())
+                .map(str::to_string)
+        };
+
+        let mut index_writer_wlocks: Vec<_> = self
+            .shards
+            .iter()
+            .map(|shard| shard.index_writer.write().unwrap())
+            .collect();
+
+        // Commit pending pages first so that they are part of the snapshot we read from.
+        let mut searchers = Vec::with_capacity(self.shards.len());
+        for (shard, index_writer_wlock) in self.shards.iter().zip(&mut index_writer_wlocks) {
+            index_writer_wlock.commit()?;
+            searchers.push(shard.index.reader()?.searcher());
+        }
+        let searcher = ShardSearcher::new(searchers);
+
+        if !self.schema.get_field_entry(body_field).is_stored() {
+            for segment_reader in searcher.segment_readers() {
+                let store_reader = segment_reader.get_store_reader(1)?;
+                for document in store_reader.iter(segment_reader.alive_bitset()) {
+                    let document: TantivyDocument = document?;
+                    if get_text(&document, raw_html_field).is_none() {
+                        let url = get_text(&document, url_field).unwrap_or_default();
+                        anyhow::bail!(
+                            "Can't reindex: the page '{url}' has neither its body (store_body) nor \
+                             its raw HTML (store_raw_html) stored, so it would become unsearchable \
+                             by its body. Crawl again with new_index: true instead."
+                        );
+                    }
+                }
+            }
+        }
+
+        // Old documents stay readable through `searcher` until we commit.
+        for index_writer_wlock in &mut index_writer_wlocks {
+            index_writer_wlock.delete_all_documents()?;
+        }
+
+        // Stats are rebuilt aside and only replace the old ones once the rewrite is committed.
+        let mut domain_stats: HashMap<String, RawDomainStats> = HashMap::new();
+        let timestamp = crate::crawler::unix_timestamp();
+        let config = self.config();
+        let mut rewrite = || -> anyhow::Result<u64> {
+            let mut reindexed = 0;
+
+            for segment_reader in searcher.segment_readers() {
+                let store_reader = segment_reader.get_store_reader(1)?;
+
+                for document in store_reader.iter(segment_reader.alive_bitset()) {
+                    let document: TantivyDocument = document?;
+                    let url = get_text(&document, url_field).unwrap_or_default();
+                    let domain = get_text(&document, domain_field).unwrap_or_default();
+                    let raw_html = get_text(&document, raw_html_field);
+                    let crawled_at = document
+                        .get_first(crawled_at_field)
+                        .and_then(|value| value.as_u64())
+                        .unwrap_or_else(crate::crawler::unix_timestamp);
+
+                    let content = match &raw_html {
+                        Some(raw_html) => {
+                            reindexed += 1;
+                            self.parse_html(&config, &url, &Html::parse_document(raw_html), &domain)
+                        }
+                        None => PageContent {
+                            title: get_text(&document, title_field).unwrap_or_default(),
+                            description: get_text(&document, description_field).unwrap_or_default(),
+                            body: get_text(&document, body_field).unwrap_or_default(),
+                            doc_type: get_text(&document, doc_type_field)
+                                .unwrap_or_else(|| DOC_TYPE_HTML.to_string()),
+                            image_url: get_text(&document, image_url_field).unwrap_or_default(),
+                            breadcrumb: get_text(&document, breadcrumb_field).unwrap_or_default(),
+                            keywords: get_text(&document, keywords_field).unwrap_or_default(),
+                        },
+                    };
+
+                    let size = self.write_document(
+                        &index_writer_wlocks[shard_of(&domain, self.shards.len())],
+                        &url,
+                        &domain,
+                        content,
+                        raw_html.as_deref(),
+                        crawled_at,
+                    )?;
+                    domain_stats
+                        .entry(domain)
+                        .or_default()
+                        .add_page(&url, size, timestamp);
+                }
+            }
+
+            #[cfg(test)]
+            if self.fail_reindex.load(Ordering::Relaxed) {
+                anyhow::bail!("simulated reindex failure");
+            }
+            for index_writer_wlock in &mut index_writer_wlocks {
+                index_writer_wlock.commit()?;
+            }
+            Ok(reindexed)
+        };
+
+        match rewrite() {
+            Ok(reindexed) => {
+                self.is_dirty.store(false, Ordering::Relaxed);
+                self.replace_domain_stats(&domain_stats)
+                    .context("Reindexed, but could not write the rebuilt domain stats")?;
+                self.reload()?;
+                Ok(reindexed)
+            }
+            Err(e) => {
+                for index_writer_wlock in &mut index_writer_wlocks {
+                    index_writer_wlock.rollback()?;
+                }
+                Err(e)
             }
-            _ => current_term.push(c),
         }
     }
-    // Add final term if non-empty
-    if !current_term.is_empty() {
-        terms.push(current_term);
+
+    fn update_domain_stats(&self, domain: &str, url: &str, size: u64) -> anyhow::Result<()> {
+        #[cfg(test)]
+        if self.fail_stats_writes.load(Ordering::Relaxed) {
+            anyhow::bail!("simulated stats write failure");
+        }
+
+        let timestamp = crate::crawler::unix_timestamp();
+
+        if self.config().batch_domain_stats {
+            self.pending_domain_stats
+                .lock()
+                .unwrap()
+                .entry(domain.to_string())
+                .or_default()
+                .add_page(url, size, timestamp);
+            return Ok(());
+        }
+
+        let stats_key = format!("domain:{domain}");
+        let mut stats = self.read_domain_stats(&stats_key)?;
+        stats.add_page(url, size, timestamp);
+        self.stats_db
+            .insert(stats_key, bincode::serialize(&stats)?)?;
+
+        Ok(())
+    }
+
+    /// Replaces the stats of all domains with `domain_stats`, in a single batch. Pending batched
+    /// stats are dropped, since they are part of `domain_stats`.
+    fn replace_domain_stats(
+        &self,
+        domain_stats: &HashMap<String, RawDomainStats>,
+    ) -> anyhow::Result<()> {
+        let mut pending_stats = self.pending_domain_stats.lock().unwrap();
+
+        let mut batch = sled::Batch::default();
+        for key in self.stats_db.scan_prefix("domain:").keys() {
+            batch.remove(key?);
+        }
+        for (domain, stats) in domain_stats {
+            batch.insert(
+                format!("domain:{domain}").as_bytes(),
+                bincode::serialize(stats)?,
+            );
+        }
+        self.stats_db.apply_batch(batch)?;
+        pending_stats.clear();
+
+        Ok(())
+    }
+
+    /// Takes a replaced page of `size` out of the stored stats of `domain`.
+    fn remove_from_domain_stats(&self, domain: &str, size: u64) -> anyhow::Result<()> {
+        // Held so that a concurrent flush of batched stats can't overwrite the update.
+        let _pending_stats = self.pending_domain_stats.lock().unwrap();
+
+        let stats_key = format!("domain:{domain}");
+        let mut stats = self.read_domain_stats(&stats_key)?;
+        if stats.page_count == 0 {
+            return Ok(());
+        }
+        stats.remove_page(size);
+        self.stats_db
+            .insert(stats_key, bincode::serialize(&stats)?)?;
+
+        Ok(())
+    }
+
+    fn read_domain_stats(&self, stats_key: &str) -> anyhow::Result<RawDomainStats> {
+        match self.stats_db.get(stats_key)? {
+            Some(current_stats) => RawDomainStats::decode(&current_stats),
+            None => Ok(Default::default()),
+        }
+    }
+
+    /// Writes domain stats accumulated with `batch_domain_stats` to the stats DB, in a single
+    /// batch. Called after every commit, see [`Indexer::commit_and_flush`].
+    pub fn flush_domain_stats(&self) -> anyhow::Result<()> {
+        let mut pending_stats = self.pending_domain_stats.lock().unwrap();
+        if pending_stats.is_empty() {
+            return Ok(());
+        }
+
+        let mut batch = sled::Batch::default();
+        for (domain, pending) in pending_stats.iter() {
+            let stats_key = format!("domain:{domain}");
+            let mut stats = self.read_domain_stats(&stats_key)?;
+            stats.merge(pending);
+            batch.insert(stats_key.as_bytes(), bincode::serialize(&stats)?);
+        }
+        self.stats_db.apply_batch(batch)?;
+        // Only cleared once written, so a failed flush is retried on the next commit.
+        pending_stats.clear();
+
+        Ok(())
+    }
+
+    /// Whether `url` belongs to an indexed page, either exactly or in its displayed form without
+    /// hidden query parameters (see [`display_url`]).
+    pub fn is_indexed_url(&self, url: &str, hidden_url_params: &[String]) -> anyhow::Result<bool> {
+        let searcher = self.searcher();
+        Ok(self.find_url(&searcher, url, hidden_url_params)?.is_some())
+    }
+
+    /// Returns the stored text of the page at `url`, matched like in [`Indexer::is_indexed_url`].
+    /// `None` if the page isn't indexed or the index doesn't store bodies.
+    pub fn cached_page(
+        &self,
+        url: &str,
+        hidden_url_params: &[String],
+    ) -> anyhow::Result<Option<CachedPage>> {
+        let searcher = self.searcher();
+        let body_field = self.schema.get_field("body").unwrap();
+        // Check the schema of the index itself, which may predate a config change.
+        if !searcher.schema().get_field_entry(body_field).is_stored() {
+            return Ok(None);
+        }
+        let Some(doc_address) = self.find_url(&searcher, url, hidden_url_params)? else {
+            return Ok(None);
+        };
+
+        let document = searcher.doc(doc_address)?;
+        let get_str = |field_name: &str| {
+            document
+                .get_first(self.schema.get_field(field_name).unwrap())
+                .and_then(|value| value.as_str())
+                .unwrap_or_default()
+                .to_string()
+        };
+        let crawled_at = document
+            .get_first(self.schema.get_field("crawled_at").unwrap())
+            .and_then(|value| value.as_u64())
+            .unwrap_or_default();
+
+        Ok(Some(CachedPage {
+            url: display_url(&get_str("url"), hidden_url_params),
+            title: get_str("title"),
+            body: get_str("body"),
+            crawled_at,
+        }))
+    }
+
+    /// Finds the document of `url`, see [`Indexer::is_indexed_url`].
+    fn find_url(
+        &self,
+        searcher: &ShardSearcher,
+        url: &str,
+        hidden_url_params: &[String],
+    ) -> anyhow::Result<Option<DocAddress>> {
+        let url_field = self.schema.get_field("url").unwrap();
+        let find_exact = |url: &str| -> anyhow::Result<Option<DocAddress>> {
+            let query = TermQuery::new(
+                Term::from_field_text(url_field, url),
+                IndexRecordOption::Basic,
+            );
+            let top_docs = searcher.search(&query, &TopDocs::with_limit(1))?;
+            Ok(top_docs.first().map(|(_score, doc_address)| *doc_address))
+        };
+
+        if let Some(doc_address) = find_exact(url)? {
+            return Ok(Some(doc_address));
+        }
+        if hidden_url_params.is_empty() {
+            return Ok(None);
+        }
+
+        // Compare with the displayed form of indexed URLs with the same path.
+        let base = url.split(['?', '#']).next().unwrap_or_default();
+        let mut upper_bound = base.as_bytes().to_vec();
+        upper_bound.push(0xFF); // Never part of UTF-8, so this is after every URL with the path.
+        for segment_reader in searcher.segment_readers() {
+            let inverted_index = segment_reader.inverted_index(url_field)?;
+            let mut urls = inverted_index
+                .terms()
+                .range()
+                .ge(base.as_bytes())
+                .lt(&upper_bound)
+                .into_stream()?;
+            while urls.advance() {
+                if let Ok(indexed_url) = std::str::from_utf8(urls.key()) {
+                    if display_url(indexed_url, hidden_url_params) == url {
+                        return find_exact(indexed_url);
+                    }
+                }
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Counts an API request made with `api_key` against its daily quota, unless the quota is
+    /// used up already. Returns whether the request is within the quota. Days are UTC.
+    pub fn use_api_quota(&self, api_key: &str, daily_quota: u64) -> anyhow::Result<bool> {
+        const SECS_PER_DAY: u64 = 24 * 60 * 60;
+
+        let usage = self.stats_db.open_tree("api_usage")?;
+        let today = crate::crawler::unix_timestamp() / SECS_PER_DAY;
+
+        let mut within_quota = false;
+        usage.fetch_and_update(api_key, |value| {
+            let mut day_usage: RawApiUsage = value
+                .and_then(|value| bincode::deserialize(value).ok())
+                .unwrap_or_default();
+            if day_usage.day != today {
+                day_usage = RawApiUsage {
+                    day: today,
+                    requests: 0,
+                };
+            }
+            within_quota = day_usage.requests < daily_quota;
+            if within_quota {
+                day_usage.requests += 1;
+            }
+            bincode::serialize(&day_usage).ok()
+        })?;
+
+        Ok(within_quota)
+    }
+
+    /// Records a click on a search result at `position` (starting at 1) for relevance feedback.
+    /// Returns whether it was recorded: clicks for queries longer than [`MAX_CLICK_QUERY_LEN`],
+    /// or for new queries once [`MAX_CLICK_QUERIES`] are recorded, are dropped.
+    pub fn record_click(&self, query: &str, url: &str, position: u64) -> anyhow::Result<bool> {
+        let clicks = self.stats_db.open_tree("clicks")?;
+        let key = query.trim().to_lowercase();
+        if key.len() > MAX_CLICK_QUERY_LEN {
+            return Ok(false);
+        }
+        // Counting the queries walks the whole tree, so it's only done for new ones.
+        if !clicks.contains_key(&key)? && clicks.len() >= MAX_CLICK_QUERIES {
+            return Ok(false);
+        }
+
+        clicks.fetch_and_update(key, |value| {
+            let mut stats: RawClickStats = value
+                .and_then(|value| bincode::deserialize(value).ok())
+                .unwrap_or_default();
+            let url_stats = stats.urls.entry(url.to_string()).or_default();
+            url_stats.clicks += 1;
+            url_stats.position_sum += position;
+            bincode::serialize(&stats).ok()
+        })?;
+
+        Ok(true)
+    }
+
+    /// Returns click stats per query, most clicked queries first.
+    pub fn get_click_stats(&self) -> anyhow::Result<Vec<ClickStats>> {
+        let clicks = self.stats_db.open_tree("clicks")?;
+        let mut stats = Vec::new();
+
+        for item in clicks.iter() {
+            let (key, value) = item?;
+            let raw_stats: RawClickStats = bincode::deserialize(&value)?;
+
+            let mut results: Vec<_> = raw_stats
+                .urls
+                .into_iter()
+                .map(|(url, url_stats)| UrlClickStats {
+                    url,
+                    clicks: url_stats.clicks,
+                    average_position: url_stats.position_sum as f64 / url_stats.clicks as f64,
+                })
+                .collect();
+            results.sort_by_key(|result| Reverse(result.clicks));
+
+            stats.push(ClickStats {
+                query: String::from_utf8(key.to_vec())?,
+                clicks: results.iter().map(|result| result.clicks).sum(),
+                results,
+            });
+        }
+
+        stats.sort_by_key(|stats| Reverse(stats.clicks));
+        Ok(stats)
+    }
+
+    /// Adds a finished crawl to the crawl history, dropping the oldest crawls beyond
+    /// `max_entries`.
+    pub fn record_crawl(&self, summary: &CrawlSummary, max_entries: usize) -> anyhow::Result<()> {
+        let history = self.stats_db.open_tree("crawl_history")?;
+        // Keys sort by start time, with a unique ID in case two crawls start in the same second.
+        let mut key = summary.started_at.to_be_bytes().to_vec();
+        key.extend_from_slice(&self.stats_db.generate_id()?.to_be_bytes());
+        history.insert(key, bincode::serialize(summary)?)?;
+
+        while history.len() > max_entries {
+            if history.pop_min()?.is_none() {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the crawl history, newest crawl first.
+    pub fn crawl_history(&self) -> anyhow::Result<Vec<CrawlSummary>> {
+        let history = self.stats_db.open_tree("crawl_history")?;
+        history
+            .iter()
+            .values()
+            .rev()
+            .map(|value| Ok(bincode::deserialize(&value?)?))
+            .collect()
+    }
+
+    /// Aggregate statistics of the whole index, without per-domain details.
+    pub fn summary(&self) -> anyhow::Result<IndexSummary> {
+        let domain_stats = self.get_domain_stats(SizeFormat::Decimal)?;
+        let searcher = self.searcher();
+
+        // tantivy keeps all files of an index in a single directory.
+        let mut index_size_bytes = 0;
+        for shard in 0..self.shards.len() {
+            let index_path = shard_dir(&self.config().index_dir, shard, self.shards.len());
+            for entry in std::fs::read_dir(index_path)? {
+                let metadata = entry?.metadata()?;
+                if metadata.is_file() {
+                    index_size_bytes += metadata.len();
+                }
+            }
+        }
+
+        Ok(IndexSummary {
+            num_docs: searcher.num_docs(),
+            num_domains: domain_stats.len(),
+            total_size_bytes: domain_stats.iter().map(|s| s.total_size_bytes).sum(),
+            index_size_bytes,
+            num_segments: searcher.segment_readers().len(),
+            last_indexed_at: domain_stats.iter().map(|s| s.last_updated).max(),
+            pages_unchanged: self.pages_unchanged.load(Ordering::Relaxed),
+            pages_low_quality: self.pages_low_quality.load(Ordering::Relaxed),
+        })
+    }
+
+    /// Returns the domains with at least `min_pages` indexed pages, according to their stats.
+    pub fn indexed_domains(&self, min_pages: u64) -> anyhow::Result<HashSet<String>> {
+        let mut domains = HashSet::new();
+        for item in self.stats_db.scan_prefix("domain:") {
+            let (key, value) = item?;
+            let raw_stats = RawDomainStats::decode(&value)?;
+            if raw_stats.page_count >= min_pages {
+                domains.insert(String::from_utf8(key.as_ref()[7..].to_vec())?);
+            }
+        }
+        Ok(domains)
+    }
+
+    pub fn get_domain_stats(&self, size_format: SizeFormat) -> anyhow::Result<Vec<DomainStats>> {
+        let mut stats = Vec::new();
+
+        for item in self.stats_db.scan_prefix("domain:") {
+            let (key, value) = item?;
+            let domain = String::from_utf8(key.as_ref()[7..].to_vec())?;
+            let raw_stats = RawDomainStats::decode(&value)?;
+
+            stats.push(DomainStats {
+                domain,
+                page_count: raw_stats.page_count,
+                total_size: size_format.format_size(raw_stats.total_size),
+                total_size_bytes: raw_stats.total_size,
+                last_updated: raw_stats.last_updated,
+                min_page_size: size_format.format_size(raw_stats.min_size),
+                max_page_size: size_format.format_size(raw_stats.max_size),
+                min_page_url: raw_stats.min_url,
+                max_page_url: raw_stats.max_url,
+            });
+        }
+
+        stats.sort_by(|a, b| a.domain.cmp(&b.domain));
+        Ok(stats)
+    }
+}
+
+/// Returns a response header of a crawled page, if spider recorded it.
+fn page_header(page: &Page, name: &str) -> Option<String> {
+    page.headers
+        .as_ref()?
+        .get(name)?
+        .to_str()
+        .ok()
+        .map(str::to_string)
+}
+
+/// Decodes the raw bytes of an HTML page using the encoding declared in its `Content-Type` header
+/// or, failing that, a `<meta>` tag near the start of the page. A byte order mark takes precedence
+/// over both. Without a known encoding, the page is decoded as UTF-8 with invalid bytes replaced.
+fn decode_html(bytes: &[u8], content_type: Option<&str>) -> String {
+    // Browsers only look for `<meta>` declarations in the first 1024 bytes.
+    const META_PRESCAN_BYTES: usize = 1024;
+
+    let head = String::from_utf8_lossy(&bytes[..bytes.len().min(META_PRESCAN_BYTES)]);
+    let encoding = content_type
+        .and_then(charset_label)
+        .and_then(|label| Encoding::for_label(label.as_bytes()))
+        .or_else(|| {
+            head.split("<meta")
+                .skip(1)
+                .filter_map(|tag| charset_label(tag.split('>').next().unwrap_or_default()))
+                .find_map(|label| Encoding::for_label(label.as_bytes()))
+        })
+        .unwrap_or(encoding_rs::UTF_8);
+    let (html, _encoding, _had_errors) = encoding.decode(bytes);
+    html.into_owned()
+}
+
+/// Returns the label after `charset=` in a `Content-Type` value or the attributes of a `<meta>`
+/// tag, e.g. `iso-8859-1` in `text/html; charset="ISO-8859-1"`.
+fn charset_label(text: &str) -> Option<String> {
+    let text = text.to_lowercase();
+    let (_, rest) = text.split_once("charset")?;
+    let rest = rest.trim_start().strip_prefix('=')?;
+    let label: String = rest
+        .trim_start_matches(|c: char| c.is_whitespace() || c == '"' || c == '\'')
+        .chars()
+        .take_while(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | ':' | '.'))
+        .collect();
+    (!label.is_empty()).then_some(label)
+}
+
+/// Returns the description of a page from one source, or "" if it has none.
+fn description(document: &Html, source: DescriptionSource) -> String {
+    let meta_content = |selector: &Selector| {
+        document
+            .select(selector)
+            .filter_map(|el| el.value().attr("content"))
+            .find(|content| !content.trim().is_empty())
+            .unwrap_or_default()
+            .to_string()
+    };
+    match source {
+        DescriptionSource::Meta => meta_content(&DESCRIPTION_SELECTOR),
+        DescriptionSource::OpenGraph => meta_content(&OG_DESCRIPTION_SELECTOR),
+        DescriptionSource::Twitter => meta_content(&TWITTER_DESCRIPTION_SELECTOR),
+        DescriptionSource::JsonLd => document
+            .select(&JSON_LD_SELECTOR)
+            .filter_map(|el| serde_json::from_str(&el.text().collect::<String>()).ok())
+            .find_map(|json| json_ld_description(&json))
+            .unwrap_or_default(),
+    }
+}
+
+/// Finds the first non-empty `description` in JSON-LD, which may be a single object, an array
+/// of objects or an object with a `@graph` array.
+fn json_ld_description(json: &serde_json::Value) -> Option<String> {
+    match json {
+        serde_json::Value::Array(items) => items.iter().find_map(json_ld_description),
+        serde_json::Value::Object(object) => object
+            .get("description")
+            .and_then(|description| description.as_str())
+            .filter(|description| !description.trim().is_empty())
+            .map(str::to_string)
+            .or_else(|| object.get("@graph").and_then(json_ld_description)),
+        _ => None,
+    }
+}
+
+/// The distinct keywords of the page's `<meta name="keywords">`, split on commas. Only the first
+/// `max_keywords` are kept, so stuffing a page with keywords doesn't make it match everything.
+fn keywords(document: &Html, max_keywords: usize) -> Vec<String> {
+    let mut keywords: Vec<String> = Vec::new();
+    let content = document
+        .select(&KEYWORDS_SELECTOR)
+        .filter_map(|el| el.value().attr("content"))
+        .collect::<Vec<_>>()
+        .join(",");
+    for keyword in content.split(',').map(str::trim) {
+        if keywords.len() >= max_keywords {
+            break;
+        }
+        if !keyword.is_empty()
+            && !keywords
+                .iter()
+                .any(|existing| existing.eq_ignore_ascii_case(keyword))
+        {
+            keywords.push(keyword.to_string());
+        }
+    }
+    keywords
+}
+
+/// Separates the items of a breadcrumb.
+const BREADCRUMB_SEPARATOR: &str = " › ";
+
+/// The items of the page's breadcrumb, from a breadcrumb `nav` (its list items, or its links if
+/// it has no list) or else a JSON-LD `BreadcrumbList`. Empty if the page has neither.
+fn breadcrumb(document: &Html) -> Vec<String> {
+    if let Some(nav) = document.select(&BREADCRUMB_SELECTOR).next() {
+        let mut items: Vec<_> = nav.select(&LIST_ITEM_SELECTOR).collect();
+        if items.is_empty() {
+            items = nav.select(&LINK_SELECTOR).collect();
+        }
+        return items
+            .into_iter()
+            .map(|item| item.text().collect())
+            .collect();
+    }
+    document
+        .select(&JSON_LD_SELECTOR)
+        .filter_map(|el| serde_json::from_str(&el.text().collect::<String>()).ok())
+        .find_map(|json| json_ld_breadcrumb(&json))
+        .unwrap_or_default()
+}
+
+/// Finds the item names of the first `BreadcrumbList` in JSON-LD, in order of their `position`.
+/// Like [`json_ld_description`], the list may be nested in an array or a `@graph`.
+fn json_ld_breadcrumb(json: &serde_json::Value) -> Option<Vec<String>> {
+    match json {
+        serde_json::Value::Array(items) => items.iter().find_map(json_ld_breadcrumb),
+        serde_json::Value::Object(object)
+            if object.get("@type").and_then(|kind| kind.as_str()) == Some("BreadcrumbList") =>
+        {
+            let mut items: Vec<_> = object
+                .get("itemListElement")?
+                .as_array()?
+                .iter()
+                .filter_map(|item| {
+                    let name = item
+                        .get("name")
+                        .or_else(|| item.get("item")?.get("name"))?
+                        .as_str()?;
+                    let position = item.get("position").and_then(|position| {
+                        position
+                            .as_u64()
+                            .or_else(|| position.as_str()?.trim().parse().ok())
+                    });
+                    Some((position.unwrap_or(u64::MAX), name.to_string()))
+                })
+                .collect();
+            // Stable, so items without a position keep their order.
+            items.sort_by_key(|(position, _name)| *position);
+            Some(items.into_iter().map(|(_position, name)| name).collect())
+        }
+        serde_json::Value::Object(object) => object.get("@graph").and_then(json_ld_breadcrumb),
+        _ => None,
+    }
+}
+
+/// Extracts the visible text of an element in canonical form: words separated by single spaces,
+/// with no leading or trailing whitespace. With `preserve_paragraphs`, block elements like
+/// paragraphs are separated by [`BLOCK_SEPARATOR`] instead of a space.
+fn extract_text(
+    element: ElementRef,
+    ignored_elements: &[String],
+    trim_space_before_punctuation: bool,
+    preserve_paragraphs: bool,
+) -> String {
+    normalize_whitespace(
+        &collect_text(element, ignored_elements, preserve_paragraphs),
+        trim_space_before_punctuation,
+    )
+}
+
+/// Separates blocks of extracted text with `preserve_paragraphs`. The Unicode paragraph separator
+/// is whitespace to the tokenizer, so it doesn't change the indexed tokens.
+pub const BLOCK_SEPARATOR: char = '\u{2029}';
+
+/// Elements whose text is a block of its own with `preserve_paragraphs`.
+const BLOCK_ELEMENTS: &[&str] = &[
+    "p",
+    "li",
+    "div",
+    "h1",
+    "h2",
+    "h3",
+    "h4",
+    "h5",
+    "h6",
+    "blockquote",
+    "pre",
+    "tr",
+    "td",
+    "th",
+    "dt",
+    "dd",
+    "section",
+    "article",
+    "header",
+    "footer",
+    "aside",
+    "nav",
+    "figcaption",
+    "br",
+];
+
+fn collect_text(
+    element: ElementRef,
+    ignored_elements: &[String],
+    preserve_paragraphs: bool,
+) -> String {
+    let mut text = String::new();
+
+    for child in element.children() {
+        match child.value() {
+            // If the child is an element, check if it's ignored, e.g. a <script>
+            Node::Element(e) if !ignored_elements.iter().any(|name| name == e.name()) => {
+                let is_block = preserve_paragraphs && BLOCK_ELEMENTS.contains(&e.name());
+                if is_block {
+                    text.push(BLOCK_SEPARATOR);
+                }
+                if let Some(el_ref) = ElementRef::wrap(child) {
+                    text.push_str(&collect_text(el_ref, ignored_elements, preserve_paragraphs));
+                }
+                if is_block {
+                    text.push(BLOCK_SEPARATOR);
+                }
+            }
+            // If the child is a text node, append its content
+            Node::Text(t) => {
+                let t = t.trim();
+                if !t.is_empty() {
+                    text.push_str(t);
+                    text.push(' '); // Add a space between text nodes
+                }
+            }
+            _ => {}
+        }
+    }
+
+    text
+}
+
+/// Parses a docstore compression setting, e.g. "lz4", "zstd", "zstd(compression_level=5)" or
+/// "none".
+fn parse_docstore_compression(value: &str) -> anyhow::Result<Compressor> {
+    use serde::de::{value::StrDeserializer, IntoDeserializer};
+
+    let deserializer: StrDeserializer<serde::de::value::Error> = value.into_deserializer();
+    Compressor::deserialize(deserializer)
+        .with_context(|| format!("invalid docstore_compression '{value}'"))
+}
+
+/// Returns whether the given content is a PDF document, based on its magic bytes.
+pub(crate) fn is_pdf(bytes: &[u8]) -> bool {
+    bytes.starts_with(b"%PDF-")
+}
+
+/// Returns the title from the PDF's document information dictionary, if any.
+#[cfg(feature = "pdf")]
+fn pdf_title(pdf: &lopdf::Document) -> Option<String> {
+    let (_, info) = pdf.dereference(pdf.trailer.get(b"Info").ok()?).ok()?;
+    let title = info.as_dict().ok()?.get(b"Title").ok()?;
+
+    lopdf::decode_text_string(title)
+        .ok()
+        .filter(|title| !title.trim().is_empty())
+}
+
+/// Collapses runs of whitespace into single spaces and trims the ends. If
+/// `trim_space_before_punctuation` is set, also removes the space before standalone closing
+/// punctuation, e.g. "word ." becomes "word.".
+///
+/// Blocks separated by [`BLOCK_SEPARATOR`] are normalized separately and stay separated by a
+/// single one. Empty blocks are dropped.
+fn normalize_whitespace(text: &str, trim_space_before_punctuation: bool) -> String {
+    const CLOSING_PUNCTUATION: &[char] = &['.', ',', ';', ':', '!', '?', ')', ']', '}'];
+
+    if text.contains(BLOCK_SEPARATOR) {
+        return text
+            .split(BLOCK_SEPARATOR)
+            .map(|block| normalize_whitespace(block, trim_space_before_punctuation))
+            .filter(|block| !block.is_empty())
+            .collect::<Vec<_>>()
+            .join(BLOCK_SEPARATOR.encode_utf8(&mut [0; 4]));
+    }
+
+    let mut normalized = String::with_capacity(text.len());
+
+    for word in text.split_whitespace() {
+        let is_punctuation = word.chars().all(|c| CLOSING_PUNCTUATION.contains(&c));
+        let attach_to_previous =
+            normalized.is_empty() || (trim_space_before_punctuation && is_punctuation);
+        if !attach_to_previous {
+            normalized.push(' ');
+        }
+        normalized.push_str(word);
+    }
+
+    normalized
+}
+
+/// Decodes HTML entities in text, e.g. "&amp;" to "&" or "&#8212;" to "—". Only common named
+/// entities are known, others are left as they are.
+fn decode_entities(text: &str) -> Cow<'_, str> {
+    /// Entities are at most this long, including "&" and ";".
+    const MAX_ENTITY_LEN: usize = 10;
+
+    if !text.contains('&') {
+        return Cow::Borrowed(text);
+    }
+
+    let mut decoded = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('&') {
+        decoded.push_str(&rest[..start]);
+        rest = &rest[start..];
+
+        let entity = rest
+            .char_indices()
+            .take(MAX_ENTITY_LEN)
+            .find(|(_, c)| *c == ';')
+            .and_then(|(end, _)| Some((decode_entity(&rest[1..end])?, end)));
+        match entity {
+            Some((c, end)) => {
+                decoded.push(c);
+                rest = &rest[end + 1..];
+            }
+            None => {
+                decoded.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    decoded.push_str(rest);
+
+    Cow::Owned(decoded)
+}
+
+/// Decodes the name of an entity (without "&" and ";"), e.g. "amp" or "#x2014".
+fn decode_entity(name: &str) -> Option<char> {
+    if let Some(number) = name.strip_prefix('#') {
+        let code = match number.strip_prefix(['x', 'X']) {
+            Some(hex) => u32::from_str_radix(hex, 16).ok()?,
+            None => number.parse().ok()?,
+        };
+        return char::from_u32(code);
+    }
+
+    let c = match name {
+        "amp" => '&',
+        "lt" => '<',
+        "gt" => '>',
+        "quot" => '"',
+        "apos" => '\'',
+        "nbsp" => '\u{a0}',
+        "copy" => '©',
+        "reg" => '®',
+        "trade" => '™',
+        "hellip" => '…',
+        "mdash" => '—',
+        "ndash" => '–',
+        "lsquo" => '‘',
+        "rsquo" => '’',
+        "ldquo" => '“',
+        "rdquo" => '”',
+        "laquo" => '«',
+        "raquo" => '»',
+        "middot" => '·',
+        "bull" => '•',
+        "deg" => '°',
+        "euro" => '€',
+        "pound" => '£',
+        "times" => '×',
+        _ => return None,
+    };
+    Some(c)
+}
+
+/// Whether `domain` is one of `trusted_domains` or a subdomain of one, e.g. "docs.rust-lang.org"
+/// for "rust-lang.org". Case-insensitive.
+fn is_trusted_domain(domain: &str, trusted_domains: &[String]) -> bool {
+    let domain = domain.to_ascii_lowercase();
+    trusted_domains.iter().any(|trusted| {
+        let trusted = trusted.to_ascii_lowercase();
+        domain == trusted
+            || domain
+                .strip_suffix(&trusted)
+                .is_some_and(|subdomain| subdomain.ends_with('.'))
+    })
+}
+
+/// Returns the top-level domain of a domain, e.g. "org" for "www.rust-lang.org". IP addresses and
+/// hosts like "localhost" have none and give "".
+pub fn tld(domain: &str) -> String {
+    let host = domain.split(':').next().unwrap_or_default();
+    match host.rsplit_once('.') {
+        Some((_, tld)) if !tld.is_empty() && !tld.chars().all(|c| c.is_ascii_digit()) => {
+            tld.to_ascii_lowercase()
+        }
+        _ => String::new(),
+    }
+}
+
+/// Returns the texts of the page's links by target URL, without fragments. Links to the page itself
+/// are skipped, since they say nothing new about it.
+fn anchor_texts(document: &Html, url: &str) -> BTreeMap<String, Vec<String>> {
+    let mut anchor_texts: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    for link in document.select(&LINK_SELECTOR) {
+        let Some(target) = link
+            .value()
+            .attr("href")
+            .and_then(|href| resolve_url(url, href))
+        else {
+            continue;
+        };
+        let target = match target.split_once('#') {
+            Some((target, _fragment)) => target.to_string(),
+            None => target,
+        };
+        if target == url {
+            continue;
+        }
+        anchor_texts
+            .entry(target)
+            .or_default()
+            .push(link.text().collect());
+    }
+    anchor_texts
+}
+
+/// Resolves a link found on the page at `base` to an absolute HTTP(S) URL. Links with other
+/// schemes, like `data:`, give `None`.
+fn resolve_url(base: &str, link: &str) -> Option<String> {
+    let link = link.trim();
+    let (scheme, rest) = base.split_once("://")?;
+    let origin_end = rest.find(['/', '?', '#']).unwrap_or(rest.len());
+    let origin = format!("{scheme}://{}", &rest[..origin_end]);
+
+    if link.starts_with("http://") || link.starts_with("https://") {
+        Some(link.to_string())
+    } else if let Some(link) = link.strip_prefix("//") {
+        Some(format!("{scheme}://{link}"))
+    } else if link.starts_with('/') {
+        Some(format!("{origin}{link}"))
+    } else if link.is_empty() || link.contains(':') {
+        None
+    } else {
+        // Relative to the directory of the base path.
+        let path = url_path(base);
+        let directory = path.rfind('/').map_or("", |end| &path[..=end]);
+        Some(format!("{origin}/{directory}{link}"))
+    }
+}
+
+/// Returns the path of a URL without the leading `/`, query and fragment.
+fn url_path(url: &str) -> &str {
+    let without_scheme = url.split_once("://").map_or(url, |(_, rest)| rest);
+    let path = without_scheme.split_once('/').map_or("", |(_, path)| path);
+    path.split(['?', '#']).next().unwrap_or_default()
+}
+
+/// Returns the number of non-empty path segments in a URL, e.g. `https://a.com/b/c` has depth 2.
+fn url_depth(url: &str) -> u64 {
+    url_path(url)
+        .split('/')
+        .filter(|segment| !segment.is_empty())
+        .count() as u64
+}
+
+/// Returns the words of a URL path, e.g. `https://a.com/how-to/configure_nginx` gives
+/// "how to configure nginx".
+fn url_slug(url: &str) -> String {
+    url_path(url)
+        .split(['/', '-', '_'])
+        .filter(|word| !word.is_empty())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Neutralizes query syntax that users shouldn't control:
+///
+/// - `;` is always replaced with a space, for better performance in tantivy.
+/// - With `strip_boosts`, `^` and the boost number after it are removed.
+/// - With `strip_wildcards`, `*` is replaced with a space.
+fn sanitize_query(query_str: &str, strip_boosts: bool, strip_wildcards: bool) -> String {
+    let mut sanitized = String::with_capacity(query_str.len());
+    let mut chars = query_str.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            ';' => sanitized.push(' '),
+            '*' if strip_wildcards => sanitized.push(' '),
+            '^' if strip_boosts => {
+                while chars.next_if(|c| c.is_ascii_digit() || *c == '.').is_some() {}
+            }
+            _ => sanitized.push(c),
+        }
+    }
+
+    sanitized
+}
+
+/// Splits the top-level exclusions (`-word`) off a parsed query, so that alternatives to the query
+/// can be added without bypassing them. Returns the rest of the query, which may match nothing,
+/// and the excluded queries.
+fn split_exclusions(query: Box<dyn Query>) -> (Box<dyn Query>, Vec<Box<dyn Query>>) {
+    let Some(boolean_query) = query.downcast_ref::<BooleanQuery>() else {
+        return (query, Vec::new());
+    };
+    let mut exclusions = Vec::new();
+    let mut rest = Vec::new();
+    for (occur, clause) in boolean_query.clauses() {
+        match occur {
+            Occur::MustNot => exclusions.push(clause.box_clone()),
+            // The parser matches everything else for a query of only exclusions, which the
+            // alternatives take care of instead.
+            _ if clause.downcast_ref::<AllQuery>().is_some() => {}
+            _ => rest.push((*occur, clause.box_clone())),
+        }
+    }
+    if exclusions.is_empty() {
+        return (query, Vec::new());
+    }
+
+    let rest: Box<dyn Query> = if rest.is_empty() {
+        Box::new(EmptyQuery)
+    } else {
+        Box::new(BooleanQuery::new(rest))
+    };
+    (rest, exclusions)
+}
+
+/// Returns the words of the query a matching page may contain, for boosts built from the query
+/// text. Excluded words (`-word`, `-"some phrase"` and `NOT word`), field-qualified words like
+/// `title:rust`, and the `AND`/`OR` operators are left out, as is the `+` of required words.
+fn positive_query_text(query_str: &str) -> String {
+    let mut words = Vec::new();
+    let mut after_not = false;
+    let mut in_excluded_phrase = false;
+
+    for word in query_str.split_whitespace() {
+        if in_excluded_phrase {
+            in_excluded_phrase = !word.ends_with('"');
+            continue;
+        }
+        match word {
+            "AND" | "OR" => continue,
+            "NOT" => {
+                after_not = true;
+                continue;
+            }
+            _ => {}
+        }
+
+        let excluded = std::mem::take(&mut after_not) || word.starts_with('-');
+        let word = word.trim_start_matches(['-', '+']);
+        if excluded {
+            in_excluded_phrase = word.starts_with('"') && (word.len() == 1 || !word.ends_with('"'));
+            continue;
+        }
+        // Paths like `std::io` aren't field names.
+        let is_field_qualified = word.split_once(':').is_some_and(|(field, value)| {
+            !field.is_empty()
+                && field.chars().all(|c| c.is_alphanumeric() || c == '_')
+                && !value.starts_with(':')
+        });
+        if !word.is_empty() && !is_field_qualified {
+            words.push(word);
+        }
+    }
+
+    words.join(" ")
+}
+
+/// Removes `key:` filters (e.g. `type:pdf` or `site:example.com`) from the query. Returns the
+/// rest of the query and the lowercased values.
+fn extract_filters(query_str: &str, key: &str) -> (String, Vec<String>) {
+    let mut values = Vec::new();
+    let rest = query_str
+        .split_whitespace()
+        .filter(|word| {
+            match word
+                .strip_prefix(key)
+                .and_then(|word| word.strip_prefix(':'))
+            {
+                Some(value) if !value.is_empty() => {
+                    values.push(value.to_lowercase());
+                    false
+                }
+                _ => true,
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(" ");
+
+    (rest, values)
+}
+
+/// The score of a page whose URL was pasted into the query, see [`Indexer::add_url_queries`].
+const URL_MATCH_SCORE: Score = 100.0;
+
+/// Removes URL-shaped (`https://...`, `www.example.com/...`) and email-shaped (`user@example.com`)
+/// words from the query. Returns the rest of the query, the URLs and the emails. Punctuation
+/// around a word, like a trailing comma, isn't part of it.
+fn extract_urls_and_emails(query_str: &str) -> (String, Vec<String>, Vec<String>) {
+    let mut urls = Vec::new();
+    let mut emails = Vec::new();
+    let rest = query_str
+        .split_whitespace()
+        .filter(|word| {
+            let word = word
+                .trim_start_matches(['"', '(', '<'])
+                .trim_end_matches(['"', ')', '>', ',', '.', ';', '!', '?']);
+            let lowercase = word.to_lowercase();
+            let host = ["https://", "http://", "www."]
+                .iter()
+                .find_map(|prefix| lowercase.strip_prefix(prefix))
+                .map(|rest| rest.split(['/', '?', '#']).next().unwrap_or_default());
+            if host.is_some_and(|host| host.contains('.')) {
+                urls.push(word.to_string());
+                return false;
+            }
+            match word.split_once('@') {
+                Some((user, domain))
+                    if !user.is_empty()
+                        && domain.contains('.')
+                        && !domain.starts_with('.')
+                        && !domain.ends_with('.')
+                        && !word.contains(['/', ':', '"']) =>
+                {
+                    emails.push(word.to_lowercase());
+                    false
+                }
+                _ => true,
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(" ");
+
+    (rest, urls, emails)
+}
+
+/// The URLs a pasted URL may be indexed as: with `https://` or `http://`, with or without `www.`
+/// and a trailing `/`. The host is lowercased, the path is kept as is and a `#fragment` dropped.
+fn url_variants(url: &str) -> Vec<String> {
+    let url = url.split('#').next().unwrap_or_default();
+    let without_scheme = ["https://", "http://"]
+        .iter()
+        .find_map(|scheme| {
+            url.get(..scheme.len())
+                .filter(|prefix| prefix.eq_ignore_ascii_case(scheme))
+                .map(|_| &url[scheme.len()..])
+        })
+        .unwrap_or(url);
+    let (host, path) = match without_scheme.find(['/', '?']) {
+        Some(i) => without_scheme.split_at(i),
+        None => (without_scheme, ""),
+    };
+    let host = host.to_lowercase();
+    let host = host.strip_prefix("www.").unwrap_or(&host);
+    let path = path.strip_suffix('/').unwrap_or(path);
+
+    let mut variants = Vec::new();
+    for scheme in ["https://", "http://"] {
+        for www in ["", "www."] {
+            for slash in ["", "/"] {
+                variants.push(format!("{scheme}{www}{host}{path}{slash}"));
+            }
+        }
+    }
+    variants
+}
+
+/// Removes words with a trailing `*` (e.g. `prog*`) outside of quotes from the query. Returns the
+/// rest of the query and the lowercased prefixes. Since short prefixes match too many terms, those
+/// are kept as plain words without the `*`.
+fn extract_prefix_terms(query_str: &str) -> (String, Vec<String>) {
+    const MIN_PREFIX_LEN: usize = 3;
+
+    let mut prefixes = Vec::new();
+    let rest = split_query_terms(query_str)
+        .into_iter()
+        .filter_map(|term| {
+            let Some(prefix) = term.strip_suffix('*') else {
+                return Some(term);
+            };
+            if prefix.is_empty() || !prefix.chars().all(char::is_alphanumeric) {
+                return Some(term);
+            }
+            if prefix.chars().count() < MIN_PREFIX_LEN {
+                return Some(prefix.to_string());
+            }
+            prefixes.push(prefix.to_lowercase());
+            None
+        })
+        .collect::<Vec<_>>()
+        .join(" ");
+
+    (rest, prefixes)
+}
+
+/// Splits a query string into terms, preserving quoted phrases
+fn split_query_terms(query_str: &str) -> Vec<String> {
+    let mut terms = Vec::new();
+    let mut current_term = String::new();
+    let mut in_quotes = false;
+
+    for c in query_str.chars() {
+        if c == '"' {
+            in_quotes = !in_quotes;
+        }
+
+        match c {
+            ' ' if !in_quotes => {
+                // Space outside quotes - add current term if non-empty
+                if !current_term.is_empty() {
+                    terms.push(current_term.clone());
+                    current_term.clear();
+                }
+            }
+            _ => current_term.push(c),
+        }
+    }
+    // Add final term if non-empty
+    if !current_term.is_empty() {
+        terms.push(current_term);
+    }
+    terms
+}
+
+/// Applies boosting to tech terms in the query
+fn boost_tech_terms(query_str: &str, tech_term_boost: f32) -> String {
+    let terms = split_query_terms(query_str);
+
+    terms
+        .into_iter()
+        .map(|term| {
+            if !term.contains('"')
+                && TECH_TERMS_TO_BOOST
+                    .iter()
+                    .any(|tech| tech.eq_ignore_ascii_case(&term))
+            {
+                format!("{}^{}", term, tech_term_boost)
+            } else {
+                term
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Maps a lowercase query term to its alternatives.
+type Synonyms = HashMap<String, Vec<String>>;
+
+/// Parses synonyms with one `term: alt1, alt2` entry per line. Blank lines and lines starting with
+/// `#` are ignored.
+fn parse_synonyms(contents: &str) -> Synonyms {
+    contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|line| {
+            let (term, alternatives) = line.split_once(':')?;
+            let alternatives = alternatives
+                .split(',')
+                .map(str::trim)
+                .filter(|alternative| !alternative.is_empty())
+                .map(str::to_string)
+                .collect();
+            Some((term.trim().to_lowercase(), alternatives))
+        })
+        .collect()
+}
+
+/// Adds the other terms of each group in [`TECH_TERM_EQUIVALENTS`] as synonyms. Entries from the
+/// synonyms file take precedence.
+fn with_tech_term_synonyms(synonyms: &Synonyms) -> Synonyms {
+    let mut synonyms = synonyms.clone();
+    for group in TECH_TERM_EQUIVALENTS {
+        for term in *group {
+            synonyms.entry(term.to_string()).or_insert_with(|| {
+                group
+                    .iter()
+                    .filter(|alternative| alternative != &term)
+                    .map(|alternative| alternative.to_string())
+                    .collect()
+            });
+        }
+    }
+    synonyms
+}
+
+/// Expands each query term with synonyms into an OR group, with the alternatives boosted by
+/// `synonym_boost`. Quoted phrases are left untouched. Terms may already carry a boost, e.g.
+/// `k8s^1.5`, which is kept.
+fn expand_synonyms(query_str: &str, synonyms: &Synonyms, synonym_boost: f32) -> String {
+    if synonyms.is_empty() {
+        return query_str.to_string();
+    }
+
+    split_query_terms(query_str)
+        .into_iter()
+        .map(|term| {
+            let base_term = term.split('^').next().unwrap_or_default();
+            match synonyms.get(&base_term.to_lowercase()) {
+                Some(alternatives) if !term.contains('"') => {
+                    let alternatives = alternatives.iter().map(|alternative| {
+                        if alternative.contains(char::is_whitespace) {
+                            format!("\"{alternative}\"^{synonym_boost}")
+                        } else {
+                            format!("{alternative}^{synonym_boost}")
+                        }
+                    });
+                    let group = std::iter::once(term.clone())
+                        .chain(alternatives)
+                        .collect::<Vec<_>>()
+                        .join(" ");
+                    format!("({group})")
+                }
+                _ => term,
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Returns the length in positions of the smallest window containing a position of every list,
+/// e.g. 2 for adjacent terms, or `None` if a list is empty.
+fn min_span(positions: &[Vec<u32>]) -> Option<u32> {
+    if positions.iter().any(Vec::is_empty) {
+        return None;
+    }
+
+    let mut all: Vec<(u32, usize)> = positions
+        .iter()
+        .enumerate()
+        .flat_map(|(list, positions)| positions.iter().map(move |&position| (position, list)))
+        .collect();
+    all.sort_unstable();
+
+    // Slide a window over the sorted positions, shrinking it from the left while it still
+    // contains every list.
+    let mut counts = vec![0; positions.len()];
+    let mut covered = 0;
+    let mut start = 0;
+    let mut min_span = u32::MAX;
+    for &(end_position, list) in &all {
+        counts[list] += 1;
+        if counts[list] == 1 {
+            covered += 1;
+        }
+        while covered == positions.len() {
+            let (start_position, start_list) = all[start];
+            min_span = min_span.min(end_position - start_position + 1);
+            counts[start_list] -= 1;
+            if counts[start_list] == 0 {
+                covered -= 1;
+            }
+            start += 1;
+        }
+    }
+    Some(min_span)
+}
+
+/// The maximum number of query terms checked for [`SearchResult::matched_terms`].
+const MAX_MATCHED_TERMS: usize = 32;
+
+/// Sets the [`SearchResult::matched_terms`] of each result, if they were computed.
+fn add_matched_terms(results: &mut [SearchResult], matched_terms: Option<Vec<Vec<String>>>) {
+    if let Some(matched_terms) = matched_terms {
+        for (result, terms) in results.iter_mut().zip(matched_terms) {
+            result.matched_terms = Some(terms);
+        }
+    }
+}
+
+/// Builds a search result without a snippet from the fast fields of a document, without loading
+/// the stored document.
+fn fast_search_result(
+    searcher: &ShardSearcher,
+    doc_address: DocAddress,
+    options: &SearchOptions,
+) -> anyhow::Result<SearchResult> {
+    let domain = fast_str(searcher, doc_address, "domain")?;
+    Ok(SearchResult {
+        title: fast_str(searcher, doc_address, "title")?,
+        url: display_url(
+            &fast_str(searcher, doc_address, "url")?,
+            &options.hidden_url_params,
+        ),
+        snippet: String::new(),
+        description_snippet: String::new(),
+        image_url: fast_str(searcher, doc_address, "image_url")?,
+        breadcrumb: fast_str(searcher, doc_address, "breadcrumb")?,
+        crawled_at: searcher
+            .segment_reader(doc_address.segment_ord)
+            .fast_fields()
+            .u64("crawled_at")?
+            .first(doc_address.doc_id)
+            .unwrap_or_default(),
+        tld: tld(&domain),
+        is_trusted: is_trusted_domain(&domain, &options.trusted_domains),
+        matched_terms: None,
+    })
+}
+
+/// Reads the first value of a string fast field of a document, or "" if it has none.
+fn fast_str(
+    searcher: &ShardSearcher,
+    doc_address: DocAddress,
+    field_name: &str,
+) -> anyhow::Result<String> {
+    let column = searcher
+        .segment_reader(doc_address.segment_ord)
+        .fast_fields()
+        .str(field_name)?
+        .with_context(|| format!("'{field_name}' is not a fast field"))?;
+    let mut value = String::new();
+    if let Some(ord) = column.term_ords(doc_address.doc_id).next() {
+        column.ord_to_str(ord, &mut value)?;
+    }
+    Ok(value)
+}
+
+/// Keeps only the highest-ranked result of each title, compared case- and
+/// whitespace-insensitively, and returns at most `num_docs` results. Results without a title are
+/// never collapsed.
+fn dedupe_by_title(
+    searcher: &ShardSearcher,
+    top_docs: Vec<(Score, DocAddress)>,
+    num_docs: usize,
+) -> anyhow::Result<Vec<(Score, DocAddress)>> {
+    let mut seen_titles = HashSet::new();
+    let mut deduped = Vec::with_capacity(num_docs);
+    for (score, doc_address) in top_docs {
+        if deduped.len() == num_docs {
+            break;
+        }
+        let title = fast_str(searcher, doc_address, "title")?
+            .split_whitespace()
+            .collect::<Vec<_>>()
+            .join(" ")
+            .to_lowercase();
+        if title.is_empty() || seen_titles.insert(title) {
+            deduped.push((score, doc_address));
+        }
+    }
+    Ok(deduped)
+}
+
+/// Builds a snippet from up to `max_fragments` highlighted fragments of the text, in the order they
+/// appear and joined with " … ". Text with fewer matching regions gets fewer fragments.
+fn multi_fragment_snippet(
+    snippet_generator: &SnippetGenerator,
+    text: &str,
+    max_fragments: usize,
+    max_chars: usize,
+) -> String {
+    const SEPARATOR: &str = " … ";
+
+    // Each chosen fragment is blanked out with spaces, so the next one comes from another part of
+    // the text while offsets stay the same.
+    let mut remaining = text.to_string();
+    let mut fragments = Vec::new();
+
+    for _ in 0..max_fragments {
+        let snippet = snippet_generator.snippet(&remaining);
+        if snippet.highlighted().is_empty() {
+            break;
+        }
+        let Some(position) = remaining.find(snippet.fragment()) else {
+            break;
+        };
+
+        let end = position + snippet.fragment().len();
+        remaining.replace_range(position..end, &" ".repeat(end - position));
+        fragments.push((position, snippet_html(&snippet, max_chars)));
+    }
+
+    if fragments.is_empty() {
+        // Nothing matched, so fall back to the start of the text like a single snippet does.
+        return snippet_html(&snippet_generator.snippet(text), max_chars);
+    }
+
+    fragments.sort_by_key(|(position, _html)| *position);
+    fragments
+        .into_iter()
+        // The separator already marks the gap after a truncated fragment.
+        .map(|(_position, html)| html.trim_end_matches(ELLIPSIS).to_string())
+        .collect::<Vec<_>>()
+        .join(SEPARATOR)
+}
+
+const ELLIPSIS: char = '…';
+
+/// Renders a snippet as HTML like [`Snippet::to_html`], but cuts fragments longer than
+/// `max_chars` characters at the last word boundary before the limit and appends an ellipsis.
+///
+/// Tantivy limits fragments by bytes and only at token boundaries, so a single long token, e.g. a
+/// run of CJK text without spaces, can still make a fragment of any length.
+///
+/// Fragments spanning several blocks (see [`BLOCK_SEPARATOR`]) are narrowed to the block of the
+/// first match.
+fn snippet_html(snippet: &Snippet, max_chars: usize) -> String {
+    let (fragment, highlighted) = matching_block(snippet.fragment(), snippet.highlighted());
+    let (text, is_cut) = match fragment.char_indices().nth(max_chars) {
+        None => (fragment, false),
+        Some((limit, _)) => {
+            // Without any boundary, e.g. in a very long word, cut between characters, but not
+            // before a combining mark.
+            let end = word_boundary_before(fragment, limit).unwrap_or_else(|| {
+                (0..=limit)
+                    .rev()
+                    .filter(|&i| fragment.is_char_boundary(i))
+                    .find(|&i| !fragment[i..].starts_with(is_combining_mark))
+                    .unwrap_or(0)
+            });
+            (fragment[..end].trim_end(), true)
+        }
+    };
+
+    let mut highlighted: Vec<_> = highlighted
+        .into_iter()
+        .filter(|range| range.start < text.len())
+        .map(|range| range.start..range.end.min(text.len()))
+        .collect();
+    highlighted.sort_by_key(|range| range.start);
+
+    let mut html = String::new();
+    let mut start_from = 0;
+    for range in highlighted {
+        // Skip overlapping ranges, which would repeat text.
+        if range.start < start_from {
+            continue;
+        }
+        html.push_str(&escape_snippet_text(&text[start_from..range.start]));
+        html.push_str("<b>");
+        html.push_str(&escape_snippet_text(&text[range.clone()]));
+        html.push_str("</b>");
+        start_from = range.end;
+    }
+    html.push_str(&escape_snippet_text(&text[start_from..]));
+    if is_cut {
+        html.push(ELLIPSIS);
+    }
+    html
+}
+
+/// The start of `text` as a snippet without highlights, cut like [`snippet_html`]. Only the first
+/// block (see [`BLOCK_SEPARATOR`]) is used.
+fn plain_snippet(text: &str, max_chars: usize) -> String {
+    let text = text.split(BLOCK_SEPARATOR).next().unwrap_or_default();
+    match text.char_indices().nth(max_chars) {
+        None => escape_snippet_text(text),
+        Some((limit, _)) => {
+            let end = word_boundary_before(text, limit).unwrap_or(limit);
+            let mut html = escape_snippet_text(text[..end].trim_end());
+            html.push(ELLIPSIS);
+            html
+        }
+    }
+}
+
+/// Whether a snippet would look broken next to its result: it has no words (e.g. only whitespace
+/// or an ellipsis), or it's just the title again.
+fn is_redundant_snippet(snippet: &str, title: &str) -> bool {
+    let text = snippet.replace("<b>", "").replace("</b>", "");
+    let words = |text: &str| {
+        text.split_whitespace()
+            .map(str::to_lowercase)
+            .collect::<Vec<_>>()
+    };
+    !text.chars().any(char::is_alphanumeric) || words(&text) == words(&escape_snippet_text(title))
+}
+
+/// Returns the block of the fragment that contains its first highlighted range, with the
+/// highlighted ranges inside it. Fragments without block separators are returned as they are.
+fn matching_block<'a>(
+    fragment: &'a str,
+    highlighted: &[Range<usize>],
+) -> (&'a str, Vec<Range<usize>>) {
+    let first_match = highlighted
+        .iter()
+        .map(|range| range.start)
+        .min()
+        .unwrap_or(0);
+    let start = fragment[..first_match]
+        .rfind(BLOCK_SEPARATOR)
+        .map_or(0, |i| i + BLOCK_SEPARATOR.len_utf8());
+    let end = fragment[first_match..]
+        .find(BLOCK_SEPARATOR)
+        .map_or(fragment.len(), |i| first_match + i);
+    let highlighted = highlighted
+        .iter()
+        .filter(|range| range.start >= start && range.end <= end)
+        .map(|range| range.start - start..range.end - start)
+        .collect();
+    (&fragment[start..end], highlighted)
+}
+
+/// Returns the last position at or before `pos` where text may be cut without splitting a word:
+/// next to whitespace, or between CJK characters, which aren't separated by spaces.
+fn word_boundary_before(text: &str, pos: usize) -> Option<usize> {
+    (1..=pos)
+        .rev()
+        .filter(|&i| text.is_char_boundary(i))
+        .find(|&i| {
+            let before = text[..i].chars().next_back().unwrap();
+            let after = text[i..].chars().next();
+            before.is_whitespace()
+                || after.is_some_and(char::is_whitespace)
+                || (is_cjk(before) || after.is_some_and(is_cjk))
+                    && !after.is_some_and(is_combining_mark)
+        })
+}
+
+fn is_cjk(c: char) -> bool {
+    matches!(c,
+        '\u{3000}'..='\u{30FF}' // CJK punctuation, Hiragana and Katakana
+        | '\u{3400}'..='\u{4DBF}' // CJK Extension A
+        | '\u{4E00}'..='\u{9FFF}' // CJK Unified Ideographs
+        | '\u{AC00}'..='\u{D7AF}' // Hangul syllables
+        | '\u{F900}'..='\u{FAFF}' // CJK Compatibility Ideographs
+        | '\u{FF00}'..='\u{FFEF}' // Half- and full-width forms
+    )
+}
+
+fn is_combining_mark(c: char) -> bool {
+    matches!(c,
+        '\u{0300}'..='\u{036F}'
+        | '\u{1AB0}'..='\u{1AFF}'
+        | '\u{1DC0}'..='\u{1DFF}'
+        | '\u{20D0}'..='\u{20FF}'
+        | '\u{FE20}'..='\u{FE2F}'
+    )
+}
+
+/// Escapes text like tantivy's snippets do.
+fn escape_snippet_text(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#x27;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Removes hidden query parameters (e.g. tracking parameters) from a URL before showing it. Entries
+/// ending with `*` hide all parameters with that prefix, e.g. `utm_*`.
+fn display_url(url: &str, hidden_params: &[String]) -> String {
+    let (url_without_fragment, fragment) = match url.split_once('#') {
+        Some((rest, fragment)) => (rest, Some(fragment)),
+        None => (url, None),
+    };
+    let Some((base, query)) = url_without_fragment.split_once('?') else {
+        return url.to_string();
+    };
+    if hidden_params.is_empty() {
+        return url.to_string();
+    }
+
+    let is_hidden = |param: &str| {
+        let name = param.split('=').next().unwrap_or_default();
+        hidden_params
+            .iter()
+            .any(|hidden| match hidden.strip_suffix('*') {
+                Some(prefix) => name.starts_with(prefix),
+                None => name == hidden,
+            })
+    };
+    let query = query
+        .split('&')
+        .filter(|param| !param.is_empty() && !is_hidden(param))
+        .collect::<Vec<_>>()
+        .join("&");
+
+    let mut display_url = base.to_string();
+    if !query.is_empty() {
+        display_url.push('?');
+        display_url.push_str(&query);
+    }
+    if let Some(fragment) = fragment {
+        display_url.push('#');
+        display_url.push_str(fragment);
+    }
+    display_url
+}
+
+/// Removes boilerplate like " | Example" from the end of a title, if the part after the last
+/// separator is the site's name or matches its domain. Separators must be surrounded by spaces.
+fn trim_title_suffix<'a>(
+    title: &'a str,
+    separators: &[String],
+    domain: &str,
+    site_name: Option<&str>,
+) -> &'a str {
+    // Compare names by their lowercase letters and digits only, so "Example.com" matches
+    // "example.com" and "Foo Bar" matches "foobar.org".
+    let normalize = |name: &str| {
+        name.chars()
+            .filter(|c| c.is_alphanumeric())
+            .flat_map(char::to_lowercase)
+            .collect::<String>()
+    };
+
+    let Some((position, separator)) = separators
+        .iter()
+        .filter_map(|separator| {
+            title
+                .rfind(&format!(" {separator} "))
+                .map(|position| (position, separator))
+        })
+        .max_by_key(|(position, _separator)| *position)
+    else {
+        return title;
+    };
+
+    let (rest, suffix) = (
+        title[..position].trim_end(),
+        &title[position + separator.len() + 2..],
+    );
+    let suffix = normalize(suffix);
+    if rest.is_empty() || suffix.is_empty() {
+        return title;
+    }
+
+    let domain = domain.trim_start_matches("www.");
+    let matches_domain =
+        normalize(domain) == suffix || domain.split('.').any(|label| normalize(label) == suffix);
+    let matches_site_name = site_name.is_some_and(|site_name| normalize(site_name) == suffix);
+
+    if matches_domain || matches_site_name {
+        rest
+    } else {
+        title
+    }
+}
+
+/// Scores how much a page reads like prose rather than a list of links, from 0 to 1: half for the
+/// share of its text outside of links, 0.3 for the average length of its sentences and 0.2 for
+/// having a title and a description.
+fn quality_score(document: &Html, content: &PageContent) -> f32 {
+    /// Sentences at least this long on average get full marks.
+    const PROSE_SENTENCE_WORDS: f32 = 12.0;
+
+    let visible_chars = |text: &str| text.chars().filter(|c| !c.is_whitespace()).count();
+    let body_chars = visible_chars(&content.body);
+    let link_chars: usize = document
+        .select(&LINK_SELECTOR)
+        .flat_map(|link| link.text())
+        .map(visible_chars)
+        .sum();
+    // Links outside of the extracted body can make up more than all of it.
+    let text_share = if body_chars == 0 {
+        0.0
+    } else {
+        1.0 - (link_chars as f32 / body_chars as f32).min(1.0)
+    };
+
+    // Only text ending in punctuation counts, so runs of link text aren't taken for one long
+    // sentence.
+    let (sentences, words) = content
+        .body
+        .split_inclusive(['.', '!', '?'])
+        .filter(|sentence| sentence.ends_with(['.', '!', '?']))
+        .map(|sentence| sentence.split_whitespace().count())
+        .filter(|&words| words > 0)
+        .fold((0, 0), |(sentences, total), words| {
+            (sentences + 1, total + words)
+        });
+    let sentence_score = if sentences == 0 {
+        0.0
+    } else {
+        (words as f32 / sentences as f32 / PROSE_SENTENCE_WORDS).min(1.0)
+    };
+
+    let metadata_score = [&content.title, &content.description]
+        .iter()
+        .filter(|text| !text.is_empty())
+        .count() as f32
+        / 2.0;
+
+    0.5 * text_share + 0.3 * sentence_score + 0.2 * metadata_score
+}
+
+/// The extracted contents of a page, ready to be indexed.
+struct PageContent {
+    title: String,
+    description: String,
+    body: String,
+    /// [`DOC_TYPE_HTML`] or `"pdf"`, for `type:` filters.
+    doc_type: String,
+    /// Absolute URL of the page's image, or empty.
+    image_url: String,
+    /// The page's breadcrumb with [`BREADCRUMB_SEPARATOR`] between items, or empty.
+    breadcrumb: String,
+    /// The page's meta keywords, see [`keywords`], or empty.
+    keywords: String,
+}
+
+const DOC_TYPE_HTML: &str = "html";
+#[cfg(feature = "pdf")]
+const DOC_TYPE_PDF: &str = "pdf";
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct RawDomainStats {
+    page_count: u64,
+    total_size: u64,
+    min_size: u64,
+    max_size: u64,
+    min_url: String,
+    max_url: String,
+    /// Unix timestamp of the last page indexed for the domain.
+    last_updated: u64,
+}
+
+impl Default for RawDomainStats {
+    fn default() -> Self {
+        Self {
+            page_count: Default::default(),
+            total_size: Default::default(),
+            min_size: u64::MAX,
+            max_size: Default::default(),
+            min_url: Default::default(),
+            max_url: Default::default(),
+            last_updated: Default::default(),
+        }
+    }
+}
+
+/// The names of the fields that were added, removed or changed between two schemas.
+fn changed_fields(old: &Schema, new: &Schema) -> Vec<String> {
+    let entries = |schema: &Schema| -> BTreeMap<String, FieldEntry> {
+        schema
+            .fields()
+            .map(|(_field, entry)| (entry.name().to_string(), entry.clone()))
+            .collect()
+    };
+    let old = entries(old);
+    let new = entries(new);
+
+    let mut changed: Vec<_> = old
+        .iter()
+        .filter(|(name, entry)| new.get(*name) != Some(entry))
+        .chain(new.iter().filter(|(name, _entry)| !old.contains_key(*name)))
+        .map(|(name, _entry)| name.clone())
+        .collect();
+    changed.sort();
+    changed
+}
+
+/// [`RawDomainStats`] as stored before `last_updated` was added.
+#[derive(Serialize, Deserialize)]
+struct LegacyRawDomainStats {
+    page_count: u64,
+    total_size: u64,
+    min_size: u64,
+    max_size: u64,
+    min_url: String,
+    max_url: String,
+}
+
+impl RawDomainStats {
+    /// Decodes stats read from the stats DB. Stats of older versions lack `last_updated`, which
+    /// is then 0 until the domain is indexed again.
+    fn decode(bytes: &[u8]) -> anyhow::Result<Self> {
+        // bincode ignores trailing bytes, so the current layout has to be tried first.
+        match bincode::deserialize(bytes) {
+            Ok(stats) => Ok(stats),
+            Err(e) => {
+                let LegacyRawDomainStats {
+                    page_count,
+                    total_size,
+                    min_size,
+                    max_size,
+                    min_url,
+                    max_url,
+                } = bincode::deserialize(bytes).map_err(|_| e)?;
+                Ok(Self {
+                    page_count,
+                    total_size,
+                    min_size,
+                    max_size,
+                    min_url,
+                    max_url,
+                    last_updated: 0,
+                })
+            }
+        }
+    }
+
+    fn add_page(&mut self, url: &str, size: u64, timestamp: u64) {
+        self.page_count += 1;
+        self.total_size += size;
+        self.last_updated = self.last_updated.max(timestamp);
+
+        // Update min size and URL
+        if size < self.min_size {
+            self.min_size = size;
+            self.min_url = url.to_string();
+        }
+        // Update max size and URL
+        if size > self.max_size {
+            self.max_size = size;
+            self.max_url = url.to_string();
+        }
+    }
+
+    /// Takes out a page of `size` that is being replaced. The smallest and largest page are kept,
+    /// since the next smallest and largest aren't known.
+    fn remove_page(&mut self, size: u64) {
+        self.page_count = self.page_count.saturating_sub(1);
+        self.total_size = self.total_size.saturating_sub(size);
+    }
+
+    /// Adds the stats of pages indexed after the ones in `self`.
+    fn merge(&mut self, later: &RawDomainStats) {
+        self.page_count += later.page_count;
+        self.total_size += later.total_size;
+        self.last_updated = self.last_updated.max(later.last_updated);
+
+        // Strict comparisons like in `add_page`, so ties keep the earlier page.
+        if later.min_size < self.min_size {
+            self.min_size = later.min_size;
+            self.min_url = later.min_url.clone();
+        }
+        if later.max_size > self.max_size {
+            self.max_size = later.max_size;
+            self.max_url = later.max_url.clone();
+        }
+    }
+}
+
+/// HTTP validators of a page, compared on a re-crawl to tell whether it changed.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct PageValidators {
+    etag: Option<String>,
+    last_modified: Option<String>,
+}
+
+/// API requests made with a key on one day.
+#[derive(Default, Serialize, Deserialize)]
+struct RawApiUsage {
+    /// Days since the Unix epoch.
+    day: u64,
+    requests: u64,
+}
+
+/// The longest query, in bytes, that clicks are recorded for.
+pub const MAX_CLICK_QUERY_LEN: usize = 200;
+/// The most queries that clicks are recorded for, which bounds the size of the `clicks` tree.
+const MAX_CLICK_QUERIES: usize = 10_000;
+
+#[derive(Default, Serialize, Deserialize)]
+struct RawClickStats {
+    urls: BTreeMap<String, RawUrlClickStats>,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct RawUrlClickStats {
+    clicks: u64,
+    position_sum: u64,
+}
+
+/// Clicks on the results of a query.
+#[derive(Serialize)]
+pub struct ClickStats {
+    pub query: String,
+    pub clicks: u64,
+    /// Clicked results, most clicked first.
+    pub results: Vec<UrlClickStats>,
+}
+
+#[derive(Serialize)]
+pub struct UrlClickStats {
+    pub url: String,
+    pub clicks: u64,
+    /// The average position of the result when clicked, starting at 1.
+    pub average_position: f64,
+}
+
+/// The stored text of an indexed page.
+#[derive(Serialize)]
+pub struct CachedPage {
+    pub url: String,
+    pub title: String,
+    pub body: String,
+    /// Unix timestamp of when the page was first indexed.
+    pub crawled_at: u64,
+}
+
+#[derive(Serialize)]
+pub struct IndexSummary {
+    pub num_docs: u64,
+    pub num_domains: usize,
+    /// Size of the extracted text of all pages.
+    pub total_size_bytes: u64,
+    /// Size of the index files on disk.
+    pub index_size_bytes: u64,
+    pub num_segments: usize,
+    /// Unix timestamp of the last page indexed, i.e. of the last crawl.
+    pub last_indexed_at: Option<u64>,
+    /// Crawled pages not indexed again since the server started because they were unchanged.
+    pub pages_unchanged: u64,
+    /// Pages not indexed since the server started because they were below `min_quality`.
+    pub pages_low_quality: u64,
+}
+
+/// Domain stats with human-readable values.
+#[derive(Serialize)]
+pub struct DomainStats {
+    pub domain: String,
+    pub page_count: u64,
+    pub total_size: String,
+    /// `total_size` in bytes, for sorting and totals.
+    pub total_size_bytes: u64,
+    /// Unix timestamp of the last page indexed for the domain.
+    pub last_updated: u64,
+    pub min_page_size: String,
+    pub max_page_size: String,
+    pub min_page_url: String,
+    pub max_page_url: String,
+}
+
+/// An error caused by the query itself, with a message that can be shown to the user.
+#[derive(Debug)]
+pub struct InvalidQuery(pub String);
+
+impl std::fmt::Display for InvalidQuery {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for InvalidQuery {}
+
+/// The number of documents of a domain that match a query.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct DomainCount {
+    pub domain: String,
+    pub count: u64,
+}
+
+/// How long the phases of a search took, see [`Indexer::search_timed`].
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SearchTimings {
+    /// Parsing the query and adding our boosts and filters.
+    pub parse: Duration,
+    /// Collecting and ranking the top documents.
+    pub collect: Duration,
+    /// Loading the documents and generating their snippets, usually the slowest part.
+    pub snippets: Duration,
+    /// The whole search, measured separately from the phases. At least their sum.
+    pub total: Duration,
+}
+
+impl Serialize for SearchTimings {
+    /// Serialized as fractional milliseconds, e.g. `{"parse_ms": 0.12, ...}`.
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::SerializeStruct;
+
+        let ms = |duration: Duration| duration.as_secs_f64() * 1000.0;
+        let mut timings = serializer.serialize_struct("SearchTimings", 4)?;
+        timings.serialize_field("parse_ms", &ms(self.parse))?;
+        timings.serialize_field("collect_ms", &ms(self.collect))?;
+        timings.serialize_field("snippets_ms", &ms(self.snippets))?;
+        timings.serialize_field("total_ms", &ms(self.total))?;
+        timings.end()
+    }
+}
+
+/// The result of a web search.
+#[derive(Serialize)]
+pub struct SearchResult {
+    pub title: String,
+    pub url: String,
+    /// A relevant snippet from the page, from the first source in
+    /// [`SearchOptions::snippet_preference`] that matches the query.
+    pub snippet: String,
+    /// A relevant snippet from the meta description. Empty if the description doesn't match the
+    /// query.
+    pub description_snippet: String,
+    /// URL of the page's `og:image`, or empty.
+    pub image_url: String,
+    /// The page's breadcrumb, e.g. "Docs › Guides", with `extract_breadcrumbs`. Otherwise empty.
+    pub breadcrumb: String,
+    /// Unix timestamp of when the page was crawled.
+    pub crawled_at: u64,
+    /// Top-level domain of the page, see [`tld`].
+    pub tld: String,
+    /// Whether the page is from one of [`SearchOptions::trusted_domains`].
+    pub is_trusted: bool,
+    /// The query terms found in the page, if requested with [`SearchOptions::matched_terms`].
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub matched_terms: Option<Vec<String>>,
+}
+
+/// Per-request search options.
+pub struct SearchOptions {
+    /// Whether to filter results using the safe search blocklists.
+    pub safe_search: bool,
+    /// The number of threads to generate result snippets on. 1 generates them sequentially.
+    pub snippet_threads: usize,
+    /// Whether to generate snippets. When disabled, results have an empty snippet.
+    pub include_snippet: bool,
+    /// Query parameters removed from result URLs, see [`display_url`].
+    pub hidden_url_params: Vec<String>,
+    /// The maximum number of body fragments in a snippet, see [`multi_fragment_snippet`].
+    pub snippet_fragments: usize,
+    /// The maximum length of a snippet fragment in characters, see [`snippet_html`].
+    pub snippet_max_chars: usize,
+    /// Whether to show only the highest-ranked result of each title, see [`dedupe_by_title`].
+    pub dedupe_by_title: bool,
+    /// Where the snippet is taken from, in order of preference. The first non-empty one is used.
+    pub snippet_preference: Vec<SnippetSource>,
+    /// Whether to decay scores with the age of pages, see [`IndexerConfig::news_half_life_secs`].
+    pub news_mode: bool,
+    /// Whether to fill in [`SearchResult::matched_terms`].
+    pub matched_terms: bool,
+    /// Whether to skip snippets without words or identical to the title, see
+    /// [`is_redundant_snippet`].
+    pub collapse_redundant_snippets: bool,
+    /// Domains whose results are marked as trusted, see [`is_trusted_domain`].
+    pub trusted_domains: Vec<String>,
+    /// Whether to match the query against page titles only, for navigational queries. Tech term
+    /// and synonym boosts still apply, but the body, description and other fields are ignored.
+    pub title_only: bool,
+}
+
+impl Default for SearchOptions {
+    fn default() -> Self {
+        Self {
+            safe_search: true,
+            snippet_threads: 1,
+            include_snippet: true,
+            hidden_url_params: Vec::new(),
+            snippet_fragments: 1,
+            snippet_max_chars: 150,
+            dedupe_by_title: false,
+            snippet_preference: vec![SnippetSource::Description, SnippetSource::BodyMatch],
+            news_mode: false,
+            matched_terms: false,
+            collapse_redundant_snippets: false,
+            trusted_domains: Vec::new(),
+            title_only: false,
+        }
+    }
+}
+
+/// The order of pages when browsing without a query.
+#[derive(Clone, Copy, Debug)]
+pub enum BrowseOrder {
+    /// Most recently crawled first.
+    Recent,
+    Random,
+}
+
+pub struct SearchPage {
+    pub page: Page,
+    pub domain: String,
+}
+
+pub async fn start(
+    config: &IndexerConfig,
+) -> anyhow::Result<(Arc<Indexer>, mpsc::Sender<SearchPage>)> {
+    let indexer = Arc::new(Indexer::new(config).await?);
+    let add_page_indexer = indexer.clone();
+    let commit_indexer = indexer.clone();
+
+    let (tx, mut rx) = mpsc::channel(1000);
+
+    tokio::task::spawn(async move {
+        while let Some(page) = rx.recv().await {
+            if let Err(e) = add_page_indexer.add_page(&page) {
+                let url = page.page.get_url();
+                eprintln!("ERROR: could not index page '{url}': {e}");
+            }
+            add_page_indexer.finish_page();
+        }
+    });
+
+    // Periodically commit.
+    // NOTE: Committing can block, and is also non-async, so we use a dedicated thread.
+    let commit_interval_ms = config.commit_interval_ms;
+    // With a manual reload policy, readers are reloaded here at most this often.
+    let reload_delay = (config.reload_policy == ReaderReload::Manual && config.reload_delay_ms > 0)
+        .then(|| Duration::from_millis(config.reload_delay_ms));
+    std::thread::spawn(move || {
+        // Whether there are commits that searches don't see yet.
+        let mut reload_pending = false;
+        let mut last_reload = Instant::now();
+        loop {
+            std::thread::sleep(Duration::from_millis(commit_interval_ms));
+
+            // Only commit if there's something to commit.
+            if commit_indexer.is_dirty.load(Ordering::Relaxed) {
+                if let Err(e) = commit_indexer.commit() {
+                    eprintln!("ERROR: could not commit index: {e}");
+                } else {
+                    commit_indexer.is_dirty.store(false, Ordering::Relaxed);
+                    reload_pending = true;
+
+                    // Batched stats are kept until their pages are committed.
+                    if let Err(e) = commit_indexer.flush_domain_stats() {
+                        eprintln!("ERROR: could not write domain stats: {e}");
+                    }
+                }
+            }
+
+            if let Some(reload_delay) = reload_delay {
+                if reload_pending && last_reload.elapsed() >= reload_delay {
+                    if let Err(e) = commit_indexer.reload() {
+                        eprintln!("ERROR: could not reload index: {e}");
+                    } else {
+                        reload_pending = false;
+                    }
+                    last_reload = Instant::now();
+                }
+            }
+        }
+    });
+
+    Ok((indexer, tx))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use scraper::Html;
+
+    use crate::{config::Config, test_utils::TEST_DIR};
+
+    const TECH_TERM_BOOST: f32 = 1.5;
+
+    async fn with_indexer<C, F>(test_name: &str, configure: C, f: F)
+    where
+        C: FnOnce(&mut IndexerConfig),
+        F: FnOnce(&Indexer) -> anyhow::Result<()>,
+    {
+        let mut config = Config::load_test(test_name).indexer;
+        configure(&mut config);
+        let indexer = Indexer::new(&config).await.unwrap();
+
+        let result = f(&indexer);
+
+        // Clean up after test.
+        indexer.delete().await.unwrap();
+        result.unwrap();
+    }
+
+    #[test]
+    fn test_url_depth() {
+        assert_eq!(url_depth("https://example.com"), 0);
+        assert_eq!(url_depth("https://example.com/"), 0);
+        assert_eq!(url_depth("https://example.com/docs/"), 1);
+        assert_eq!(url_depth("https://example.com/docs/guide/intro.html"), 3);
+        assert_eq!(url_depth("https://example.com/docs?page=/a/b#/c"), 1);
+    }
+
+    #[test]
+    fn test_url_slug() {
+        assert_eq!(url_slug("https://example.com"), "");
+        assert_eq!(
+            url_slug("https://example.com/blog/how-to_configure--nginx/?ref=a-b"),
+            "blog how to configure nginx"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_news_mode() {
+        with_indexer(
+            "test_news_mode",
+            |config| config.news_half_life_secs = 86_400,
+            |indexer| {
+                const DAY: u64 = 86_400;
+                let now = crate::crawler::unix_timestamp();
+                // Equally relevant pages of different ages, and a more relevant but older one.
+                let pages = [
+                    ("old", "Rust release notes", now - 10 * DAY),
+                    ("new", "Rust release notes", now - DAY / 24),
+                    (
+                        "relevant",
+                        "Rust release notes, all Rust releases",
+                        now - 3 * DAY,
+                    ),
+                ];
+                {
+                    let index_writer = indexer.shard("example.com").index_writer.write().unwrap();
+                    for (page, body, crawled_at) in pages {
+                        let content = PageContent {
+                            title: "Release".to_string(),
+                            description: String::new(),
+                            body: body.to_string(),
+                            doc_type: DOC_TYPE_HTML.to_string(),
+                            image_url: String::new(),
+                            breadcrumb: String::new(),
+                            keywords: String::new(),
+                        };
+                        let url = format!("https://example.com/{page}");
+                        indexer.write_document(
+                            &index_writer,
+                            &url,
+                            "example.com",
+                            content,
+                            None,
+                            crawled_at,
+                        )?;
+                    }
+                }
+                indexer.commit_and_reload()?;
+
+                let urls = |news_mode| {
+                    let options = SearchOptions {
+                        news_mode,
+                        ..Default::default()
+                    };
+                    anyhow::Ok(
+                        indexer
+                            .search("rust release", 10, &options)?
+                            .into_iter()
+                            .map(|result| result.url.replace("https://example.com/", ""))
+                            .collect::<Vec<_>>(),
+                    )
+                };
+
+                assert_eq!(urls(false)?[0], "relevant");
+                // The newer of the equally relevant pages ranks first, and relevance only makes
+                // up for a few days.
+                assert_eq!(urls(true)?, ["new", "relevant", "old"]);
+
+                // Without a half-life, news mode does nothing.
+                indexer.set_config(IndexerConfig {
+                    news_half_life_secs: 0,
+                    ..indexer.config().as_ref().clone()
+                });
+                assert_eq!(urls(true)?, urls(false)?);
+
+                Ok(())
+            },
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn test_title_only() {
+        with_indexer(
+            "test_title_only",
+            |config| config.tech_term_boost = 3.0,
+            |indexer| {
+                let pages = [
+                    ("title", "<title>Ownership Guide</title><body>Notes</body>"),
+                    ("body", "<title>Notes</title><body>Ownership guide</body>"),
+                    ("rust", "<title>Rust Notes</title><body>Ownership</body>"),
+                ];
+                for (page, html) in pages {
+                    indexer.add_html(
+                        &format!("https://example.com/{page}"),
+                        html,
+                        "example.com",
+                    )?;
+                }
+                indexer.commit_and_reload()?;
+
+                let urls = |query, title_only| {
+                    let options = SearchOptions {
+                        title_only,
+                        ..Default::default()
+                    };
+                    anyhow::Ok(
+                        indexer
+                            .search(query, 10, &options)?
+                            .into_iter()
+                            .map(|result| result.url.replace("https://example.com/", ""))
+                            .collect::<BTreeSet<_>>(),
+                    )
+                };
+
+                assert_eq!(
+                    urls("ownership", false)?,
+                    BTreeSet::from(["title".into(), "body".into(), "rust".into()])
+                );
+                // The pages matching only in the body are left out, with prefixes too.
+                assert_eq!(urls("ownership", true)?, BTreeSet::from(["title".into()]));
+                assert_eq!(urls("owner*", true)?, BTreeSet::from(["title".into()]));
+
+                // Tech terms are still boosted.
+                let options = SearchOptions {
+                    title_only: true,
+                    ..Default::default()
+                };
+                let results = indexer.search("rust guide", 10, &options)?;
+                assert_eq!(results.len(), 2);
+                assert_eq!(results[0].url, "https://example.com/rust");
+
+                Ok(())
+            },
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn test_index_keywords() {
+        with_indexer(
+            "test_index_keywords",
+            |config| {
+                config.index_keywords = true;
+                config.max_keywords = 3;
+            },
+            |indexer| {
+                indexer.add_html(
+                    "https://example.com/safety",
+                    r#"<head><title>Safety</title>
+                    <meta name="keywords" content="Ferrocene, ferrocene, certification,, iso, misra">
+                    </head><body>Safety-critical Rust</body>"#,
+                    "example.com",
+                )?;
+                indexer.add_html(
+                    "https://example.com/ferrocene",
+                    "<title>Toolchains</title><body>Ferrocene is a Rust toolchain</body>",
+                    "example.com",
+                )?;
+                indexer.commit_and_reload()?;
+
+                let urls = |query| -> anyhow::Result<Vec<String>> {
+                    let results = indexer.search(query, 10, &SearchOptions::default())?;
+                    Ok(results.into_iter().map(|result| result.url).collect())
+                };
+                assert_eq!(urls("certification")?, ["https://example.com/safety"]);
+                // Keywords count less than the page's own text.
+                assert_eq!(
+                    urls("ferrocene")?,
+                    ["https://example.com/ferrocene", "https://example.com/safety"]
+                );
+                // Only the first distinct keywords are indexed.
+                assert!(urls("misra")?.is_empty());
+
+                Ok(())
+            },
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn test_index_url_slug() {
+        with_indexer(
+            "test_index_url_slug",
+            |config| config.index_url_slug = true,
+            |indexer| {
+                indexer.add_html(
+                    "https://example.com/how-to-configure-nginx",
+                    "<title>Web Servers</title><body>A short guide</body>",
+                    "example.com",
+                )?;
+                indexer.add_html(
+                    "https://example.com/nginx",
+                    "<title>Nginx Configuration</title><body>Configure nginx</body>",
+                    "example.com",
+                )?;
+                indexer.commit_and_reload()?;
+
+                let results = indexer.search("configure nginx", 10, &SearchOptions::default())?;
+                assert_eq!(results.len(), 2);
+                // Slugs count less than the page's own text.
+                assert_eq!(results[0].url, "https://example.com/nginx");
+
+                Ok(())
+            },
+        )
+        .await;
+    }
+
+    #[test]
+    fn test_anchor_texts() {
+        let document = Html::parse_document(
+            r##"<body>
+                <a href="/async">Rust <em>async</em> book</a>
+                <a href="https://other.com/async#intro">  The   async book </a>
+                <a href="https://other.com/async">Async</a>
+                <a href="#top">Top</a>
+                <a href="/docs/">Back</a>
+                <a href="mailto:a@example.com">Mail</a>
+                <a>No target</a>
+            </body>"##,
+        );
+        let anchor_texts = anchor_texts(&document, "https://example.com/docs/");
+        assert_eq!(
+            anchor_texts,
+            BTreeMap::from([
+                (
+                    "https://example.com/async".to_string(),
+                    vec!["Rust async book".to_string()]
+                ),
+                (
+                    "https://other.com/async".to_string(),
+                    vec!["  The   async book ".to_string(), "Async".to_string()]
+                ),
+            ])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_index_anchor_text() {
+        with_indexer(
+            "test_index_anchor_text",
+            |config| config.index_anchor_text = true,
+            |indexer| {
+                for page in ["a", "b"] {
+                    indexer.add_html(
+                        &format!("https://example.com/{page}"),
+                        r#"<title>Links</title><body>
+                            <a href="/tokio">Rust async book</a>
+                            <a href="/tokio#runtime">The   Rust async book</a>
+                            <a href="/tokio">Rust async book</a>
+                        </body>"#,
+                        "example.com",
+                    )?;
+                }
+                indexer.add_html(
+                    "https://example.com/tokio",
+                    "<title>Tokio</title><body>A runtime for writing reliable applications</body>",
+                    "example.com",
+                )?;
+                indexer.commit_and_reload()?;
+
+                // Each distinct text is attributed to the target once.
+                assert_eq!(
+                    indexer.anchor_text("https://example.com/tokio")?,
+                    "Rust async book\nThe Rust async book"
+                );
+                assert_eq!(indexer.anchor_text("https://example.com/a")?, "");
+
+                // The target is found by the words of links to it, besides the linking pages.
+                let results = indexer.search("async book", 10, &SearchOptions::default())?;
+                assert_eq!(results.len(), 3);
+                assert!(results
+                    .iter()
+                    .any(|result| result.url == "https://example.com/tokio"));
+
+                Ok(())
+            },
+        )
+        .await;
+    }
+
+    #[test]
+    fn test_tld() {
+        assert_eq!(tld("www.rust-lang.org"), "org");
+        assert_eq!(tld("mit.EDU"), "edu");
+        assert_eq!(tld("bbc.co.uk"), "uk");
+        assert_eq!(tld("example.com:8080"), "com");
+        assert_eq!(tld("127.0.0.1"), "");
+        assert_eq!(tld("localhost"), "");
+    }
+
+    #[test]
+    fn test_resolve_url() {
+        let base = "https://example.com/blog/post?id=1";
+        assert_eq!(
+            resolve_url(base, "https://cdn.example.com/a.png").as_deref(),
+            Some("https://cdn.example.com/a.png")
+        );
+        assert_eq!(
+            resolve_url(base, "//cdn.example.com/a.png").as_deref(),
+            Some("https://cdn.example.com/a.png")
+        );
+        assert_eq!(
+            resolve_url(base, "/images/a.png").as_deref(),
+            Some("https://example.com/images/a.png")
+        );
+        assert_eq!(
+            resolve_url(base, "a.png").as_deref(),
+            Some("https://example.com/blog/a.png")
+        );
+        assert_eq!(resolve_url(base, "data:image/png;base64,AAAA"), None);
+        assert_eq!(resolve_url(base, ""), None);
+    }
+
+    #[tokio::test]
+    async fn test_breadcrumb() {
+        with_indexer(
+            "test_breadcrumb",
+            |config| config.extract_breadcrumbs = true,
+            |indexer| {
+                indexer.add_html(
+                    "https://example.com/docs/guides/install",
+                    r#"<title>Install</title><body>
+                    <nav aria-label="Breadcrumb"><ol>
+                        <li><a href="/docs">Docs</a></li> <li>/</li>
+                        <li><a href="/docs/guides">Guides</a></li>
+                    </ol></nav>
+                    <p>Install Rust with rustup</p></body>"#,
+                    "example.com",
+                )?;
+                indexer.add_html(
+                    "https://example.com/blog/release",
+                    r#"<title>Release</title><body>
+                    <script type="application/ld+json">{"@graph": [{"@type": "BreadcrumbList",
+                        "itemListElement": [
+                            {"@type": "ListItem", "position": 2, "name": "Blog"},
+                            {"@type": "ListItem", "position": 1, "item": {"name": "Home"}}
+                        ]}]}</script>
+                    <p>Rust release notes</p></body>"#,
+                    "example.com",
+                )?;
+                indexer.add_html(
+                    "https://example.com/about",
+                    "<title>About</title><body>About Rust</body>",
+                    "example.com",
+                )?;
+                indexer.commit_and_reload()?;
+
+                let breadcrumb = |query| -> anyhow::Result<String> {
+                    let results = indexer.search(query, 10, &SearchOptions::default())?;
+                    assert_eq!(results.len(), 1);
+                    Ok(results[0].breadcrumb.clone())
+                };
+                assert_eq!(breadcrumb("rustup")?, "Docs › Guides");
+                assert_eq!(breadcrumb("release")?, "Home › Blog");
+                assert_eq!(breadcrumb("about")?, "");
+                Ok(())
+            },
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn test_image_url() {
+        with_indexer(
+            "test_image_url",
+            |_| {},
+            |indexer| {
+                indexer.add_html(
+                    "https://example.com/blog/rust",
+                    r#"<head><title>Rust</title><meta property="og:image" content="/rust.png"></head>
+                    <body>Rust ownership</body>"#,
+                    "example.com",
+                )?;
+                indexer.add_html(
+                    "https://example.com/go",
+                    "<title>Go</title><body>Go ownership</body>",
+                    "example.com",
+                )?;
+                indexer.commit_and_reload()?;
+
+                let results = indexer.search("rust", 10, &SearchOptions::default())?;
+                assert_eq!(results.len(), 1);
+                assert_eq!(results[0].image_url, "https://example.com/rust.png");
+
+                let results = indexer.search("go", 10, &SearchOptions::default())?;
+                assert_eq!(results[0].image_url, "");
+
+                Ok(())
+            },
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn test_depth_penalty() {
+        with_indexer(
+            "test_depth_penalty",
+            |config| config.depth_penalty = 0.1,
+            |indexer| {
+                let html = "<title>Rust</title><body>Rust ownership</body>";
+                // Add the deep page first so it doesn't win ties by insertion order.
+                indexer.add_html("https://example.com/a/b/c/rust", html, "example.com")?;
+                indexer.add_html("https://example.com/rust", html, "example.com")?;
+                indexer.commit_and_reload()?;
+
+                let results = indexer.search("rust", 10, &SearchOptions::default())?;
+                assert_eq!(results.len(), 2);
+                assert_eq!(results[0].url, "https://example.com/rust");
+
+                Ok(())
+            },
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn test_prefer_https() {
+        with_indexer(
+            "test_prefer_https",
+            |config| config.prefer_https = true,
+            |indexer| {
+                let html = "<title>Rust</title><body>Rust ownership</body>";
+                // Add the HTTP page first so it would win ties by insertion order.
+                indexer.add_html("http://example.com/rust", html, "example.com")?;
+                indexer.add_html("https://example.com/rust", html, "example.com")?;
+                indexer.commit_and_reload()?;
+
+                let results = indexer.search("rust", 10, &SearchOptions::default())?;
+                assert_eq!(results.len(), 2);
+                assert_eq!(results[0].url, "https://example.com/rust");
+
+                Ok(())
+            },
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn test_title_coverage_boost() {
+        for title_coverage_boost in [0.0, 3.0] {
+            let test_name = format!("test_title_coverage_boost_{title_coverage_boost}");
+            with_indexer(
+                &test_name,
+                |config| config.title_coverage_boost = title_coverage_boost,
+                |indexer| {
+                    indexer.add_html(
+                        "https://example.com/partial",
+                        "<title>Tokio Tutorial</title><body>Tokio tutorial for beginners</body>",
+                        "example.com",
+                    )?;
+                    indexer.add_html(
+                        "https://example.com/full",
+                        "<title>Rust Tokio Tutorial</title><body>Getting started</body>",
+                        "example.com",
+                    )?;
+                    indexer.add_html(
+                        "https://example.com/other",
+                        "<title>Rust Book</title><body>Ownership</body>",
+                        "example.com",
+                    )?;
+                    indexer.commit_and_reload()?;
+
+                    let results =
+                        indexer.search("rust tokio tutorial", 10, &SearchOptions::default())?;
+                    let expected_first = if title_coverage_boost > 0.0 {
+                        "https://example.com/full"
+                    } else {
+                        "https://example.com/partial"
+                    };
+                    assert_eq!(results[0].url, expected_first);
+
+                    // Titles with query terms don't bring back excluded pages.
+                    let results =
+                        indexer.search("rust book -tokio", 10, &SearchOptions::default())?;
+                    let urls: Vec<_> = results.iter().map(|result| result.url.as_str()).collect();
+                    assert_eq!(urls, ["https://example.com/other"]);
+
+                    Ok(())
+                },
+            )
+            .await;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_safe_search_blocked_domains() {
+        with_indexer(
+            "test_safe_search_blocked_domains",
+            |config| config.safe_search.blocked_domains = vec!["blocked.com".to_string()],
+            |indexer| {
+                let html = "<title>Rust</title><body>Rust ownership</body>";
+                indexer.add_html("https://allowed.com/rust", html, "allowed.com")?;
+                indexer.add_html("https://blocked.com/rust", html, "blocked.com")?;
+                indexer.commit_and_reload()?;
+
+                let results = indexer.search("rust", 10, &SearchOptions::default())?;
+                assert_eq!(results.len(), 1);
+                assert_eq!(results[0].url, "https://allowed.com/rust");
+
+                // Blocked pages stay in the index.
+                let options = SearchOptions {
+                    safe_search: false,
+                    ..Default::default()
+                };
+                assert_eq!(indexer.search("rust", 10, &options)?.len(), 2);
+
+                Ok(())
+            },
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn test_safe_search_blocked_terms() {
+        with_indexer(
+            "test_safe_search_blocked_terms",
+            |config| config.safe_search.blocked_terms = vec!["casino".to_string()],
+            |indexer| {
+                indexer.add_html(
+                    "https://example.com/clean",
+                    "<title>Rust</title><body>Rust ownership</body>",
+                    "example.com",
+                )?;
+                indexer.add_html(
+                    "https://example.com/casino",
+                    "<title>Rust</title><body>Rust casino bonus</body>",
+                    "example.com",
+                )?;
+                indexer.commit_and_reload()?;
+
+                let results = indexer.search("rust", 10, &SearchOptions::default())?;
+                assert_eq!(results.len(), 1);
+                assert_eq!(results[0].url, "https://example.com/clean");
+
+                let options = SearchOptions {
+                    safe_search: false,
+                    ..Default::default()
+                };
+                assert_eq!(indexer.search("rust", 10, &options)?.len(), 2);
+
+                Ok(())
+            },
+        )
+        .await;
+    }
+
+    #[test]
+    fn test_min_span() {
+        assert_eq!(min_span(&[vec![3], vec![4]]), Some(2));
+        assert_eq!(min_span(&[vec![0, 10], vec![4, 12], vec![11]]), Some(3));
+        assert_eq!(min_span(&[vec![7], vec![1]]), Some(7));
+        assert_eq!(min_span(&[vec![5]]), Some(1));
+        assert_eq!(min_span(&[vec![1, 2], vec![]]), None);
+    }
+
+    #[tokio::test]
+    async fn test_proximity_boost() {
+        let filler = "lorem ipsum dolor sit amet ".repeat(10);
+        let pages = [
+            // A bit more relevant by term frequency, but with distant terms.
+            (
+                "distant",
+                format!("Rust {filler} memory ownership, safe Rust"),
+            ),
+            ("adjacent", format!("Rust ownership {filler} memory safe")),
+        ];
+
+        for proximity_boost in [0.0, 0.5] {
+            with_indexer(
+                &format!("test_proximity_boost_{proximity_boost}"),
+                |config| config.proximity_boost = proximity_boost,
+                |indexer| {
+                    for (page, body) in &pages {
+                        indexer.add_html(
+                            &format!("https://example.com/{page}"),
+                            &format!("<title>Page</title><body>{body}</body>"),
+                            "example.com",
+                        )?;
+                    }
+                    indexer.commit_and_reload()?;
+
+                    let results =
+                        indexer.search("rust ownership", 10, &SearchOptions::default())?;
+                    assert_eq!(results.len(), 2);
+                    let first = if proximity_boost > 0.0 {
+                        "https://example.com/adjacent"
+                    } else {
+                        "https://example.com/distant"
+                    };
+                    assert_eq!(results[0].url, first);
+
+                    // Results beyond the requested number are re-ranked too.
+                    let results = indexer.search("rust ownership", 1, &SearchOptions::default())?;
+                    assert_eq!(results[0].url, first);
+
+                    Ok(())
+                },
+            )
+            .await;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_matched_terms() {
+        with_indexer(
+            "test_matched_terms",
+            |_config| {},
+            |indexer| {
+                indexer.add_html(
+                    "https://example.com/ownership",
+                    "<title>Rust ownership</title>\
+                     <meta name=\"description\" content=\"Borrowing explained\">\
+                     <body>Rust moves values.</body>",
+                    "example.com",
+                )?;
+                indexer.commit_and_reload()?;
+
+                // Terms are listed once even if they match several fields, and site filters and
+                // terms missing from the page aren't listed.
+                let options = SearchOptions {
+                    matched_terms: true,
+                    ..Default::default()
+                };
+                let results = indexer.search(
+                    "Rust OR borrowing OR lifetimes site:example.com",
+                    10,
+                    &options,
+                )?;
+                assert_eq!(results.len(), 1);
+                assert_eq!(
+                    results[0].matched_terms,
+                    Some(vec!["rust".to_string(), "borrow".to_string()])
+                );
+
+                // Also without snippets.
+                let options = SearchOptions {
+                    include_snippet: false,
+                    ..options
+                };
+                let results = indexer.search("rust ownership", 10, &options)?;
+                assert_eq!(
+                    results[0].matched_terms,
+                    Some(vec!["rust".to_string(), "ownership".to_string()])
+                );
+
+                let results = indexer.search("rust", 10, &SearchOptions::default())?;
+                assert_eq!(results[0].matched_terms, None);
+
+                Ok(())
+            },
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn test_phrase_boost() {
+        with_indexer(
+            "test_phrase_boost",
+            |config| config.phrase_boost = 2.0,
+            |indexer| {
+                // Same terms and length, but only one page has them as an exact phrase. Add the
+                // scattered page first so it doesn't lose ties by insertion order.
+                indexer.add_html(
+                    "https://example.com/scattered",
+                    "<title>Guide</title><body>safety of memory and borrow rules</body>",
+                    "example.com",
+                )?;
+                indexer.add_html(
+                    "https://example.com/phrase",
+                    "<title>Guide</title><body>memory safety of and borrow rules</body>",
+                    "example.com",
+                )?;
+                indexer.commit_and_reload()?;
+
+                let results = indexer.search("memory safety", 10, &SearchOptions::default())?;
+                assert_eq!(results.len(), 2);
+                assert_eq!(results[0].url, "https://example.com/phrase");
+
+                // The phrase doesn't bring back excluded pages, and doesn't include excluded words.
+                let results = indexer.search("memory safety -of", 10, &SearchOptions::default())?;
+                assert!(results.is_empty());
+
+                Ok(())
+            },
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn test_snippet_threads() {
+        with_indexer(
+            "test_snippet_threads",
+            |_config| {},
+            |indexer| {
+                for i in 0..10 {
+                    indexer.add_html(
+                        &format!("https://example.com/{i}"),
+                        &format!("<title>Page {i}</title><body>Rust page number {i}</body>"),
+                        "example.com",
+                    )?;
+                }
+                indexer.commit_and_reload()?;
+
+                let sequential = indexer.search("rust", 10, &SearchOptions::default())?;
+                for snippet_threads in [2, 3, 4, 20] {
+                    let options = SearchOptions {
+                        snippet_threads,
+                        ..Default::default()
+                    };
+                    let parallel = indexer.search("rust", 10, &options)?;
+
+                    assert_eq!(parallel.len(), sequential.len());
+                    for (a, b) in parallel.iter().zip(&sequential) {
+                        assert_eq!(a.url, b.url);
+                        assert_eq!(a.snippet, b.snippet);
+                    }
+                }
+
+                Ok(())
+            },
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn test_search_without_snippet() {
+        with_indexer(
+            "test_search_without_snippet",
+            |_config| {},
+            |indexer| {
+                indexer.add_html(
+                    "https://example.com/rust",
+                    "<title>Rust</title><body>Rust ownership</body>",
+                    "example.com",
+                )?;
+                indexer.commit_and_reload()?;
+
+                let results = indexer.search("ownership", 10, &SearchOptions::default())?;
+                assert_eq!(results[0].snippet, "Rust <b>ownership</b>");
+
+                let options = SearchOptions {
+                    include_snippet: false,
+                    ..Default::default()
+                };
+                // Use a fresh searcher, with an empty docstore cache.
+                indexer.shards[0].reader.reload()?;
+                let results = indexer.search("ownership", 10, &options)?;
+                assert_eq!(results.len(), 1);
+                assert_eq!(results[0].title, "Rust");
+                assert_eq!(results[0].url, "https://example.com/rust");
+                assert!(results[0].snippet.is_empty());
+
+                // The stored documents (with their bodies) weren't loaded.
+                let cache_stats = indexer.shards[0].reader.searcher().doc_store_cache_stats();
+                assert_eq!(cache_stats.cache_hits + cache_stats.cache_misses, 0);
+
+                Ok(())
+            },
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn test_dedupe_by_title() {
+        with_indexer(
+            "test_dedupe_by_title",
+            |_config| {},
+            |indexer| {
+                indexer.add_html(
+                    "https://example.com/a",
+                    "<title>Rust Guide</title><body>Rust ownership ownership</body>",
+                    "example.com",
+                )?;
+                indexer.add_html(
+                    "https://mirror.example.com/a",
+                    "<title>rust  guide</title><body>Rust ownership</body>",
+                    "mirror.example.com",
+                )?;
+                indexer.add_html(
+                    "https://example.com/b",
+                    "<title>Rust Borrowing</title><body>Rust borrowing</body>",
+                    "example.com",
+                )?;
+                indexer.commit_and_reload()?;
+
+                let results = indexer.search("rust ownership", 2, &SearchOptions::default())?;
+                assert_eq!(results.len(), 2);
+                assert_eq!(results[0].title, "Rust Guide");
+                assert_eq!(results[1].title, "rust guide");
+
+                let options = SearchOptions {
+                    dedupe_by_title: true,
+                    ..Default::default()
+                };
+                // The lower-ranked duplicate is dropped and the next result takes its place.
+                let results = indexer.search("rust ownership", 2, &options)?;
+                assert_eq!(results.len(), 2);
+                assert_eq!(results[0].url, "https://example.com/a");
+                assert_eq!(results[1].title, "Rust Borrowing");
+
+                Ok(())
+            },
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn test_reindex() {
+        let test_name = "test_reindex";
+        let mut config = Config::load_test(test_name).indexer;
+        config.store_raw_html = true;
+        let indexer = Indexer::new(&config).await.unwrap();
+
+        indexer
+            .add_html(
+                "https://example.com/rust",
+                "<title>Rust</title><body><nav>Menu</nav>Rust ownership</body>",
+                "example.com",
+            )
+            .unwrap();
+        indexer.commit_and_reload().unwrap();
+        assert_eq!(
+            indexer
+                .search("menu", 10, &SearchOptions::default())
+                .unwrap()
+                .len(),
+            1
+        );
+        drop(indexer);
+
+        // Reopen the index with <nav> ignored, as after a config change.
+        config.new_index = false;
+        config.ignored_elements.push("nav".to_string());
+        let indexer = Indexer::new(&config).await.unwrap();
+
+        assert_eq!(indexer.reindex().unwrap(), 1);
+        assert!(indexer
+            .search("menu", 10, &SearchOptions::default())
+            .unwrap()
+            .is_empty());
+        let results = indexer
+            .search("ownership", 10, &SearchOptions::default())
+            .unwrap();
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].url, "https://example.com/rust");
+
+        let stats = indexer.get_domain_stats(SizeFormat::Decimal).unwrap();
+        assert_eq!(stats.len(), 1);
+        assert_eq!(stats[0].page_count, 1);
+
+        drop(indexer);
+        let _ = std::fs::remove_dir_all(&config.index_dir);
+        let _ = std::fs::remove_dir_all(&config.db_dir);
+    }
+
+    #[tokio::test]
+    async fn test_reindex_without_stored_body() {
+        with_indexer(
+            "test_reindex_without_stored_body",
+            |config| {
+                config.store_body = false;
+                config.store_raw_html = false;
+            },
+            |indexer| {
+                indexer.add_html(
+                    "https://example.com/rust",
+                    "<title>Rust</title><body>Rust ownership</body>",
+                    "example.com",
+                )?;
+                indexer.commit_and_reload()?;
+
+                let err = indexer.reindex().unwrap_err();
+                assert!(format!("{err:#}").contains("store_raw_html"));
+                // The page is still searchable by its body.
+                let results = indexer.search("ownership", 10, &SearchOptions::default())?;
+                assert_eq!(results.len(), 1);
+                assert_eq!(indexer.get_domain_stats(SizeFormat::Decimal)?.len(), 1);
+
+                Ok(())
+            },
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn test_failed_reindex_keeps_stats() {
+        with_indexer(
+            "test_failed_reindex_keeps_stats",
+            |config| config.store_raw_html = true,
+            |indexer| {
+                for page in ["rust", "go"] {
+                    indexer.add_html(
+                        &format!("https://example.com/{page}"),
+                        &format!("<title>{page}</title><body>{page} ownership</body>"),
+                        "example.com",
+                    )?;
+                }
+                indexer.commit_and_reload()?;
+
+                indexer.fail_reindex.store(true, Ordering::Relaxed);
+                assert!(indexer.reindex().is_err());
+                // Neither the pages nor their stats are lost.
+                let results = indexer.search("ownership", 10, &SearchOptions::default())?;
+                assert_eq!(results.len(), 2);
+                let stats = indexer.get_domain_stats(SizeFormat::Decimal)?;
+                assert_eq!(stats.len(), 1);
+                assert_eq!(stats[0].page_count, 2);
+
+                indexer.fail_reindex.store(false, Ordering::Relaxed);
+                assert_eq!(indexer.reindex()?, 2);
+                let stats = indexer.get_domain_stats(SizeFormat::Decimal)?;
+                assert_eq!(stats[0].page_count, 2);
+
+                Ok(())
+            },
+        )
+        .await;
+    }
+
+    #[test]
+    fn test_sanitize_query() {
+        assert_eq!(sanitize_query("rust;go", false, false), "rust go");
+        assert_eq!(
+            sanitize_query("rust^100 go^1.5", false, false),
+            "rust^100 go^1.5"
+        );
+        assert_eq!(sanitize_query("rust^100 go^1.5", true, false), "rust go");
+        assert_eq!(sanitize_query("\"rust go\"^2", true, false), "\"rust go\"");
+        assert_eq!(sanitize_query("rust^", true, false), "rust");
+        assert_eq!(sanitize_query("rust* *", false, false), "rust* *");
+        assert_eq!(sanitize_query("rust* *", false, true), "rust   ");
+    }
+
+    #[test]
+    fn test_positive_query_text() {
+        assert_eq!(positive_query_text("rust -tokio async"), "rust async");
+        assert_eq!(positive_query_text("+rust NOT tokio"), "rust");
+        assert_eq!(
+            positive_query_text("rust -\"async runtime\" \"memory safety\""),
+            "rust \"memory safety\""
+        );
+        assert_eq!(positive_query_text("-\"tokio\" rust"), "rust");
+        assert_eq!(positive_query_text("rust AND go OR c++"), "rust go c++");
+        assert_eq!(positive_query_text("title:rust lang:en std::io"), "std::io");
+    }
+
+    #[tokio::test]
+    async fn test_strip_query_boosts() {
+        for strip_query_boosts in [false, true] {
+            with_indexer(
+                "test_strip_query_boosts",
+                |config| config.strip_query_boosts = strip_query_boosts,
+                |indexer| {
+                    indexer.add_html(
+                        "https://example.com/alpha",
+                        "<title>A</title><body>alpha gamma gamma gamma gamma</body>",
+                        "example.com",
+                    )?;
+                    indexer.add_html(
+                        "https://example.com/beta",
+                        "<title>B</title><body>beta</body>",
+                        "example.com",
+                    )?;
+                    indexer.commit_and_reload()?;
+
+                    let results =
+                        indexer.search("alpha^100 beta", 10, &SearchOptions::default())?;
+                    assert_eq!(results.len(), 2);
+                    // The shorter page wins unless the user's boost is applied.
+                    let expected_first = if strip_query_boosts { "B" } else { "A" };
+                    assert_eq!(results[0].title, expected_first);
+
+                    Ok(())
+                },
+            )
+            .await;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_body_without_positions() {
+        with_indexer(
+            "test_body_without_positions",
+            |config| {
+                config.body_index_option = BodyIndexOption::Freqs;
+                config.phrase_boost = 1.5;
+            },
+            |indexer| {
+                indexer.add_html(
+                    "https://example.com/rust",
+                    "<title>Rust</title><body>Rust ownership and borrowing</body>",
+                    "example.com",
+                )?;
+                indexer.commit_and_reload()?;
+
+                // Term search works, and the phrase boost is skipped.
+                let results =
+                    indexer.search("ownership borrowing", 10, &SearchOptions::default())?;
+                assert_eq!(results.len(), 1);
+
+                let err = indexer
+                    .search("\"ownership and borrowing\"", 10, &SearchOptions::default())
+                    .err()
+                    .expect("phrase queries need positions");
+                assert!(err.downcast_ref::<InvalidQuery>().is_some());
+
+                Ok(())
+            },
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn test_body_not_stored() {
+        with_indexer(
+            "test_body_not_stored",
+            |config| config.store_body = false,
+            |indexer| {
+                indexer.add_html(
+                    "https://example.com/rust",
+                    "<title>Rust</title>\
+                     <meta name=\"description\" content=\"Rust ownership explained\">\
+                     <body>Rust ownership and borrowing</body>",
+                    "example.com",
+                )?;
+                indexer.commit_and_reload()?;
+
+                let results = indexer.search("borrowing", 10, &SearchOptions::default())?;
+                assert_eq!(results.len(), 1);
+                assert_eq!(results[0].title, "Rust");
+                assert_eq!(results[0].snippet, "");
+
+                // The stored description is still highlighted, and used as the snippet.
+                let results = indexer.search("ownership", 10, &SearchOptions::default())?;
+                assert!(results[0].description_snippet.contains("<b>ownership</b>"));
+                assert_eq!(results[0].snippet, results[0].description_snippet);
+
+                Ok(())
+            },
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn test_docstore_compression_zstd() {
+        with_indexer(
+            "test_docstore_compression_zstd",
+            |config| config.docstore_compression = "zstd".to_string(),
+            |indexer| {
+                assert!(matches!(
+                    indexer.index().settings().docstore_compression,
+                    Compressor::Zstd(_)
+                ));
+
+                indexer.add_html(
+                    "https://example.com/rust",
+                    "<title>Rust</title><body>Rust ownership</body>",
+                    "example.com",
+                )?;
+                indexer.commit_and_reload()?;
+
+                let results = indexer.search("ownership", 10, &SearchOptions::default())?;
+                assert_eq!(results.len(), 1);
+                assert_eq!(results[0].title, "Rust");
+
+                Ok(())
+            },
+        )
+        .await;
+    }
+
+    #[test]
+    fn test_parse_docstore_compression() {
+        assert!(matches!(
+            parse_docstore_compression("lz4").unwrap(),
+            Compressor::Lz4
+        ));
+        assert!(matches!(
+            parse_docstore_compression("none").unwrap(),
+            Compressor::None
+        ));
+        assert!(matches!(
+            parse_docstore_compression("zstd(compression_level=5)").unwrap(),
+            Compressor::Zstd(_)
+        ));
+
+        let err = parse_docstore_compression("gzip").unwrap_err();
+        assert!(format!("{err:#}").contains("invalid docstore_compression 'gzip'"));
+    }
+
+    #[test]
+    fn test_word_boundary_before() {
+        let text = "Grüße aus München";
+        assert_eq!(word_boundary_before(text, 14), Some(12));
+        assert_eq!(&text[..word_boundary_before(text, 9).unwrap()], "Grüße ");
+        assert_eq!(word_boundary_before("Donaudampfschiff", 8), None);
+
+        // CJK characters can be split anywhere.
+        let text = "東京大学の研究";
+        assert_eq!(&text[..word_boundary_before(text, 7).unwrap()], "東京");
+    }
+
+    #[tokio::test]
+    async fn test_snippet_max_chars() {
+        with_indexer(
+            "test_snippet_max_chars",
+            |_config| {},
+            |indexer| {
+                // Both bodies are a single token (short enough to be indexed), so tantivy can't make a
+                // shorter fragment.
+                indexer.add_html(
+                    "https://example.com/de",
+                    "<title>Mütze</title><body>Schifffahrtsgesellschaftsmütze</body>",
+                    "example.com",
+                )?;
+                indexer.add_html(
+                    "https://example.com/ja",
+                    "<title>研究</title><body>東京大学の研究者が新しい論</body>",
+                    "example.com",
+                )?;
+                indexer.commit_and_reload()?;
+
+                let options = SearchOptions {
+                    snippet_max_chars: 12,
+                    ..Default::default()
+                };
+                let results = indexer.search("schifffahrtsgesellschaftsmütze", 10, &options)?;
+                assert_eq!(results[0].snippet, "<b>Schifffahrts</b>…");
+
+                let results = indexer.search("東京大学の研究者が新しい論", 10, &options)?;
+                assert_eq!(results[0].snippet, "<b>東京大学の研究者が新しい</b>…");
+                assert!(!results[0].snippet.contains(char::REPLACEMENT_CHARACTER));
+
+                // Short fragments are left alone.
+                let results =
+                    indexer.search("東京大学の研究者が新しい論", 10, &SearchOptions::default())?;
+                assert_eq!(results[0].snippet, "<b>東京大学の研究者が新しい論</b>");
+
+                Ok(())
+            },
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn test_snippet_fragments() {
+        with_indexer(
+            "test_snippet_fragments",
+            |_config| {},
+            |indexer| {
+                let filler = "lorem ipsum dolor sit amet ".repeat(20);
+                indexer.add_html(
+                    "https://example.com/rust",
+                    &format!(
+                        "<title>Rust</title><body>Ownership comes first. {filler} \
+                         Borrowing and ownership again. {filler} The end.</body>"
+                    ),
+                    "example.com",
+                )?;
+                indexer.commit_and_reload()?;
+
+                let options = SearchOptions {
+                    snippet_fragments: 3,
+                    ..Default::default()
+                };
+                let snippet = &indexer.search("ownership", 10, &options)?[0].snippet;
+                let fragments: Vec<_> = snippet.split(" … ").collect();
+                // Only two parts of the page match, so there are only two fragments.
+                assert_eq!(fragments.len(), 2, "{snippet}");
+                assert!(fragments[0].contains("<b>Ownership</b> comes first"));
+                assert!(fragments[1].contains("<b>ownership</b> again"));
+
+                let snippet =
+                    &indexer.search("ownership", 10, &SearchOptions::default())?[0].snippet;
+                assert!(!snippet.contains(" … "));
+
+                Ok(())
+            },
+        )
+        .await;
+    }
+
+    #[test]
+    fn test_display_url() {
+        let hidden = ["utm_*", "ref"].map(String::from);
+
+        assert_eq!(
+            display_url(
+                "https://example.com/a?utm_source=x&id=1&ref=feed#top",
+                &hidden
+            ),
+            "https://example.com/a?id=1#top"
+        );
+        assert_eq!(
+            display_url("https://example.com/a?utm_source=x&utm_medium=y", &hidden),
+            "https://example.com/a"
+        );
+        assert_eq!(
+            display_url("https://example.com/a?reference=1", &hidden),
+            "https://example.com/a?reference=1"
+        );
+        assert_eq!(
+            display_url("https://example.com/a", &hidden),
+            "https://example.com/a"
+        );
+        assert_eq!(
+            display_url("https://example.com/a?utm_source=x", &[]),
+            "https://example.com/a?utm_source=x"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_search_hides_url_params() {
+        with_indexer(
+            "test_search_hides_url_params",
+            |_config| {},
+            |indexer| {
+                indexer.add_html(
+                    "https://example.com/rust?id=1&utm_source=feed",
+                    "<title>Rust</title><body>Rust ownership</body>",
+                    "example.com",
+                )?;
+                indexer.commit_and_reload()?;
+
+                for include_snippet in [true, false] {
+                    let options = SearchOptions {
+                        include_snippet,
+                        hidden_url_params: vec!["utm_source".to_string()],
+                        ..Default::default()
+                    };
+                    let results = indexer.search("ownership", 10, &options)?;
+                    assert_eq!(results[0].url, "https://example.com/rust?id=1");
+                }
+
+                // The stored URL is untouched.
+                let results = indexer.search("ownership", 10, &SearchOptions::default())?;
+                assert_eq!(
+                    results[0].url,
+                    "https://example.com/rust?id=1&utm_source=feed"
+                );
+
+                Ok(())
+            },
+        )
+        .await;
+    }
+
+    #[test]
+    fn test_trim_title_suffix() {
+        let separators = ["|", "-", "—"].map(String::from);
+        let trim =
+            |title, domain, site_name| trim_title_suffix(title, &separators, domain, site_name);
+
+        assert_eq!(
+            trim("Article Title | Example", "example.com", None),
+            "Article Title"
+        );
+        assert_eq!(
+            trim("Article Title - Example.com", "www.example.com", None),
+            "Article Title"
+        );
+        assert_eq!(
+            trim("Article Title — Example", "blog.example.org", None),
+            "Article Title"
+        );
+        assert_eq!(
+            trim("Intro - Part 1 | Example", "example.com", None),
+            "Intro - Part 1"
+        );
+        assert_eq!(
+            trim(
+                "Array | MDN Web Docs",
+                "developer.mozilla.org",
+                Some("MDN Web Docs")
+            ),
+            "Array"
+        );
+        // Suffixes that aren't the site are kept.
+        assert_eq!(trim("Rust | Go", "example.com", None), "Rust | Go");
+        assert_eq!(
+            trim("Article Title | Example", "other.com", None),
+            "Article Title | Example"
+        );
+        // Separators must be standalone.
+        assert_eq!(trim("Self-Example", "example.com", None), "Self-Example");
+        assert_eq!(trim(" | Example", "example.com", None), " | Example");
+
+        assert_eq!(
+            trim_title_suffix("Article Title | Example", &[], "example.com", None),
+            "Article Title | Example"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_content_selectors() {
+        with_indexer(
+            "test_content_selectors",
+            |config| {
+                config.content_selectors =
+                    HashMap::from([("example.com".to_string(), "#content".to_string())]);
+            },
+            |indexer| {
+                let html = r#"<title>Rust Guide</title><body>
+                    <nav>Navigation</nav><div id="content">Ownership rules</div>
+                </body>"#;
+                indexer.add_html("https://example.com/guide", html, "example.com")?;
+                // Other domains and pages without a match use the whole body.
+                indexer.add_html("https://other.com/guide", html, "other.com")?;
+                indexer.add_html(
+                    "https://example.com/home",
+                    "<title>Home Page</title><body><nav>Navigation</nav></body>",
+                    "example.com",
+                )?;
+                indexer.commit_and_reload()?;
+
+                let search = |query: &str| -> anyhow::Result<Vec<String>> {
+                    let mut urls = indexer
+                        .search(query, 10, &SearchOptions::default())?
+                        .into_iter()
+                        .map(|result| result.url)
+                        .collect::<Vec<_>>();
+                    urls.sort();
+                    Ok(urls)
+                };
+                assert_eq!(
+                    search("ownership")?,
+                    ["https://example.com/guide", "https://other.com/guide"]
+                );
+                assert_eq!(
+                    search("navigation")?,
+                    ["https://example.com/home", "https://other.com/guide"]
+                );
+
+                Ok(())
+            },
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn test_invalid_content_selector() {
+        let mut config = Config::load_test("test_invalid_content_selector").indexer;
+        config.content_selectors = HashMap::from([("example.com".to_string(), "[".to_string())]);
+        let Err(e) = Indexer::new(&config).await else {
+            panic!("expected an error");
+        };
+        assert!(e.to_string().contains("example.com"));
+        // Nothing was created on disk.
+        assert!(!std::path::Path::new(&config.index_dir).exists());
+    }
+
+    #[tokio::test]
+    async fn test_filter_junk_tokens() {
+        let gibberish = "q7Zk".repeat(25);
+        let html = format!("<title>Junk</title><body>Ownership {gibberish} zxcvbnmlkjhgf</body>");
+
+        with_indexer(
+            "test_filter_junk_tokens",
+            |config| {
+                config.filter_junk_tokens = true;
+                config.max_token_length = 20;
+            },
+            |indexer| {
+                indexer.add_html("https://example.com/", &html, "example.com")?;
+                indexer.commit_and_reload()?;
+
+                let options = SearchOptions::default();
+                assert_eq!(indexer.search("ownership", 10, &options)?.len(), 1);
+
+                // The junk terms were never added to the dictionary.
+                let searcher = indexer.searcher();
+                let body_field = indexer.schema.get_field("body").unwrap();
+                for junk in [gibberish.to_lowercase().as_str(), "zxcvbnmlkjhgf"] {
+                    let term = Term::from_field_text(body_field, junk);
+                    assert_eq!(searcher.doc_freq(&term)?, 0);
+                }
+
+                Ok(())
+            },
+        )
+        .await;
+
+        with_indexer(
+            "test_filter_junk_tokens_disabled",
+            |_config| {},
+            |indexer| {
+                indexer.add_html("https://example.com/", &html, "example.com")?;
+                indexer.commit_and_reload()?;
+
+                let searcher = indexer.searcher();
+                let body_field = indexer.schema.get_field("body").unwrap();
+                let term = Term::from_field_text(body_field, "zxcvbnmlkjhgf");
+                assert_eq!(searcher.doc_freq(&term)?, 1);
+
+                Ok(())
+            },
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn test_h1_fallback_title() {
+        with_indexer(
+            "test_h1_fallback_title",
+            |_config| {},
+            |indexer| {
+                let pages = [
+                    (
+                        "https://example.com/empty",
+                        "<title></title><body><h1>Understanding <em>Ownership</em></h1>Text</body>",
+                    ),
+                    (
+                        "https://example.com/short",
+                        "<title>Home</title><body><h1>Welcome to the Example Blog</h1>Text</body>",
+                    ),
+                    (
+                        "https://example.com/adequate",
+                        "<title>Borrowing in Rust</title><body><h1>Borrowing</h1>Text</body>",
+                    ),
+                    (
+                        "https://example.com/no-h1",
+                        "<title>Home</title><body>Text</body>",
+                    ),
+                ];
+                for (url, html) in pages {
+                    indexer.add_html(url, html, "example.com")?;
+                }
+                indexer.commit_and_reload()?;
+
+                let mut titles: Vec<_> = indexer
+                    .search("text", 10, &SearchOptions::default())?
+                    .into_iter()
+                    .map(|result| result.title)
+                    .collect();
+                titles.sort();
+                assert_eq!(
+                    titles,
+                    [
+                        "Borrowing in Rust",
+                        "Home",
+                        "Understanding Ownership",
+                        "Welcome to the Example Blog",
+                    ]
+                );
+
+                Ok(())
+            },
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn test_domain_stats_size_format() {
+        with_indexer(
+            "test_domain_stats_size_format",
+            |_config| {},
+            |indexer| {
+                let body = "a".repeat(2000);
+                indexer.add_html(
+                    "https://example.com/",
+                    &format!("<title>A</title><body>{body}</body>"),
+                    "example.com",
+                )?;
+
+                let stats = indexer.get_domain_stats(SizeFormat::Decimal)?;
+                assert_eq!(stats[0].total_size, "2 kB");
+                let stats = indexer.get_domain_stats(SizeFormat::Binary)?;
+                assert_eq!(stats[0].total_size, "1.95 KiB");
+                assert_eq!(stats[0].max_page_size, "1.95 KiB");
+
+                Ok(())
+            },
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn test_legacy_domain_stats() {
+        with_indexer(
+            "test_legacy_domain_stats",
+            |_config| {},
+            |indexer| {
+                let legacy = LegacyRawDomainStats {
+                    page_count: 2,
+                    total_size: 300,
+                    min_size: 100,
+                    max_size: 200,
+                    min_url: "https://example.com/small".to_string(),
+                    max_url: "https://example.com/large".to_string(),
+                };
+                indexer
+                    .stats_db
+                    .insert("domain:example.com", bincode::serialize(&legacy)?)?;
+
+                let stats = indexer.get_domain_stats(SizeFormat::Decimal)?;
+                assert_eq!(stats[0].page_count, 2);
+                assert_eq!(stats[0].last_updated, 0);
+                assert_eq!(stats[0].max_page_url, "https://example.com/large");
+
+                // New pages add to the old counts, which are then stored in the current layout.
+                indexer.add_html(
+                    "https://example.com/new",
+                    "<title>New</title><body>New page</body>",
+                    "example.com",
+                )?;
+                indexer.flush_domain_stats()?;
+                let stats = indexer.get_domain_stats(SizeFormat::Decimal)?;
+                assert_eq!(stats[0].page_count, 3);
+                assert!(stats[0].last_updated > 0);
+                assert_eq!(stats[0].max_page_url, "https://example.com/large");
+
+                Ok(())
+            },
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn test_click_limits() {
+        with_indexer(
+            "test_click_limits",
+            |_config| {},
+            |indexer| {
+                let url = "https://example.com/";
+                assert!(!indexer.record_click(&"a".repeat(MAX_CLICK_QUERY_LEN + 1), url, 1)?);
+                assert!(indexer.record_click("rust", url, 1)?);
+
+                // Once full, only queries that are already recorded get more clicks.
+                let mut batch = sled::Batch::default();
+                for i in 1..MAX_CLICK_QUERIES {
+                    batch.insert(
+                        format!("query {i}").as_bytes(),
+                        bincode::serialize(&RawClickStats::default())?,
+                    );
+                }
+                indexer.stats_db.open_tree("clicks")?.apply_batch(batch)?;
+                assert!(!indexer.record_click("go", url, 1)?);
+                assert!(indexer.record_click("rust", url, 3)?);
+
+                let stats = indexer.get_click_stats()?;
+                assert_eq!(stats.len(), MAX_CLICK_QUERIES);
+                assert_eq!(stats[0].query, "rust");
+                assert_eq!(stats[0].results[0].average_position, 2.0);
+
+                Ok(())
+            },
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn test_warmup() {
+        with_indexer(
+            "test_warmup",
+            |_config| {},
+            |indexer| {
+                // An empty index.
+                indexer.warmup()?;
+
+                indexer.add_html(
+                    "https://example.com/rust",
+                    "<title>Rust</title><body>The Rust language</body>",
+                    "example.com",
+                )?;
+                indexer.commit_and_reload()?;
+                indexer.warmup()?;
+
+                Ok(())
+            },
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn test_failing_stats_write() {
+        with_indexer(
+            "test_failing_stats_write",
+            |_config| {},
+            |indexer| {
+                indexer.fail_stats_writes.store(true, Ordering::Relaxed);
+
+                indexer.add_html(
+                    "https://example.com/rust",
+                    "<title>Rust</title><body>Rust ownership</body>",
+                    "example.com",
+                )?;
+                indexer.commit_and_reload()?;
+
+                let results = indexer.search("rust", 10, &SearchOptions::default())?;
+                assert_eq!(results.len(), 1);
+                assert!(indexer.get_domain_stats(SizeFormat::Decimal)?.is_empty());
+
+                Ok(())
+            },
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn test_schema_changed() {
+        let mut config = Config::load_test("test_schema_changed").indexer;
+        drop(Indexer::new(&config).await.unwrap());
+
+        // Reopening with the same schema works.
+        config.new_index = false;
+        drop(Indexer::new(&config).await.unwrap());
+
+        config.store_body = !config.store_body;
+        let err = Indexer::new(&config)
+            .await
+            .err()
+            .expect("the schema changed");
+        let err = format!("{err:#}");
+        assert!(err.contains("changed fields: body"), "{err}");
+        assert!(err.contains("new_index: true"), "{err}");
+
+        // Clean up after test.
+        config.new_index = true;
+        Indexer::new(&config).await.unwrap().delete().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_broken_stats_db() {
+        let mut config = Config::load_test("test_broken_stats_db").indexer;
+        drop(Indexer::new(&config).await.unwrap());
+
+        // Simulate a DB that sled can't open.
+        tokio::fs::remove_dir_all(&config.db_dir).await.unwrap();
+        tokio::fs::write(&config.db_dir, "garbage").await.unwrap();
+        config.new_index = false;
+
+        let err = Indexer::new(&config)
+            .await
+            .err()
+            .expect("the broken DB can't be opened");
+        assert!(format!("{err:#}").contains("recover_stats_db"));
+
+        config.recover_stats_db = true;
+        let indexer = Indexer::new(&config).await.unwrap();
+        assert!(indexer
+            .get_domain_stats(SizeFormat::Decimal)
+            .unwrap()
+            .is_empty());
+
+        // Clean up after test.
+        indexer.delete().await.unwrap();
+        let parent = std::path::Path::new(&config.db_dir).parent().unwrap();
+        let mut entries = tokio::fs::read_dir(parent).await.unwrap();
+        while let Some(entry) = entries.next_entry().await.unwrap() {
+            if entry
+                .file_name()
+                .to_string_lossy()
+                .starts_with("db_test_broken_stats_db.db.broken-")
+            {
+                tokio::fs::remove_file(entry.path()).await.unwrap();
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_batched_domain_stats() {
+        let pages = [
+            ("https://a.com/1", "a.com", 30),
+            ("https://a.com/2", "a.com", 10),
+            ("https://b.com/1", "b.com", 20),
+            ("https://a.com/3", "a.com", 10),
+            ("https://a.com/4", "a.com", 50),
+        ];
+
+        let mut per_page_stats: HashMap<&str, RawDomainStats> = HashMap::new();
+        for (url, domain, size) in pages {
+            per_page_stats
+                .entry(domain)
+                .or_default()
+                .add_page(url, size, 0);
+        }
+
+        // Merging batches gives the same stats as adding the pages one by one.
+        let mut batched_stats = RawDomainStats::default();
+        let mut batch = RawDomainStats::default();
+        for (url, _domain, size) in pages.iter().filter(|(_, domain, _)| *domain == "a.com") {
+            batch.add_page(url, *size, 0);
+            if batch.page_count == 2 {
+                batched_stats.merge(&std::mem::take(&mut batch));
+            }
+        }
+        batched_stats.merge(&batch);
+        assert_eq!(batched_stats, per_page_stats["a.com"]);
+        assert_eq!(batched_stats.min_url, "https://a.com/2");
+
+        with_indexer(
+            "test_batched_domain_stats",
+            |config| config.batch_domain_stats = true,
+            |indexer| {
+                for (url, domain, _size) in pages {
+                    indexer.add_html(url, "<title>Page</title><body>Text</body>", domain)?;
+                }
+                assert!(indexer.get_domain_stats(SizeFormat::Decimal)?.is_empty());
+
+                indexer.commit_and_reload()?;
+                let stats = indexer.get_domain_stats(SizeFormat::Decimal)?;
+                assert_eq!(stats.len(), 2);
+                assert_eq!(stats[0].page_count, 4);
+                assert_eq!(stats[1].page_count, 1);
+
+                // As on shutdown, the page is committed along with its stats.
+                indexer.add_html(
+                    "https://b.com/2",
+                    "<title>Page</title><body>Text</body>",
+                    "b.com",
+                )?;
+                indexer.commit_and_flush()?;
+                assert_eq!(
+                    indexer.get_domain_stats(SizeFormat::Decimal)?[1].page_count,
+                    2
+                );
+                indexer.reload()?;
+                assert_eq!(
+                    indexer.search("text", 10, &SearchOptions::default())?.len(),
+                    6
+                );
+
+                Ok(())
+            },
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn test_index_allowlist() {
+        with_indexer(
+            "test_index_allowlist",
+            |config| config.index_allowlist = vec!["rust-lang.org".to_string()],
+            |indexer| {
+                let html = "<title>Ownership</title><body>Rust ownership</body>";
+                indexer.add_html("https://rust-lang.org/learn", html, "rust-lang.org")?;
+                indexer.add_html("https://doc.rust-lang.org/book", html, "doc.rust-lang.org")?;
+                indexer.add_html("https://example.com/rust", html, "example.com")?;
+                indexer.add_html("https://notrust-lang.org/rust", html, "notrust-lang.org")?;
+                indexer.commit_and_reload()?;
+
+                let results = indexer.search("ownership", 10, &SearchOptions::default())?;
+                let mut urls: Vec<_> = results.iter().map(|result| result.url.as_str()).collect();
+                urls.sort();
+                assert_eq!(
+                    urls,
+                    [
+                        "https://doc.rust-lang.org/book",
+                        "https://rust-lang.org/learn"
+                    ]
+                );
+
+                Ok(())
+            },
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn test_commit_crawl() {
+        let config = Config::load_test("test_commit_crawl").indexer;
+        let indexer = Arc::new(Indexer::new(&config).await.unwrap());
+
+        // Stands in for the page receiver, which is still working through the crawled pages
+        // when the crawl completes.
+        let receiver = tokio::spawn({
+            let indexer = indexer.clone();
+            async move {
+                for page in ["ownership", "borrowing"] {
+                    tokio::time::sleep(Duration::from_millis(20)).await;
+                    indexer
+                        .add_html(
+                            &format!("https://example.com/{page}"),
+                            &format!("<title>Rust {page}</title><body>Rust {page}</body>"),
+                            "example.com",
+                        )
+                        .unwrap();
+                    indexer.finish_page();
+                }
+            }
+        });
+
+        tokio::time::timeout(Duration::from_secs(5), indexer.commit_crawl(2))
+            .await
+            .unwrap()
+            .unwrap();
+        // Searchable right away, without another commit.
+        let results = indexer
+            .search("rust", 10, &SearchOptions::default())
+            .unwrap();
+        assert_eq!(results.len(), 2);
+
+        receiver.await.unwrap();
+        // Clean up after test.
+        indexer.delete().await.unwrap();
+    }
+
+    #[cfg(feature = "pdf")]
+    #[tokio::test]
+    async fn test_add_pdf() {
+        with_indexer(
+            "test_add_pdf",
+            |config| config.index_pdfs = true,
+            |indexer| {
+                let bytes = include_bytes!("../../test_files/sample.pdf");
+                assert!(is_pdf(bytes));
+
+                indexer.add_pdf("https://example.com/guide.pdf", bytes, "example.com")?;
+                indexer.commit_and_reload()?;
+
+                let results = indexer.search("borrowing", 10, &SearchOptions::default())?;
+                assert_eq!(results.len(), 1);
+                assert_eq!(results[0].title, "The Rust PDF Guide");
+                assert_eq!(results[0].url, "https://example.com/guide.pdf");
+
+                Ok(())
+            },
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn test_decode_html() {
+        with_indexer(
+            "test_decode_html",
+            |_config| {},
+            |indexer| {
+                let bytes = include_bytes!("../../test_files/latin1.html");
+                // "é" in Latin-1, which isn't valid UTF-8.
+                assert!(bytes.contains(&0xE9));
+
+                // The `<meta charset>` is used without a `Content-Type` charset.
+                let html = decode_html(bytes, None);
+                assert!(html.contains("<title>Café recipes</title>"));
+                assert!(html.contains("Crème brûlée is a French dessert, served à la carte."));
+                assert_eq!(decode_html(bytes, Some("text/html")), html);
+                // The header takes precedence.
+                assert!(decode_html(bytes, Some("text/html; charset=\"UTF-8\""))
+                    .contains("Caf\u{FFFD} recipes"));
+                assert_eq!(
+                    decode_html("Caf\u{e9}".as_bytes(), Some("text/html; charset=unknown")),
+                    "Café"
+                );
+
+                indexer.add_html("https://example.com/", &html, "example.com")?;
+                indexer.commit_and_reload()?;
+
+                let results = indexer.search("brûlée", 10, &SearchOptions::default())?;
+                assert_eq!(results.len(), 1);
+                assert_eq!(results[0].title, "Café recipes");
+
+                Ok(())
+            },
+        )
+        .await;
+    }
+
+    #[test]
+    fn test_charset_label() {
+        assert_eq!(
+            charset_label("text/html; charset=ISO-8859-1").as_deref(),
+            Some("iso-8859-1")
+        );
+        assert_eq!(
+            charset_label(
+                r#" http-equiv="Content-Type" content="text/html; charset=windows-1251""#
+            )
+            .as_deref(),
+            Some("windows-1251")
+        );
+        assert_eq!(
+            charset_label(r#" charset = 'utf-8'"#).as_deref(),
+            Some("utf-8")
+        );
+        assert_eq!(charset_label("text/html"), None);
+        assert_eq!(charset_label("charset="), None);
+    }
+
+    #[tokio::test]
+    async fn test_domain_counts() {
+        with_indexer(
+            "test_domain_counts",
+            |_config| {},
+            |indexer| {
+                for (domain, pages) in [("a.com", 3), ("b.com", 2), ("c.com", 1)] {
+                    for page in 0..pages {
+                        indexer.add_html(
+                            &format!("https://{domain}/{page}"),
+                            "<title>Rust</title><body>Rust ownership</body>",
+                            domain,
+                        )?;
+                    }
+                }
+                indexer.add_html(
+                    "https://d.com/",
+                    "<title>Go</title><body>Go channels</body>",
+                    "d.com",
+                )?;
+                indexer.commit_and_reload()?;
+
+                let options = SearchOptions::default();
+                let counts = indexer.domain_counts("rust", 10, &options)?;
+                assert_eq!(
+                    counts,
+                    [("a.com", 3), ("b.com", 2), ("c.com", 1)]
+                        .into_iter()
+                        .map(|(domain, count)| DomainCount {
+                            domain: domain.to_string(),
+                            count,
+                        })
+                        .collect::<Vec<_>>()
+                );
+                // The counts match the distribution of the results.
+                let results = indexer.search("rust", 10, &options)?;
+                for count in &counts {
+                    let in_results = results
+                        .iter()
+                        .filter(|result| result.url.contains(&count.domain))
+                        .count();
+                    assert_eq!(in_results as u64, count.count);
+                }
+
+                // Bounded to the top domains.
+                let counts = indexer.domain_counts("rust", 2, &options)?;
+                assert_eq!(counts.len(), 2);
+                assert_eq!(counts[1].domain, "b.com");
+
+                // `site:` restricts results to a domain.
+                let results = indexer.search("rust site:b.com", 10, &options)?;
+                assert_eq!(results.len(), 2);
+                assert!(results
+                    .iter()
+                    .all(|result| result.url.starts_with("https://b.com/")));
+                let counts = indexer.domain_counts("rust site:b.com", 10, &options)?;
+                assert_eq!(counts.len(), 1);
+
+                Ok(())
+            },
+        )
+        .await;
+    }
+
+    #[cfg(feature = "pdf")]
+    #[tokio::test]
+    async fn test_doc_type_filter() {
+        with_indexer(
+            "test_doc_type_filter",
+            |config| config.index_pdfs = true,
+            |indexer| {
+                let bytes = include_bytes!("../../test_files/sample.pdf");
+                indexer.add_pdf("https://example.com/guide.pdf", bytes, "example.com")?;
+                indexer.add_html(
+                    "https://example.com/guide",
+                    "<title>Rust Guide</title><body>Rust ownership and borrowing</body>",
+                    "example.com",
+                )?;
+                indexer.commit_and_reload()?;
+
+                assert_eq!(
+                    indexer
+                        .search("borrowing", 10, &SearchOptions::default())?
+                        .len(),
+                    2
+                );
+
+                let results =
+                    indexer.search("borrowing type:pdf", 10, &SearchOptions::default())?;
+                assert_eq!(results.len(), 1);
+                assert_eq!(results[0].url, "https://example.com/guide.pdf");
+
+                let results =
+                    indexer.search("type:HTML borrowing", 10, &SearchOptions::default())?;
+                assert_eq!(results.len(), 1);
+                assert_eq!(results[0].url, "https://example.com/guide");
+
+                // Filters alone list all documents of the type.
+                let results = indexer.search("type:pdf", 10, &SearchOptions::default())?;
+                assert_eq!(results.len(), 1);
+                assert_eq!(results[0].url, "https://example.com/guide.pdf");
+
+                Ok(())
+            },
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn test_no_terms_behavior() {
+        with_indexer(
+            "test_no_terms_behavior",
+            |_config| {},
+            |indexer| {
+                indexer.add_html(
+                    "https://example.com/rust",
+                    "<title>Rust</title><body>Rust ownership</body>",
+                    "example.com",
+                )?;
+                indexer.commit_and_reload()?;
+
+                let Err(e) = indexer.search("!!! ?? ...", 10, &SearchOptions::default()) else {
+                    panic!("expected an error");
+                };
+                assert!(e.downcast_ref::<InvalidQuery>().is_some());
+                assert_eq!(
+                    indexer.search("rust", 10, &SearchOptions::default())?.len(),
+                    1
+                );
+
+                Ok(())
+            },
+        )
+        .await;
+
+        with_indexer(
+            "test_no_terms_behavior_match_all",
+            |config| config.no_terms_behavior = NoTermsBehavior::MatchAll,
+            |indexer| {
+                indexer.add_html(
+                    "https://example.com/rust",
+                    "<title>Rust</title><body>Rust ownership</body>",
+                    "example.com",
+                )?;
+                indexer.commit_and_reload()?;
+
+                let results = indexer.search("!!! ?? ...", 10, &SearchOptions::default())?;
+                assert_eq!(results.len(), 1);
+                assert!(indexer
+                    .search("golang", 10, &SearchOptions::default())?
+                    .is_empty());
+
+                Ok(())
+            },
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn test_prefix_query() {
+        with_indexer(
+            "test_prefix_query",
+            // Wildcard stripping doesn't affect prefix terms.
+            |config| config.strip_query_wildcards = true,
+            |indexer| {
+                for (path, word) in [
+                    ("program", "program"),
+                    ("programming", "programming"),
+                    ("programmer", "programmers"),
+                    ("progress", "progress"),
+                    ("project", "project"),
+                ] {
+                    indexer.add_html(
+                        &format!("https://example.com/{path}"),
+                        &format!("<title>Page</title><body>About {word}</body>"),
+                        "example.com",
+                    )?;
+                }
+                indexer.commit_and_reload()?;
+
+                let search = |query: &str| -> anyhow::Result<Vec<String>> {
+                    let mut urls = indexer
+                        .search(query, 10, &SearchOptions::default())?
+                        .into_iter()
+                        .map(|result| result.url)
+                        .collect::<Vec<_>>();
+                    urls.sort();
+                    Ok(urls)
+                };
+
+                // Prefixes match stems: "programming" is indexed as "program".
+                assert_eq!(search("programm*")?, ["https://example.com/programmer"]);
+                // The stem of the prefix matches stemmed forms shorter than the prefix.
+                assert_eq!(
+                    search("Programming*")?,
+                    [
+                        "https://example.com/program",
+                        "https://example.com/programmer",
+                        "https://example.com/programming",
+                    ]
+                );
+                assert_eq!(search("prog*")?.len(), 4);
+                // Prefixes combine with other terms, and quoted `*` isn't a prefix.
+                assert_eq!(search("proj* progress")?.len(), 2);
+                assert!(search("\"prog*\"")?.is_empty());
+                // Excluded words still exclude pages matching a prefix.
+                assert_eq!(search("prog* -programmers")?.len(), 3);
+                // Too short to be a prefix, so it's searched as a plain word.
+                assert!(search("pr*")?.is_empty());
+
+                Ok(())
+            },
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn test_vocabulary() {
+        with_indexer(
+            "test_vocabulary",
+            |config| config.num_shards = 2,
+            |indexer| {
+                for (url, domain, body) in [
+                    ("https://docs.rs/", "docs.rs", "Rust docs"),
+                    ("https://tokio.rs/", "tokio.rs", "Rust async runtime"),
+                    ("https://example.com/", "example.com", "Rust and Go"),
+                ] {
+                    indexer.add_html(url, &format!("<body>{body}</body>"), domain)?;
+                    // Separate segments, to be merged.
+                    indexer.commit_and_reload()?;
+                }
+
+                let vocabulary = |field_name, min_freq| {
+                    let mut terms = Vec::new();
+                    indexer.vocabulary(field_name, min_freq, |term, doc_freq| {
+                        terms.push((term.to_string(), doc_freq));
+                        Ok(())
+                    })?;
+                    anyhow::Ok(terms)
+                };
+                let terms = vocabulary("body", 1)?;
+                assert!(terms.windows(2).all(|pair| pair[0].0 < pair[1].0));
+                assert!(terms.contains(&("rust".to_string(), 3)));
+                assert!(terms.contains(&("async".to_string(), 1)));
+                assert_eq!(vocabulary("body", 2)?, [("rust".to_string(), 3)]);
+
+                let error = vocabulary("raw_html", 1).unwrap_err();
+                assert!(error.downcast_ref::<InvalidQuery>().is_some());
+                Ok(())
+            },
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn test_related_searches() {
+        with_indexer(
+            "test_related_searches",
+            |_config| {},
+            |indexer| {
+                let pages = [
+                    (
+                        "rust1",
+                        "Rust Ownership",
+                        "Rust ownership and borrowing rules",
+                    ),
+                    ("rust2", "Rust Lifetimes", "Lifetimes and ownership in Rust"),
+                    (
+                        "rust3",
+                        "Rust Traits",
+                        "Traits and ownership of Rust values",
+                    ),
+                    (
+                        "python",
+                        "Python Memory",
+                        "Python reference counting and values",
+                    ),
+                    ("go", "Go Memory", "Go garbage collection and values"),
+                    ("js", "JavaScript Closures", "Closures capture variables"),
+                    ("css", "CSS Grid", "Grid layout of pages"),
+                    ("sql", "SQL Joins", "Joins combine tables"),
+                    ("docker", "Docker Images", "Images and containers"),
+                ];
+                for (path, title, body) in pages {
+                    indexer.add_html(
+                        &format!("https://example.com/{path}"),
+                        &format!("<title>{title}</title><body>{body}</body>"),
+                        "example.com",
+                    )?;
+                }
+                indexer.commit_and_reload()?;
+
+                let options = SearchOptions::default();
+                let related = indexer.related_searches("rust", 3, &options)?;
+                // Common to all Rust pages, and rare elsewhere.
+                assert_eq!(related.first().map(String::as_str), Some("rust ownership"));
+                assert!(related.len() <= 3);
+                // The query itself and words common to the whole index are left out.
+                assert!(!related.iter().any(|query| query.ends_with(" rust")));
+                assert!(!related.contains(&"rust and".to_string()));
+
+                assert!(indexer.related_searches("kotlin", 3, &options)?.is_empty());
+                assert!(indexer.related_searches("rust", 0, &options)?.is_empty());
+
+                Ok(())
+            },
+        )
+        .await;
+    }
+
+    #[test]
+    fn test_extract_filters() {
+        assert_eq!(
+            extract_filters("rust type:pdf  async type:", "type"),
+            ("rust async type:".to_string(), vec!["pdf".to_string()])
+        );
+        assert_eq!(
+            extract_filters("rust site:Example.com types:pdf", "site"),
+            (
+                "rust types:pdf".to_string(),
+                vec!["example.com".to_string()]
+            )
+        );
+        assert_eq!(
+            extract_filters("rust", "type"),
+            ("rust".to_string(), vec![])
+        );
+    }
+
+    #[test]
+    fn test_extract_urls_and_emails() {
+        assert_eq!(
+            extract_urls_and_emails(
+                "rust (https://Example.com/docs), www.rust-lang.org Ferris@Rust-Lang.org a@b www.x"
+            ),
+            (
+                "rust a@b www.x".to_string(),
+                vec![
+                    "https://Example.com/docs".to_string(),
+                    "www.rust-lang.org".to_string()
+                ],
+                vec!["ferris@rust-lang.org".to_string()]
+            )
+        );
+        assert_eq!(
+            extract_urls_and_emails("rust"),
+            ("rust".to_string(), vec![], vec![])
+        );
+    }
+
+    #[test]
+    fn test_url_variants() {
+        let variants = url_variants("HTTP://WWW.Example.com/Docs/#intro");
+        assert_eq!(variants.len(), 8);
+        assert_eq!(variants[0], "https://example.com/Docs");
+        assert!(variants.contains(&"http://www.example.com/Docs/".to_string()));
+        assert!(url_variants("example.com").contains(&"https://example.com/".to_string()));
     }
-    terms
-}
 
-/// Applies boosting to tech terms in the query
-fn boost_tech_terms(query_str: &str, tech_term_boost: f32) -> String {
-    let terms = split_query_terms(query_str);
+    #[tokio::test]
+    async fn test_exact_url_queries() {
+        with_indexer(
+            "test_exact_url_queries",
+            |config| config.exact_url_queries = true,
+            |indexer| {
+                indexer.add_html(
+                    "https://www.example.com/docs/ownership/",
+                    "<title>Ownership</title><body>Contact ferris@rust-lang.org</body>",
+                    "example.com",
+                )?;
+                // Has all the words of the URL and the email, but not the URL itself.
+                indexer.add_html(
+                    "https://example.org/",
+                    "<title>HTTPS</title><body>www example com docs ownership \
+                     org lang rust ferris https http</body>",
+                    "example.org",
+                )?;
+                indexer.commit_and_reload()?;
 
-    terms
-        .into_iter()
-        .map(|term| {
-            if !term.contains('"')
-                && TECH_TERMS_TO_BOOST
-                    .iter()
-                    .any(|tech| tech.eq_ignore_ascii_case(&term))
-            {
-                format!("{}^{}", term, tech_term_boost)
-            } else {
-                term
-            }
-        })
-        .collect::<Vec<_>>()
-        .join(" ")
-}
+                let urls = |query| -> anyhow::Result<Vec<String>> {
+                    let results = indexer.search(query, 10, &SearchOptions::default())?;
+                    Ok(results.into_iter().map(|result| result.url).collect())
+                };
+                for query in [
+                    "https://www.example.com/docs/ownership/",
+                    "http://example.com/docs/ownership",
+                    "www.example.com/docs/ownership#borrowing",
+                ] {
+                    assert_eq!(
+                        urls(query)?,
+                        ["https://www.example.com/docs/ownership/"],
+                        "{query}"
+                    );
+                }
+                assert_eq!(
+                    urls("ownership https://www.example.com/docs/ownership")?[0],
+                    "https://www.example.com/docs/ownership/"
+                );
+                assert_eq!(
+                    urls("ferris@rust-lang.org")?,
+                    ["https://www.example.com/docs/ownership/"]
+                );
+                assert!(urls("https://example.com/docs/borrowing")?.is_empty());
+                Ok(())
+            },
+        )
+        .await;
+    }
 
-#[derive(Serialize, Deserialize)]
-struct RawDomainStats {
-    page_count: u64,
-    total_size: u64,
-    min_size: u64,
-    max_size: u64,
-    min_url: String,
-    max_url: String,
-}
+    #[test]
+    fn test_extract_prefix_terms() {
+        assert_eq!(
+            extract_prefix_terms("Prog* rust \"async fn*\" ab* c++* *"),
+            (
+                "rust \"async fn*\" ab c++* *".to_string(),
+                vec!["prog".to_string()]
+            )
+        );
+        assert_eq!(extract_prefix_terms("rust"), ("rust".to_string(), vec![]));
+    }
 
-impl Default for RawDomainStats {
-    fn default() -> Self {
-        Self {
-            page_count: Default::default(),
-            total_size: Default::default(),
-            min_size: u64::MAX,
-            max_size: Default::default(),
-            min_url: Default::default(),
-            max_url: Default::default(),
-        }
+    #[test]
+    fn test_expand_synonyms() {
+        let synonyms = parse_synonyms(
+            "# Comment\nk8s: kubernetes\n\nJS: javascript, ecmascript\nvs: visual studio\n",
+        );
+        assert_eq!(synonyms.len(), 3);
+
+        assert_eq!(
+            expand_synonyms("k8s pods", &synonyms, 0.5),
+            "(k8s kubernetes^0.5) pods"
+        );
+        // Lookup is case-insensitive and keeps existing boosts.
+        assert_eq!(
+            expand_synonyms("js^1.5", &synonyms, 0.5),
+            "(js^1.5 javascript^0.5 ecmascript^0.5)"
+        );
+        // Multi-word alternatives become phrases.
+        assert_eq!(
+            expand_synonyms("vs", &synonyms, 0.5),
+            "(vs \"visual studio\"^0.5)"
+        );
+        // Quoted phrases are untouched.
+        assert_eq!(
+            expand_synonyms("\"k8s\" docs", &synonyms, 0.5),
+            "\"k8s\" docs"
+        );
     }
-}
 
-/// Domain stats with human-readable values.
-#[derive(Serialize)]
-pub struct DomainStats {
-    pub domain: String,
-    pub page_count: u64,
-    pub total_size: String,
-    pub min_page_size: String,
-    pub max_page_size: String,
-    pub min_page_url: String,
-    pub max_page_url: String,
-}
+    #[tokio::test]
+    async fn test_synonyms() {
+        let synonyms_file = format!("{TEST_DIR}/synonyms_test_synonyms");
 
-/// The result of a web search.
-#[derive(Serialize)]
-pub struct SearchResult {
-    pub title: String,
-    pub url: String,
-    /// A relevant snippet from the page.
-    pub snippet: String,
-}
+        with_indexer(
+            "test_synonyms",
+            |config| {
+                std::fs::create_dir_all(TEST_DIR).unwrap();
+                std::fs::write(&synonyms_file, "k8s: kubernetes\n").unwrap();
+                config.synonyms_file = Some(synonyms_file.clone());
+            },
+            |indexer| {
+                indexer.add_html(
+                    "https://example.com/kubernetes",
+                    "<title>Pods</title><body>Deploying pods with Kubernetes</body>",
+                    "example.com",
+                )?;
+                indexer.commit_and_reload()?;
 
-pub struct SearchPage {
-    pub page: Page,
-    pub domain: String,
-}
+                let results = indexer.search("k8s", 10, &SearchOptions::default())?;
+                assert_eq!(results.len(), 1);
+                assert_eq!(results[0].url, "https://example.com/kubernetes");
 
-pub async fn start(
-    config: &IndexerConfig,
-) -> anyhow::Result<(Arc<Indexer>, mpsc::Sender<SearchPage>)> {
-    let indexer = Arc::new(Indexer::new(config).await?);
-    let add_page_indexer = indexer.clone();
-    let commit_indexer = indexer.clone();
+                Ok(())
+            },
+        )
+        .await;
 
-    let (tx, mut rx) = mpsc::channel(1000);
+        std::fs::remove_file(&synonyms_file).unwrap();
+    }
 
-    tokio::task::spawn(async move {
-        while let Some(page) = rx.recv().await {
-            if let Err(e) = add_page_indexer.add_page(&page) {
-                let url = page.page.get_url();
-                eprintln!("ERROR: could not index page '{url}': {e}");
-            }
-        }
-    });
+    #[tokio::test]
+    async fn test_multi_field_boost() {
+        with_indexer(
+            "test_multi_field_boost",
+            |_| {},
+            |indexer| {
+                // Every field contains each of its words once. "borrowing" is the rarer term, so
+                // on BM25 alone the page matching it in the title wins.
+                indexer.add_html(
+                    "https://example.com/both",
+                    "<title>Ownership</title><body>Ownership</body>",
+                    "example.com",
+                )?;
+                indexer.add_html(
+                    "https://example.com/body",
+                    "<title>Borrowing</title><body>Ownership</body>",
+                    "example.com",
+                )?;
+                indexer.add_html(
+                    "https://example.com/title",
+                    "<title>Ownership</title><body>Lifetimes</body>",
+                    "example.com",
+                )?;
+                indexer.commit_and_reload()?;
 
-    // Periodically commit.
-    // NOTE: Committing can block, and is also non-async, so we use a dedicated thread.
-    let commit_interval_ms = config.commit_interval_ms;
-    std::thread::spawn(move || loop {
-        std::thread::sleep(Duration::from_millis(commit_interval_ms));
+                let query = "ownership borrowing";
+                let results = indexer.search(query, 10, &SearchOptions::default())?;
+                assert_eq!(results[0].url, "https://example.com/body");
 
-        // Skip if there's nothing to commit.
-        if !commit_indexer.is_dirty.load(Ordering::Relaxed) {
-            continue;
-        }
+                let mut config = (*indexer.config()).clone();
+                config.multi_field_boost = 3.0;
+                indexer.set_config(config);
+                let results = indexer.search(query, 10, &SearchOptions::default())?;
+                assert_eq!(results[0].url, "https://example.com/both");
 
-        let mut index_writer_wlock = commit_indexer.index_writer.write().unwrap();
-        if let Err(e) = index_writer_wlock.commit() {
-            eprintln!("ERROR: could not commit index: {e}");
-        } else {
-            commit_indexer.is_dirty.store(false, Ordering::Relaxed);
-        }
-    });
+                // Excluded words are neither boosted nor bypassed.
+                let results =
+                    indexer.search("lifetimes -ownership", 10, &SearchOptions::default())?;
+                assert!(results.is_empty());
 
-    Ok((indexer, tx))
-}
+                Ok(())
+            },
+        )
+        .await;
+    }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use scraper::Html;
+    #[tokio::test]
+    async fn test_expand_tech_terms() {
+        with_indexer(
+            "test_expand_tech_terms",
+            |config| config.expand_tech_terms = true,
+            |indexer| {
+                indexer.add_html(
+                    "https://example.com/streams",
+                    "<title>Streams in Node.js</title><body>Reading files in node</body>",
+                    "example.com",
+                )?;
+                indexer.commit_and_reload()?;
 
-    const TECH_TERM_BOOST: f32 = 1.5;
+                let results = indexer.search("nodejs", 10, &SearchOptions::default())?;
+                assert_eq!(results.len(), 1);
+                assert_eq!(results[0].url, "https://example.com/streams");
+
+                let mut config = (*indexer.config()).clone();
+                config.expand_tech_terms = false;
+                indexer.set_config(config);
+                let results = indexer.search("nodejs", 10, &SearchOptions::default())?;
+                assert!(results.is_empty());
+
+                Ok(())
+            },
+        )
+        .await;
+    }
 
     #[test]
     fn test_split_query_terms() {
@@ -605,6 +7046,8 @@ mod tests {
 
     #[test]
     fn test_extract_text() {
+        let ignored = vec!["script".to_string()];
+
         // Test case 1: Simple text without script
         let html = r#"<body>Hello world</body>"#;
         let document = Html::parse_document(html);
@@ -612,7 +7055,7 @@ mod tests {
             .select(&Selector::parse("body").unwrap())
             .next()
             .unwrap();
-        assert_eq!(extract_text(body), "Hello world ");
+        assert_eq!(extract_text(body, &ignored, false, false), "Hello world");
 
         // Test case 2: Text with script at root level
         let html = r#"<body>Hello <script>alert('hidden');</script>world</body>"#;
@@ -621,7 +7064,7 @@ mod tests {
             .select(&Selector::parse("body").unwrap())
             .next()
             .unwrap();
-        assert_eq!(extract_text(body), "Hello world ");
+        assert_eq!(extract_text(body, &ignored, false, false), "Hello world");
 
         // Test case 3: Text with nested script
         let html =
@@ -631,7 +7074,10 @@ mod tests {
             .select(&Selector::parse("body").unwrap())
             .next()
             .unwrap();
-        assert_eq!(extract_text(body), "Hello nested text world ");
+        assert_eq!(
+            extract_text(body, &ignored, false, false),
+            "Hello nested text world"
+        );
 
         // Test case 4: Multiple scripts and nested elements
         let html = r#"<body>
@@ -648,6 +7094,649 @@ mod tests {
             .select(&Selector::parse("body").unwrap())
             .next()
             .unwrap();
-        assert_eq!(extract_text(body), "Title Content Paragraph text ");
+        assert_eq!(
+            extract_text(body, &ignored, false, false),
+            "Title Content Paragraph text"
+        );
+
+        // Test case 5: Punctuation in its own text node
+        let html = r#"<body><a href="/">word</a> . <b>next</b> ,  end</body>"#;
+        let document = Html::parse_document(html);
+        let body = document
+            .select(&Selector::parse("body").unwrap())
+            .next()
+            .unwrap();
+        assert_eq!(
+            extract_text(body, &ignored, false, false),
+            "word . next , end"
+        );
+        assert_eq!(extract_text(body, &ignored, true, false), "word. next, end");
+
+        // Test case 6: Blocks separated with `preserve_paragraphs`
+        let html = r#"<body>
+            <h1>Title</h1>
+            <div><p>First  paragraph</p><p>Second <b>one</b></p></div>
+            <ul><li>Item</li><li></li></ul>
+            Tail
+        </body>"#;
+        let document = Html::parse_document(html);
+        let body = document
+            .select(&Selector::parse("body").unwrap())
+            .next()
+            .unwrap();
+        assert_eq!(
+            extract_text(body, &ignored, false, true),
+            "Title\u{2029}First paragraph\u{2029}Second one\u{2029}Item\u{2029}Tail"
+        );
+        assert_eq!(
+            extract_text(body, &ignored, false, false),
+            "Title First paragraph Second one Item Tail"
+        );
+    }
+
+    #[test]
+    fn test_normalize_whitespace() {
+        assert_eq!(normalize_whitespace("  a \n\t b  ", false), "a b");
+        assert_eq!(normalize_whitespace("", true), "");
+        assert_eq!(
+            normalize_whitespace("word . (see ) ?!", true),
+            "word. (see)?!"
+        );
+        // Punctuation attached to a word is left alone.
+        assert_eq!(
+            normalize_whitespace("learn .NET today", true),
+            "learn .NET today"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_preserve_paragraphs() {
+        let html = "<title>Pets</title><body>\
+            <p>Cats and dogs are popular pets in many households.</p>\
+            <p>Parrots need a lot of attention.</p>\
+            <p>Fish are quiet.</p>\
+            </body>";
+
+        for preserve_paragraphs in [false, true] {
+            with_indexer(
+                &format!("test_preserve_paragraphs_{preserve_paragraphs}"),
+                |config| config.preserve_paragraphs = preserve_paragraphs,
+                |indexer| {
+                    indexer.add_html("https://example.com/", html, "example.com")?;
+                    indexer.commit_and_reload()?;
+
+                    let options = SearchOptions {
+                        snippet_preference: vec![SnippetSource::BodyMatch],
+                        ..Default::default()
+                    };
+                    let results = indexer.search("parrots", 10, &options)?;
+                    let snippet = &results[0].snippet;
+                    if preserve_paragraphs {
+                        // The snippet stays within the paragraph of the match.
+                        assert_eq!(snippet, "<b>Parrots</b> need a lot of attention.");
+                    } else {
+                        assert!(snippet.starts_with("Cats and dogs"));
+                        assert!(snippet.contains("<b>Parrots</b> need a lot of attention. Fish"));
+                    }
+
+                    // The indexed words are the same either way, so ranking is too.
+                    let page = indexer.cached_page("https://example.com/", &[])?.unwrap();
+                    assert_eq!(
+                        page.body.split_whitespace().collect::<Vec<_>>().join(" "),
+                        "Cats and dogs are popular pets in many households. \
+                         Parrots need a lot of attention. Fish are quiet."
+                    );
+
+                    Ok(())
+                },
+            )
+            .await;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_collapse_redundant_snippets() {
+        with_indexer(
+            "test_collapse_redundant_snippets",
+            |_config| {},
+            |indexer| {
+                indexer.add_html(
+                    "https://example.com/ferris",
+                    r#"<title>Ferris</title>
+                    <meta name="description" content="The Rust mascot, a friendly crab">
+                    <body>Ferris</body>"#,
+                    "example.com",
+                )?;
+                indexer.commit_and_reload()?;
+
+                let snippet = |snippet_preference: &[SnippetSource], collapse: bool| {
+                    let options = SearchOptions {
+                        snippet_preference: snippet_preference.to_vec(),
+                        collapse_redundant_snippets: collapse,
+                        ..Default::default()
+                    };
+                    anyhow::Ok(indexer.search("ferris", 10, &options)?[0].snippet.clone())
+                };
+                use SnippetSource::*;
+
+                assert_eq!(snippet(&[BodyMatch, Description], false)?, "<b>Ferris</b>");
+                // The body only repeats the title and the description doesn't match, so the
+                // start of the description is shown instead.
+                assert_eq!(
+                    snippet(&[BodyMatch, Description], true)?,
+                    "The Rust mascot, a friendly crab"
+                );
+                // A title snippet was asked for, so it's kept.
+                assert_eq!(snippet(&[BodyMatch, Title], true)?, "<b>Ferris</b>");
+                Ok(())
+            },
+        )
+        .await;
+    }
+
+    #[test]
+    fn test_is_redundant_snippet() {
+        assert!(is_redundant_snippet(" \u{2026} ", "Rust"));
+        assert!(is_redundant_snippet("", "Rust"));
+        assert!(is_redundant_snippet("<b>Rust</b>  &amp; Go", "rust & go"));
+        assert!(!is_redundant_snippet("<b>Rust</b> ownership", "Rust"));
+    }
+
+    #[tokio::test]
+    async fn test_snippet_preference() {
+        with_indexer(
+            "test_snippet_preference",
+            |_config| {},
+            |indexer| {
+                indexer.add_html(
+                    "https://example.com/rust",
+                    r#"<title>Rust ownership</title>
+                    <meta name="description" content="A guide to ownership">
+                    <body>Ownership rules apply to every value. Borrowing comes later.</body>"#,
+                    "example.com",
+                )?;
+                indexer.commit_and_reload()?;
+
+                let snippet = |query: &str, snippet_preference: &[SnippetSource]| {
+                    let options = SearchOptions {
+                        snippet_preference: snippet_preference.to_vec(),
+                        ..Default::default()
+                    };
+                    anyhow::Ok(indexer.search(query, 10, &options)?[0].snippet.clone())
+                };
+                let body = "<b>Ownership</b> rules apply to every value. Borrowing comes later";
+                let description = "A guide to <b>ownership</b>";
+                let title = "Rust <b>ownership</b>";
+                use SnippetSource::*;
+
+                // Every source matches, so the first one is used.
+                assert_eq!(
+                    snippet("ownership", &[BodyMatch, Description, Title])?,
+                    body
+                );
+                assert_eq!(
+                    snippet("ownership", &[Description, BodyMatch, Title])?,
+                    description
+                );
+                assert_eq!(
+                    snippet("ownership", &[Title, BodyMatch, Description])?,
+                    title
+                );
+
+                // Sources that don't match are skipped.
+                assert_eq!(
+                    snippet("borrowing", &[Description, Title, BodyMatch])?,
+                    "Rust ownership"
+                );
+                assert_eq!(
+                    snippet("borrowing", &[Description, BodyMatch])?,
+                    "Ownership rules apply to every value. <b>Borrowing</b> comes later"
+                );
+                assert_eq!(
+                    snippet("rust", &[BodyMatch, Description, Title])?,
+                    "<b>Rust</b> ownership"
+                );
+                assert_eq!(
+                    snippet("guide", &[BodyMatch, Description])?,
+                    "A <b>guide</b> to ownership"
+                );
+                assert_eq!(snippet("rust", &[BodyMatch, Description])?, "");
+                assert_eq!(snippet("ownership", &[])?, "");
+
+                Ok(())
+            },
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn test_description_sources() {
+        with_indexer(
+            "test_description_sources",
+            |_config| {},
+            |indexer| {
+                let pages = [
+                    (
+                        "https://example.com/og",
+                        r#"<meta property="og:description" content="Open Graph crabs"><body>og</body>"#,
+                    ),
+                    (
+                        "https://example.com/json-ld",
+                        r#"<script type="application/ld+json">
+                            {"@graph": [{"@type": "WebSite"}, {"description": "JSON-LD lobsters"}]}
+                        </script><body>json</body>"#,
+                    ),
+                    (
+                        "https://example.com/both",
+                        r#"<meta name="twitter:description" content="Twitter prawns">
+                        <meta name="description" content="Standard shrimp"><body>both</body>"#,
+                    ),
+                ];
+                for (url, html) in pages {
+                    indexer.add_html(url, html, "example.com")?;
+                }
+                indexer.commit_and_reload()?;
+
+                let description_of = |query: &str| -> anyhow::Result<String> {
+                    let results = indexer.search(query, 10, &SearchOptions::default())?;
+                    assert_eq!(results.len(), 1);
+                    Ok(results[0].description_snippet.clone())
+                };
+                assert_eq!(description_of("crabs")?, "Open Graph <b>crabs</b>");
+                assert_eq!(description_of("lobsters")?, "JSON-LD <b>lobsters</b>");
+                // The standard meta description takes precedence over the others.
+                assert_eq!(description_of("shrimp")?, "Standard <b>shrimp</b>");
+                assert!(indexer
+                    .search("prawns", 10, &SearchOptions::default())?
+                    .is_empty());
+
+                Ok(())
+            },
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn test_reload_policy() {
+        with_indexer(
+            "test_reload_policy",
+            |config| config.reload_policy = ReaderReload::Manual,
+            |indexer| {
+                indexer.add_html(
+                    "https://example.com/",
+                    "<title>Rust</title><body>Rust ownership</body>",
+                    "example.com",
+                )?;
+                indexer.commit()?;
+                // Longer than the delay of `ReloadPolicy::OnCommitWithDelay`.
+                std::thread::sleep(Duration::from_millis(1000));
+                let options = SearchOptions::default();
+                assert!(indexer.search("rust", 10, &options)?.is_empty());
+
+                indexer.commit_and_reload()?;
+                assert_eq!(indexer.search("rust", 10, &options)?.len(), 1);
+                Ok(())
+            },
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn test_num_shards() {
+        let pages = [
+            ("docs.rs", "Rust documentation for crates"),
+            (
+                "example.com",
+                "An example page about Rust and Rust ownership",
+            ),
+            (
+                "rust-lang.org",
+                "Rust ownership, borrowing and Rust lifetimes in Rust",
+            ),
+            ("tokio.rs", "An asynchronous runtime for Rust"),
+        ];
+        let add_pages = |indexer: &Indexer| -> anyhow::Result<()> {
+            for (domain, body) in pages {
+                indexer.add_html(
+                    &format!("https://{domain}/"),
+                    &format!("<title>{domain}</title><body>{body}</body>"),
+                    domain,
+                )?;
+            }
+            indexer.commit_and_reload()
+        };
+        let search = |indexer: &Indexer| -> anyhow::Result<Vec<(String, String)>> {
+            let options = SearchOptions {
+                snippet_preference: vec![SnippetSource::BodyMatch],
+                ..Default::default()
+            };
+            Ok(indexer
+                .search("rust ownership", 10, &options)?
+                .into_iter()
+                .map(|result| (result.url, result.snippet))
+                .collect())
+        };
+
+        let mut single_results = Vec::new();
+        with_indexer(
+            "test_num_shards_1",
+            |_config| {},
+            |indexer| {
+                add_pages(indexer)?;
+                single_results = search(indexer)?;
+                Ok(())
+            },
+        )
+        .await;
+        assert_eq!(single_results.len(), 4);
+
+        with_indexer(
+            "test_num_shards_2",
+            |config| config.num_shards = 2,
+            |indexer| {
+                add_pages(indexer)?;
+                let sharded_results = search(indexer)?;
+                // Both shards have pages.
+                for shard in &indexer.shards {
+                    assert!(shard.reader.searcher().num_docs() > 0);
+                }
+
+                // Results from both shards are merged into the same ranking, with the same
+                // snippets, as with a single index.
+                assert_eq!(sharded_results, single_results);
+                assert_eq!(sharded_results[0].0, "https://rust-lang.org/");
+                assert_eq!(indexer.summary()?.num_docs, 4);
+
+                // Pages are still in their shard after a reindex.
+                indexer.reindex()?;
+                assert_eq!(search(indexer)?, single_results);
+                for (domain, _body) in pages {
+                    assert!(indexer.is_indexed_url(&format!("https://{domain}/"), &[])?);
+                }
+
+                Ok(())
+            },
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn test_change_num_shards() {
+        let mut config = Config::load_test("test_change_num_shards").indexer;
+        config.num_shards = 2;
+        drop(Indexer::new(&config).await.unwrap());
+        config.new_index = false;
+
+        // Neither more nor fewer shards open the index, and trying doesn't change it.
+        for num_shards in [4, 3, 1, 4] {
+            let err = Indexer::new(&IndexerConfig {
+                num_shards,
+                ..config.clone()
+            })
+            .await
+            .err()
+            .expect("the shard count changed");
+            assert!(format!("{err:#}").contains("new_index: true"));
+        }
+        assert!(!std::path::Path::new(&config.index_dir)
+            .join("shard-2")
+            .exists());
+
+        // Clean up after test.
+        Indexer::new(&config).await.unwrap().delete().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_score_expression() {
+        // The HTTP page is more relevant, the HTTPS page is shallower.
+        let pages = [
+            (
+                "http://example.com/docs/rust/",
+                "Rust ownership and Rust borrowing",
+            ),
+            ("https://example.com/", "Rust ownership"),
+        ];
+        for (score_expression, first) in [
+            ("", "http://example.com/docs/rust/"),
+            ("bm25", "http://example.com/docs/rust/"),
+            ("score = bm25 + https", "https://example.com/"),
+            ("bm25 / (1 + depth * 10)", "https://example.com/"),
+            ("-bm25", "https://example.com/"),
+        ] {
+            with_indexer(
+                "test_score_expression",
+                |config| config.score_expression = score_expression.to_string(),
+                |indexer| {
+                    for (url, body) in pages {
+                        indexer.add_html(
+                            url,
+                            &format!("<title>Page</title><body>{body}</body>"),
+                            "example.com",
+                        )?;
+                    }
+                    indexer.commit_and_reload()?;
+
+                    let results = indexer.search("rust", 10, &SearchOptions::default())?;
+                    assert_eq!(results.len(), 2);
+                    assert_eq!(results[0].url, first, "{score_expression}");
+                    Ok(())
+                },
+            )
+            .await;
+        }
+
+        // Invalid expressions and unknown fields are rejected at startup.
+        for score_expression in ["bm25 +", "log(in_degree)", "title"] {
+            let mut config = Config::load_test("test_score_expression_invalid").indexer;
+            config.score_expression = score_expression.to_string();
+            let error = Indexer::new(&config).await.err().unwrap();
+            assert!(error
+                .to_string()
+                .starts_with(&format!("Invalid score_expression '{score_expression}'")));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_min_quality() {
+        let links: String = (0..20)
+            .map(|i| format!(r#"<li><a href="/page{i}">Rust page {i}</a></li>"#))
+            .collect();
+        let link_list = format!("<title>Rust links</title><body><ul>{links}</ul></body>");
+        let prose = r#"<title>Rust ownership</title>
+            <meta name="description" content="How Rust manages memory">
+            <body><p>Rust manages memory through a system of ownership with rules that the
+            compiler checks. If any of the rules are violated, the program won't compile.
+            See <a href="/borrowing">borrowing</a> for how values are passed around.</p></body>"#;
+
+        with_indexer(
+            "test_min_quality",
+            |config| config.min_quality = 0.3,
+            |indexer| {
+                indexer.add_html("https://example.com/links", &link_list, "example.com")?;
+                indexer.add_html("https://example.com/ownership", prose, "example.com")?;
+                indexer.commit_and_reload()?;
+
+                let results = indexer.search("rust", 10, &SearchOptions::default())?;
+                assert_eq!(results.len(), 1);
+                assert_eq!(results[0].url, "https://example.com/ownership");
+                assert_eq!(indexer.summary()?.pages_low_quality, 1);
+
+                // Everything is indexed by default.
+                indexer.set_config(IndexerConfig {
+                    min_quality: 0.0,
+                    ..indexer.config().as_ref().clone()
+                });
+                indexer.add_html("https://example.com/links", &link_list, "example.com")?;
+                indexer.commit_and_reload()?;
+                let results = indexer.search("rust", 10, &SearchOptions::default())?;
+                assert_eq!(results.len(), 2);
+                assert_eq!(indexer.summary()?.pages_low_quality, 1);
+
+                Ok(())
+            },
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn test_skip_unchanged_pages() {
+        with_indexer(
+            "test_skip_unchanged_pages",
+            |config| config.skip_unchanged_pages = true,
+            |indexer| {
+                let url = "https://example.com/";
+                let html = |word: &str| format!("<title>Page</title><body>{word}</body>");
+                let validators = |etag: Option<&str>, last_modified: &str| PageValidators {
+                    etag: etag.map(str::to_string),
+                    last_modified: Some(last_modified.to_string()),
+                };
+                let monday = "Mon, 05 Jan 2026 10:00:00 GMT";
+                let tuesday = "Tue, 06 Jan 2026 10:00:00 GMT";
+                let count = |query: &str| -> anyhow::Result<usize> {
+                    indexer.commit_and_reload()?;
+                    Ok(indexer.search(query, 10, &SearchOptions::default())?.len())
+                };
+
+                assert!(indexer.add_html_if_changed(
+                    url,
+                    &html("apples"),
+                    "example.com",
+                    &validators(Some("v1"), monday)
+                )?);
+                assert_eq!(count("apples")?, 1);
+
+                // A page with the same `ETag` isn't indexed again, whatever its body.
+                assert!(!indexer.add_html_if_changed(
+                    url,
+                    &html("bananas"),
+                    "example.com",
+                    &validators(Some("v1"), tuesday)
+                )?);
+                assert_eq!(count("bananas")?, 0);
+                assert_eq!(indexer.summary()?.pages_unchanged, 1);
+
+                // A new `ETag` means the page changed, even with the same `Last-Modified`.
+                assert!(indexer.add_html_if_changed(
+                    url,
+                    &html("cherries"),
+                    "example.com",
+                    &validators(Some("v2"), monday)
+                )?);
+                assert_eq!(count("cherries")?, 1);
+                // The changed page replaces the one indexed before.
+                assert_eq!(count("apples")?, 0);
+
+                // Without an `ETag`, `Last-Modified` decides.
+                assert!(!indexer.add_html_if_changed(
+                    url,
+                    &html("dates"),
+                    "example.com",
+                    &validators(None, monday)
+                )?);
+                assert!(indexer.add_html_if_changed(
+                    url,
+                    &html("dates"),
+                    "example.com",
+                    &validators(None, tuesday)
+                )?);
+                assert_eq!(count("dates")?, 1);
+                assert_eq!(count("cherries")?, 0);
+                let summary = indexer.summary()?;
+                assert_eq!(summary.num_docs, 1);
+                let stats = indexer.get_domain_stats(SizeFormat::Decimal)?;
+                assert_eq!(stats[0].page_count, 1);
+
+                Ok(())
+            },
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn test_search_timings() {
+        with_indexer(
+            "test_search_timings",
+            |_config| {},
+            |indexer| {
+                for page in 0..5 {
+                    indexer.add_html(
+                        &format!("https://example.com/{page}"),
+                        "<title>Rust</title><body>Rust ownership and borrowing</body>",
+                        "example.com",
+                    )?;
+                }
+                indexer.commit_and_reload()?;
+
+                let (results, timings) =
+                    indexer.search_timed("rust", 10, &SearchOptions::default())?;
+                assert_eq!(results.len(), 5);
+                // The phases follow each other, so they add up to about the total.
+                let phases = timings.parse + timings.collect + timings.snippets;
+                assert!(phases <= timings.total);
+                assert!(timings.total - phases < Duration::from_millis(100));
+
+                let json = serde_json::to_value(timings)?;
+                for field in ["parse_ms", "collect_ms", "snippets_ms", "total_ms"] {
+                    assert!(json[field].as_f64().unwrap() >= 0.0);
+                }
+
+                Ok(())
+            },
+        )
+        .await;
+    }
+
+    #[test]
+    fn test_decode_entities() {
+        assert_eq!(
+            decode_entities("Tom &amp; Jerry&nbsp;&copy;&#8212;&#x2014;"),
+            "Tom & Jerry\u{a0}©——"
+        );
+        // Unknown and unterminated entities are left alone.
+        assert_eq!(
+            decode_entities("&unknown; AT&T &amp"),
+            "&unknown; AT&T &amp"
+        );
+        assert!(matches!(decode_entities("no entities"), Cow::Borrowed(_)));
+    }
+
+    #[tokio::test]
+    async fn test_extracted_entities() {
+        with_indexer(
+            "test_extracted_entities",
+            |config| config.decode_entities = true,
+            |indexer| {
+                indexer.add_html(
+                    "https://example.com/",
+                    "<title>Tom&nbsp;&amp;&nbsp;Jerry</title>\
+                     <meta name=\"description\" content=\"Cat&nbsp;and mouse &amp;amp; more\">\
+                     <body>Classic&nbsp;cartoon &copy; 1940</body>",
+                    "example.com",
+                )?;
+                indexer.commit_and_reload()?;
+
+                let results = indexer.search("classic cartoon", 10, &SearchOptions::default())?;
+                assert_eq!(results.len(), 1);
+                assert_eq!(results[0].title, "Tom & Jerry");
+
+                let page = indexer.cached_page("https://example.com/", &[])?.unwrap();
+                assert_eq!(page.title, "Tom & Jerry");
+                assert_eq!(page.body, "Classic cartoon © 1940");
+
+                let results = indexer.search("mouse", 10, &SearchOptions::default())?;
+                assert_eq!(
+                    results[0].description_snippet,
+                    "Cat and <b>mouse</b> &amp; more"
+                );
+
+                // Entity names aren't indexed as words.
+                assert!(indexer
+                    .search("nbsp", 10, &SearchOptions::default())?
+                    .is_empty());
+
+                Ok(())
+            },
+        )
+        .await;
     }
 }
@@ -0,0 +1,432 @@
+//! Arithmetic expressions over the query score and fast fields of a document, used to re-rank
+//! results with `score_expression`.
+
+use std::sync::Arc;
+use tantivy::{
+    columnar::ColumnValues,
+    schema::{FieldType, Schema},
+    DocId, Score, SegmentReader,
+};
+
+const SECS_PER_DAY: f64 = 24.0 * 60.0 * 60.0;
+
+/// A parsed `score_expression`, e.g. `bm25 * (1 + https * 0.1) / (1 + depth)`.
+///
+/// Expressions combine numbers, variables, `+ - * /`, parentheses and the functions `log(x)`
+/// (natural logarithm of 1 + x, so 0 stays 0), `sqrt(x)`, `min(a, b)` and `max(a, b)`. Variables
+/// are:
+///
+/// - `bm25`: the score of the query.
+/// - Any u64 or bool fast field of the schema, like `depth`, `size`, `crawled_at` or `https` (1 for
+///   true, 0 for false).
+/// - `age_days`: days since the page was crawled.
+/// - `recency`: `1 / (1 + age_days)`, 1 for a page crawled just now.
+#[derive(Debug)]
+pub struct ScoreExpression {
+    expr: Expr,
+    /// The fast fields the expression reads, indexed by [`Expr::Field`].
+    fields: Vec<(String, FieldKind)>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum FieldKind {
+    U64,
+    Bool,
+}
+
+#[derive(Debug)]
+enum Expr {
+    Number(f64),
+    Bm25,
+    Field(usize),
+    /// Days since the time in the given field.
+    AgeDays(usize),
+    Neg(Box<Expr>),
+    Binary(char, Box<Expr>, Box<Expr>),
+    Call(Function, Vec<Expr>),
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Function {
+    Log,
+    Sqrt,
+    Min,
+    Max,
+}
+
+impl Function {
+    fn parse(name: &str) -> Option<(Self, usize)> {
+        match name {
+            "log" => Some((Self::Log, 1)),
+            "sqrt" => Some((Self::Sqrt, 1)),
+            "min" => Some((Self::Min, 2)),
+            "max" => Some((Self::Max, 2)),
+            _ => None,
+        }
+    }
+}
+
+impl ScoreExpression {
+    /// Parses an expression, optionally written as `score = ...`, and checks that the fields it
+    /// references are u64 or bool fast fields of `schema`.
+    pub fn parse(text: &str, schema: &Schema) -> anyhow::Result<Self> {
+        let text = text.trim();
+        let text = text
+            .strip_prefix("score")
+            .and_then(|rest| rest.trim_start().strip_prefix('='))
+            .unwrap_or(text);
+
+        let mut parser = Parser {
+            tokens: tokenize(text)?,
+            position: 0,
+            schema,
+            fields: Vec::new(),
+        };
+        let expr = parser.expr()?;
+        if let Some(token) = parser.tokens.get(parser.position) {
+            anyhow::bail!("Unexpected '{token}'");
+        }
+
+        Ok(Self {
+            expr,
+            fields: parser.fields,
+        })
+    }
+
+    /// Returns a function computing the new score of a document of the segment from its query
+    /// score. `now` is the Unix timestamp that ages are relative to. Results that aren't finite,
+    /// e.g. after dividing by 0, count as 0.
+    pub fn segment_scorer(
+        self: &Arc<Self>,
+        segment_reader: &SegmentReader,
+        now: u64,
+    ) -> impl Fn(DocId, Score) -> Score + 'static {
+        let fast_fields = segment_reader.fast_fields();
+        // The fields were checked against the schema when parsing.
+        let columns: Vec<Column> = self
+            .fields
+            .iter()
+            .map(|(name, kind)| match kind {
+                FieldKind::U64 => {
+                    Column::U64(fast_fields.u64(name).unwrap().first_or_default_col(0))
+                }
+                FieldKind::Bool => {
+                    Column::Bool(fast_fields.bool(name).unwrap().first_or_default_col(false))
+                }
+            })
+            .collect();
+        let expression = self.clone();
+
+        move |doc: DocId, score: Score| {
+            let field = |index: usize| columns[index].get(doc);
+            let score = expression.expr.eval(score as f64, &field, now as f64) as Score;
+            if score.is_finite() {
+                score
+            } else {
+                0.0
+            }
+        }
+    }
+}
+
+enum Column {
+    U64(Arc<dyn ColumnValues<u64>>),
+    Bool(Arc<dyn ColumnValues<bool>>),
+}
+
+impl Column {
+    fn get(&self, doc: DocId) -> f64 {
+        match self {
+            Column::U64(column) => column.get_val(doc) as f64,
+            Column::Bool(column) => f64::from(u8::from(column.get_val(doc))),
+        }
+    }
+}
+
+impl Expr {
+    /// Evaluates the expression for a document, whose fields are read with `field`.
+    fn eval(&self, bm25: f64, field: &dyn Fn(usize) -> f64, now: f64) -> f64 {
+        let eval = |expr: &Expr| expr.eval(bm25, field, now);
+        match self {
+            Expr::Number(number) => *number,
+            Expr::Bm25 => bm25,
+            Expr::Field(index) => field(*index),
+            Expr::AgeDays(index) => (now - field(*index)).max(0.0) / SECS_PER_DAY,
+            Expr::Neg(expr) => -eval(expr),
+            Expr::Binary(op, left, right) => {
+                let (left, right) = (eval(left), eval(right));
+                match op {
+                    '+' => left + right,
+                    '-' => left - right,
+                    '*' => left * right,
+                    _ => left / right,
+                }
+            }
+            Expr::Call(function, args) => match function {
+                Function::Log => eval(&args[0]).max(0.0).ln_1p(),
+                Function::Sqrt => eval(&args[0]).max(0.0).sqrt(),
+                Function::Min => eval(&args[0]).min(eval(&args[1])),
+                Function::Max => eval(&args[0]).max(eval(&args[1])),
+            },
+        }
+    }
+}
+
+/// Splits an expression into numbers, names and single-character operators.
+fn tokenize(text: &str) -> anyhow::Result<Vec<String>> {
+    let mut tokens = Vec::new();
+    let mut chars = text.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if c.is_ascii_digit() || c == '.' {
+            let mut number = String::new();
+            while let Some(&c) = chars.peek().filter(|c| c.is_ascii_digit() || **c == '.') {
+                number.push(c);
+                chars.next();
+            }
+            tokens.push(number);
+        } else if c.is_ascii_alphabetic() || c == '_' {
+            let mut name = String::new();
+            while let Some(&c) = chars
+                .peek()
+                .filter(|c| c.is_ascii_alphanumeric() || **c == '_')
+            {
+                name.push(c);
+                chars.next();
+            }
+            tokens.push(name);
+        } else if "+-*/(),".contains(c) {
+            tokens.push(c.to_string());
+            chars.next();
+        } else {
+            anyhow::bail!("Unexpected '{c}'");
+        }
+    }
+    Ok(tokens)
+}
+
+/// A recursive descent parser, with the usual precedence of operators.
+struct Parser<'a> {
+    tokens: Vec<String>,
+    position: usize,
+    schema: &'a Schema,
+    fields: Vec<(String, FieldKind)>,
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> Option<&str> {
+        self.tokens.get(self.position).map(String::as_str)
+    }
+
+    fn next(&mut self) -> anyhow::Result<String> {
+        let token = self
+            .tokens
+            .get(self.position)
+            .cloned()
+            .ok_or_else(|| anyhow::anyhow!("Unexpected end of expression"))?;
+        self.position += 1;
+        Ok(token)
+    }
+
+    fn expect(&mut self, expected: &str) -> anyhow::Result<()> {
+        let token = self.next()?;
+        anyhow::ensure!(token == expected, "Expected '{expected}', found '{token}'");
+        Ok(())
+    }
+
+    /// `term (('+' | '-') term)*`
+    fn expr(&mut self) -> anyhow::Result<Expr> {
+        let mut expr = self.term()?;
+        while let Some(op @ ("+" | "-")) = self.peek() {
+            let op = op.chars().next().unwrap();
+            self.position += 1;
+            expr = Expr::Binary(op, Box::new(expr), Box::new(self.term()?));
+        }
+        Ok(expr)
+    }
+
+    /// `unary (('*' | '/') unary)*`
+    fn term(&mut self) -> anyhow::Result<Expr> {
+        let mut expr = self.unary()?;
+        while let Some(op @ ("*" | "/")) = self.peek() {
+            let op = op.chars().next().unwrap();
+            self.position += 1;
+            expr = Expr::Binary(op, Box::new(expr), Box::new(self.unary()?));
+        }
+        Ok(expr)
+    }
+
+    /// `'-' unary | primary`
+    fn unary(&mut self) -> anyhow::Result<Expr> {
+        if self.peek() == Some("-") {
+            self.position += 1;
+            return Ok(Expr::Neg(Box::new(self.unary()?)));
+        }
+        self.primary()
+    }
+
+    /// A number, a variable, a function call or an expression in parentheses.
+    fn primary(&mut self) -> anyhow::Result<Expr> {
+        let token = self.next()?;
+        if token == "(" {
+            let expr = self.expr()?;
+            self.expect(")")?;
+            return Ok(expr);
+        }
+        if token.starts_with(|c: char| c.is_ascii_digit() || c == '.') {
+            let number = token
+                .parse()
+                .map_err(|_| anyhow::anyhow!("Invalid number '{token}'"))?;
+            return Ok(Expr::Number(number));
+        }
+        if !token.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_') {
+            anyhow::bail!("Unexpected '{token}'");
+        }
+
+        if self.peek() == Some("(") {
+            let (function, arity) = Function::parse(&token)
+                .ok_or_else(|| anyhow::anyhow!("Unknown function '{token}'"))?;
+            self.position += 1;
+            let mut args = vec![self.expr()?];
+            while self.peek() == Some(",") {
+                self.position += 1;
+                args.push(self.expr()?);
+            }
+            self.expect(")")?;
+            anyhow::ensure!(
+                args.len() == arity,
+                "{token}() takes {arity} argument(s), got {}",
+                args.len()
+            );
+            return Ok(Expr::Call(function, args));
+        }
+
+        Ok(match token.as_str() {
+            "bm25" => Expr::Bm25,
+            "age_days" => Expr::AgeDays(self.field("crawled_at")?),
+            "recency" => Expr::Binary(
+                '/',
+                Box::new(Expr::Number(1.0)),
+                Box::new(Expr::Binary(
+                    '+',
+                    Box::new(Expr::Number(1.0)),
+                    Box::new(Expr::AgeDays(self.field("crawled_at")?)),
+                )),
+            ),
+            name => Expr::Field(self.field(name)?),
+        })
+    }
+
+    /// Returns the index of a fast field in `fields`, adding it if it's new.
+    fn field(&mut self, name: &str) -> anyhow::Result<usize> {
+        if let Some(index) = self.fields.iter().position(|(field, _kind)| field == name) {
+            return Ok(index);
+        }
+
+        let field_entry = self
+            .schema
+            .get_field(name)
+            .ok()
+            .map(|field| self.schema.get_field_entry(field))
+            .filter(|field_entry| field_entry.is_fast());
+        let kind = match field_entry.map(|field_entry| field_entry.field_type()) {
+            Some(FieldType::U64(_)) => FieldKind::U64,
+            Some(FieldType::Bool(_)) => FieldKind::Bool,
+            _ => anyhow::bail!(
+                "Unknown variable '{name}'. Use bm25, age_days, recency or a u64 or bool fast \
+                 field: {}",
+                numeric_fast_fields(self.schema).join(", ")
+            ),
+        };
+        self.fields.push((name.to_string(), kind));
+        Ok(self.fields.len() - 1)
+    }
+}
+
+/// The names of the u64 and bool fast fields of the schema.
+fn numeric_fast_fields(schema: &Schema) -> Vec<&str> {
+    schema
+        .fields()
+        .filter(|(_field, field_entry)| {
+            field_entry.is_fast()
+                && matches!(
+                    field_entry.field_type(),
+                    FieldType::U64(_) | FieldType::Bool(_)
+                )
+        })
+        .map(|(_field, field_entry)| field_entry.name())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tantivy::schema::{FAST, STORED, STRING};
+
+    fn schema() -> Schema {
+        let mut schema_builder = Schema::builder();
+        schema_builder.add_u64_field("depth", FAST);
+        schema_builder.add_u64_field("crawled_at", STORED | FAST);
+        schema_builder.add_bool_field("https", FAST);
+        schema_builder.add_u64_field("stored_only", STORED);
+        schema_builder.add_text_field("url", STRING | FAST);
+        schema_builder.build()
+    }
+
+    #[test]
+    fn test_parse() {
+        let schema = schema();
+        let eval = |text: &str, bm25: f64, values: &[f64]| {
+            let expression = ScoreExpression::parse(text, &schema).unwrap();
+            expression
+                .expr
+                .eval(bm25, &|index| values[index], 10.0 * SECS_PER_DAY)
+        };
+
+        assert_eq!(eval("bm25", 2.0, &[]), 2.0);
+        assert_eq!(eval("score = bm25 * 2", 2.0, &[]), 4.0);
+        // Precedence and associativity.
+        assert_eq!(eval("1 + 2 * 3 - 4 / 2 - 1", 0.0, &[]), 4.0);
+        assert_eq!(eval("(1 + 2) * -3", 0.0, &[]), -9.0);
+        assert_eq!(eval("bm25 / (1 + depth)", 6.0, &[2.0]), 2.0);
+        assert_eq!(eval("bm25 + https * 0.5", 1.0, &[1.0]), 1.5);
+        assert_eq!(
+            eval("log(0) + sqrt(16) + max(1, 2) + min(1, 2)", 0.0, &[]),
+            7.0
+        );
+        assert_eq!(eval("age_days", 0.0, &[8.0 * SECS_PER_DAY]), 2.0);
+        assert_eq!(eval("recency", 0.0, &[9.0 * SECS_PER_DAY]), 0.5);
+
+        // Fields are read once, however often they are used.
+        let expression = ScoreExpression::parse("depth * depth + age_days + recency", &schema);
+        assert_eq!(expression.unwrap().fields.len(), 2);
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let schema = schema();
+        let error = |text: &str| {
+            ScoreExpression::parse(text, &schema)
+                .unwrap_err()
+                .to_string()
+        };
+
+        assert_eq!(
+            error("bm25 + log(in_degree)"),
+            "Unknown variable 'in_degree'. Use bm25, age_days, recency or a u64 or bool fast \
+             field: depth, crawled_at, https"
+        );
+        // Not fast, or not numeric.
+        assert!(error("stored_only").starts_with("Unknown variable 'stored_only'"));
+        assert!(error("url").starts_with("Unknown variable 'url'"));
+        assert_eq!(error("exp(bm25)"), "Unknown function 'exp'");
+        assert_eq!(error("min(bm25)"), "min() takes 2 argument(s), got 1");
+        assert_eq!(error("bm25 *"), "Unexpected end of expression");
+        assert_eq!(error("(bm25"), "Unexpected end of expression");
+        assert_eq!(error("bm25 bm25"), "Unexpected 'bm25'");
+        assert_eq!(error("bm25 ^ 2"), "Unexpected '^'");
+        assert_eq!(error("1.2.3"), "Invalid number '1.2.3'");
+        assert_eq!(error(""), "Unexpected end of expression");
+    }
+}
@@ -0,0 +1,242 @@
+//! Splitting the index into shards by domain (see `IndexerConfig::num_shards`), and searching all
+//! shards as if they were a single index.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    path::{Path, PathBuf},
+};
+use tantivy::{
+    collector::Collector,
+    query::{Bm25StatisticsProvider, EnableScoring, Query},
+    schema::{Field, Schema},
+    snippet::SnippetGenerator,
+    DocAddress, Score, Searcher, SegmentReader, TantivyDocument, Term,
+};
+
+/// Returns the shard storing the pages of `domain`. Uses FNV-1a rather than the standard library's
+/// hasher, which may change between releases, since a domain has to stay in its shard.
+pub fn shard_of(domain: &str, num_shards: usize) -> usize {
+    const FNV_OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+    const FNV_PRIME: u64 = 0x0100_0000_01b3;
+
+    let hash = domain
+        .bytes()
+        .map(|byte| byte.to_ascii_lowercase())
+        .fold(FNV_OFFSET_BASIS, |hash, byte| {
+            (hash ^ u64::from(byte)).wrapping_mul(FNV_PRIME)
+        });
+    (hash % num_shards as u64) as usize
+}
+
+/// The directory of a shard. A single shard is stored in `index_dir` itself, like an index from
+/// before sharding.
+pub fn shard_dir(index_dir: &str, shard: usize, num_shards: usize) -> PathBuf {
+    if num_shards == 1 {
+        PathBuf::from(index_dir)
+    } else {
+        Path::new(index_dir).join(format!("shard-{shard}"))
+    }
+}
+
+/// Whether the index in `index_dir` exists with exactly `num_shards` shards. Pages of a domain
+/// would be looked for in the wrong shard otherwise. Doesn't create anything, so checking a wrong
+/// shard count leaves the index as it was.
+pub fn has_shard_layout(index_dir: &str, num_shards: usize) -> bool {
+    let sharded = Path::new(index_dir).join("shard-0").exists();
+    let extra_shard = Path::new(index_dir).join(format!("shard-{num_shards}"));
+    sharded == (num_shards > 1)
+        && (0..num_shards).all(|shard| {
+            shard_dir(index_dir, shard, num_shards)
+                .join("meta.json")
+                .exists()
+        })
+        && !extra_shard.exists()
+}
+
+/// A snapshot of all shards, searched like a single [`Searcher`]. Segments are numbered across
+/// shards, in shard order, so a [`DocAddress`] identifies a document in any shard. Scores are
+/// computed from the statistics of all shards, so they can be compared across shards.
+pub struct ShardSearcher {
+    searchers: Vec<Searcher>,
+    /// The segments of all shards, in order.
+    segment_readers: Vec<SegmentReader>,
+    /// The shard of each segment, and the segment's number within the shard.
+    segments: Vec<(usize, u32)>,
+}
+
+impl ShardSearcher {
+    pub fn new(searchers: Vec<Searcher>) -> Self {
+        let mut segment_readers = Vec::new();
+        let mut segments = Vec::new();
+        for (shard, searcher) in searchers.iter().enumerate() {
+            for (segment_ord, segment_reader) in searcher.segment_readers().iter().enumerate() {
+                segment_readers.push(segment_reader.clone());
+                segments.push((shard, segment_ord as u32));
+            }
+        }
+
+        Self {
+            searchers,
+            segment_readers,
+            segments,
+        }
+    }
+
+    pub fn schema(&self) -> &Schema {
+        self.searchers[0].schema()
+    }
+
+    pub fn segment_readers(&self) -> &[SegmentReader] {
+        &self.segment_readers
+    }
+
+    pub fn segment_reader(&self, segment_ord: u32) -> &SegmentReader {
+        &self.segment_readers[segment_ord as usize]
+    }
+
+    pub fn num_docs(&self) -> u64 {
+        self.searchers.iter().map(Searcher::num_docs).sum()
+    }
+
+    pub fn doc_freq(&self, term: &Term) -> tantivy::Result<u64> {
+        self.searchers
+            .iter()
+            .map(|searcher| searcher.doc_freq(term))
+            .sum()
+    }
+
+    pub fn doc(&self, doc_address: DocAddress) -> tantivy::Result<TantivyDocument> {
+        let (shard, segment_ord) = self.segments[doc_address.segment_ord as usize];
+        self.searchers[shard].doc(DocAddress::new(segment_ord, doc_address.doc_id))
+    }
+
+    /// Like [`Searcher::search`], over all shards. Shards are searched in parallel, and the
+    /// results of their segments are merged by the collector, like those of a single index.
+    pub fn search<C: Collector>(
+        &self,
+        query: &dyn Query,
+        collector: &C,
+    ) -> tantivy::Result<C::Fruit> {
+        let mut first_segment = 0;
+        let shards: Vec<_> = self
+            .searchers
+            .iter()
+            .map(|searcher| {
+                let shard = (searcher, first_segment);
+                first_segment += searcher.segment_readers().len();
+                shard
+            })
+            .collect();
+
+        let search_shard = &|(searcher, first_segment): (&Searcher, usize)| {
+            let enable_scoring = if collector.requires_scoring() {
+                EnableScoring::enabled_from_statistics_provider(self, searcher)
+            } else {
+                EnableScoring::disabled_from_searcher(searcher)
+            };
+            let weight = query.weight(enable_scoring)?;
+            searcher
+                .segment_readers()
+                .iter()
+                .enumerate()
+                .map(|(segment_ord, segment_reader)| {
+                    let segment_ord = (first_segment + segment_ord) as u32;
+                    collector.collect_segment(weight.as_ref(), segment_ord, segment_reader)
+                })
+                .collect::<tantivy::Result<Vec<_>>>()
+        };
+
+        let segment_fruits = if shards.len() == 1 {
+            search_shard(shards[0])?
+        } else {
+            std::thread::scope(|scope| {
+                let handles: Vec<_> = shards
+                    .into_iter()
+                    .map(|shard| scope.spawn(move || search_shard(shard)))
+                    .collect();
+                handles
+                    .into_iter()
+                    .map(|handle| handle.join().unwrap())
+                    .collect::<tantivy::Result<Vec<_>>>()
+            })?
+            .into_iter()
+            .flatten()
+            .collect()
+        };
+        collector.merge_fruits(segment_fruits)
+    }
+
+    /// Like [`SnippetGenerator::create`], but weighs terms by their frequency in all shards.
+    /// Terms that no shard has are never highlighted.
+    pub fn snippet_generator(
+        &self,
+        query: &dyn Query,
+        field: Field,
+    ) -> tantivy::Result<SnippetGenerator> {
+        /// tantivy's default.
+        const MAX_NUM_CHARS: usize = 150;
+
+        let mut terms = BTreeSet::new();
+        query.query_terms(&mut |term, _need_positions| {
+            if term.field() == field {
+                terms.insert(term.clone());
+            }
+        });
+
+        let mut terms_text = BTreeMap::new();
+        for term in terms {
+            let value = term.value();
+            let Some(text) = value.as_str() else {
+                continue;
+            };
+            let doc_freq = self.doc_freq(&term)?;
+            if doc_freq > 0 {
+                terms_text.insert(text.to_string(), 1.0 / (1.0 + doc_freq as Score));
+            }
+        }
+        let tokenizer = self.searchers[0].index().tokenizer_for_field(field)?;
+        Ok(SnippetGenerator::new(
+            terms_text,
+            tokenizer,
+            field,
+            MAX_NUM_CHARS,
+        ))
+    }
+}
+
+impl Bm25StatisticsProvider for ShardSearcher {
+    fn total_num_tokens(&self, field: Field) -> tantivy::Result<u64> {
+        self.searchers
+            .iter()
+            .map(|searcher| searcher.total_num_tokens(field))
+            .sum()
+    }
+
+    fn total_num_docs(&self) -> tantivy::Result<u64> {
+        self.searchers
+            .iter()
+            .map(|searcher| searcher.total_num_docs())
+            .sum()
+    }
+
+    fn doc_freq(&self, term: &Term) -> tantivy::Result<u64> {
+        ShardSearcher::doc_freq(self, term)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_shard_of() {
+        // Stable across runs, and independent of case.
+        assert_eq!(shard_of("example.com", 1), 0);
+        assert_eq!(shard_of("example.com", 4), shard_of("EXAMPLE.com", 4));
+        let shards: BTreeSet<_> = ["docs.rs", "example.com", "rust-lang.org", "tokio.rs"]
+            .iter()
+            .map(|domain| shard_of(domain, 2))
+            .collect();
+        assert_eq!(shards, BTreeSet::from([0, 1]));
+    }
+}
@@ -127,3 +127,23 @@ pub const TECH_TERMS_TO_BOOST: &[&str] = &[
     "yaml",
     "zig",
 ];
+
+/// Groups of tech terms from [`TECH_TERMS_TO_BOOST`] (plus a few common short forms) that mean the
+/// same thing. Only single words are listed, since the query parser would turn e.g. `node.js` into
+/// a phrase.
+pub const TECH_TERM_EQUIVALENTS: &[&[&str]] = &[
+    &["go", "golang"],
+    &["javascript", "js"],
+    &["typescript", "ts"],
+    &["nodejs", "node"],
+    &["kubernetes", "k8s"],
+    &["postgres", "postgresql"],
+    &["react", "reactjs"],
+    &["vue", "vuejs"],
+    &["rust", "rustlang"],
+    &["python", "pythonlang"],
+    &["ruby", "rubylang"],
+    &["dart", "dartlang"],
+    &["elixir", "elixirlang"],
+    &["swift", "swiftlang"],
+];
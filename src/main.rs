@@ -4,7 +4,9 @@ use mimalloc::MiMalloc;
 static GLOBAL: MiMalloc = MiMalloc;
 
 use anyhow::Context;
-use std::sync::Arc;
+use arc_swap::ArcSwap;
+use std::{collections::HashSet, sync::Arc};
+use tokio::sync::mpsc;
 
 mod config;
 mod crawler;
@@ -14,40 +16,163 @@ mod routes;
 mod test_utils;
 
 use config::Config;
-use indexer::Indexer;
+use crawler::SharedCrawlStatus;
+use indexer::{Indexer, SearchPage};
+use routes::LiveServerConfig;
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     let config = Config::load().context("Failed to load config")?;
 
+    match std::env::args().nth(1).as_deref() {
+        None => {}
+        Some("reindex") => return reindex(&config).await.context("Failed to reindex"),
+        Some(command) => anyhow::bail!("Unknown command '{command}', expected 'reindex'"),
+    }
+
     // Start the background indexing task. Periodically commits.
     let (indexer, indexer_tx) = indexer::start(&config.indexer)
         .await
         .context("Failed to start indexer")?;
 
-    if config.indexer.new_index {
-        crawler::initial_crawl(indexer_tx, &config.crawler)
-            .await
-            .context("Failed to do initial crawl")?;
+    let crawl_status = SharedCrawlStatus::default();
+
+    // With `skip_indexed_domains`, an existing index is expanded with the domains not in it yet.
+    if config.indexer.new_index || config.crawler.skip_indexed_domains {
+        let indexed_domains = if config.crawler.skip_indexed_domains {
+            indexer
+                .indexed_domains(config.crawler.skip_indexed_domains_min_pages)
+                .context("Failed to read the indexed domains")?
+        } else {
+            HashSet::new()
+        };
+        crawler::initial_crawl(
+            indexer_tx.clone(),
+            &config.crawler,
+            crawl_status.clone(),
+            &indexed_domains,
+            &indexer,
+        )
+        .await
+        .context("Failed to do initial crawl")?;
+
+        if config.indexer.commit_after_crawl {
+            let pages_crawled = crawl_status.read().unwrap().pages_crawled;
+            indexer
+                .commit_crawl(pages_crawled)
+                .await
+                .context("Failed to commit the crawled pages")?;
+        }
     }
 
-    run_server(indexer, &config)
+    if config.indexer.warmup {
+        match indexer.warmup() {
+            Ok(duration) => println!("Warmed up index in {duration:?}"),
+            // Not fatal, the first searches will just be slower.
+            Err(e) => eprintln!("ERROR: Failed to warm up index: {e:#}"),
+        }
+    }
+
+    run_server(indexer, indexer_tx, crawl_status, &config)
         .await
         .context("Failed to run server")
 }
 
-async fn run_server(indexer: Arc<Indexer>, config: &Config) -> anyhow::Result<()> {
-    let port = std::env::var("PORT").unwrap_or("3000".to_string());
-    let app = routes::create_router(indexer, &config.server);
+/// Re-extracts the text of all pages with stored raw HTML, without crawling.
+async fn reindex(config: &Config) -> anyhow::Result<()> {
+    // Open the existing index instead of wiping it.
+    let indexer_config = config::IndexerConfig {
+        new_index: false,
+        ..config.indexer.clone()
+    };
+    let indexer = Indexer::new(&indexer_config).await?;
+
+    let start = std::time::Instant::now();
+    let reindexed = indexer.reindex()?;
+    println!("Reindexed {reindexed} pages in {:?}", start.elapsed());
 
-    println!("Server starting on http://localhost:{}", port);
+    Ok(())
+}
 
-    let listener = tokio::net::TcpListener::bind(format!("0.0.0.0:{}", port))
+async fn run_server(
+    indexer: Arc<Indexer>,
+    indexer_tx: mpsc::Sender<SearchPage>,
+    crawl_status: SharedCrawlStatus,
+    config: &Config,
+) -> anyhow::Result<()> {
+    let addr = config
+        .server
+        .socket_addr(std::env::var("PORT").ok().as_deref())
+        .context("Invalid server address")?;
+    let live_config = Arc::new(ArcSwap::from_pointee(config.server.clone()));
+    let app = routes::create_reloadable_router(
+        indexer.clone(),
+        indexer_tx,
+        crawl_status,
+        config,
+        live_config.clone(),
+    );
+    #[cfg(unix)]
+    tokio::spawn(reload_on_sighup(
+        config.clone(),
+        live_config,
+        indexer.clone(),
+    ));
+
+    println!("Server starting on http://{addr}");
+
+    let listener = tokio::net::TcpListener::bind(addr)
         .await
-        .context("Failed to bind")?;
+        .with_context(|| format!("Failed to bind to {addr}"))?;
     axum::serve(listener, app)
+        .with_graceful_shutdown(async {
+            if let Err(e) = tokio::signal::ctrl_c().await {
+                eprintln!("ERROR: Failed to listen for Ctrl-C: {e}");
+                std::future::pending::<()>().await;
+            }
+        })
         .await
         .context("Failed to serve")?;
 
+    // Pages indexed since the last periodic commit would be lost otherwise. With
+    // `batch_domain_stats`, their stats are written after the commit.
+    indexer
+        .commit_and_flush()
+        .context("Failed to commit the index before exiting")?;
+
     Ok(())
 }
+
+/// Reloads `config.yaml` on every SIGHUP, e.g. `kill -HUP <pid>`. Settings that only apply at
+/// startup keep their values, see [`Config::reloaded`].
+#[cfg(unix)]
+async fn reload_on_sighup(
+    mut config: Config,
+    live_config: LiveServerConfig,
+    indexer: Arc<Indexer>,
+) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangups = match signal(SignalKind::hangup()) {
+        Ok(hangups) => hangups,
+        Err(e) => {
+            eprintln!("ERROR: Failed to listen for SIGHUP, the config can't be reloaded: {e}");
+            return;
+        }
+    };
+
+    while hangups.recv().await.is_some() {
+        let reloaded = match Config::load() {
+            Ok(reloaded) => config.reloaded(reloaded),
+            Err(e) => {
+                eprintln!("ERROR: Failed to reload config, keeping the current one: {e:#}");
+                continue;
+            }
+        };
+
+        live_config.store(Arc::new(reloaded.server.clone()));
+        indexer.set_config(reloaded.indexer.clone());
+        config = reloaded;
+        println!("Reloaded config");
+    }
+}
@@ -0,0 +1,69 @@
+use axum::{response::Html, Extension};
+use tera::Context;
+
+use super::ServerState;
+
+/// Renders the about page with the configured description and a summary of the index.
+pub async fn about_handler(
+    Extension(ServerState {
+        indexer,
+        config,
+        templates,
+        ..
+    }): Extension<ServerState>,
+) -> Html<String> {
+    let mut context = Context::new();
+    context.insert("title", &config.name);
+    context.insert("about", &config.about);
+
+    match indexer.get_domain_stats(config.size_format) {
+        Ok(stats) => {
+            let total_pages: u64 = stats.iter().map(|s| s.page_count).sum();
+            context.insert("total_pages", &total_pages);
+            context.insert("num_domains", &stats.len());
+        }
+        Err(e) => {
+            eprintln!("ERROR: Failed to get domain stats: {e}");
+            context.insert("error", "Failed to get index statistics");
+        }
+    }
+
+    Html(
+        templates
+            .render("about.html", &context)
+            .unwrap_or_else(|e| {
+                eprintln!("Template error: {e}");
+                "Template error".to_string()
+            }),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::routes::test_utils::{body_text, get, with_app, TestApp};
+
+    #[tokio::test]
+    async fn test_about_handler() {
+        with_app(
+            "test_about_handler",
+            |config| config.server.about = "A search engine for programming docs.".to_string(),
+            |TestApp { app, indexer, .. }| async move {
+                indexer.add_html(
+                    "https://example.com/",
+                    "<title>Page</title><body>Text</body>",
+                    "example.com",
+                )?;
+
+                let response = get(&app, "/about").await?;
+                assert_eq!(response.status(), 200);
+                let body = body_text(response).await?;
+                assert!(body.contains("About test_server"));
+                assert!(body.contains("A search engine for programming docs."));
+                assert!(body.contains("1 pages from 1 domains"));
+
+                Ok(())
+            },
+        )
+        .await;
+    }
+}
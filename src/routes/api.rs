@@ -0,0 +1,883 @@
+use axum::{
+    body::Body,
+    extract::Query,
+    http::{header, StatusCode},
+    response::IntoResponse,
+    Extension, Json,
+};
+use futures_util::{stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+use tantivy::schema::FieldEntry;
+use tokio::sync::mpsc;
+
+use super::ServerState;
+use crate::{
+    crawler::{self, CrawlStatus, CrawlSummary},
+    indexer::{ClickStats, DomainCount, IndexSummary, InvalidQuery, SearchTimings},
+};
+
+#[derive(Serialize)]
+pub struct SearchResponse {
+    query: String,
+    results: Vec<Map<String, Value>>,
+    /// The top-level domains of the results with their counts, with `group=tld`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    groups: Option<Vec<super::TldGroup>>,
+    /// How long each phase of the search took, with `search_timings`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    timings: Option<SearchTimings>,
+    /// The domains with the most matches, with `domain_facets`.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    domains: Vec<DomainCount>,
+}
+
+/// Searches the index, returning JSON results. Results can be restricted to a comma-separated
+/// list of fields with `fields`, e.g. `fields=title,url`. Snippets, the slowest part of a search,
+/// are only generated when the `snippet` or `description_snippet` field is requested.
+pub async fn search_handler(
+    Query(params): Query<Vec<(String, String)>>,
+    Extension(ServerState {
+        indexer, config, ..
+    }): Extension<ServerState>,
+) -> Result<Json<SearchResponse>, (StatusCode, String)> {
+    let query = super::query_param(&params);
+    let params: HashMap<_, _> = params.into_iter().collect();
+    if query.is_empty() {
+        return Err((StatusCode::BAD_REQUEST, "Missing query".to_string()));
+    }
+
+    let num_results = match params.get("n") {
+        None => config.results_per_query,
+        Some(n) => match n.parse::<usize>() {
+            Ok(n) if (1..=config.max_results_per_query).contains(&n) => n,
+            _ => {
+                return Err((
+                    StatusCode::BAD_REQUEST,
+                    format!(
+                        "n must be a number from 1 to {}",
+                        config.max_results_per_query
+                    ),
+                ))
+            }
+        },
+    };
+
+    let fields = params
+        .get("fields")
+        .map(|fields| fields.split(',').map(str::trim).collect::<Vec<_>>());
+
+    let mut options = super::search_options(&params, &config);
+    options.include_snippet = fields.as_ref().is_none_or(|fields| {
+        fields.contains(&"snippet") || fields.contains(&"description_snippet")
+    });
+
+    let (mut results, timings) = indexer
+        .search_timed(&query, num_results, &options)
+        .map_err(|e| {
+            eprintln!("ERROR: Search error for '{query}': {e}");
+            if let Some(e) = e.downcast_ref::<InvalidQuery>() {
+                (StatusCode::BAD_REQUEST, e.to_string())
+            } else {
+                (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "An error occurred while searching".to_string(),
+                )
+            }
+        })?;
+    let groups =
+        super::group_by_tld_requested(&params, &config).then(|| super::group_by_tld(&mut results));
+    let domains = indexer
+        .domain_counts(&query, config.domain_facets, &options)
+        .map_err(|e| {
+            eprintln!("ERROR: Domain facets error for '{query}': {e}");
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "An error occurred while searching".to_string(),
+            )
+        })?;
+
+    let results = results
+        .iter()
+        .map(|result| {
+            let Ok(Value::Object(mut result)) = serde_json::to_value(result) else {
+                unreachable!("search results serialize to JSON objects");
+            };
+            if let Some(fields) = &fields {
+                result.retain(|field, _| fields.contains(&field.as_str()));
+            }
+            result
+        })
+        .collect();
+
+    Ok(Json(SearchResponse {
+        query,
+        results,
+        groups,
+        timings: config.search_timings.then_some(timings),
+        domains,
+    }))
+}
+
+#[derive(Deserialize)]
+pub struct CrawlRequest {
+    domain: String,
+}
+
+#[derive(Serialize)]
+pub struct CrawlJob {
+    job_id: u64,
+    domain: String,
+}
+
+/// Starts crawling a domain in the background, using the configured crawl limits. Progress is
+/// reported by the crawl status endpoint.
+pub async fn crawl_handler(
+    Extension(ServerState {
+        indexer_tx,
+        crawl_status,
+        config,
+        crawler_config,
+        ..
+    }): Extension<ServerState>,
+    Json(CrawlRequest { domain }): Json<CrawlRequest>,
+) -> Result<(StatusCode, Json<CrawlJob>), (StatusCode, String)> {
+    if !config.enable_crawl_api {
+        return Err((
+            StatusCode::FORBIDDEN,
+            "The crawl API is disabled".to_string(),
+        ));
+    }
+
+    let domain = domain.trim().to_lowercase();
+    if domain.is_empty() {
+        return Err((StatusCode::BAD_REQUEST, "Missing domain".to_string()));
+    }
+    if !is_valid_hostname(&domain) {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!("'{domain}' is not a valid hostname, expected e.g. example.com"),
+        ));
+    }
+
+    match crawler::spawn_domain_crawl(domain.clone(), indexer_tx, &crawler_config, crawl_status) {
+        Some(job_id) => Ok((StatusCode::ACCEPTED, Json(CrawlJob { job_id, domain }))),
+        None => Err((
+            StatusCode::CONFLICT,
+            format!("{domain} is already being crawled"),
+        )),
+    }
+}
+
+/// Whether `domain` is a public hostname like `example.com`: dot-separated labels of letters,
+/// digits and inner hyphens. Single labels like `localhost`, IP addresses, ports, paths and URLs
+/// are rejected, so that the crawl API can't be pointed at internal hosts.
+fn is_valid_hostname(domain: &str) -> bool {
+    let labels: Vec<_> = domain.split('.').collect();
+    let is_valid_label = |label: &&str| {
+        (1..=63).contains(&label.len())
+            && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+            && !label.starts_with('-')
+            && !label.ends_with('-')
+    };
+    domain.len() <= 253
+        && labels.len() >= 2
+        && labels.iter().all(is_valid_label)
+        // Top-level domains are never numeric, IPv4 addresses end in a number.
+        && !labels[labels.len() - 1]
+            .chars()
+            .all(|c| c.is_ascii_digit())
+}
+
+pub async fn crawl_status_handler(
+    Extension(ServerState { crawl_status, .. }): Extension<ServerState>,
+) -> Json<CrawlStatus> {
+    let status = crawl_status.read().unwrap().clone();
+    Json(status)
+}
+
+/// Returns the recorded crawls of the domains file, newest first.
+pub async fn crawl_history_handler(
+    Extension(ServerState { indexer, .. }): Extension<ServerState>,
+) -> Result<Json<Vec<CrawlSummary>>, (StatusCode, String)> {
+    indexer.crawl_history().map(Json).map_err(|e| {
+        eprintln!("ERROR: Could not read crawl history: {e}");
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "An error occurred while reading the crawl history".to_string(),
+        )
+    })
+}
+
+/// Returns the recorded result clicks per query, most clicked queries first.
+pub async fn click_stats_handler(
+    Extension(ServerState {
+        indexer, config, ..
+    }): Extension<ServerState>,
+) -> Result<Json<Vec<ClickStats>>, (StatusCode, String)> {
+    if !config.click_tracking {
+        return Err((
+            StatusCode::FORBIDDEN,
+            "Click tracking is disabled".to_string(),
+        ));
+    }
+
+    indexer.get_click_stats().map(Json).map_err(|e| {
+        eprintln!("ERROR: Could not read click stats: {e}");
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "An error occurred while reading click stats".to_string(),
+        )
+    })
+}
+
+/// Returns aggregate index statistics, for dashboards that don't need per-domain stats.
+pub async fn summary_handler(
+    Extension(ServerState { indexer, .. }): Extension<ServerState>,
+) -> Result<Json<IndexSummary>, (StatusCode, String)> {
+    indexer.summary().map(Json).map_err(|e| {
+        eprintln!("ERROR: Could not get index summary: {e}");
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "An error occurred while reading index statistics".to_string(),
+        )
+    })
+}
+
+/// A line of the vocabulary export.
+#[derive(Serialize)]
+struct VocabularyTerm<'a> {
+    term: &'a str,
+    doc_freq: u64,
+}
+
+/// Exports the terms of an indexed field (`field`, by default `body`) with the number of documents
+/// containing them, as JSON lines in term order, e.g. for an external autocomplete service. Terms
+/// in fewer than `min_freq` documents are left out. It can't be lower than `vocabulary_min_freq`,
+/// which keeps the output manageable. The terms are read on a blocking thread and streamed as
+/// they're read.
+pub async fn vocabulary_handler(
+    Query(params): Query<HashMap<String, String>>,
+    Extension(ServerState {
+        indexer, config, ..
+    }): Extension<ServerState>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    if !config.vocabulary_export {
+        return Err((
+            StatusCode::FORBIDDEN,
+            "The vocabulary export is disabled".to_string(),
+        ));
+    }
+
+    let field = params.get("field").map_or("body", String::as_str);
+    let min_freq = match params.get("min_freq") {
+        None => config.vocabulary_min_freq,
+        Some(min_freq) => match min_freq.parse::<u64>() {
+            Ok(min_freq) if min_freq >= config.vocabulary_min_freq => min_freq,
+            _ => {
+                return Err((
+                    StatusCode::BAD_REQUEST,
+                    format!(
+                        "min_freq must be a number of at least {}",
+                        config.vocabulary_min_freq
+                    ),
+                ))
+            }
+        },
+    };
+
+    // Lines are sent as they're read, so the reader waits while the client is slow, and stops once
+    // it's gone.
+    let (lines_tx, mut lines_rx) = mpsc::channel::<anyhow::Result<Vec<u8>>>(256);
+    let field = field.to_string();
+    tokio::task::spawn_blocking({
+        let field = field.clone();
+        move || {
+            let result = indexer.vocabulary(&field, min_freq, |term, doc_freq| {
+                let mut line = serde_json::to_vec(&VocabularyTerm { term, doc_freq })?;
+                line.push(b'\n');
+                lines_tx
+                    .blocking_send(Ok(line))
+                    .map_err(|_| anyhow::anyhow!("The client disconnected"))
+            });
+            if let Err(e) = result {
+                let _ = lines_tx.blocking_send(Err(e));
+            }
+        }
+    });
+
+    // An error before the first line, like an unknown field, can still be reported with a status.
+    let first_line = match lines_rx.recv().await {
+        Some(Err(e)) => {
+            eprintln!("ERROR: Could not export the vocabulary of '{field}': {e}");
+            return Err(match e.downcast_ref::<InvalidQuery>() {
+                Some(e) => (StatusCode::BAD_REQUEST, e.to_string()),
+                None => (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "An error occurred while reading the vocabulary".to_string(),
+                ),
+            });
+        }
+        first_line => first_line,
+    };
+    // Later errors can only cut the response short.
+    let other_lines = stream::unfold(lines_rx, move |mut lines_rx| {
+        let field = field.clone();
+        async move {
+            let line = lines_rx.recv().await?.inspect_err(|e| {
+                eprintln!("ERROR: Could not export the vocabulary of '{field}': {e}");
+            });
+            Some((line, lines_rx))
+        }
+    });
+    let lines = stream::iter(first_line).chain(other_lines);
+
+    Ok((
+        [(header::CONTENT_TYPE, "application/x-ndjson")],
+        Body::from_stream(lines),
+    ))
+}
+
+#[derive(Serialize)]
+pub struct SchemaResponse {
+    /// Field names, types and indexing options.
+    fields: Vec<FieldEntry>,
+    config: SchemaConfig,
+}
+
+/// Config values that are useful to clients. Paths and other deployment details are left out.
+#[derive(Serialize)]
+pub struct SchemaConfig {
+    results_per_query: usize,
+    tech_term_boost: f32,
+}
+
+/// Describes the index schema and relevant config, for debugging and client generation.
+pub async fn schema_handler(
+    Extension(ServerState {
+        indexer, config, ..
+    }): Extension<ServerState>,
+) -> Json<SchemaResponse> {
+    let fields = indexer
+        .schema()
+        .fields()
+        .map(|(_field, entry)| entry.clone())
+        .collect();
+
+    Json(SchemaResponse {
+        fields,
+        config: SchemaConfig {
+            results_per_query: config.results_per_query,
+            tech_term_boost: indexer.config().tech_term_boost,
+        },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::{header, Request};
+    use std::sync::Arc;
+    use tower::ServiceExt;
+
+    use crate::{
+        config::ServerConfig,
+        crawler::CrawlSummary,
+        routes::test_utils::{body_text, get, with_app, TestApp},
+    };
+
+    fn crawl_request(domain: &str) -> anyhow::Result<Request<String>> {
+        Ok(Request::builder()
+            .method("POST")
+            .uri("/api/crawl")
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(format!(r#"{{"domain":"{domain}"}}"#))?)
+    }
+
+    #[tokio::test]
+    async fn test_search_fields() {
+        with_app(
+            "test_search_fields",
+            |_config| {},
+            |TestApp { app, indexer, .. }| async move {
+                indexer.add_html(
+                    "https://example.com/test",
+                    "<title>Test</title><body>A test page</body>",
+                    "example.com",
+                )?;
+                indexer.commit_and_reload()?;
+
+                let response = get(&app, "/api/search?q=test&fields=title,url").await?;
+
+                assert_eq!(response.status(), 200);
+                let body = body_text(response).await?;
+                assert!(body.contains(r#""query":"test""#));
+                assert!(body.contains(r#""title":"Test""#));
+                assert!(body.contains(r#""url":"https://example.com/test""#));
+                assert!(!body.contains("snippet"));
+
+                Ok(())
+            },
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn test_group_by_tld() {
+        with_app(
+            "test_group_by_tld",
+            |config| config.server.tld_grouping = true,
+            |TestApp { app, indexer, .. }| async move {
+                for (url, domain) in [
+                    ("https://www.rust-lang.org/learn", "www.rust-lang.org"),
+                    ("https://mit.edu/rust", "mit.edu"),
+                    ("https://example.org/rust", "example.org"),
+                    ("http://127.0.0.1/rust", "127.0.0.1"),
+                ] {
+                    indexer.add_html(
+                        url,
+                        "<title>Rust</title><body>Rust programming</body>",
+                        domain,
+                    )?;
+                }
+                indexer.commit_and_reload()?;
+
+                let response = get(&app, "/api/search?q=rust&group=tld&fields=url,tld").await?;
+                assert_eq!(response.status(), 200);
+                let body: serde_json::Value = serde_json::from_str(&body_text(response).await?)?;
+
+                let results = body["results"].as_array().unwrap();
+                let tld_of = |url: &str| {
+                    results
+                        .iter()
+                        .find(|result| result["url"] == url)
+                        .map(|result| result["tld"].as_str().unwrap().to_string())
+                };
+                assert_eq!(tld_of("https://www.rust-lang.org/learn").unwrap(), "org");
+                assert_eq!(tld_of("https://mit.edu/rust").unwrap(), "edu");
+                assert_eq!(tld_of("https://example.org/rust").unwrap(), "org");
+                assert_eq!(tld_of("http://127.0.0.1/rust").unwrap(), "");
+
+                // Results of the same top-level domain are adjacent, in the order of the groups.
+                let groups = body["groups"].as_array().unwrap();
+                let mut expected_order = Vec::new();
+                for group in groups {
+                    let tld = group["tld"].as_str().unwrap();
+                    let count = group["count"].as_u64().unwrap() as usize;
+                    assert_eq!(count, if tld == "org" { 2 } else { 1 });
+                    expected_order.extend(std::iter::repeat_n(tld, count));
+                }
+                let order: Vec<_> = results
+                    .iter()
+                    .map(|result| result["tld"].as_str().unwrap())
+                    .collect();
+                assert_eq!(order, expected_order);
+                assert_eq!(groups.len(), 3);
+
+                Ok(())
+            },
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn test_search_num_results() {
+        with_app(
+            "test_search_num_results",
+            |_config| {},
+            |TestApp { app, indexer, .. }| async move {
+                for page in 0..3 {
+                    indexer.add_html(
+                        &format!("https://example.com/{page}"),
+                        "<title>Test</title><body>A test page</body>",
+                        "example.com",
+                    )?;
+                }
+                indexer.commit_and_reload()?;
+
+                let response = get(&app, "/api/search?q=test&n=2&fields=url").await?;
+                assert_eq!(response.status(), 200);
+                let body: serde_json::Value = serde_json::from_str(&body_text(response).await?)?;
+                assert_eq!(body["results"].as_array().unwrap().len(), 2);
+
+                // Over the limit of 20 in the test config.
+                for n in ["21", "0", "many"] {
+                    let response = get(&app, &format!("/api/search?q=test&n={n}")).await?;
+                    assert_eq!(response.status(), 400);
+                    assert_eq!(
+                        body_text(response).await?,
+                        "n must be a number from 1 to 20"
+                    );
+                }
+
+                Ok(())
+            },
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn test_search_timings() {
+        with_app(
+            "test_search_timings",
+            |config| config.server.search_timings = true,
+            |TestApp { app, indexer, .. }| async move {
+                indexer.add_html(
+                    "https://example.com/test",
+                    "<title>Test</title><body>A test page</body>",
+                    "example.com",
+                )?;
+                indexer.commit_and_reload()?;
+
+                let response = get(&app, "/api/search?q=test").await?;
+                assert_eq!(response.status(), 200);
+                let body: serde_json::Value = serde_json::from_str(&body_text(response).await?)?;
+                let timings = &body["timings"];
+                let phases = ["parse_ms", "collect_ms", "snippets_ms"]
+                    .iter()
+                    .map(|phase| timings[phase].as_f64().unwrap())
+                    .collect::<Vec<_>>();
+                assert!(phases.iter().all(|phase| *phase >= 0.0));
+                // The total is measured separately, so it's about the sum of the phases.
+                let total = timings["total_ms"].as_f64().unwrap();
+                let sum: f64 = phases.iter().sum();
+                assert!(sum <= total + 0.01);
+                assert!(total - sum < 100.0);
+
+                Ok(())
+            },
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn test_live_config() {
+        with_app(
+            "test_live_config",
+            |_config| {},
+            |TestApp {
+                 app,
+                 indexer,
+                 config,
+                 live_config,
+                 ..
+             }| async move {
+                for i in 0..3 {
+                    indexer.add_html(
+                        &format!("https://example.com/{i}"),
+                        "<title>Test</title><body>A test page</body>",
+                        "example.com",
+                    )?;
+                }
+                indexer.commit_and_reload()?;
+
+                let num_results = || async {
+                    let response = get(&app, "/api/search?q=test").await?;
+                    let body: serde_json::Value =
+                        serde_json::from_str(&body_text(response).await?)?;
+                    anyhow::Ok(body["results"].as_array().unwrap().len())
+                };
+                assert_eq!(num_results().await?, 3);
+
+                live_config.store(Arc::new(ServerConfig {
+                    results_per_query: 2,
+                    ..config.server.clone()
+                }));
+                assert_eq!(num_results().await?, 2);
+
+                Ok(())
+            },
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn test_oversized_body() {
+        with_app(
+            "test_oversized_body",
+            |config| config.server.enable_crawl_api = true,
+            |TestApp { app, .. }| async move {
+                let response = app.oneshot(crawl_request(&"a".repeat(2000))?).await?;
+                assert_eq!(response.status(), 413);
+
+                Ok(())
+            },
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn test_schema() {
+        with_app(
+            "test_schema",
+            |_config| {},
+            |TestApp { app, .. }| async move {
+                let response = get(&app, "/api/schema").await?;
+
+                assert_eq!(response.status(), 200);
+                let body: serde_json::Value = serde_json::from_str(&body_text(response).await?)?;
+                let field_names: Vec<_> = body["fields"]
+                    .as_array()
+                    .unwrap()
+                    .iter()
+                    .map(|field| field["name"].as_str().unwrap())
+                    .collect();
+                for name in ["title", "description", "body", "url", "domain", "size"] {
+                    assert!(field_names.contains(&name), "missing field {name}");
+                }
+                assert_eq!(body["config"]["results_per_query"], 10);
+                assert!(!body.to_string().contains("test_files"));
+
+                Ok(())
+            },
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn test_vocabulary() {
+        with_app(
+            "test_vocabulary",
+            |config| {
+                config.server.vocabulary_export = true;
+                config.server.vocabulary_min_freq = 2;
+            },
+            |TestApp { app, indexer, .. }| async move {
+                for (page, body) in [("rust", "Rust ownership"), ("go", "Go ownership")] {
+                    indexer.add_html(
+                        &format!("https://example.com/{page}"),
+                        &format!("<title>{page}</title><body>{body}</body>"),
+                        "example.com",
+                    )?;
+                }
+                indexer.commit_and_reload()?;
+
+                let response = get(&app, "/api/vocabulary?field=body").await?;
+                assert_eq!(response.status(), 200);
+                assert_eq!(
+                    response.headers()[header::CONTENT_TYPE],
+                    "application/x-ndjson"
+                );
+                let terms: Vec<serde_json::Value> = body_text(response)
+                    .await?
+                    .lines()
+                    .map(serde_json::from_str)
+                    .collect::<Result<_, _>>()?;
+                // Only "ownership" is in both pages.
+                assert_eq!(
+                    terms,
+                    [serde_json::json!({"term": "ownership", "doc_freq": 2})]
+                );
+
+                let response = get(&app, "/api/vocabulary?field=title&min_freq=3").await?;
+                assert_eq!(response.status(), 200);
+                assert!(body_text(response).await?.is_empty());
+
+                // More lines than fit in the channel at once are all streamed.
+                let words: Vec<_> = (0..1000).map(|i| format!("word{i}")).collect();
+                for page in ["a", "b"] {
+                    indexer.add_html(
+                        &format!("https://example.com/{page}"),
+                        &format!("<title>{page}</title><body>{}</body>", words.join(" ")),
+                        "example.com",
+                    )?;
+                }
+                indexer.commit_and_reload()?;
+                let response = get(&app, "/api/vocabulary?field=body").await?;
+                assert_eq!(body_text(response).await?.lines().count(), 1001);
+
+                // Below the configured minimum, or not an indexed field.
+                let response = get(&app, "/api/vocabulary?min_freq=1").await?;
+                assert_eq!(response.status(), 400);
+                let response = get(&app, "/api/vocabulary?field=raw_html").await?;
+                assert_eq!(response.status(), 400);
+
+                Ok(())
+            },
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn test_crawl_history() {
+        with_app(
+            "test_crawl_history",
+            |_config| {},
+            |TestApp { app, indexer, .. }| async move {
+                let crawl = |started_at, pages_crawled| CrawlSummary {
+                    started_at,
+                    duration_secs: 1.5,
+                    pages_crawled,
+                    domains_crawled: 1,
+                    domains_failed: 0,
+                    time_limited: false,
+                };
+                // Only the last two crawls are kept, and the crawls of the same second are told
+                // apart.
+                for (started_at, pages_crawled) in [(100, 1), (200, 2), (200, 3)] {
+                    indexer.record_crawl(&crawl(started_at, pages_crawled), 2)?;
+                }
+
+                let response = get(&app, "/api/crawls").await?;
+                assert_eq!(response.status(), 200);
+                let crawls: Vec<CrawlSummary> = serde_json::from_str(&body_text(response).await?)?;
+                assert_eq!(crawls, [crawl(200, 3), crawl(200, 2)]);
+
+                Ok(())
+            },
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn test_summary() {
+        with_app(
+            "test_summary",
+            |_config| {},
+            |TestApp { app, indexer, .. }| async move {
+                indexer.add_html(
+                    "https://example.com/rust",
+                    "<title>Rust</title><body>Rust ownership</body>",
+                    "example.com",
+                )?;
+                indexer.commit_and_reload()?;
+
+                let response = get(&app, "/api/summary").await?;
+
+                assert_eq!(response.status(), 200);
+                let body: serde_json::Value = serde_json::from_str(&body_text(response).await?)?;
+                assert_eq!(body["num_docs"], 1);
+                assert_eq!(body["num_domains"], 1);
+                assert_eq!(body["total_size_bytes"], "Rust ownership".len());
+                assert!(body["index_size_bytes"].as_u64().unwrap() > 0);
+                assert!(body["num_segments"].as_u64().unwrap() >= 1);
+                assert!(body["last_indexed_at"].as_u64().unwrap() > 0);
+
+                Ok(())
+            },
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn test_crawl_status_in_progress() {
+        with_app(
+            "test_crawl_status_in_progress",
+            |_config| {},
+            |TestApp {
+                 app, crawl_status, ..
+             }| async move {
+                {
+                    let mut status = crawl_status.write().unwrap();
+                    status.start_crawl();
+                    status.start_domain("example.com");
+                }
+
+                let response = get(&app, "/api/crawl-status").await?;
+
+                assert_eq!(response.status(), 200);
+                let body = body_text(response).await?;
+                assert!(body.contains(r#""state":"crawling""#));
+                assert!(body.contains(r#""example.com":0"#));
+
+                Ok(())
+            },
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn test_crawl_enqueues_domain() {
+        with_app(
+            "test_crawl_enqueues_domain",
+            |config| config.server.enable_crawl_api = true,
+            |TestApp {
+                 app, crawl_status, ..
+             }| async move {
+                let response = app.oneshot(crawl_request("example.invalid")?).await?;
+
+                assert_eq!(response.status(), 202);
+                let body = body_text(response).await?;
+                assert!(body.contains(r#""job_id":1"#));
+                assert!(body.contains(r#""domain":"example.invalid""#));
+
+                // The crawl was registered; it may already have finished in the background. The
+                // `.invalid` TLD never resolves, so nothing is fetched.
+                let status = crawl_status.read().unwrap();
+                assert!(
+                    status.domains_in_progress.contains_key("example.invalid")
+                        || status.on_demand_domains_finished == 1
+                );
+                // It's not counted as part of a crawl of the domains file.
+                assert_eq!(status.domains_finished, 0);
+                assert_eq!(status.pages_crawled, 0);
+
+                Ok(())
+            },
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn test_crawl_rejects_invalid_domain() {
+        with_app(
+            "test_crawl_rejects_invalid_domain",
+            |config| config.server.enable_crawl_api = true,
+            |TestApp {
+                 app, crawl_status, ..
+             }| async move {
+                for domain in [
+                    "localhost",
+                    "127.0.0.1",
+                    "example.com:8080",
+                    "example.com/path",
+                    "https://example.com",
+                    "-example.com",
+                    "example..com",
+                    "exa mple.com",
+                ] {
+                    let response = app.clone().oneshot(crawl_request(domain)?).await?;
+                    assert_eq!(response.status(), 400, "{domain}");
+                }
+                assert!(crawl_status.read().unwrap().domains_in_progress.is_empty());
+
+                Ok(())
+            },
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn test_crawl_rejects_domain_in_progress() {
+        with_app(
+            "test_crawl_rejects_domain_in_progress",
+            |config| config.server.enable_crawl_api = true,
+            |TestApp {
+                 app, crawl_status, ..
+             }| async move {
+                crawl_status.write().unwrap().start_domain("example.com");
+
+                let response = app.oneshot(crawl_request("example.com")?).await?;
+                assert_eq!(response.status(), 409);
+
+                Ok(())
+            },
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn test_crawl_disabled() {
+        with_app(
+            "test_crawl_disabled",
+            |_config| {},
+            |TestApp { app, .. }| async move {
+                let response = app.oneshot(crawl_request("example.com")?).await?;
+                assert_eq!(response.status(), 403);
+
+                Ok(())
+            },
+        )
+        .await;
+    }
+}
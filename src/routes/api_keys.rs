@@ -0,0 +1,108 @@
+use axum::{
+    extract::Request,
+    http::{header, HeaderMap, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+
+use super::ServerState;
+
+/// Requires a valid API key with quota left for `/api/*` requests, if `api_keys` are configured.
+/// The key is taken from the `X-API-Key` header or an `Authorization: Bearer` header.
+pub async fn api_key_auth(request: Request, next: Next) -> Response {
+    let Some(ServerState {
+        indexer, config, ..
+    }) = request.extensions().get::<ServerState>().cloned()
+    else {
+        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    };
+    if config.api_keys.is_empty() {
+        return next.run(request).await;
+    }
+
+    let Some(api_key) = api_key(request.headers()) else {
+        return (StatusCode::UNAUTHORIZED, "Missing API key").into_response();
+    };
+    let Some(&daily_quota) = config.api_keys.get(&api_key) else {
+        return (StatusCode::UNAUTHORIZED, "Invalid API key").into_response();
+    };
+
+    match indexer.use_api_quota(&api_key, daily_quota) {
+        Ok(true) => next.run(request).await,
+        Ok(false) => (
+            StatusCode::TOO_MANY_REQUESTS,
+            format!("Daily quota of {daily_quota} requests exceeded"),
+        )
+            .into_response(),
+        Err(e) => {
+            eprintln!("ERROR: Could not count API request: {e}");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+fn api_key(headers: &HeaderMap) -> Option<String> {
+    let header_value = |name| {
+        headers
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .map(str::trim)
+            .filter(|value| !value.is_empty())
+    };
+    header_value("x-api-key")
+        .or_else(|| header_value(header::AUTHORIZATION.as_str())?.strip_prefix("Bearer "))
+        .map(|key| key.trim().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        body::Body,
+        http::{header, Request},
+        Router,
+    };
+    use std::collections::HashMap;
+    use tower::ServiceExt;
+
+    use crate::routes::test_utils::{with_app, TestApp};
+
+    async fn status(app: &Router, uri: &str, headers: &[(&str, &str)]) -> anyhow::Result<u16> {
+        let mut request = Request::builder().uri(uri);
+        for (name, value) in headers {
+            request = request.header(*name, *value);
+        }
+        let response = app.clone().oneshot(request.body(Body::empty())?).await?;
+        Ok(response.status().as_u16())
+    }
+
+    #[tokio::test]
+    async fn test_api_key_auth() {
+        with_app(
+            "test_api_key_auth",
+            |config| config.server.api_keys = HashMap::from([("valid-key".to_string(), 2)]),
+            |TestApp { app, .. }| async move {
+                // Missing and invalid keys.
+                assert_eq!(status(&app, "/api/summary", &[]).await?, 401);
+                assert_eq!(
+                    status(&app, "/api/summary", &[("X-API-Key", "wrong-key")]).await?,
+                    401
+                );
+
+                // A valid key, with either header, until the quota is used up.
+                assert_eq!(
+                    status(&app, "/api/summary", &[("X-API-Key", "valid-key")]).await?,
+                    200
+                );
+                let bearer = [(header::AUTHORIZATION.as_str(), "Bearer valid-key")];
+                assert_eq!(status(&app, "/api/summary", &bearer).await?, 200);
+                assert_eq!(status(&app, "/api/summary", &bearer).await?, 429);
+
+                // HTML pages stay open.
+                assert_eq!(status(&app, "/about", &[]).await?, 200);
+
+                Ok(())
+            },
+        )
+        .await;
+    }
+}
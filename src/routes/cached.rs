@@ -0,0 +1,89 @@
+use axum::{
+    extract::Query,
+    http::StatusCode,
+    response::{Html, IntoResponse, Response},
+    Extension,
+};
+use std::collections::HashMap;
+use tera::Context;
+
+use super::ServerState;
+use crate::indexer::BLOCK_SEPARATOR;
+
+/// Renders the stored text of an indexed page, as it was when crawled.
+pub async fn cached_handler(
+    Query(params): Query<HashMap<String, String>>,
+    Extension(ServerState {
+        indexer,
+        config,
+        templates,
+        ..
+    }): Extension<ServerState>,
+) -> Response {
+    if !config.cached_pages {
+        return (StatusCode::FORBIDDEN, "Cached pages are disabled").into_response();
+    }
+
+    let Some(url) = params.get("url").filter(|url| !url.is_empty()) else {
+        return (StatusCode::BAD_REQUEST, "Missing URL").into_response();
+    };
+
+    let page = match indexer.cached_page(url, &config.hidden_url_params) {
+        Ok(Some(page)) => page,
+        Ok(None) => return (StatusCode::NOT_FOUND, "No cached copy of this page").into_response(),
+        Err(e) => {
+            eprintln!("ERROR: Could not get cached page '{url}': {e}");
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+
+    let mut context = Context::new();
+    context.insert("title", &config.name);
+    context.insert("page", &page);
+    // With `preserve_paragraphs`, the blocks of the page are shown as paragraphs.
+    let paragraphs: Vec<_> = page.body.split(BLOCK_SEPARATOR).collect();
+    context.insert("paragraphs", &paragraphs);
+
+    match templates.render("cached.html", &context) {
+        Ok(html) => Html(html).into_response(),
+        Err(e) => {
+            eprintln!("Template error: {e}");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::routes::test_utils::{body_text, get, with_app, TestApp};
+
+    #[tokio::test]
+    async fn test_cached_handler() {
+        with_app(
+            "test_cached_handler",
+            |config| config.server.cached_pages = true,
+            |TestApp { app, indexer, .. }| async move {
+                indexer.add_html(
+                    "https://example.com/rust",
+                    "<title>Rust Page</title><body>Rust <b>ownership</b> &lt;3</body>",
+                    "example.com",
+                )?;
+                indexer.commit_and_reload()?;
+
+                let response = get(&app, "/cached?url=https%3A%2F%2Fexample.com%2Frust").await?;
+                assert_eq!(response.status(), 200);
+                let body = body_text(response).await?;
+                assert!(body.contains("Rust Page"));
+                // The text is escaped, not rendered as HTML.
+                assert!(body.contains("Rust ownership &lt;3"));
+                assert!(body.contains("as it was crawled on 20"));
+
+                let response = get(&app, "/cached?url=https%3A%2F%2Fexample.com%2Fgo").await?;
+                assert_eq!(response.status(), 404);
+
+                Ok(())
+            },
+        )
+        .await;
+    }
+}
@@ -0,0 +1,111 @@
+use axum::{
+    extract::Query,
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+    Extension,
+};
+use std::collections::HashMap;
+
+use super::ServerState;
+use crate::indexer::MAX_CLICK_QUERY_LEN;
+
+/// Records a click on a search result and redirects to it. Only indexed URLs are accepted, so
+/// that this can't be used as an open redirect.
+pub async fn click_handler(
+    Query(params): Query<HashMap<String, String>>,
+    Extension(ServerState {
+        indexer, config, ..
+    }): Extension<ServerState>,
+) -> Response {
+    if !config.click_tracking {
+        return (StatusCode::FORBIDDEN, "Click tracking is disabled").into_response();
+    }
+
+    let Some(url) = params.get("url").filter(|url| !url.is_empty()) else {
+        return (StatusCode::BAD_REQUEST, "Missing URL").into_response();
+    };
+    let query = params.get("q").cloned().unwrap_or_default();
+    if query.len() > MAX_CLICK_QUERY_LEN {
+        return (StatusCode::BAD_REQUEST, "Query too long").into_response();
+    }
+    let Some(position) = params
+        .get("pos")
+        .and_then(|pos| pos.parse::<u64>().ok())
+        .filter(|&pos| pos > 0)
+    else {
+        return (StatusCode::BAD_REQUEST, "Missing or invalid position").into_response();
+    };
+
+    match indexer.is_indexed_url(url, &config.hidden_url_params) {
+        Ok(true) => {}
+        Ok(false) => return (StatusCode::BAD_REQUEST, "Unknown URL").into_response(),
+        Err(e) => {
+            eprintln!("ERROR: Could not look up clicked URL '{url}': {e}");
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    }
+
+    // Recording is best-effort, the user should get to the page regardless.
+    if let Err(e) = indexer.record_click(&query, url, position) {
+        eprintln!("ERROR: Could not record click on '{url}': {e}");
+    }
+
+    (StatusCode::FOUND, [(header::LOCATION, url.clone())]).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::header;
+
+    use crate::routes::test_utils::{get, with_app, TestApp};
+
+    #[tokio::test]
+    async fn test_click_handler() {
+        with_app(
+            "test_click_handler",
+            |config| config.server.click_tracking = true,
+            |TestApp { app, indexer, .. }| async move {
+                indexer.add_html(
+                    "https://example.com/rust",
+                    "<title>Rust Page</title><body>Rust</body>",
+                    "example.com",
+                )?;
+                indexer.commit_and_reload()?;
+
+                let click = |url: &str| format!("/click?url={url}&q=Rust&pos=2");
+
+                // URLs that aren't indexed are rejected.
+                let response = get(&app, &click("https%3A%2F%2Fevil.example%2F")).await?;
+                assert_eq!(response.status(), 400);
+                assert!(response.headers().get(header::LOCATION).is_none());
+
+                // So are clicks without a position, or with an overly long query.
+                let url = "https%3A%2F%2Fexample.com%2Frust";
+                for uri in [
+                    format!("/click?url={url}&q=Rust"),
+                    format!("/click?url={url}&q=Rust&pos=0"),
+                    format!("/click?url={url}&q={}&pos=1", "a".repeat(201)),
+                ] {
+                    assert_eq!(get(&app, &uri).await?.status(), 400);
+                }
+
+                let response = get(&app, &click(url)).await?;
+                assert_eq!(response.status(), 302);
+                assert_eq!(
+                    response.headers()[header::LOCATION],
+                    "https://example.com/rust"
+                );
+
+                let stats = indexer.get_click_stats()?;
+                assert_eq!(stats.len(), 1);
+                assert_eq!(stats[0].query, "rust");
+                assert_eq!(stats[0].results[0].url, "https://example.com/rust");
+                assert_eq!(stats[0].results[0].clicks, 1);
+                assert_eq!(stats[0].results[0].average_position, 2.0);
+
+                Ok(())
+            },
+        )
+        .await;
+    }
+}
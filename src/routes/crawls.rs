@@ -0,0 +1,73 @@
+use axum::{response::Html, Extension};
+use tera::Context;
+
+use super::ServerState;
+
+/// Renders the crawl history, newest crawl first.
+pub async fn crawls_handler(
+    Extension(ServerState {
+        indexer,
+        config,
+        templates,
+        ..
+    }): Extension<ServerState>,
+) -> Html<String> {
+    let mut context = Context::new();
+    context.insert("title", &config.name);
+
+    match indexer.crawl_history() {
+        Ok(crawls) => context.insert("crawls", &crawls),
+        Err(e) => {
+            eprintln!("ERROR: Failed to get crawl history: {e}");
+            context.insert("error", "Failed to get the crawl history");
+        }
+    }
+
+    Html(
+        templates
+            .render("crawls.html", &context)
+            .unwrap_or_else(|e| {
+                eprintln!("Template error: {e}");
+                "Template error".to_string()
+            }),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        crawler::CrawlSummary,
+        routes::test_utils::{body_text, get, with_app, TestApp},
+    };
+
+    #[tokio::test]
+    async fn test_crawls_handler() {
+        with_app(
+            "test_crawls_handler",
+            |_config| {},
+            |TestApp { app, indexer, .. }| async move {
+                indexer.record_crawl(
+                    &CrawlSummary {
+                        started_at: 1_700_000_000,
+                        duration_secs: 61.7,
+                        pages_crawled: 1234,
+                        domains_crawled: 5,
+                        domains_failed: 2,
+                        time_limited: true,
+                    },
+                    10,
+                )?;
+
+                let response = get(&app, "/crawls").await?;
+                assert_eq!(response.status(), 200);
+                let body = body_text(response).await?;
+                assert!(body.contains("2023-11-14 22:13 UTC"));
+                assert!(body.contains("62s (time limit)"));
+                assert!(body.contains("<td>1234</td>"));
+
+                Ok(())
+            },
+        )
+        .await;
+    }
+}
@@ -0,0 +1,174 @@
+use atom_syndication::{Entry, Feed, FixedDateTime, Link, Text};
+use axum::{
+    extract::Query,
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    Extension,
+};
+use chrono::DateTime;
+use std::collections::HashMap;
+
+use super::ServerState;
+use crate::indexer::InvalidQuery;
+
+/// Serves the top results for a query as an Atom feed, newest crawl first, so that a query can be
+/// followed in a feed reader.
+pub async fn feed_handler(
+    Query(params): Query<Vec<(String, String)>>,
+    headers: HeaderMap,
+    Extension(ServerState {
+        indexer, config, ..
+    }): Extension<ServerState>,
+) -> Response {
+    if !config.feeds {
+        return (StatusCode::FORBIDDEN, "Feeds are disabled").into_response();
+    }
+
+    let query = super::query_param(&params);
+    let params: HashMap<_, _> = params.into_iter().collect();
+    if query.is_empty() {
+        return (StatusCode::BAD_REQUEST, "Missing query").into_response();
+    }
+
+    let options = super::search_options(&params, &config);
+    let mut results = match indexer.search(&query, config.results_per_query, &options) {
+        Ok(results) => results,
+        Err(e) => {
+            eprintln!("ERROR: Search error for feed '{query}': {e}");
+            return match e.downcast_ref::<InvalidQuery>() {
+                Some(e) => (StatusCode::BAD_REQUEST, e.to_string()).into_response(),
+                None => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+            };
+        }
+    };
+    // Stable, so results crawled at the same time keep their ranking.
+    results.sort_by_key(|result| std::cmp::Reverse(result.crawled_at));
+
+    let host = headers
+        .get(header::HOST)
+        .and_then(|host| host.to_str().ok())
+        .unwrap_or("localhost");
+    let feed_url = format!("http://{host}/feed?q={}", percent_encode(&query));
+
+    let entries: Vec<Entry> = results
+        .iter()
+        .map(|result| Entry {
+            title: Text::plain(&result.title),
+            id: result.url.clone(),
+            updated: timestamp(result.crawled_at),
+            links: vec![Link {
+                href: result.url.clone(),
+                ..Default::default()
+            }],
+            summary: Some(Text::html(&result.snippet)),
+            ..Default::default()
+        })
+        .collect();
+
+    let feed = Feed {
+        title: Text::plain(format!("{}: {query}", config.name)),
+        id: feed_url.clone(),
+        updated: timestamp(results.first().map_or(0, |result| result.crawled_at)),
+        links: vec![Link {
+            href: feed_url,
+            rel: "self".to_string(),
+            ..Default::default()
+        }],
+        entries,
+        ..Default::default()
+    };
+
+    (
+        [
+            (
+                header::CONTENT_TYPE,
+                "application/atom+xml; charset=utf-8".to_string(),
+            ),
+            (
+                header::CACHE_CONTROL,
+                format!("public, max-age={}", config.feed_ttl_secs),
+            ),
+        ],
+        feed.to_string(),
+    )
+        .into_response()
+}
+
+fn timestamp(secs: u64) -> FixedDateTime {
+    DateTime::from_timestamp(secs as i64, 0)
+        .unwrap_or_default()
+        .fixed_offset()
+}
+
+/// Percent-encodes everything but unreserved characters, for use in a query parameter.
+fn percent_encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (byte as char).to_string()
+            }
+            _ => format!("%{byte:02X}"),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        body::Body,
+        http::{header, Request},
+    };
+    use tower::ServiceExt;
+
+    use crate::routes::test_utils::{body_text, with_app, TestApp};
+
+    #[tokio::test]
+    async fn test_feed_handler() {
+        with_app(
+            "test_feed_handler",
+            |config| config.server.feeds = true,
+            |TestApp { app, indexer, .. }| async move {
+                for page in ["ownership", "borrowing", "lifetimes"] {
+                    indexer.add_html(
+                        &format!("https://example.com/{page}"),
+                        &format!("<title>Rust {page}</title><body>Rust {page}</body>"),
+                        "example.com",
+                    )?;
+                }
+                indexer.commit_and_reload()?;
+
+                let response = app
+                    .oneshot(
+                        Request::builder()
+                            .uri("/feed?q=rust")
+                            .header(header::HOST, "search.example")
+                            .body(Body::empty())?,
+                    )
+                    .await?;
+                assert_eq!(response.status(), 200);
+                assert_eq!(
+                    response.headers()[header::CONTENT_TYPE],
+                    "application/atom+xml; charset=utf-8"
+                );
+                assert_eq!(
+                    response.headers()[header::CACHE_CONTROL],
+                    "public, max-age=3600"
+                );
+
+                let feed: atom_syndication::Feed = body_text(response).await?.parse()?;
+                assert_eq!(feed.id, "http://search.example/feed?q=rust");
+                assert_eq!(feed.entries.len(), 3);
+                for page in ["ownership", "borrowing", "lifetimes"] {
+                    let url = format!("https://example.com/{page}");
+                    assert!(feed.entries.iter().any(|entry| entry.id == url
+                        && entry.links[0].href == url
+                        && entry.title.value == format!("Rust {page}")));
+                }
+
+                Ok(())
+            },
+        )
+        .await;
+    }
+}
@@ -1,38 +1,89 @@
 use axum::{extract::Query, response::Html, Extension};
+use axum_extra::extract::cookie::{Cookie, CookieJar, SameSite};
 use std::{collections::HashMap, time::Instant};
 use tera::Context;
 
 use super::ServerState;
+use crate::{
+    config::EmptyQueryBehavior,
+    indexer::{BrowseOrder, InvalidQuery},
+};
+
+/// Cookie with the user's recent searches, as a JSON array with the most recent first.
+const RECENT_SEARCHES_COOKIE: &str = "recent_searches";
 
 pub async fn index_handler(
-    Query(params): Query<HashMap<String, String>>,
-    Extension(ServerState { indexer, config }): Extension<ServerState>,
-) -> Html<String> {
+    Query(params): Query<Vec<(String, String)>>,
+    mut jar: CookieJar,
+    Extension(ServerState {
+        indexer,
+        config,
+        templates,
+        ..
+    }): Extension<ServerState>,
+) -> (CookieJar, Html<String>) {
     let mut context = Context::new();
     context.insert("title", &config.name);
+    context.insert("click_tracking", &config.click_tracking);
+    context.insert("cached_pages", &config.cached_pages);
+    context.insert("thumbnails", &config.thumbnails);
+    context.insert("tld_grouping", &config.tld_grouping);
+    context.insert("show_result_count", &config.show_result_count);
 
-    let query = params
-        .iter()
-        .filter_map(|(k, v)| if k == "q" { Some(v.clone()) } else { None })
-        .collect::<Vec<_>>()
-        .join("");
+    let query = super::query_param(&params);
+    let params: HashMap<_, _> = params.into_iter().collect();
 
     if !query.is_empty() {
         context.insert("query", &query);
 
         let start = Instant::now();
-        let search_result = indexer.search(&query, config.results_per_query);
+        let options = super::search_options(&params, &config);
+
+        let search_result = indexer.search_timed(&query, config.results_per_query, &options);
         let duration = start.elapsed();
 
         match search_result {
-            Ok(results) => {
+            Ok((mut results, timings)) => {
+                if config.search_timings {
+                    context.insert("timings", &timings);
+                }
+                if super::group_by_tld_requested(&params, &config) {
+                    context.insert("tld_groups", &super::group_by_tld(&mut results));
+                }
+                if config.recent_searches > 0 {
+                    let cookie = recent_searches_cookie(&jar, &query, config.recent_searches);
+                    jar = jar.add(cookie);
+                }
+
                 context.insert("results", &results);
                 context.insert("num_results", &results.len());
                 context.insert("duration", &format!("{duration:?}"));
+                if results.is_empty() {
+                    let unfiltered = without_filters(&query);
+                    if unfiltered != query && !unfiltered.is_empty() {
+                        context.insert("unfiltered_query", &unfiltered);
+                    }
+                    context.insert(
+                        "multiple_terms",
+                        &(unfiltered.split_whitespace().count() > 1),
+                    );
+                }
+
+                // Suggestions and facets are optional, so the page still works if they fail.
+                if !results.is_empty() {
+                    match indexer.related_searches(&query, config.related_searches, &options) {
+                        Ok(related) => context.insert("related_searches", &related),
+                        Err(e) => eprintln!("ERROR: Related searches error for '{query}': {e}"),
+                    }
+                    match indexer.domain_counts(&query, config.domain_facets, &options) {
+                        Ok(domains) => context.insert("domain_facets", &domains),
+                        Err(e) => eprintln!("ERROR: Domain facets error for '{query}': {e}"),
+                    }
+                }
             }
             Err(e) => {
                 eprintln!("ERROR: Search error for '{query}': {e}");
-                let error_msg = if e.to_string().contains("Query too long") {
+                let error_msg = if let Some(e) = e.downcast_ref::<InvalidQuery>() {
                     e.to_string()
                 } else {
                     "An error occurred while searching".to_string()
@@ -40,9 +91,31 @@ pub async fn index_handler(
                 context.insert("error", &error_msg);
             }
         }
+    } else {
+        if config.recent_searches > 0 {
+            context.insert("recent_searches", &recent_searches(&jar));
+        }
+
+        let browse = match config.empty_query_behavior {
+            EmptyQueryBehavior::None => None,
+            EmptyQueryBehavior::Recent => Some((BrowseOrder::Recent, "Recently crawled pages")),
+            EmptyQueryBehavior::Random => Some((BrowseOrder::Random, "Random pages")),
+        };
+
+        if let Some((order, heading)) = browse {
+            let options = super::search_options(&params, &config);
+            // Browsing is optional, so the page still works if it fails.
+            match indexer.browse(order, config.results_per_query, &options) {
+                Ok(results) => {
+                    context.insert("results", &results);
+                    context.insert("browse_heading", heading);
+                }
+                Err(e) => eprintln!("ERROR: Browse error: {e}"),
+            }
+        }
     }
 
-    let html = match super::TEMPLATES.render("index.html", &context) {
+    let html = match templates.render("index.html", &context) {
         Ok(html) => html,
         Err(e) => {
             eprintln!("Template error: {e}");
@@ -51,7 +124,7 @@ pub async fn index_handler(
             context.insert("title", &config.name);
             context.insert("error", "An internal error occurred");
 
-            super::TEMPLATES
+            templates
                 .render("index.html", &context)
                 .unwrap_or_else(|e| {
                     eprintln!("Critical template error: {e}");
@@ -59,99 +132,400 @@ pub async fn index_handler(
                 })
         }
     };
-    Html(html)
+    (jar, Html(html))
+}
+
+/// Removes `site:` and `type:` filters from a query, for suggesting a broader search.
+fn without_filters(query: &str) -> String {
+    query
+        .split_whitespace()
+        .filter(|word| !word.starts_with("site:") && !word.starts_with("type:"))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Reads the recent searches from the cookie, ignoring a malformed cookie.
+fn recent_searches(jar: &CookieJar) -> Vec<String> {
+    jar.get(RECENT_SEARCHES_COOKIE)
+        .and_then(|cookie| serde_json::from_str(cookie.value()).ok())
+        .unwrap_or_default()
+}
+
+/// Returns the recent searches cookie with `query` moved to the front, keeping at most `max`
+/// searches. Searches are only kept in the cookie, not on the server.
+fn recent_searches_cookie(jar: &CookieJar, query: &str, max: usize) -> Cookie<'static> {
+    let mut searches = recent_searches(jar);
+    searches.retain(|search| !search.eq_ignore_ascii_case(query));
+    searches.insert(0, query.to_string());
+    searches.truncate(max);
+
+    Cookie::build((
+        RECENT_SEARCHES_COOKIE,
+        serde_json::to_string(&searches).unwrap(),
+    ))
+    .path("/")
+    .http_only(true)
+    .same_site(SameSite::Lax)
+    .permanent()
+    .build()
 }
 
 #[cfg(test)]
 mod tests {
-    use axum::{body, http::Request, Router};
-    use std::sync::Arc;
+    use axum::{
+        body::Body,
+        http::{header, Request},
+    };
     use tower::ServiceExt;
 
     use crate::{
-        config::{Config, ServerConfig},
-        indexer::Indexer,
+        config::EmptyQueryBehavior,
+        routes::test_utils::{body_text, get, with_app, TestApp},
+        test_utils::TEST_DIR,
     };
 
-    async fn with_app<F, T>(test_name: &str, f: F)
-    where
-        F: FnOnce(Router, ServerConfig) -> T,
-        T: std::future::Future<Output = anyhow::Result<()>>,
-    {
-        let config = Config::load_test(test_name);
-        let indexer = Arc::new(Indexer::new(&config.indexer).await.unwrap());
-        let app = super::super::create_router(indexer.clone(), &config.server);
-
-        f(app, config.server).await.unwrap();
+    #[tokio::test]
+    async fn test_index_handler_no_query() {
+        with_app(
+            "test_index_handler_no_query",
+            |_config| {},
+            |TestApp { app, config, .. }| async move {
+                let response = get(&app, "/").await?;
+                assert_eq!(response.status(), 200);
+                let body = body_text(response).await?;
+                assert!(body.contains(&config.server.name));
+                assert!(!body.contains("results")); // No results section when no query
 
-        // Clean up after test.
-        indexer.delete().await.unwrap();
+                Ok(())
+            },
+        )
+        .await;
     }
 
     #[tokio::test]
-    async fn test_index_handler_no_query() {
-        with_app("test_index_handler_no_query", |app, config| {
-            async move {
-                let response = app
-                    .oneshot(Request::builder().uri("/").body("".to_string()).unwrap())
-                    .await?;
+    async fn test_index_handler_with_query() {
+        with_app(
+            "test_index_handler_with_query",
+            |_config| {},
+            |TestApp { app, config, .. }| async move {
+                let response = get(&app, "/?q=test").await?;
+                assert_eq!(response.status(), 200);
+                let body = body_text(response).await?;
+                assert!(body.contains(&config.server.name));
+                assert!(body.contains("query")); // Query should be shown
+
+                Ok(())
+            },
+        )
+        .await;
+    }
 
+    #[tokio::test]
+    async fn test_index_handler_no_results() {
+        with_app(
+            "test_index_handler_no_results",
+            |_config| {},
+            |TestApp { app, .. }| async move {
+                let response = get(&app, "/?q=zzyzx%20qwxv%20site%3Aexample.com").await?;
                 assert_eq!(response.status(), 200);
-                let body = String::from_utf8(
-                    body::to_bytes(response.into_body(), 10_000).await?.to_vec(),
+                let body = body_text(response).await?;
+                assert!(body.contains("No results found"));
+                assert!(body.contains("Check the spelling"));
+                assert!(body.contains("Try fewer"));
+                assert!(body.contains(r#"href="/?q=zzyzx%20qwxv""#));
+                assert!(!body.contains("Found 0 results"));
+
+                Ok(())
+            },
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn test_index_handler_theme() {
+        let theme_dir = format!("{TEST_DIR}/theme_test_index_handler_theme");
+        std::fs::create_dir_all(&theme_dir).unwrap();
+        std::fs::write(
+            format!("{theme_dir}/index.html"),
+            "<h1>Themed {{ title }}</h1>{% for result in results %}{{ result.url }}{% endfor %}",
+        )
+        .unwrap();
+
+        with_app(
+            "test_index_handler_theme",
+            |config| config.server.theme_dir = Some(theme_dir.clone()),
+            |TestApp { app, indexer, .. }| async move {
+                indexer.add_html(
+                    "https://example.com/",
+                    "<title>Test</title><body>Test</body>",
+                    "example.com",
                 )?;
-                assert!(body.contains(&config.name));
-                assert!(!body.contains("results")); // No results section when no query
+                indexer.commit_and_reload()?;
+
+                let response = get(&app, "/?q=test").await?;
+                assert_eq!(response.status(), 200);
+                assert_eq!(
+                    body_text(response).await?,
+                    "<h1>Themed test_server</h1>https://example.com/"
+                );
+                // Pages the theme doesn't have use the default templates.
+                let response = get(&app, "/about").await?;
+                assert_eq!(response.status(), 200);
+                assert!(body_text(response).await?.contains("About test_server"));
 
                 Ok(())
-            }
-        })
+            },
+        )
         .await;
+
+        std::fs::remove_dir_all(theme_dir).unwrap();
     }
 
     #[tokio::test]
-    async fn test_index_handler_with_query() {
-        with_app("test_index_handler_with_query", |app, config| {
-            async move {
-                let response = app
-                    .oneshot(Request::builder().uri("/?q=test").body("".to_string())?)
-                    .await?;
+    async fn test_index_handler_security_headers() {
+        with_app(
+            "test_index_handler_security_headers",
+            |_config| {},
+            |TestApp { app, config, .. }| async move {
+                let response = get(&app, "/").await?;
+                assert_eq!(response.status(), 200);
+                let headers = response.headers();
+                assert_eq!(
+                    headers[header::CONTENT_SECURITY_POLICY],
+                    config.server.content_security_policy.as_str()
+                );
+                assert_eq!(headers[header::X_CONTENT_TYPE_OPTIONS], "nosniff");
+                assert!(headers.contains_key(header::REFERRER_POLICY));
 
+                Ok(())
+            },
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn test_index_handler_multiple_queries() {
+        with_app(
+            "test_index_handler_multiple_queries",
+            |_config| {},
+            |TestApp { app, indexer, .. }| async move {
+                let pages = [
+                    (
+                        "https://example.com/rust",
+                        "<title>Rust Page</title><body>Rust</body>",
+                    ),
+                    (
+                        "https://example.com/go",
+                        "<title>Go Page</title><body>Golang</body>",
+                    ),
+                ];
+                for (url, html) in pages {
+                    indexer.add_html(url, html, "example.com")?;
+                }
+                indexer.commit_and_reload()?;
+
+                let response = get(&app, "/?q=rust&q=golang").await?;
                 assert_eq!(response.status(), 200);
-                let body = String::from_utf8(
-                    body::to_bytes(response.into_body(), 10_000).await?.to_vec(),
-                )?;
-                assert!(body.contains(&config.name));
-                assert!(body.contains("query")); // Query should be shown
+                let body = body_text(response).await?;
+                // Searches for "rust golang", not just one of them or "rustgolang".
+                assert!(body.contains("Found 2 results"));
+                assert!(body.contains("Rust Page"));
+                assert!(body.contains("Go Page"));
 
                 Ok(())
-            }
-        })
+            },
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn test_index_handler_related_searches() {
+        with_app(
+            "test_index_handler_related_searches",
+            |_config| {},
+            |TestApp { app, indexer, .. }| async move {
+                let pages = [
+                    ("rust1", "Rust ownership rules"),
+                    ("rust2", "Rust ownership and lifetimes"),
+                    ("python", "Python garbage collection"),
+                    ("go", "Go garbage collection"),
+                    ("css", "CSS grid layout"),
+                ];
+                for (path, body) in pages {
+                    indexer.add_html(
+                        &format!("https://example.com/{path}"),
+                        &format!("<title>Page</title><body>{body}</body>"),
+                        "example.com",
+                    )?;
+                }
+                indexer.commit_and_reload()?;
+
+                let response = get(&app, "/?q=rust").await?;
+                assert_eq!(response.status(), 200);
+                let body = body_text(response).await?;
+                assert!(body.contains("Related searches"));
+                assert!(body.contains(r#"<a href="/?q=rust%20ownership">rust ownership</a>"#));
+
+                Ok(())
+            },
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn test_index_handler_trusted_domains() {
+        with_app(
+            "test_index_handler_trusted_domains",
+            |config| config.server.trusted_domains = vec!["Rust-Lang.org".to_string()],
+            |TestApp { app, indexer, .. }| async move {
+                for (url, domain) in [
+                    ("https://doc.rust-lang.org/book", "doc.rust-lang.org"),
+                    ("https://notrust-lang.org/book", "notrust-lang.org"),
+                ] {
+                    indexer.add_html(url, "<title>Rust</title><body>Rust book</body>", domain)?;
+                }
+                indexer.commit_and_reload()?;
+
+                let response = get(&app, "/?q=rust").await?;
+                assert_eq!(response.status(), 200);
+                let body = body_text(response).await?;
+                assert_eq!(body.matches(r#"class="trusted-badge""#).count(), 1);
+
+                let response = get(&app, "/api/search?q=rust").await?;
+                assert_eq!(response.status(), 200);
+                let body: serde_json::Value = serde_json::from_str(&body_text(response).await?)?;
+                let trusted: Vec<_> = body["results"]
+                    .as_array()
+                    .unwrap()
+                    .iter()
+                    .map(|result| {
+                        (
+                            result["url"].as_str().unwrap(),
+                            result["is_trusted"].clone(),
+                        )
+                    })
+                    .collect();
+                assert!(trusted.contains(&("https://doc.rust-lang.org/book", true.into())));
+                assert!(trusted.contains(&("https://notrust-lang.org/book", false.into())));
+
+                Ok(())
+            },
+        )
         .await;
     }
 
     #[tokio::test]
     async fn test_index_handler_long_query() {
-        with_app("test_index_handler_long_query", |app, _config| {
-            async move {
+        with_app(
+            "test_index_handler_long_query",
+            |_config| {},
+            |TestApp { app, .. }| async move {
                 let long_query = "x".repeat(300);
+                let response = get(&app, &format!("/?q={long_query}")).await?;
+                assert_eq!(response.status(), 200);
+                let body = body_text(response).await?;
+                assert!(body.contains("Query too long")); // Should show error message
+
+                Ok(())
+            },
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn test_index_handler_empty_query_recent() {
+        with_app(
+            "test_index_handler_empty_query_recent",
+            |config| config.server.empty_query_behavior = EmptyQueryBehavior::Recent,
+            |TestApp { app, indexer, .. }| async move {
+                indexer.add_html(
+                    "https://example.com/rust",
+                    "<title>Rust Page</title><body>Rust</body>",
+                    "example.com",
+                )?;
+                indexer.commit_and_reload()?;
+
+                let response = get(&app, "/").await?;
+                assert_eq!(response.status(), 200);
+                let body = body_text(response).await?;
+                assert!(body.contains("Recently crawled pages"));
+                assert!(body.contains("Rust Page"));
+                assert!(body.contains("https://example.com/rust"));
+
+                Ok(())
+            },
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn test_index_handler_recent_searches() {
+        with_app(
+            "test_index_handler_recent_searches",
+            |config| config.server.recent_searches = 2,
+            |TestApp { app, .. }| async move {
+                let get_with_cookie = |uri: String, cookie: &str| {
+                    Request::builder()
+                        .uri(uri)
+                        .header(header::COOKIE, cookie)
+                        .body(Body::empty())
+                };
+
+                // Each search sends back the cookie with the query at the front.
+                let mut cookie = String::new();
+                for query in ["go", "rust", "zig", "Rust"] {
+                    let response = app
+                        .clone()
+                        .oneshot(get_with_cookie(format!("/?q={query}"), &cookie)?)
+                        .await?;
+                    let set_cookie = response.headers()[header::SET_COOKIE].to_str()?;
+                    assert!(set_cookie.contains("HttpOnly"));
+                    cookie = set_cookie.split(';').next().unwrap().to_string();
+                }
+
                 let response = app
-                    .oneshot(
-                        Request::builder()
-                            .uri(&format!("/?q={}", long_query))
-                            .body("".to_string())?,
-                    )
+                    .oneshot(get_with_cookie("/".to_string(), &cookie)?)
                     .await?;
+                assert!(response.headers().get(header::SET_COOKIE).is_none());
+                let body = body_text(response).await?;
+                assert!(body.contains("Recent searches"));
+                assert!(body.contains(r#"<a href="/?q=Rust">Rust</a>"#));
+                assert!(body.contains(r#"<a href="/?q=zig">zig</a>"#));
+                assert!(!body.contains("/?q=go"));
+                assert!(!body.contains("/?q=rust"));
 
-                assert_eq!(response.status(), 200);
-                let body = String::from_utf8(
-                    body::to_bytes(response.into_body(), 10_000).await?.to_vec(),
+                Ok(())
+            },
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn test_index_handler_description_snippet() {
+        with_app(
+            "test_index_handler_description_snippet",
+            |_config| {},
+            |TestApp { app, indexer, .. }| async move {
+                indexer.add_html(
+                    "https://example.com/rust",
+                    r#"<head>
+                        <title>Rust</title>
+                        <meta name="description" content="A clear guide to ownership in Rust">
+                    </head>
+                    <body>Some unrelated text. Later, the body mentions ownership once.</body>"#,
+                    "example.com",
                 )?;
-                assert!(body.contains("Query too long")); // Should show error message
+                indexer.commit_and_reload()?;
+
+                let response = get(&app, "/?q=ownership").await?;
+                assert_eq!(response.status(), 200);
+                let body = body_text(response).await?;
+                assert!(body.contains("A clear guide to <b>ownership</b> in Rust"));
+                assert!(!body.contains("the body mentions"));
 
                 Ok(())
-            }
-        })
+            },
+        )
         .await;
     }
 }
@@ -0,0 +1,147 @@
+use axum::{
+    extract::Query,
+    http::{header, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+    Extension,
+};
+use std::collections::HashMap;
+
+use super::ServerState;
+use crate::indexer::InvalidQuery;
+
+/// Runs a search and redirects straight to the top result, like "I'm Feeling Lucky". Queries
+/// without results get a message instead.
+pub async fn lucky_handler(
+    Query(params): Query<Vec<(String, String)>>,
+    Extension(ServerState {
+        indexer, config, ..
+    }): Extension<ServerState>,
+) -> Response {
+    if !config.lucky {
+        return (StatusCode::FORBIDDEN, "Feeling lucky is disabled").into_response();
+    }
+
+    let query = super::query_param(&params);
+    let params: HashMap<_, _> = params.into_iter().collect();
+    if query.is_empty() {
+        return (StatusCode::BAD_REQUEST, "Missing query").into_response();
+    }
+
+    let options = super::search_options(&params, &config);
+    let results = match indexer.search(&query, 1, &options) {
+        Ok(results) => results,
+        Err(e) => {
+            eprintln!("ERROR: Search error for lucky query '{query}': {e}");
+            return match e.downcast_ref::<InvalidQuery>() {
+                Some(e) => (StatusCode::BAD_REQUEST, e.to_string()).into_response(),
+                None => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+            };
+        }
+    };
+    let Some(result) = results.first() else {
+        return (
+            StatusCode::NOT_FOUND,
+            format!("No results for \"{query}\", try different words."),
+        )
+            .into_response();
+    };
+
+    // Only redirect to indexed web pages, so this can't be used as an open redirect.
+    let url = &result.url;
+    let location = match indexer.is_indexed_url(url, &config.hidden_url_params) {
+        Ok(true) => is_web_url(url)
+            .then(|| HeaderValue::from_str(url).ok())
+            .flatten(),
+        Ok(false) => None,
+        Err(e) => {
+            eprintln!("ERROR: Could not look up lucky URL '{url}': {e}");
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+    match location {
+        Some(location) => (StatusCode::FOUND, [(header::LOCATION, location)]).into_response(),
+        None => {
+            eprintln!("ERROR: Not redirecting to top result '{url}' for '{query}'");
+            (
+                StatusCode::BAD_GATEWAY,
+                "The top result can't be redirected to",
+            )
+                .into_response()
+        }
+    }
+}
+
+/// Whether `url` is an absolute `http(s)` URL with a host, and no whitespace or backslashes that
+/// browsers might interpret differently.
+fn is_web_url(url: &str) -> bool {
+    let lowercase = url.to_ascii_lowercase();
+    let Some(rest) = ["https://", "http://"]
+        .iter()
+        .find_map(|scheme| lowercase.strip_prefix(scheme))
+    else {
+        return false;
+    };
+    let host = rest.split(['/', '?', '#']).next().unwrap_or_default();
+    !host.is_empty() && !url.contains(|c: char| c.is_whitespace() || c.is_control() || c == '\\')
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::header;
+
+    use crate::routes::test_utils::{body_text, get, with_app, TestApp};
+
+    #[tokio::test]
+    async fn test_lucky_handler() {
+        with_app(
+            "test_lucky_handler",
+            |config| config.server.lucky = true,
+            |TestApp { app, indexer, .. }| async move {
+                for (url, body) in [
+                    (
+                        "https://example.com/ownership",
+                        "Rust ownership, Rust borrowing",
+                    ),
+                    ("https://example.com/async", "Rust async"),
+                ] {
+                    indexer.add_html(
+                        url,
+                        &format!("<title>Rust</title><body>{body}</body>"),
+                        "example.com",
+                    )?;
+                }
+                indexer.commit_and_reload()?;
+
+                let response = get(&app, "/lucky?q=rust+borrowing").await?;
+                assert_eq!(response.status(), 302);
+                assert_eq!(
+                    response.headers()[header::LOCATION],
+                    "https://example.com/ownership"
+                );
+
+                // No results get a message, not a redirect.
+                let response = get(&app, "/lucky?q=haskell").await?;
+                assert_eq!(response.status(), 404);
+                assert!(response.headers().get(header::LOCATION).is_none());
+                assert!(body_text(response)
+                    .await?
+                    .starts_with("No results for \"haskell\""));
+
+                assert_eq!(get(&app, "/lucky?q=").await?.status(), 400);
+
+                Ok(())
+            },
+        )
+        .await;
+    }
+
+    #[test]
+    fn test_is_web_url() {
+        assert!(super::is_web_url("https://example.com/rust?q=1"));
+        assert!(super::is_web_url("HTTP://example.com"));
+        assert!(!super::is_web_url("javascript:alert(1)"));
+        assert!(!super::is_web_url("https:///evil.example"));
+        assert!(!super::is_web_url("https://\\evil.example"));
+        assert!(!super::is_web_url("//evil.example"));
+    }
+}
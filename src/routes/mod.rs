@@ -1,19 +1,56 @@
+use arc_swap::ArcSwap;
 use axum::{
-    routing::{get, get_service},
-    Extension, Router,
+    extract::Request,
+    http::{header, HeaderValue, Response},
+    middleware::{self, Next},
+    routing::{get, get_service, post},
+    Router,
 };
-use std::sync::Arc;
+use serde::Serialize;
+use std::{collections::HashMap, sync::Arc, time::Duration};
 use tera::Tera;
-use tower_http::services::ServeDir;
+use tokio::sync::mpsc;
+use tower_http::{
+    limit::RequestBodyLimitLayer, services::ServeDir, set_header::SetResponseHeaderLayer,
+    timeout::TimeoutLayer,
+};
 
+mod about;
+mod api;
+mod api_keys;
+mod cached;
+mod click;
+mod crawls;
+mod feed;
 mod index;
+mod lucky;
+mod robots;
 mod stats;
+#[cfg(test)]
+mod test_utils;
 
-use crate::{config::ServerConfig, indexer::Indexer};
+use crate::{
+    config::{Config, CrawlerConfig, ServerConfig},
+    crawler::SharedCrawlStatus,
+    indexer::{Indexer, SearchOptions, SearchPage, SearchResult},
+};
+use about::about_handler;
+use api::{
+    click_stats_handler, crawl_handler, crawl_history_handler, crawl_status_handler,
+    schema_handler, search_handler, summary_handler, vocabulary_handler,
+};
+use api_keys::api_key_auth;
+use cached::cached_handler;
+use click::click_handler;
+use crawls::crawls_handler;
+use feed::feed_handler;
 use index::index_handler;
+use lucky::lucky_handler;
+use robots::robots_handler;
 use stats::stats_handler;
 
 lazy_static::lazy_static! {
+    /// The default templates, used for everything a theme doesn't override.
     static ref TEMPLATES: Tera = {
         let mut tera = match Tera::new("templates/**/*") {
             Ok(t) => t,
@@ -30,18 +67,206 @@ lazy_static::lazy_static! {
 #[derive(Clone)]
 struct ServerState {
     indexer: Arc<Indexer>,
-    config: ServerConfig,
+    indexer_tx: mpsc::Sender<SearchPage>,
+    crawl_status: SharedCrawlStatus,
+    /// The config at the start of the request.
+    config: Arc<ServerConfig>,
+    crawler_config: CrawlerConfig,
+    /// [`TEMPLATES`] with the templates of the configured theme, if any.
+    templates: Arc<Tera>,
+}
+
+/// Loads the templates of `theme_dir`, falling back to the default templates for any it doesn't
+/// have. If the theme can't be loaded, only the defaults are used.
+fn load_templates(theme_dir: Option<&str>) -> Tera {
+    let Some(theme_dir) = theme_dir else {
+        return TEMPLATES.clone();
+    };
+
+    let theme =
+        Tera::new(&format!("{}/**/*", theme_dir.trim_end_matches('/'))).and_then(|mut theme| {
+            theme.autoescape_on(vec![]); // Like the defaults
+            theme.extend(&TEMPLATES)?;
+            Ok(theme)
+        });
+    match theme {
+        Ok(theme) => theme,
+        Err(e) => {
+            eprintln!("ERROR: Could not load theme '{theme_dir}', using default templates: {e}");
+            TEMPLATES.clone()
+        }
+    }
 }
 
-pub fn create_router(indexer: Arc<Indexer>, config: &ServerConfig) -> Router {
+/// Server settings that can be replaced while the server is running, e.g. on SIGHUP.
+pub type LiveServerConfig = Arc<ArcSwap<ServerConfig>>;
+
+/// Creates the router. Requests use whatever is in `live_config` when they start. The request
+/// limits, `content_security_policy` and `theme_dir` are taken from `config` and can't be changed.
+pub fn create_reloadable_router(
+    indexer: Arc<Indexer>,
+    indexer_tx: mpsc::Sender<SearchPage>,
+    crawl_status: SharedCrawlStatus,
+    config: &Config,
+    live_config: LiveServerConfig,
+) -> Router {
+    // Snippets contain crawled HTML and autoescaping is off, so restrict what pages can load.
+    let content_security_policy = if config.server.content_security_policy.is_empty() {
+        None
+    } else {
+        match HeaderValue::from_str(&config.server.content_security_policy) {
+            Ok(value) => Some(value),
+            Err(e) => {
+                eprintln!("ERROR: Invalid content_security_policy, not sending it: {e}");
+                None
+            }
+        }
+    };
+
     let state = ServerState {
         indexer,
-        config: config.clone(),
+        indexer_tx,
+        crawl_status,
+        config: live_config.load_full(),
+        crawler_config: config.crawler.clone(),
+        templates: Arc::new(load_templates(config.server.theme_dir.as_deref())),
+    };
+    // Each request gets the state with the config current at that point.
+    let with_state = move |mut request: Request, next: Next| {
+        let state = ServerState {
+            config: live_config.load_full(),
+            ..state.clone()
+        };
+        async move {
+            request.extensions_mut().insert(state);
+            next.run(request).await
+        }
     };
 
     Router::new()
         .route("/", get(index_handler))
         .route("/stats", get(stats_handler))
+        .route("/crawls", get(crawls_handler))
+        .route("/about", get(about_handler))
+        .route("/robots.txt", get(robots_handler))
+        .route("/click", get(click_handler))
+        .route("/cached", get(cached_handler))
+        .route("/feed", get(feed_handler))
+        .route("/lucky", get(lucky_handler))
+        .merge(api_router())
         .nest_service("/assets", get_service(ServeDir::new("assets")))
-        .layer(Extension(state))
+        .layer(middleware::from_fn(with_state))
+        .layer(RequestBodyLimitLayer::new(
+            config.server.max_request_body_bytes,
+        ))
+        .layer(TimeoutLayer::new(Duration::from_millis(
+            config.server.request_timeout_ms,
+        )))
+        .layer(SetResponseHeaderLayer::if_not_present(
+            header::CONTENT_SECURITY_POLICY,
+            move |response: &Response<_>| {
+                is_html(response)
+                    .then(|| content_security_policy.clone())
+                    .flatten()
+            },
+        ))
+        .layer(SetResponseHeaderLayer::overriding(
+            header::X_CONTENT_TYPE_OPTIONS,
+            HeaderValue::from_static("nosniff"),
+        ))
+        .layer(SetResponseHeaderLayer::if_not_present(
+            header::REFERRER_POLICY,
+            HeaderValue::from_static("strict-origin-when-cross-origin"),
+        ))
+}
+
+/// The `/api/*` routes, which require an API key if `api_keys` are configured.
+fn api_router() -> Router {
+    Router::new()
+        .route("/api/search", get(search_handler))
+        .route("/api/crawl", post(crawl_handler))
+        .route("/api/crawl-status", get(crawl_status_handler))
+        .route("/api/crawls", get(crawl_history_handler))
+        .route("/api/schema", get(schema_handler))
+        .route("/api/clicks", get(click_stats_handler))
+        .route("/api/summary", get(summary_handler))
+        .route("/api/vocabulary", get(vocabulary_handler))
+        .route_layer(middleware::from_fn(api_key_auth))
+}
+
+fn is_html<B>(response: &Response<B>) -> bool {
+    response
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|content_type| content_type.to_str().ok())
+        .is_some_and(|content_type| content_type.starts_with("text/html"))
+}
+
+/// Returns the search query of a request. Multiple `q` parameters are joined with spaces, so
+/// `?q=foo&q=bar` searches for "foo bar".
+fn query_param(params: &[(String, String)]) -> String {
+    params
+        .iter()
+        .filter(|(key, value)| key == "q" && !value.is_empty())
+        .map(|(_key, value)| value.as_str())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// The results of one top-level domain, for `?group=tld`.
+#[derive(Debug, PartialEq, Serialize)]
+struct TldGroup {
+    /// Empty for results without a top-level domain, e.g. IP addresses.
+    tld: String,
+    count: usize,
+}
+
+/// Whether the request asks for results grouped by top-level domain and grouping is enabled.
+fn group_by_tld_requested(params: &HashMap<String, String>, config: &ServerConfig) -> bool {
+    config.tld_grouping && params.get("group").is_some_and(|group| group == "tld")
+}
+
+/// Orders results by top-level domain, keeping their ranking within each one. Top-level domains
+/// are ordered by their best result. Returns each top-level domain with its number of results.
+fn group_by_tld(results: &mut [SearchResult]) -> Vec<TldGroup> {
+    let mut groups: Vec<TldGroup> = Vec::new();
+    for result in results.iter() {
+        match groups.iter_mut().find(|group| group.tld == result.tld) {
+            Some(group) => group.count += 1,
+            None => groups.push(TldGroup {
+                tld: result.tld.clone(),
+                count: 1,
+            }),
+        }
+    }
+    // Stable, so the ranking within each group is kept.
+    results.sort_by_key(|result| {
+        groups
+            .iter()
+            .position(|group| group.tld == result.tld)
+            .unwrap_or_default()
+    });
+    groups
+}
+
+/// Builds the search options for a request from its query parameters.
+fn search_options(params: &HashMap<String, String>, config: &ServerConfig) -> SearchOptions {
+    let safe_search =
+        !(config.allow_disabling_safe_search && params.get("safe").is_some_and(|safe| safe == "0"));
+
+    SearchOptions {
+        safe_search,
+        snippet_threads: config.snippet_threads,
+        hidden_url_params: config.hidden_url_params.clone(),
+        snippet_fragments: config.snippet_fragments,
+        snippet_max_chars: config.snippet_max_chars,
+        dedupe_by_title: config.dedupe_by_title,
+        snippet_preference: config.snippet_preference.clone(),
+        collapse_redundant_snippets: config.collapse_redundant_snippets,
+        trusted_domains: config.trusted_domains.clone(),
+        news_mode: params.get("mode").is_some_and(|mode| mode == "news"),
+        title_only: config.title_search && params.get("mode").is_some_and(|mode| mode == "title"),
+        matched_terms: config.matched_terms,
+        ..Default::default()
+    }
 }
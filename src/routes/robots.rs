@@ -0,0 +1,36 @@
+use axum::{http::header, response::IntoResponse, Extension};
+
+use super::ServerState;
+
+/// Serves our own robots.txt, so that other crawlers don't index our result pages.
+pub async fn robots_handler(
+    Extension(ServerState { config, .. }): Extension<ServerState>,
+) -> impl IntoResponse {
+    (
+        [(header::CONTENT_TYPE, "text/plain; charset=utf-8")],
+        config.robots_txt.clone(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::routes::test_utils::{body_text, get, with_app, TestApp};
+
+    #[tokio::test]
+    async fn test_robots_handler() {
+        with_app(
+            "test_robots_handler",
+            |_config| {},
+            |TestApp { app, .. }| async move {
+                let response = get(&app, "/robots.txt").await?;
+                assert_eq!(response.status(), 200);
+                let body = body_text(response).await?;
+                assert!(body.contains("User-agent: *"));
+                assert!(body.lines().any(|line| line == "Disallow: /"));
+
+                Ok(())
+            },
+        )
+        .await;
+    }
+}
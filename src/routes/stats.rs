@@ -1,33 +1,39 @@
-use axum::{response::Html, Extension};
-use std::str::FromStr;
+use axum::{extract::Query, response::Html, Extension};
+use std::{cmp::Reverse, collections::HashMap};
 use tera::Context;
 
-use super::{ServerState, TEMPLATES};
+use super::ServerState;
 
+/// Renders domain stats, sorted alphabetically or with `sort=pages|size|recent` (descending).
 pub async fn stats_handler(
-    Extension(ServerState { indexer, config }): Extension<ServerState>,
+    Query(params): Query<HashMap<String, String>>,
+    Extension(ServerState {
+        indexer,
+        config,
+        templates,
+        ..
+    }): Extension<ServerState>,
 ) -> Html<String> {
     let mut context = Context::new();
     context.insert("title", &config.name);
 
-    match indexer.get_domain_stats() {
-        Ok(stats) => {
+    match indexer.get_domain_stats(config.size_format) {
+        Ok(mut stats) => {
             let total_pages: u64 = stats.iter().map(|s| s.page_count).sum();
-            let total_size: u64 = stats
-                .iter()
-                .map(|s| {
-                    bytesize::ByteSize::from_str(&s.total_size)
-                        .map(|size| size.as_u64())
-                        .unwrap_or(0)
-                })
-                .sum();
+            let total_size: u64 = stats.iter().map(|s| s.total_size_bytes).sum();
+
+            // Stats are sorted by domain already, and the sort is stable, so ties stay
+            // alphabetical.
+            match params.get("sort").map(String::as_str) {
+                Some("pages") => stats.sort_by_key(|s| Reverse(s.page_count)),
+                Some("size") => stats.sort_by_key(|s| Reverse(s.total_size_bytes)),
+                Some("recent") => stats.sort_by_key(|s| Reverse(s.last_updated)),
+                _ => {}
+            }
 
             context.insert("stats", &stats);
             context.insert("total_pages", &total_pages);
-            context.insert(
-                "total_size",
-                &humansize::format_size(total_size, humansize::DECIMAL),
-            );
+            context.insert("total_size", &config.size_format.format_size(total_size));
         }
         Err(e) => {
             eprintln!("ERROR: Failed to get domain stats: {e}");
@@ -36,7 +42,7 @@ pub async fn stats_handler(
     }
 
     Html(
-        TEMPLATES
+        templates
             .render("stats.html", &context)
             .unwrap_or_else(|e| {
                 eprintln!("Template error: {e}");
@@ -44,3 +50,36 @@ pub async fn stats_handler(
             }),
     )
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::routes::test_utils::{body_text, get, with_app, TestApp};
+
+    #[tokio::test]
+    async fn test_stats_handler_sort_by_pages() {
+        with_app(
+            "test_stats_handler_sort_by_pages",
+            |_config| {},
+            |TestApp { app, indexer, .. }| async move {
+                let pages = [
+                    ("https://a.com/", "a.com"),
+                    ("https://b.com/", "b.com"),
+                    ("https://b.com/2", "b.com"),
+                ];
+                for (url, domain) in pages {
+                    indexer.add_html(url, "<title>Page</title><body>Text</body>", domain)?;
+                }
+
+                let response = get(&app, "/stats?sort=pages").await?;
+                assert_eq!(response.status(), 200);
+                let body = body_text(response).await?;
+                let a_pos = body.find("<td>a.com</td>").unwrap();
+                let b_pos = body.find("<td>b.com</td>").unwrap();
+                assert!(b_pos < a_pos, "b.com has more pages and should come first");
+
+                Ok(())
+            },
+        )
+        .await;
+    }
+}
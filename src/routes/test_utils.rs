@@ -0,0 +1,77 @@
+use arc_swap::ArcSwap;
+use axum::{
+    body::{self, Body},
+    http::Request,
+    response::Response,
+    Router,
+};
+use std::{future::Future, sync::Arc};
+use tokio::sync::mpsc;
+use tower::ServiceExt;
+
+use super::LiveServerConfig;
+use crate::{
+    config::Config,
+    crawler::SharedCrawlStatus,
+    indexer::{Indexer, SearchPage},
+};
+
+/// A router on a test index, see [`with_app`].
+pub struct TestApp {
+    pub app: Router,
+    pub indexer: Arc<Indexer>,
+    pub crawl_status: SharedCrawlStatus,
+    /// Keeps the channel for the pages of crawls started through the API open.
+    pub _indexer_rx: mpsc::Receiver<SearchPage>,
+    pub config: Config,
+    /// Replaces the server config of `app` for the following requests, like a config reload.
+    pub live_config: LiveServerConfig,
+}
+
+/// Runs `f` with a router on a new index for `test_name`, after `configure` adjusted the test
+/// config. The index is deleted afterwards.
+pub async fn with_app<F, T>(test_name: &str, configure: impl FnOnce(&mut Config), f: F)
+where
+    F: FnOnce(TestApp) -> T,
+    T: Future<Output = anyhow::Result<()>>,
+{
+    let mut config = Config::load_test(test_name);
+    configure(&mut config);
+    let indexer = Arc::new(Indexer::new(&config.indexer).await.unwrap());
+    let crawl_status = SharedCrawlStatus::default();
+    let (indexer_tx, indexer_rx) = mpsc::channel(1);
+    let live_config = Arc::new(ArcSwap::from_pointee(config.server.clone()));
+    let app = super::create_reloadable_router(
+        indexer.clone(),
+        indexer_tx,
+        crawl_status.clone(),
+        &config,
+        live_config.clone(),
+    );
+
+    f(TestApp {
+        app,
+        indexer: indexer.clone(),
+        crawl_status,
+        _indexer_rx: indexer_rx,
+        config,
+        live_config,
+    })
+    .await
+    .unwrap();
+
+    // Clean up after test.
+    indexer.delete().await.unwrap();
+}
+
+/// Sends a GET request for `uri` to `app`.
+pub async fn get(app: &Router, uri: &str) -> anyhow::Result<Response> {
+    let request = Request::builder().uri(uri).body(Body::empty())?;
+    Ok(app.clone().oneshot(request).await?)
+}
+
+/// Reads the whole body of `response` as text.
+pub async fn body_text(response: Response) -> anyhow::Result<String> {
+    let body = body::to_bytes(response.into_body(), 1_000_000).await?;
+    Ok(String::from_utf8(body.to_vec())?)
+}